@@ -1,4 +1,11 @@
-use clap::{App, Arg, ArgMatches, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use code;
+use color;
+use convert;
+use format;
+use mono;
+use quantize;
 
 pub fn build_app<'a>(name: &str) -> ArgMatches<'a> {
     App::new(name)
@@ -65,7 +72,75 @@ pub fn build_app<'a>(name: &str) -> ArgMatches<'a> {
                 ),
         )
         .subcommand(SubCommand::with_name("convert")
-                .about("Convert 256 color PCX to 16 color BMP")
+                .about("Convert image, SRC format is detected, DST format is taken from its extension")
+                .arg(Arg::with_name("to")
+                        .help("destination format (default by DST extension)")
+                        .short("t")
+                        .long("to")
+                        .takes_value(true)
+                        .possible_values(&format::OUTPUT_FORMATS),
+                )
+                .arg(Arg::with_name("depth")
+                        .help("bits per pixel of the destination image")
+                        .short("d")
+                        .long("depth")
+                        .takes_value(true)
+                        .possible_values(&["1", "4", "8", "16", "24", "32"]),
+                )
+                .arg(Arg::with_name("colors")
+                        .help("number of palette colors (default 2^depth)")
+                        .short("n")
+                        .long("colors")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("compression")
+                        .help("compression of BMP (rle8, rle4, png), TGA, Sun Raster or SGI (rle) images")
+                        .short("c")
+                        .long("compression")
+                        .takes_value(true)
+                        .possible_values(&convert::COMPRESSIONS),
+                )
+                .arg(Arg::with_name("dither")
+                        .help("dithering used for palette reduction")
+                        .long("dither")
+                        .takes_value(true)
+                        .possible_values(&quantize::DITHER_MODES),
+                )
+                .arg(Arg::with_name("metric")
+                        .help("color distance used for palette matching")
+                        .short("m")
+                        .long("metric")
+                        .takes_value(true)
+                        .possible_values(&color::COLOR_METRICS),
+                )
+                .arg(Arg::with_name("plain")
+                        .help("write plain (ASCII) Netpbm")
+                        .long("plain"),
+                )
+                .arg(Arg::with_name("maxval")
+                        .help("maximum Netpbm sample value, upto 65535 (default 255)")
+                        .long("maxval")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("interlace")
+                        .help("write Adam7 interlaced PNG")
+                        .long("interlace"),
+                )
+                .arg(Arg::with_name("threshold")
+                        .help("brightness from which pixels become white in 1 bit images, WBMP and packed monochrome (default 128)")
+                        .long("threshold")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("layout")
+                        .help("layout of packed monochrome, rows or SSD1306 pages")
+                        .long("layout")
+                        .takes_value(true)
+                        .possible_values(&mono::LAYOUTS),
+                )
+                .arg(Arg::with_name("invert")
+                        .help("set bits of packed monochrome are black")
+                        .long("invert"),
+                )
                 .arg(Arg::with_name("SRC")
                         .help("Source image file")
                         .required(true)
@@ -77,6 +152,167 @@ pub fn build_app<'a>(name: &str) -> ArgMatches<'a> {
                         .index(2),
                 ),
         )
+        .subcommand(SubCommand::with_name("export-code")
+                .about("Export image as C header or Rust module of constant arrays")
+                .arg(Arg::with_name("lang")
+                        .help("language of the source code, guessed from the DST extension (.h, .c, .rs) if not set")
+                        .short("l")
+                        .long("lang")
+                        .takes_value(true)
+                        .possible_values(&code::LANGUAGES),
+                )
+                .arg(Arg::with_name("pixel-format")
+                        .help("pixel format of the data (default rgb565)")
+                        .short("f")
+                        .long("pixel-format")
+                        .takes_value(true)
+                        .possible_values(&code::PIXEL_FORMATS),
+                )
+                .arg(Arg::with_name("byte-order")
+                        .help("byte order of rgb565 and rgb888 pixels (default little)")
+                        .long("byte-order")
+                        .takes_value(true)
+                        .possible_values(&code::BYTE_ORDERS),
+                )
+                .arg(Arg::with_name("row-padding")
+                        .help("pad rows to a multiple of bytes (default 1)")
+                        .long("row-padding")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("name")
+                        .help("symbol name prefix (default SRC file name)")
+                        .long("name")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("rle")
+                        .help("PackBits compress the data")
+                        .long("rle"),
+                )
+                .arg(Arg::with_name("threshold")
+                        .help("brightness from which mono pixels are white (default 128)")
+                        .long("threshold")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("dither")
+                        .help("dithering used for indexed8 and mono pixels")
+                        .long("dither")
+                        .takes_value(true)
+                        .possible_values(&quantize::DITHER_MODES),
+                )
+                .arg(Arg::with_name("metric")
+                        .help("color distance used for palette matching")
+                        .short("m")
+                        .long("metric")
+                        .takes_value(true)
+                        .possible_values(&color::COLOR_METRICS),
+                )
+                .arg(Arg::with_name("layout")
+                        .help("layout of mono pixels, rows or SSD1306 pages")
+                        .long("layout")
+                        .takes_value(true)
+                        .possible_values(&mono::LAYOUTS),
+                )
+                .arg(Arg::with_name("invert")
+                        .help("set bits of mono pixels are black")
+                        .long("invert"),
+                )
+                .arg(Arg::with_name("SRC")
+                        .help("Source image file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(Arg::with_name("DST")
+                        .help("Destination source code file")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(SubCommand::with_name("raw")
+                .about("Raw framebuffer pixels, formats are named from the high bits: rgb565, bgr888, rgba8888, xrgb1555, gray8")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("import")
+                        .about("Convert raw pixels to BMP, 16 and 32 bit pixels keep their bitfields")
+                        .arg(Arg::with_name("width")
+                                .help("image width in pixels")
+                                .long("width")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(Arg::with_name("height")
+                                .help("image height in pixels")
+                                .long("height")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(Arg::with_name("format")
+                                .help("pixel format")
+                                .short("f")
+                                .long("format")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(Arg::with_name("stride")
+                                .help("bytes per row (default width * pixel size)")
+                                .long("stride")
+                                .takes_value(true),
+                        )
+                        .arg(Arg::with_name("byte-order")
+                                .help("byte order of pixel values (default little)")
+                                .long("byte-order")
+                                .takes_value(true)
+                                .possible_values(&code::BYTE_ORDERS),
+                        )
+                        .arg(Arg::with_name("bottom-up")
+                                .help("the first row is the bottom one")
+                                .long("bottom-up"),
+                        )
+                        .arg(Arg::with_name("SRC")
+                                .help("Source raw file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("DST")
+                                .help("Destination BMP file")
+                                .required(true)
+                                .index(2),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("export")
+                        .about("Convert image to raw pixels")
+                        .arg(Arg::with_name("format")
+                                .help("pixel format")
+                                .short("f")
+                                .long("format")
+                                .takes_value(true)
+                                .required(true),
+                        )
+                        .arg(Arg::with_name("stride")
+                                .help("bytes per row, padded with zeros (default width * pixel size)")
+                                .long("stride")
+                                .takes_value(true),
+                        )
+                        .arg(Arg::with_name("byte-order")
+                                .help("byte order of pixel values (default little)")
+                                .long("byte-order")
+                                .takes_value(true)
+                                .possible_values(&code::BYTE_ORDERS),
+                        )
+                        .arg(Arg::with_name("bottom-up")
+                                .help("write the bottom row first")
+                                .long("bottom-up"),
+                        )
+                        .arg(Arg::with_name("SRC")
+                                .help("Source image file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("DST")
+                                .help("Destination raw file")
+                                .required(true)
+                                .index(2),
+                        ),
+                ),
+        )
         .subcommand(SubCommand::with_name("logo")
                 .about("Add logo to 24 bit per pixel BMP file")
                 .arg(Arg::with_name("SRC")
@@ -95,8 +331,253 @@ pub fn build_app<'a>(name: &str) -> ArgMatches<'a> {
                         .index(3),
                 ),
         )
+        .subcommand(SubCommand::with_name("dcx")
+                .about("Multi-page DCX (PCX container) tools")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list")
+                        .about("List DCX pages")
+                        .arg(Arg::with_name("FILE")
+                                .help("DCX file")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("extract")
+                        .about("Extract DCX page into PCX or BMP (by DST extension)")
+                        .arg(Arg::with_name("SRC")
+                                .help("Source DCX file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("PAGE")
+                                .help("Page number, starting from 1")
+                                .required(true)
+                                .validator(is_number_from_one)
+                                .index(2),
+                        )
+                        .arg(Arg::with_name("DST")
+                                .help("Destination image file")
+                                .required(true)
+                                .index(3),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("build")
+                        .about("Build DCX from PCX and BMP files")
+                        .arg(Arg::with_name("DST")
+                                .help("Destination DCX file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("SRC")
+                                .help("Page image files")
+                                .required(true)
+                                .multiple(true)
+                                .index(2),
+                        ),
+                ),
+        )
+        .subcommand(SubCommand::with_name("os2")
+                .about("OS/2 bitmap array, icon and pointer tools")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list")
+                        .about("List images of OS/2 bitmap file")
+                        .arg(Arg::with_name("FILE")
+                                .help("OS/2 bitmap, icon or pointer file")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("extract")
+                        .about("Extract image into BMP, icon masks become alpha channel")
+                        .arg(Arg::with_name("SRC")
+                                .help("Source OS/2 file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("IMAGE")
+                                .help("Image number, starting from 1")
+                                .required(true)
+                                .validator(is_number_from_one)
+                                .index(2),
+                        )
+                        .arg(Arg::with_name("DST")
+                                .help("Destination BMP file")
+                                .required(true)
+                                .index(3),
+                        ),
+                ),
+        )
+        .subcommand(SubCommand::with_name("ico")
+                .about("Windows icon and cursor tools")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list")
+                        .about("List images of ICO or CUR file")
+                        .arg(Arg::with_name("FILE")
+                                .help("Icon or cursor file")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("extract")
+                        .about("Extract image into BMP, AND mask becomes alpha channel")
+                        .arg(Arg::with_name("SRC")
+                                .help("Source ICO or CUR file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("IMAGE")
+                                .help("Image number, starting from 1")
+                                .required(true)
+                                .validator(is_number_from_one)
+                                .index(2),
+                        )
+                        .arg(Arg::with_name("DST")
+                                .help("Destination BMP file")
+                                .required(true)
+                                .index(3),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("build")
+                        .about("Build ICO or CUR (by DST extension) from images upto 256x256")
+                        .arg(Arg::with_name("hotspot")
+                                .help("cursor hotspot X,Y (default 0,0)")
+                                .long("hotspot")
+                                .takes_value(true),
+                        )
+                        .arg(Arg::with_name("DST")
+                                .help("Destination ICO or CUR file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("SRC")
+                                .help("Image files, one for every size")
+                                .required(true)
+                                .multiple(true)
+                                .index(2),
+                        ),
+                ),
+        )
+        .subcommand(SubCommand::with_name("avi")
+                .about("AVI video from BMP frames")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("build")
+                        .about("Build AVI from same sized images of the directory, sorted by name")
+                        .arg(Arg::with_name("fps")
+                                .help("frames per second (default 25)")
+                                .short("f")
+                                .long("fps")
+                                .takes_value(true),
+                        )
+                        .arg(Arg::with_name("compression")
+                                .help("frame compression, rle8 requires 8 bits per pixel frames")
+                                .short("c")
+                                .long("compression")
+                                .takes_value(true)
+                                .possible_values(&["none", "rle8"]),
+                        )
+                        .arg(Arg::with_name("DST")
+                                .help("Destination AVI file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("DIR")
+                                .help("Directory of frame images")
+                                .required(true)
+                                .index(2),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("extract")
+                        .about("Extract AVI frames into BMP files of the directory")
+                        .arg(Arg::with_name("SRC")
+                                .help("Source AVI file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("DIR")
+                                .help("Destination directory")
+                                .required(true)
+                                .index(2),
+                        ),
+                ),
+        )
+        .subcommand(SubCommand::with_name("gif")
+                .about("Animated GIF tools")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("build")
+                        .about("Build animated GIF from images, one frame for every image")
+                        .arg(Arg::with_name("delay")
+                                .help("delay between frames in 1/100 of second (default 10)")
+                                .long("delay")
+                                .takes_value(true),
+                        )
+                        .arg(Arg::with_name("loop")
+                                .help("number of animation repetitions, 0 is forever (default)")
+                                .long("loop")
+                                .takes_value(true),
+                        )
+                        .arg(Arg::with_name("transparent")
+                                .help("transparent palette index")
+                                .long("transparent")
+                                .takes_value(true),
+                        )
+                        .arg(Arg::with_name("local-palettes")
+                                .help("give every frame its own palette instead of the global one")
+                                .long("local-palettes"),
+                        )
+                        .arg(Arg::with_name("colors")
+                                .help("number of palette colors (default 256)")
+                                .short("n")
+                                .long("colors")
+                                .takes_value(true),
+                        )
+                        .arg(Arg::with_name("dither")
+                                .help("dithering used for palette reduction")
+                                .long("dither")
+                                .takes_value(true)
+                                .possible_values(&quantize::DITHER_MODES),
+                        )
+                        .arg(Arg::with_name("metric")
+                                .help("color distance used for palette matching")
+                                .short("m")
+                                .long("metric")
+                                .takes_value(true)
+                                .possible_values(&color::COLOR_METRICS),
+                        )
+                        .arg(Arg::with_name("DST")
+                                .help("Destination GIF file")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(Arg::with_name("SRC")
+                                .help("Frame image files")
+                                .required(true)
+                                .multiple(true)
+                                .index(2),
+                        ),
+                ),
+        )
+        .subcommand(SubCommand::with_name("extract")
+                .about("Extract bitmaps drawn by WMF or EMF metafile into BMP files of the directory")
+                .arg(Arg::with_name("SRC")
+                        .help("Source WMF or EMF file")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(Arg::with_name("DIR")
+                        .help("Destination directory")
+                        .required(true)
+                        .index(2),
+                ),
+        )
         .subcommand(SubCommand::with_name("display")
                 .about("Display image")
+                .arg(Arg::with_name("page")
+                        .help("page of multi-page image, starting from 1")
+                        .short("p")
+                        .long("page")
+                        .takes_value(true)
+                        .validator(is_number_from_one),
+                )
                 .arg(Arg::with_name("IMAGE")
                         .help("Image file for displaying")
                         .required(true)
@@ -105,3 +586,12 @@ pub fn build_app<'a>(name: &str) -> ArgMatches<'a> {
         )
         .get_matches()
 }
+
+/// Page and image numbers start from 1
+fn is_number_from_one(v: String) -> Result<(), String> {
+    match v.parse::<usize>() {
+        Ok(0) => Err("numbering starts from 1".to_owned()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
@@ -0,0 +1,464 @@
+//! # Format desciption
+//! AVI is a RIFF file: "RIFF" size "AVI " followed by chunks, lists of
+//! chunks are "LIST" size type. The layout written here is
+//!
+//! * LIST hdrl: avih main header, LIST strl with strh stream header
+//!   and strf stream format, that is BITMAPINFOHEADER and color table
+//! * LIST movi: frame chunks 00db (uncompressed) or 00dc (compressed)
+//! * idx1: index of the frame chunks
+//!
+//! Microsoft RLE (mrle) codec frames are BMP RLE8 bitmaps, delta frames
+//! skip the pixels unchanged since the previous frame.
+//! https://docs.microsoft.com/en-us/windows/win32/directshow/avi-riff-file-reference
+//! https://wiki.multimedia.cx/index.php/Microsoft_RLE
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+use encoding;
+use format;
+
+/// Main header flag, the file has idx1 index
+pub const AVIF_HASINDEX: u32 = 0x10;
+/// Index flag, the frame doesn't depend on the previous ones
+pub const AVIIF_KEYFRAME: u32 = 0x10;
+pub const AVI_MAIN_HEADER_SIZE: usize = 56;
+pub const AVI_STREAM_HEADER_SIZE: usize = 56;
+/// Frame rate used if not set
+pub const AVI_DEFAULT_FPS: u32 = 25;
+
+/// RIFF chunk: four character code and data, list data starts with the list type
+struct Chunk<'a> {
+    id: &'a [u8],
+    data: &'a [u8],
+}
+
+/// Split data into chunks, the truncated last chunk is cut to the data end
+fn chunks<'a>(data: &'a [u8]) -> Vec<Chunk<'a>> {
+    let mut result = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = (&data[pos + 4..pos + 8]).read_u32::<LittleEndian>().unwrap() as usize;
+        let start = pos + 8;
+        let end = start.saturating_add(size).min(data.len());
+        result.push(Chunk {
+            id: &data[pos..pos + 4],
+            data: &data[start..end],
+        });
+        // chunks are word aligned
+        pos = start.saturating_add(size).saturating_add(size % 2);
+    }
+    result
+}
+
+/// Sub-chunks of the list of the given type
+fn list<'a>(chunk: &Chunk<'a>, kind: &[u8]) -> Option<Vec<Chunk<'a>>> {
+    if chunk.id == b"LIST" && chunk.data.len() >= 4 && &chunk.data[..4] == kind {
+        Some(chunks(&chunk.data[4..]))
+    } else {
+        None
+    }
+}
+
+/// Collect frame chunks of the stream, "rec " lists group chunks of a frame
+fn collect_frames(chunks: &[Chunk], stream_id: &[u8], frames: &mut Vec<Vec<u8>>) {
+    for c in chunks {
+        if let Some(rec) = list(c, b"rec ") {
+            collect_frames(&rec, stream_id, frames);
+        } else if &c.id[..2] == stream_id && (&c.id[2..] == b"db" || &c.id[2..] == b"dc") {
+            frames.push(c.data.to_vec());
+        }
+    }
+}
+
+fn write_chunk(w: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    w.extend_from_slice(id);
+    w.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+    w.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        w.push(0);
+    }
+}
+
+fn write_list(w: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut list = kind.to_vec();
+    list.extend_from_slice(data);
+    write_chunk(w, b"LIST", &list);
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Single video stream AVI with DIB frames
+#[derive(Debug)]
+pub struct AVIFile {
+    /// Frame rate is `rate / scale` frames per second
+    pub rate: u32,
+    pub scale: u32,
+    /// Codec four character code of the stream header
+    pub handler: [u8; 4],
+    /// Stream format chunk: info header and color table of frames
+    pub format: Vec<u8>,
+    /// Frame chunks of the video stream as they are stored
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl AVIFile {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<AVIFile> {
+        let mut f = BufReader::new(File::open(p)?);
+        AVIFile::load_from_reader(&mut f)
+    }
+
+    /// Load the first video stream
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<AVIFile> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"AVI " {
+            return Err(invalid_data("Not an AVI file".to_owned()));
+        }
+        let top = chunks(&data[12..]);
+        let header_list = top.iter().filter_map(|c| list(c, b"hdrl")).next()
+            .ok_or_else(|| invalid_data("AVI has no hdrl list".to_owned()))?;
+        // stream number is the position of its strl list
+        let mut video = None;
+        for (stream, strl) in header_list.iter().filter_map(|c| list(c, b"strl")).enumerate() {
+            let strh = strl.iter().find(|c| c.id == b"strh");
+            let strf = strl.iter().find(|c| c.id == b"strf");
+            if let (Some(strh), Some(strf)) = (strh, strf) {
+                if strh.data.len() >= 28 && &strh.data[..4] == b"vids" {
+                    video = Some((stream, strh.data, strf.data));
+                    break;
+                }
+            }
+        }
+        let (stream, strh, strf) = video.ok_or_else(|| invalid_data("AVI has no video stream".to_owned()))?;
+        let mut handler = [0u8; 4];
+        handler.copy_from_slice(&strh[4..8]);
+        let scale = (&strh[20..24]).read_u32::<LittleEndian>()?;
+        let rate = (&strh[24..28]).read_u32::<LittleEndian>()?;
+
+        let movi = top.iter().filter_map(|c| list(c, b"movi")).next()
+            .ok_or_else(|| invalid_data("AVI has no movi list".to_owned()))?;
+        let mut frames = Vec::new();
+        collect_frames(&movi, format!("{:02}", stream).as_bytes(), &mut frames);
+        Ok(AVIFile {
+            rate: rate,
+            scale: scale,
+            handler: handler,
+            format: strf.to_vec(),
+            frames: frames,
+        })
+    }
+
+    /// Frame format, the bitmap size is of the uncompressed frame
+    pub fn info(&self) -> io::Result<bmp::BMPInfo> {
+        bmp::BMPInfo::load_from_reader(&mut Cursor::new(&self.format[..]))
+    }
+
+    pub fn fps(&self) -> f64 {
+        if self.scale == 0 { 0.0 } else { self.rate as f64 / self.scale as f64 }
+    }
+
+    /// Decode frame `idx`, compressed frames may depend on the previous ones
+    pub fn frame(&self, idx: usize) -> io::Result<bmp::BMPImage> {
+        if idx >= self.frames.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No frame {} in AVI, it has {} frames", idx + 1, self.frames.len()),
+            ));
+        }
+        Ok(self.decode_frames(idx + 1)?.pop().unwrap())
+    }
+
+    /// Decode all frames
+    pub fn images(&self) -> io::Result<Vec<bmp::BMPImage>> {
+        self.decode_frames(self.frames.len())
+    }
+
+    fn decode_frames(&self, count: usize) -> io::Result<Vec<bmp::BMPImage>> {
+        let info = self.info()?;
+        let rle = match info.bmi_header.get_compression_type() {
+            bmp::BMPCompression::RGB => false,
+            bmp::BMPCompression::RLE8 => true,
+            c => return Err(invalid_data(format!("Unsupported AVI codec: {}", c))),
+        };
+        let width = info.bmi_header.get_width();
+        let height = info.bmi_header.get_height();
+        if width <= 0 || height == 0 || height == i32::MIN {
+            return Err(invalid_data(format!("Invalid AVI frame size: {}x{} px", width, height)));
+        }
+        // frames become BMP images of 32 bit sizes
+        let size = bmp::row_stride(width, info.bmi_header.get_bit_count()).checked_mul(height.abs() as usize)
+            .filter(|&size| size <= i32::MAX as usize)
+            .ok_or_else(|| invalid_data(format!("AVI frame of {}x{} px is too large", width, height)))?;
+        let mut images = Vec::with_capacity(count);
+        let mut data = Vec::new();
+        for frame in &self.frames[..count] {
+            // empty chunks repeat the previous frame
+            if !frame.is_empty() {
+                data = if rle {
+                    encoding::rle8_decode_delta(frame, &data, width, height)?
+                } else {
+                    frame.clone()
+                };
+            }
+            data.resize(size, 0);
+            let mut info = self.info()?;
+            info.bmi_header.set_encoding(bmp::BMPCompression::RGB);
+            info.bmi_header.set_bitmap_size(size as i32);
+            let offset = bmp::BMP_FILE_HEADER_SIZE as i32 + info.get_size();
+            images.push(bmp::BMPImage {
+                header: bmp::BMPFileHeader::new(offset + size as i32, offset),
+                info: info,
+                bitmap: bmp::Bitmap {
+                    data: data.clone(),
+                    decoded_from: None,
+                },
+            });
+        }
+        Ok(images)
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        let info = self.info()?;
+        let width = info.bmi_header.get_width() as u32;
+        let height = info.bmi_header.get_height().abs() as u32;
+        let compressed = match info.bmi_header.get_compression_type() {
+            bmp::BMPCompression::RGB => false,
+            _ => true,
+        };
+        let max_frame = self.frames.iter().map(|f| f.len()).max().unwrap_or(0) as u32;
+        let rate = if self.scale == 0 { 0 } else { self.rate as u64 / self.scale as u64 };
+
+        let mut avih = Vec::with_capacity(AVI_MAIN_HEADER_SIZE);
+        avih.write_u32::<LittleEndian>(if self.rate == 0 { 0 } else {
+            (1_000_000u64 * self.scale as u64 / self.rate as u64) as u32
+        })?;
+        avih.write_u32::<LittleEndian>((max_frame as u64 * rate) as u32)?;
+        avih.write_u32::<LittleEndian>(0)?;
+        avih.write_u32::<LittleEndian>(AVIF_HASINDEX)?;
+        avih.write_u32::<LittleEndian>(self.frames.len() as u32)?;
+        avih.write_u32::<LittleEndian>(0)?;
+        // streams
+        avih.write_u32::<LittleEndian>(1)?;
+        avih.write_u32::<LittleEndian>(max_frame)?;
+        avih.write_u32::<LittleEndian>(width)?;
+        avih.write_u32::<LittleEndian>(height)?;
+        avih.extend_from_slice(&[0u8; 16]);
+
+        let mut strh = Vec::with_capacity(AVI_STREAM_HEADER_SIZE);
+        strh.extend_from_slice(b"vids");
+        strh.extend_from_slice(&self.handler);
+        // flags, priority and language, initial frames
+        strh.extend_from_slice(&[0u8; 12]);
+        strh.write_u32::<LittleEndian>(self.scale)?;
+        strh.write_u32::<LittleEndian>(self.rate)?;
+        strh.write_u32::<LittleEndian>(0)?;
+        strh.write_u32::<LittleEndian>(self.frames.len() as u32)?;
+        strh.write_u32::<LittleEndian>(max_frame)?;
+        // default quality, no fixed sample size
+        strh.write_i32::<LittleEndian>(-1)?;
+        strh.write_u32::<LittleEndian>(0)?;
+        strh.write_u16::<LittleEndian>(0)?;
+        strh.write_u16::<LittleEndian>(0)?;
+        strh.write_u16::<LittleEndian>(width as u16)?;
+        strh.write_u16::<LittleEndian>(height as u16)?;
+
+        let mut strl = Vec::new();
+        write_chunk(&mut strl, b"strh", &strh);
+        write_chunk(&mut strl, b"strf", &self.format);
+        let mut hdrl = Vec::new();
+        write_chunk(&mut hdrl, b"avih", &avih);
+        write_list(&mut hdrl, b"strl", &strl);
+
+        let id = if compressed { b"00dc" } else { b"00db" };
+        let mut movi = Vec::new();
+        let mut idx1 = Vec::with_capacity(self.frames.len() * 16);
+        for frame in &self.frames {
+            idx1.extend_from_slice(id);
+            idx1.write_u32::<LittleEndian>(AVIIF_KEYFRAME)?;
+            // offsets are counted from the movi list type
+            idx1.write_u32::<LittleEndian>(movi.len() as u32 + 4)?;
+            idx1.write_u32::<LittleEndian>(frame.len() as u32)?;
+            write_chunk(&mut movi, id, frame);
+        }
+
+        let mut riff = b"AVI ".to_vec();
+        write_list(&mut riff, b"hdrl", &hdrl);
+        write_list(&mut riff, b"movi", &movi);
+        write_chunk(&mut riff, b"idx1", &idx1);
+        w.write_all(b"RIFF")?;
+        w.write_u32::<LittleEndian>(riff.len() as u32)?;
+        w.write_all(&riff)
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, p: P) -> io::Result<()> {
+        let mut f = BufWriter::new(File::create(p)?);
+        self.save_to_writer(&mut f)?;
+        f.flush()
+    }
+}
+
+impl fmt::Display for AVIFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} frames, {:.3} fps, codec {:?}", self.frames.len(), self.fps(), String::from_utf8_lossy(&self.handler))?;
+        if let Ok(info) = self.info() {
+            write!(
+                f, ", {}x{} px, {} bits per pixel, {}",
+                info.bmi_header.get_width(), info.bmi_header.get_height().abs(),
+                info.bmi_header.get_bit_count(), info.bmi_header.get_compression_type(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Image files of the directory sorted by name, other files are skipped
+pub fn list_images<P: AsRef<Path>>(dir: P) -> io::Result<Vec<String>> {
+    let mut images = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && format::detect(&path).is_ok() {
+            images.push(path.to_string_lossy().into_owned());
+        }
+    }
+    images.sort();
+    Ok(images)
+}
+
+/// Build AVI from same sized frames, palette frames are 8 bits per pixel
+/// sharing the palette of the first frame, the others are 24 bits per pixel.
+/// RLE8 requires palette frames.
+pub fn build(sources: &[String], fps: u32, rle: bool) -> io::Result<AVIFile> {
+    let mut format = Vec::new();
+    let mut first: Option<bmp::BMPInfo> = None;
+    let mut frames = Vec::with_capacity(sources.len());
+    for src in sources {
+        let pixels = format::load_pixels(src, 0)?;
+        let mut image = match pixels.data {
+            bmp::PixelData::Indexed { .. } => bmp::BMPImage::from_pixels_with_depth(&pixels, 8)?,
+            _ if rle => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} has no palette, RLE8 requires 8 bits per pixel frames", src),
+            )),
+            _ => bmp::BMPImage::from_pixels_with_depth(&pixels, 24)?,
+        };
+        if rle {
+            image.encode_bitmap(bmp::BMPCompression::RLE8)?;
+            // frame sizes vary, zero is the uncompressed size
+            image.info.bmi_header.set_bitmap_size(0);
+        }
+        if let Some(ref f) = first {
+            check_frame(src, f, &image.info)?;
+        } else {
+            image.info.save_to_writer(&mut format)?;
+        }
+        frames.push(image.bitmap.data);
+        if first.is_none() {
+            first = Some(image.info);
+        }
+    }
+    if first.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No frames to build AVI from".to_owned()));
+    }
+    Ok(AVIFile {
+        rate: fps,
+        scale: 1,
+        handler: if rle { *b"mrle" } else { *b"DIB " },
+        format: format,
+        frames: frames,
+    })
+}
+
+/// Frames share the size, bit count and palette of the first frame
+fn check_frame(src: &str, first: &bmp::BMPInfo, info: &bmp::BMPInfo) -> io::Result<()> {
+    let (a, b) = (&first.bmi_header, &info.bmi_header);
+    if a.get_width() != b.get_width() || a.get_height() != b.get_height() || a.get_bit_count() != b.get_bit_count() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is {}x{} px, {} bits per pixel, unlike the first frame {}x{} px, {} bits per pixel",
+                src, b.get_width(), b.get_height(), b.get_bit_count(),
+                a.get_width(), a.get_height(), a.get_bit_count(),
+            ),
+        ));
+    }
+    let palette = |i: &bmp::BMPInfo| i.bmi_colors.iter().map(|c| (c.red(), c.green(), c.blue())).collect::<Vec<_>>();
+    if palette(first) != palette(info) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} palette differs from the palette of the first frame", src),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_aligned_and_cut() {
+        let data = [b'a', b'b', b'c', b'd', 1, 0, 0, 0, 7, 0, b'e', b'f', b'g', b'h', 9, 0, 0, 0, 1, 2];
+        let result = chunks(&data);
+        assert_eq!(result.len(), 2);
+        assert_eq!((result[0].id, result[0].data), (&b"abcd"[..], &[7u8][..]));
+        assert_eq!((result[1].id, result[1].data), (&b"efgh"[..], &[1u8, 2][..]));
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let pixels = bmp::Pixels::new(3, 2, bmp::PixelData::RGB((0..18).collect()));
+        let image = bmp::BMPImage::from_pixels_with_depth(&pixels, 24).unwrap();
+        let mut format = Vec::new();
+        image.info.save_to_writer(&mut format).unwrap();
+        let avi = AVIFile {
+            rate: 10,
+            scale: 1,
+            handler: *b"DIB ",
+            format: format,
+            frames: vec![image.bitmap.data.clone(), Vec::new()],
+        };
+        let mut data = Vec::new();
+        avi.save_to_writer(&mut data).unwrap();
+        let loaded = AVIFile::load_from_reader(&mut &data[..]).unwrap();
+        assert_eq!((loaded.rate, loaded.scale, loaded.handler), (10, 1, *b"DIB "));
+        assert_eq!(loaded.format, avi.format);
+        assert_eq!(loaded.frames, avi.frames);
+        // the empty frame repeats the first one
+        for mut frame in loaded.images().unwrap() {
+            assert_eq!(frame.to_pixels().unwrap().to_rgb(), pixels.to_rgb());
+        }
+    }
+
+    #[test]
+    fn invalid_frame_sizes() {
+        let pixels = bmp::Pixels::new(4, 2, bmp::PixelData::Indexed {
+            palette: vec![bmp::RGBQuad::new(0, 0, 0); 256],
+            indexes: vec![1; 8],
+        });
+        let mut image = bmp::BMPImage::from_pixels(&pixels).unwrap();
+        image.encode_bitmap(bmp::BMPCompression::RLE8).unwrap();
+        let mut format = Vec::new();
+        image.info.save_to_writer(&mut format).unwrap();
+        let mut avi = AVIFile {
+            rate: 10,
+            scale: 1,
+            handler: *b"mrle",
+            format: format,
+            frames: vec![image.bitmap.data.clone()],
+        };
+        assert_eq!(avi.images().unwrap().len(), 1);
+        // top-down, huge and empty frames
+        for &(width, height) in &[(4, -2), (0x7FFF_FFFF, 0x7FFF_FFFF), (4, i32::MIN), (0, 2)] {
+            (&mut avi.format[4..]).write_i32::<LittleEndian>(width).unwrap();
+            (&mut avi.format[8..]).write_i32::<LittleEndian>(height).unwrap();
+            assert_eq!(avi.images().unwrap_err().kind(), io::ErrorKind::InvalidData, "{}x{}", width, height);
+        }
+    }
+}
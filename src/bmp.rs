@@ -15,6 +15,9 @@
 //! The four types of bitmap headers are differentiated by the Size member,
 //! which is the first DWORD in each of the structures.
 //!
+//! A packed DIB (clipboard CF_DIB, resources, AVI frames, icons) is the same
+//! without BITMAPFILEHEADER, the bitmap follows the color table.
+//!
 //! ## For more info
 //! see https://msdn.microsoft.com/en-us/library/dd183386(v=vs.85).aspx
 //! and https://msdn.microsoft.com/en-us/library/dd183391(v=vs.85).aspx
@@ -23,13 +26,14 @@ use std::{self, fmt};
 use std::error::Error;
 use std::fs::File;
 use std::path::Path;
-use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use rand::{self, Rng};
 
 use std::process;
 
-use encoding::Rle8;
+use encoding::{Rle4, Rle8};
+use png;
 
 #[derive(Debug, Copy, Clone)]
 pub enum BMPCompression {
@@ -95,10 +99,119 @@ impl BMPCompression {
 }
 
 pub const BMP_FILE_HEADER_SIZE: u64 = 14;
+/// OS/2 1.x BITMAPCOREHEADER
+pub const BMP_CORE_HEADER_SIZE: i32 = 12;
+/// OS/2 2.x BITMAPINFOHEADER2 may be truncated down to 16 bytes
+pub const BMP_OS2_V2_MIN_HEADER_SIZE: i32 = 16;
+pub const BMP_OS2_V2_HEADER_SIZE: i32 = 64;
 pub const BMP_INFO_HEADER_SIZE: i32 = 40;
 pub const BMP_V4_INFO_HEADER_SIZE: i32 = 108;
 pub const BMP_V5_INFO_HEADER_SIZE: i32 = 124;
 
+/// LCS_sRGB color space type of the V4 and V5 headers
+pub const LCS_SRGB: i32 = 0x7352_4742;
+
+/// Size in bytes of one bitmap row, rows are padded to a 4-byte boundary.
+/// Negative widths have no rows, they are checked by the callers.
+/// https://en.wikipedia.org/wiki/BMP_file_format#Pixel_storage
+pub fn row_stride(width: i32, bit_count: i16) -> usize {
+    ((bit_count as i64 * width as i64 + 31) / 32 * 4).max(0) as usize
+}
+
+/// Position in the byte of pixel `x` of 1, 2, 4 or 8 bits per pixel,
+/// the leftmost pixel takes the most significant bits
+pub fn pixel_shift(x: usize, bit_count: i16) -> usize {
+    let pixels_per_byte = 8 / bit_count as usize;
+    (pixels_per_byte - 1 - x % pixels_per_byte) * bit_count as usize
+}
+
+/// Pack a row of pixel values into bytes, the last byte is padded with zero bits
+pub fn pack_row(row: &[u8], bit_count: i16) -> Vec<u8> {
+    let pixels_per_byte = 8 / bit_count as usize;
+    let mask = ((1u16 << bit_count) - 1) as u8;
+    let mut packed = vec![0u8; (row.len() + pixels_per_byte - 1) / pixels_per_byte];
+    for (x, value) in row.iter().enumerate() {
+        packed[x / pixels_per_byte] |= (value & mask) << pixel_shift(x, bit_count);
+    }
+    packed
+}
+
+/// Decoded image pixels, rows are stored top-down without padding.
+/// This is the common ground for conversions between BMP and other formats.
+#[derive(Debug, Clone)]
+pub struct Pixels {
+    pub width: u32,
+    pub height: u32,
+    pub data: PixelData,
+}
+
+#[derive(Debug, Clone)]
+pub enum PixelData {
+    /// One color table index per pixel
+    Indexed {
+        palette: Vec<RGBQuad>,
+        indexes: Vec<u8>,
+    },
+    /// Three bytes per pixel in red, green, blue order
+    RGB(Vec<u8>),
+    /// Four bytes per pixel in red, green, blue, alpha order
+    RGBA(Vec<u8>),
+}
+
+impl Pixels {
+    pub fn new(width: u32, height: u32, data: PixelData) -> Pixels {
+        Pixels {
+            width: width,
+            height: height,
+            data: data,
+        }
+    }
+    pub fn has_alpha(&self) -> bool {
+        match self.data {
+            PixelData::RGBA(_) => true,
+            _ => false,
+        }
+    }
+    /// Pixels as red, green, blue triples, alpha is dropped
+    pub fn to_rgb(&self) -> Vec<u8> {
+        match self.data {
+            PixelData::Indexed { ref palette, ref indexes } => {
+                let mut rgb = Vec::with_capacity(indexes.len() * 3);
+                for idx in indexes {
+                    let c = palette.get(*idx as usize).cloned().unwrap_or(RGBQuad::new(0, 0, 0));
+                    rgb.push(c.rgb_red);
+                    rgb.push(c.rgb_green);
+                    rgb.push(c.rgb_blue);
+                }
+                rgb
+            },
+            PixelData::RGB(ref rgb) => rgb.clone(),
+            PixelData::RGBA(ref rgba) => {
+                let mut rgb = Vec::with_capacity(rgba.len() / 4 * 3);
+                for px in rgba.chunks(4) {
+                    rgb.extend_from_slice(&px[..3]);
+                }
+                rgb
+            },
+        }
+    }
+    /// Pixels as red, green, blue, alpha quads, opaque if there is no alpha
+    pub fn to_rgba(&self) -> Vec<u8> {
+        match self.data {
+            PixelData::RGBA(ref rgba) => rgba.clone(),
+            _ => {
+                let rgb = self.to_rgb();
+                let mut rgba = Vec::with_capacity(rgb.len() / 3 * 4);
+                for px in rgb.chunks(3) {
+                    rgba.extend_from_slice(px);
+                    rgba.push(255);
+                }
+                rgba
+            },
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Bitmap {
     pub data: Vec<u8>,
@@ -136,7 +249,7 @@ impl Bitmap {
                             // new colors for border
                             let mut mask = (rng.next_u32() % max_color) as u8;
                             // position in u8 bit fields
-                            let shift = (pixels - idx) * bc;
+                            let shift = pixel_shift((idx - 1) as usize, bit_count) as i32;
                             // move mask (colors) into appropriate position
                             mask = mask << shift;
                             // clear border pixels (bits)
@@ -323,6 +436,364 @@ pub struct BMPImage {
 }
 
 impl BMPImage {
+    /// Build an uncompressed palette image from top-down color indexes,
+    /// one index per pixel. Indexes are packed according to `bit_count`.
+    pub fn from_indexed(
+        width: i32, height: i32,
+        bit_count: i16,
+        palette: Vec<RGBQuad>,
+        indexes: &[u8],
+    ) -> io::Result<BMPImage> {
+        match bit_count {
+            1 | 2 | 4 | 8 => {},
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bits per pixel is not a palette format", bit_count),
+            )),
+        }
+        if palette.len() > 1 << bit_count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} colors don't fit into {} bits per pixel", palette.len(), bit_count),
+            ));
+        }
+        if indexes.len() != (width * height) as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected {} pixels, got {}", width * height, indexes.len()),
+            ));
+        }
+        let stride = row_stride(width, bit_count);
+        let mut data = vec![0u8; stride * height as usize];
+        // bitmap rows are stored bottom-up
+        for (y, row) in indexes.chunks(width as usize).rev().enumerate() {
+            let packed = pack_row(row, bit_count);
+            data[y * stride..y * stride + packed.len()].copy_from_slice(&packed);
+        }
+
+        let file_header_size = BMP_FILE_HEADER_SIZE as i32 + BMP_INFO_HEADER_SIZE + 4 * palette.len() as i32;
+        let bitmap_size = data.len() as i32;
+        let colors = palette.len() as i32;
+        Ok(BMPImage {
+            header: BMPFileHeader::new(file_header_size + bitmap_size, file_header_size),
+            info: BMPInfo {
+                bmi_header: BMPGenericInfoHeader::Info(BMPInfoHeader::new(
+                    width, height,
+                    bit_count,
+                    bitmap_size,
+                    0, 0,  // x, y pixels per meter (ignored)
+                    colors, colors,  // colors used, important
+                )),
+                bmi_colors: palette,
+            },
+            bitmap: Bitmap {
+                data: data,
+                decoded_from: None,
+            },
+        })
+    }
+
+    /// Build an uncompressed 24 bits per pixel image from top-down RGB triples
+    pub fn from_rgb(width: i32, height: i32, rgb: &[u8]) -> io::Result<BMPImage> {
+        if rgb.len() != (width * height * 3) as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected {} pixels, got {}", width * height, rgb.len() / 3),
+            ));
+        }
+        let stride = row_stride(width, 24);
+        let mut data = vec![0u8; stride * height as usize];
+        for (y, row) in rgb.chunks(width as usize * 3).rev().enumerate() {
+            let line = &mut data[y * stride..];
+            for (x, px) in row.chunks(3).enumerate() {
+                line[x * 3] = px[2];
+                line[x * 3 + 1] = px[1];
+                line[x * 3 + 2] = px[0];
+            }
+        }
+        let file_header_size = BMP_FILE_HEADER_SIZE as i32 + BMP_INFO_HEADER_SIZE;
+        let bitmap_size = data.len() as i32;
+        Ok(BMPImage {
+            header: BMPFileHeader::new(file_header_size + bitmap_size, file_header_size),
+            info: BMPInfo {
+                bmi_header: BMPGenericInfoHeader::Info(BMPInfoHeader::new(
+                    width, height, 24, bitmap_size, 0, 0, 0, 0,
+                )),
+                bmi_colors: Vec::new(),
+            },
+            bitmap: Bitmap {
+                data: data,
+                decoded_from: None,
+            },
+        })
+    }
+
+    /// Build a 32 bits per pixel image with alpha channel from top-down RGBA quads.
+    /// BITMAPV4HEADER is used to carry the alpha mask.
+    pub fn from_rgba(width: i32, height: i32, rgba: &[u8]) -> io::Result<BMPImage> {
+        if rgba.len() != (width * height * 4) as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected {} pixels, got {}", width * height, rgba.len() / 4),
+            ));
+        }
+        let stride = row_stride(width, 32);
+        let mut data = vec![0u8; stride * height as usize];
+        for (y, row) in rgba.chunks(width as usize * 4).rev().enumerate() {
+            let line = &mut data[y * stride..];
+            for (x, px) in row.chunks(4).enumerate() {
+                line[x * 4] = px[2];
+                line[x * 4 + 1] = px[1];
+                line[x * 4 + 2] = px[0];
+                line[x * 4 + 3] = px[3];
+            }
+        }
+        let file_header_size = BMP_FILE_HEADER_SIZE as i32 + BMP_V4_INFO_HEADER_SIZE;
+        let bitmap_size = data.len() as i32;
+        Ok(BMPImage {
+            header: BMPFileHeader::new(file_header_size + bitmap_size, file_header_size),
+            info: BMPInfo {
+                bmi_header: BMPGenericInfoHeader::V4Info(BMPV4Header::new(
+                    width, height, 32, bitmap_size,
+                    [0x00FF_0000, 0x0000_FF00, 0x0000_00FF, 0xFF00_0000],
+                )),
+                bmi_colors: Vec::new(),
+            },
+            bitmap: Bitmap {
+                data: data,
+                decoded_from: None,
+            },
+        })
+    }
+
+    /// Build an uncompressed 16 or 32 bits per pixel bitfields image from
+    /// top-down pixel values, `masks` are red, green, blue and alpha masks
+    pub fn from_bitfields(
+        width: i32, height: i32,
+        bit_count: i16,
+        masks: [u32; 4],
+        values: &[u32],
+    ) -> io::Result<BMPImage> {
+        if bit_count != 16 && bit_count != 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bits per pixel can't have bitfields", bit_count),
+            ));
+        }
+        if values.len() != (width * height) as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected {} pixels, got {}", width * height, values.len()),
+            ));
+        }
+        let stride = row_stride(width, bit_count);
+        let bytes = bit_count as usize / 8;
+        let mut data = vec![0u8; stride * height as usize];
+        for (y, row) in values.chunks(width as usize).rev().enumerate() {
+            let line = &mut data[y * stride..];
+            for (x, value) in row.iter().enumerate() {
+                for i in 0..bytes {
+                    line[x * bytes + i] = (value >> (8 * i)) as u8;
+                }
+            }
+        }
+        let file_header_size = BMP_FILE_HEADER_SIZE as i32 + BMP_V4_INFO_HEADER_SIZE;
+        let bitmap_size = data.len() as i32;
+        Ok(BMPImage {
+            header: BMPFileHeader::new(file_header_size + bitmap_size, file_header_size),
+            info: BMPInfo {
+                bmi_header: BMPGenericInfoHeader::V4Info(BMPV4Header::new(
+                    width, height, bit_count, bitmap_size, masks,
+                )),
+                bmi_colors: Vec::new(),
+            },
+            bitmap: Bitmap {
+                data: data,
+                decoded_from: None,
+            },
+        })
+    }
+
+    /// Build BMP image with the smallest bit count able to hold the pixels
+    pub fn from_pixels(p: &Pixels) -> io::Result<BMPImage> {
+        let width = p.width as i32;
+        let height = p.height as i32;
+        match p.data {
+            PixelData::Indexed { ref palette, ref indexes } => {
+                let bit_count = if palette.len() <= 2 {
+                    1
+                } else if palette.len() <= 16 {
+                    4
+                } else {
+                    8
+                };
+                BMPImage::from_indexed(width, height, bit_count, palette.clone(), indexes)
+            },
+            PixelData::RGB(ref rgb) => BMPImage::from_rgb(width, height, rgb),
+            PixelData::RGBA(ref rgba) => BMPImage::from_rgba(width, height, rgba),
+        }
+    }
+
+    /// Build BMP image with the given bit count. Palette formats require
+    /// indexed pixels, 16 bits per pixel are stored as 5-5-5 RGB.
+    pub fn from_pixels_with_depth(p: &Pixels, bit_count: i16) -> io::Result<BMPImage> {
+        let width = p.width as i32;
+        let height = p.height as i32;
+        match (bit_count, &p.data) {
+            (1, &PixelData::Indexed { ref palette, ref indexes }) |
+            (2, &PixelData::Indexed { ref palette, ref indexes }) |
+            (4, &PixelData::Indexed { ref palette, ref indexes }) |
+            (8, &PixelData::Indexed { ref palette, ref indexes }) => {
+                BMPImage::from_indexed(width, height, bit_count, palette.clone(), indexes)
+            },
+            (1, _) | (2, _) | (4, _) | (8, _) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} bits per pixel requires a palette image", bit_count),
+            )),
+            (16, _) => BMPImage::from_rgb555(width, height, &p.to_rgb()),
+            (24, _) => BMPImage::from_rgb(width, height, &p.to_rgb()),
+            (32, _) => BMPImage::from_rgba(width, height, &p.to_rgba()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported bits per pixel: {}", bit_count),
+            )),
+        }
+    }
+
+    /// Build an uncompressed 16 bits per pixel (5-5-5) image from top-down RGB triples
+    pub fn from_rgb555(width: i32, height: i32, rgb: &[u8]) -> io::Result<BMPImage> {
+        if rgb.len() != (width * height * 3) as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Expected {} pixels, got {}", width * height, rgb.len() / 3),
+            ));
+        }
+        let stride = row_stride(width, 16);
+        let mut data = vec![0u8; stride * height as usize];
+        for (y, row) in rgb.chunks(width as usize * 3).rev().enumerate() {
+            let line = &mut data[y * stride..];
+            for (x, px) in row.chunks(3).enumerate() {
+                let value = (px[0] as u16 >> 3) << 10 | (px[1] as u16 >> 3) << 5 | px[2] as u16 >> 3;
+                line[x * 2] = value as u8;
+                line[x * 2 + 1] = (value >> 8) as u8;
+            }
+        }
+        let file_header_size = BMP_FILE_HEADER_SIZE as i32 + BMP_INFO_HEADER_SIZE;
+        let bitmap_size = data.len() as i32;
+        Ok(BMPImage {
+            header: BMPFileHeader::new(file_header_size + bitmap_size, file_header_size),
+            info: BMPInfo {
+                bmi_header: BMPGenericInfoHeader::Info(BMPInfoHeader::new(
+                    width, height, 16, bitmap_size, 0, 0, 0, 0,
+                )),
+                bmi_colors: Vec::new(),
+            },
+            bitmap: Bitmap {
+                data: data,
+                decoded_from: None,
+            },
+        })
+    }
+
+    /// Decode bitmap into top-down pixels
+    pub fn to_pixels(&mut self) -> io::Result<Pixels> {
+        match self.info.bmi_header.get_compression_type() {
+            BMPCompression::RGB | BMPCompression::BITFIELDS => {},
+            BMPCompression::RLE8 | BMPCompression::RLE4 => self.decode_bitmap()?,
+            // BI_PNG bitmap is a complete PNG stream
+            BMPCompression::PNG => return png::load_from_reader(&mut &self.bitmap.data[..]),
+            c => return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Can't decode pixels of bitmap compressed with: {}", c),
+            )),
+        }
+        let width = self.info.bmi_header.get_width();
+        let height = self.info.bmi_header.get_height();
+        let bit_count = self.info.bmi_header.get_bit_count();
+        if width <= 0 || height == 0 || height == i32::MIN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid bitmap size: {}x{} px", width, height),
+            ));
+        }
+        let (rows, top_down) = (height.abs() as usize, height < 0);
+        let stride = row_stride(width, bit_count);
+        let width = width as usize;
+        let size = stride.checked_mul(rows).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Bitmap of {}x{} px is too large", width, height),
+        ))?;
+        if self.bitmap.data.len() < size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Bitmap is truncated: {} bytes, expected {}", self.bitmap.data.len(), size),
+            ));
+        }
+        let line = |y: usize| {
+            let row = if top_down { y } else { rows - 1 - y };
+            &self.bitmap.data[row * stride..(row + 1) * stride]
+        };
+
+        let data = match bit_count {
+            1 | 2 | 4 | 8 => {
+                let bc = bit_count as usize;
+                let pixels_per_byte = 8 / bc;
+                let mask = ((1u16 << bc) - 1) as u8;
+                let mut indexes = Vec::with_capacity(width * rows);
+                for y in 0..rows {
+                    let line = line(y);
+                    for x in 0..width {
+                        indexes.push((line[x / pixels_per_byte] >> pixel_shift(x, bit_count)) & mask);
+                    }
+                }
+                PixelData::Indexed {
+                    palette: self.info.bmi_colors.clone(),
+                    indexes: indexes,
+                }
+            },
+            24 => {
+                let mut rgb = Vec::with_capacity(width * rows * 3);
+                for y in 0..rows {
+                    for px in line(y)[..width * 3].chunks(3) {
+                        rgb.push(px[2]);
+                        rgb.push(px[1]);
+                        rgb.push(px[0]);
+                    }
+                }
+                PixelData::RGB(rgb)
+            },
+            16 | 32 => {
+                let masks = self.info.get_masks();
+                let bytes = bit_count as usize / 8;
+                let mut rgba = Vec::with_capacity(width * rows * 4);
+                for y in 0..rows {
+                    for px in line(y)[..width * bytes].chunks(bytes) {
+                        let value = if bytes == 2 {
+                            px[0] as u32 | (px[1] as u32) << 8
+                        } else {
+                            px[0] as u32 | (px[1] as u32) << 8 | (px[2] as u32) << 16 | (px[3] as u32) << 24
+                        };
+                        rgba.push(mask_channel(value, masks[0]));
+                        rgba.push(mask_channel(value, masks[1]));
+                        rgba.push(mask_channel(value, masks[2]));
+                        rgba.push(if masks[3] == 0 { 255 } else { mask_channel(value, masks[3]) });
+                    }
+                }
+                if masks[3] == 0 {
+                    let rgb = rgba.chunks(4).flat_map(|px| px[..3].to_vec()).collect();
+                    PixelData::RGB(rgb)
+                } else {
+                    PixelData::RGBA(rgba)
+                }
+            },
+            _ => return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unsupported bits per pixel: {}", bit_count),
+            )),
+        };
+        Ok(Pixels::new(width as u32, rows as u32, data))
+    }
+
     pub fn meta_from_file<P: AsRef<Path>>(p: P) -> io::Result<BMPImage> {
         let mut f = BufReader::new(File::open(p)?);
         BMPImage::meta_from_reader(&mut f)
@@ -355,11 +826,52 @@ impl BMPImage {
     }
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<BMPImage> {
         let mut f = BufReader::new(File::open(p)?);
-        let mut image = BMPImage::meta_from_reader(&mut f)?;
-        image.bitmap.data = vec![0u8; image.info.bmi_header.get_bitmap_size() as usize];
-        f.read_exact(&mut image.bitmap.data)?;
+        BMPImage::load_from_reader(&mut f)
+    }
+    /// Load image from the current position, bitmap offset is counted
+    /// from the beginning of the reader
+    pub fn load_from_reader<R: ?Sized + BufRead + Seek>(r: &mut R) -> io::Result<BMPImage> {
+        let mut image = BMPImage::meta_from_reader(r)?;
+        image.load_bitmap_from_reader(r)?;
         Ok(image)
     }
+    /// Load bitmap data of the image which metadata is already loaded
+    pub fn load_bitmap_from_reader<R: ?Sized + Read + Seek>(&mut self, r: &mut R) -> io::Result<()> {
+        r.seek(SeekFrom::Start(self.header.bf_offset_bits as u64))?;
+        self.bitmap.data = read_bitmap(r, self.info.bmi_header.get_bitmap_size())?;
+        Ok(())
+    }
+    /// Load packed DIB from the current position of the reader, that is
+    /// the info header followed by the color table and the bitmap
+    pub fn load_dib_from_reader<R: ?Sized + BufRead + Seek>(r: &mut R) -> io::Result<BMPImage> {
+        let info = BMPInfo::load_from_reader(r)?;
+        let offset = BMP_FILE_HEADER_SIZE as i32 + info.get_size();
+        let bitmap_size = info.bmi_header.get_bitmap_size();
+        let data = read_bitmap(r, bitmap_size)?;
+        Ok(BMPImage {
+            header: BMPFileHeader::new(offset + bitmap_size, offset),
+            info: info,
+            bitmap: Bitmap {
+                data: data,
+                decoded_from: None,
+            },
+        })
+    }
+    /// Load packed DIB from the memory buffer
+    pub fn load_dib(data: &[u8]) -> io::Result<BMPImage> {
+        BMPImage::load_dib_from_reader(&mut Cursor::new(data))
+    }
+    /// Save image as packed DIB, without the file header
+    pub fn save_dib_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        self.info.save_to_writer(w)?;
+        w.write_all(&self.bitmap.data)
+    }
+    /// Packed DIB of the image
+    pub fn to_dib(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.info.get_size() as usize + self.bitmap.data.len());
+        self.save_dib_to_writer(&mut data)?;
+        Ok(data)
+    }
     pub fn grayscale(&mut self) {
         for quad in &mut self.info.bmi_colors {
             let average = (quad.rgb_red as u32 + quad.rgb_green as u32 + quad.rgb_blue as u32) / 3;
@@ -368,8 +880,8 @@ impl BMPImage {
             quad.rgb_blue = average as u8;
         }
     }
-    pub fn border(&mut self, width: i16) {
-        self.decode_bitmap();
+    pub fn border(&mut self, width: i16) -> io::Result<()> {
+        self.decode_bitmap()?;
         self.bitmap.border(
             width,
             self.info.bmi_header.get_width(),
@@ -382,6 +894,7 @@ impl BMPImage {
             self.encode_bitmap(enc);
         }
         */
+        Ok(())
     }
     pub fn add_logo(&mut self, logo: &str) {
         self.bitmap.add_logo(
@@ -403,41 +916,118 @@ impl BMPImage {
         Ok(0 as usize)
     }
 
-    pub fn encode_bitmap(&mut self, compression: BMPCompression) {
+    pub fn encode_bitmap(&mut self, compression: BMPCompression) -> io::Result<()> {
+        // bitmap is encoded from the uncompressed one
+        self.decode_bitmap()?;
         let width = self.info.bmi_header.get_width();
         let height = self.info.bmi_header.get_height();
+        let bit_count = self.info.bmi_header.get_bit_count();
         match compression {
-            BMPCompression::RGB => return,
-            BMPCompression::RLE8 => Rle8::encode(&mut self.bitmap, width, height),
-            BMPCompression::RLE4 => unimplemented!("bmp Rle4 bitmap encoding"),
-            BMPCompression::BITFIELDS => unimplemented!("bmp bitfield bitmap encoding"),
-            BMPCompression::JPEG => unimplemented!("bmp JPEG bitmap encoding"),
-            BMPCompression::PNG => unimplemented!("bmp PNG bitmap encoding"),
+            BMPCompression::RGB => return Ok(()),
+            BMPCompression::RLE8 | BMPCompression::RLE4 if height < 0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Top-down bitmap can't be RLE compressed".to_owned(),
+                ));
+            },
+            BMPCompression::RLE8 if bit_count == 8 => Rle8::encode(&mut self.bitmap, width, height),
+            BMPCompression::RLE4 if bit_count == 4 => Rle4::encode(&mut self.bitmap, width, height),
+            BMPCompression::RLE8 | BMPCompression::RLE4 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} can't be used with {} bits per pixel", compression, bit_count),
+                ));
+            },
+            BMPCompression::BITFIELDS | BMPCompression::JPEG => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} bitmap encoding is not supported", compression),
+                ));
+            },
+            BMPCompression::PNG => {
+                let pixels = self.to_pixels()?;
+                let mut data = Vec::new();
+                png::save_to_writer(&pixels, &mut data, false)?;
+                self.bitmap.data = data;
+            },
         };
+        self.info.bmi_header.set_encoding(compression);
+        self.update_bitmap_size();
+        Ok(())
     }
-    pub fn decode_bitmap(&mut self) {
+    pub fn decode_bitmap(&mut self) -> io::Result<()> {
         let width = self.info.bmi_header.get_width();
         let height = self.info.bmi_header.get_height();
         match self.info.bmi_header.get_compression_type() {
-            BMPCompression::RGB => return,
-            BMPCompression::RLE8 => Rle8::decode(&mut self.bitmap, width, height),
-            BMPCompression::RLE4 => unimplemented!("bmp Rle4 bitmap decoding"),
-            BMPCompression::BITFIELDS => unimplemented!("bmp bitfields decoding"),
-            BMPCompression::JPEG => unimplemented!("bmp JPEG bitmap decoding"),
-            BMPCompression::PNG => unimplemented!("bmp PNG bitmap decoding"),
+            // bitfields only describe uncompressed pixels
+            BMPCompression::RGB | BMPCompression::BITFIELDS => return Ok(()),
+            BMPCompression::RLE8 => Rle8::decode(&mut self.bitmap, width, height)?,
+            BMPCompression::RLE4 => Rle4::decode(&mut self.bitmap, width, height)?,
+            BMPCompression::JPEG => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "JPEG bitmap decoding is not supported".to_owned(),
+                ));
+            },
+            BMPCompression::PNG => {
+                // PNG may have alpha or no palette, headers are rebuilt
+                let pixels = png::load_from_reader(&mut &self.bitmap.data[..])?;
+                let mut image = BMPImage::from_pixels(&pixels)?;
+                image.bitmap.decoded_from = Some(BMPCompression::PNG);
+                *self = image;
+                return Ok(());
+            },
         };
         self.info.bmi_header.set_encoding(BMPCompression::RGB);
-
+        self.update_bitmap_size();
+        Ok(())
+    }
+    /// Sync image and file sizes with the bitmap data
+    fn update_bitmap_size(&mut self) {
         let delta = self.bitmap.data.len() as i32 - self.info.bmi_header.get_bitmap_size();
         match self.info.bmi_header {
             BMPGenericInfoHeader::Info(ref mut i) => i.bi_size_image += delta,
             BMPGenericInfoHeader::V4Info(ref mut i) => i.bv4_size_image += delta,
             BMPGenericInfoHeader::V5Info(ref mut i) => i.bv5_size_image += delta,
+            BMPGenericInfoHeader::OS2V2Info(ref mut i) => i.size_image += delta,
+            // the core header has no image size field
+            BMPGenericInfoHeader::Core(_) => {},
         }
         self.header.bf_size += delta;
     }
 }
 
+/// Read `size` bytes of bitmap, the buffer grows with the data read
+/// as the size comes from the headers
+fn read_bitmap<R: ?Sized + Read>(r: &mut R, size: i32) -> io::Result<Vec<u8>> {
+    if size < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid bitmap size: {} bytes", size),
+        ));
+    }
+    let mut data = Vec::new();
+    r.take(size as u64).read_to_end(&mut data)?;
+    if data.len() < size as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Bitmap is truncated: {} bytes, expected {}", data.len(), size),
+        ));
+    }
+    Ok(data)
+}
+
+/// Extract channel selected by `mask` and scale it to 8 bits
+pub fn mask_channel(value: u32, mask: u32) -> u8 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let max = (mask >> shift) as u64;
+    let channel = ((value & mask) >> shift) as u64;
+    (channel * 255 / max) as u8
+}
+
 impl fmt::Display for BMPImage {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.header.fmt(f)?;
@@ -445,15 +1035,19 @@ impl fmt::Display for BMPImage {
     }
 }
 
+/// File types sharing the bitmap file header: Windows and OS/2 bitmaps,
+/// OS/2 bitmap arrays, color icons, color pointers, icons and pointers
+pub const BMP_FILE_TYPES: [&'static [u8; 2]; 6] = [b"BM", b"BA", b"CI", b"CP", b"IC", b"PT"];
+
 #[derive(Debug)]
 pub struct BMPFileHeader {
-    /// The file type; must be BM
+    /// The file type; BM for bitmaps, OS/2 also uses BA, CI, CP, IC and PT
     bf_type: i16,
     /// The size, in bytes, of the bitmap file
     bf_size: i32,
-    /// Reserved; must be zero
+    /// Reserved; must be zero. X hotspot of OS/2 icons and pointers
     bf_reserved1: i16,
-    /// Reserved; must be zero
+    /// Reserved; must be zero. Y hotspot of OS/2 icons and pointers
     bf_reserved2: i16,
     /// The offset, in bytes, from the beginning of
     /// the BITMAPFILEHEADER structure to the bitmap bits
@@ -483,7 +1077,7 @@ impl BMPFileHeader {
     pub fn load_from_reader<R: ?Sized + BufRead>(r: &mut R) -> io::Result<BMPFileHeader> {
         let mut sig = [0u8; 2];
         try!(r.read_exact(&mut sig));
-        if sig != b"BM"[..] {
+        if !BMP_FILE_TYPES.iter().any(|t| sig == t[..]) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("Invalid BMP signature: '{:?}'", sig),
@@ -498,6 +1092,19 @@ impl BMPFileHeader {
         })
     }
 
+    /// Two letter file type, like BM
+    pub fn file_type(&self) -> String {
+        let t = [self.bf_type as u8, (self.bf_type >> 8) as u8];
+        String::from_utf8_lossy(&t).into_owned()
+    }
+    /// Hotspot of OS/2 icons and pointers
+    pub fn hotspot(&self) -> Option<(i16, i16)> {
+        match self.file_type().as_ref() {
+            "CI" | "CP" | "IC" | "PT" => Some((self.bf_reserved1, self.bf_reserved2)),
+            _ => None,
+        }
+    }
+
     pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
         w.write_i16::<LittleEndian>(self.bf_type)?;
         w.write_i32::<LittleEndian>(self.bf_size)?;
@@ -509,6 +1116,8 @@ impl BMPFileHeader {
 
 #[derive(Debug)]
 pub enum BMPGenericInfoHeader {
+    Core(BMPCoreHeader),
+    OS2V2Info(BMPOS2V2Header),
     Info(BMPInfoHeader),
     V4Info(BMPV4Header),
     V5Info(BMPV5Header),
@@ -520,6 +1129,8 @@ impl BMPGenericInfoHeader {
             &BMPGenericInfoHeader::Info(ref i) => i.bi_width,
             &BMPGenericInfoHeader::V4Info(ref i) => i.bv4_width,
             &BMPGenericInfoHeader::V5Info(ref i) => i.bv5_width,
+            &BMPGenericInfoHeader::Core(ref i) => i.bc_width as i32,
+            &BMPGenericInfoHeader::OS2V2Info(ref i) => i.width,
         }
     }
     pub fn get_height(&self) -> i32 {
@@ -527,6 +1138,28 @@ impl BMPGenericInfoHeader {
             &BMPGenericInfoHeader::Info(ref i) => i.bi_height,
             &BMPGenericInfoHeader::V4Info(ref i) => i.bv4_height,
             &BMPGenericInfoHeader::V5Info(ref i) => i.bv5_height,
+            &BMPGenericInfoHeader::Core(ref i) => i.bc_height as i32,
+            &BMPGenericInfoHeader::OS2V2Info(ref i) => i.height,
+        }
+    }
+    /// Icons and cursors store the doubled height of the image and the mask
+    pub fn set_height(&mut self, height: i32) {
+        match *self {
+            BMPGenericInfoHeader::Info(ref mut i) => i.bi_height = height,
+            BMPGenericInfoHeader::V4Info(ref mut i) => i.bv4_height = height,
+            BMPGenericInfoHeader::V5Info(ref mut i) => i.bv5_height = height,
+            BMPGenericInfoHeader::Core(ref mut i) => i.bc_height = height as u16,
+            BMPGenericInfoHeader::OS2V2Info(ref mut i) => i.height = height,
+        }
+    }
+    /// Size of the header structure in bytes
+    pub fn get_size(&self) -> i32 {
+        match self {
+            &BMPGenericInfoHeader::Info(ref i) => i.bi_size,
+            &BMPGenericInfoHeader::V4Info(ref i) => i.bv4_size,
+            &BMPGenericInfoHeader::V5Info(ref i) => i.bv5_size,
+            &BMPGenericInfoHeader::Core(ref i) => i.bc_size,
+            &BMPGenericInfoHeader::OS2V2Info(ref i) => i.size,
         }
     }
     pub fn get_bit_count(&self) -> i16 {
@@ -534,6 +1167,8 @@ impl BMPGenericInfoHeader {
             &BMPGenericInfoHeader::Info(ref i) => i.bi_bit_count,
             &BMPGenericInfoHeader::V4Info(ref i) => i.bv4_bit_count,
             &BMPGenericInfoHeader::V5Info(ref i) => i.bv5_bit_count,
+            &BMPGenericInfoHeader::Core(ref i) => i.bc_bit_count,
+            &BMPGenericInfoHeader::OS2V2Info(ref i) => i.bit_count,
         }
     }
     pub fn get_compression_type(&self) -> BMPCompression {
@@ -541,6 +1176,8 @@ impl BMPGenericInfoHeader {
             &BMPGenericInfoHeader::Info(ref i) => i.bi_compression,
             &BMPGenericInfoHeader::V4Info(ref i) => i.bv4_v4_compression,
             &BMPGenericInfoHeader::V5Info(ref i) => i.bv5_compression,
+            &BMPGenericInfoHeader::Core(_) => BMPCompression::RGB,
+            &BMPGenericInfoHeader::OS2V2Info(ref i) => i.compression,
         }
     }
     pub fn get_colors_used(&self) -> i32 {
@@ -548,6 +1185,8 @@ impl BMPGenericInfoHeader {
             &BMPGenericInfoHeader::Info(ref i) => i.bi_clr_used,
             &BMPGenericInfoHeader::V4Info(ref i) => i.bv4_clr_used,
             &BMPGenericInfoHeader::V5Info(ref i) => i.bv5_clr_used,
+            &BMPGenericInfoHeader::Core(_) => 0,
+            &BMPGenericInfoHeader::OS2V2Info(ref i) => i.clr_used,
         }
     }
     pub fn get_bitmap_size(&self) -> i32 {
@@ -555,17 +1194,35 @@ impl BMPGenericInfoHeader {
             &BMPGenericInfoHeader::Info(ref i) => i.bi_size_image,
             &BMPGenericInfoHeader::V4Info(ref i) => i.bv4_size_image,
             &BMPGenericInfoHeader::V5Info(ref i) => i.bv5_size_image,
+            &BMPGenericInfoHeader::Core(_) => 0,
+            &BMPGenericInfoHeader::OS2V2Info(ref i) => i.size_image,
         };
         if size == 0 {
-            size = self.get_width() * self.get_height() * self.get_bit_count() as i32 / 8
+            // sizes beyond the header field can't be read anyway
+            let rows = (self.get_height() as i64).abs();
+            let stride = row_stride(self.get_width(), self.get_bit_count()) as i64;
+            size = stride.saturating_mul(rows).min(i32::MAX as i64) as i32
         };
         size
     }
+    /// Size of compressed bitmaps, zero is the size of uncompressed one
+    pub fn set_bitmap_size(&mut self, size: i32) {
+        match *self {
+            BMPGenericInfoHeader::Info(ref mut i) => i.bi_size_image = size,
+            BMPGenericInfoHeader::V4Info(ref mut i) => i.bv4_size_image = size,
+            BMPGenericInfoHeader::V5Info(ref mut i) => i.bv5_size_image = size,
+            BMPGenericInfoHeader::OS2V2Info(ref mut i) => i.size_image = size,
+            // the core header has no image size field
+            BMPGenericInfoHeader::Core(_) => {},
+        }
+    }
     pub fn get_type(&self) -> &'static str {
         match self {
             &BMPGenericInfoHeader::Info(_) => "BMPInfoHeader",
             &BMPGenericInfoHeader::V4Info(_) => "BMPV4Header",
             &BMPGenericInfoHeader::V5Info(_) => "BMPV5Header",
+            &BMPGenericInfoHeader::Core(_) => "BMPCoreHeader",
+            &BMPGenericInfoHeader::OS2V2Info(_) => "BMPOS2V2Header",
         }
     }
     pub fn get_os_support(&self) -> &'static str {
@@ -573,6 +1230,8 @@ impl BMPGenericInfoHeader {
             BMPGenericInfoHeader::Info(_) => "Windows NT, 3.1x or later",
             BMPGenericInfoHeader::V4Info(_) => "Windows NT 4.0, 95 or later",
             BMPGenericInfoHeader::V5Info(_) => "Windows NT 5.0, 98 or later",
+            BMPGenericInfoHeader::Core(_) => "OS/2 1.x, Windows 2.x or later",
+            BMPGenericInfoHeader::OS2V2Info(_) => "OS/2 2.x or later",
         }
     }
     pub fn set_encoding(&mut self, enc :BMPCompression) {
@@ -580,6 +1239,9 @@ impl BMPGenericInfoHeader {
             BMPGenericInfoHeader::Info(ref mut i) => i.bi_compression = enc,
             BMPGenericInfoHeader::V4Info(ref mut i) => i.bv4_v4_compression = enc,
             BMPGenericInfoHeader::V5Info(ref mut i) => i.bv5_compression = enc,
+            BMPGenericInfoHeader::OS2V2Info(ref mut i) => i.compression = enc,
+            // core bitmaps are always uncompressed
+            BMPGenericInfoHeader::Core(_) => {},
         }
     }
 }
@@ -595,15 +1257,18 @@ pub struct BMPInfo {
 impl BMPInfo {
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<BMPInfo> {
         let mut f = BufReader::new(File::open(p)?);
+        // skip file header
+        f.seek(SeekFrom::Start(BMP_FILE_HEADER_SIZE))?;
         BMPInfo::load_from_reader(&mut f)
     }
+    /// Load info header and color table from the current position,
+    /// that is right after the file header
     pub fn load_from_reader<R: ?Sized + BufRead + Seek>(r: &mut R) -> io::Result<BMPInfo> {
-        // skip file header
-        r.seek(SeekFrom::Start(BMP_FILE_HEADER_SIZE))?;
         let size = r.read_i32::<LittleEndian>()?;
-        r.seek(SeekFrom::Start(BMP_FILE_HEADER_SIZE))?;
+        r.seek(SeekFrom::Current(-4))?;
 
         let header = match size {
+            BMP_CORE_HEADER_SIZE => BMPGenericInfoHeader::Core(BMPCoreHeader::load_from_reader(r)?),
             BMP_INFO_HEADER_SIZE => BMPGenericInfoHeader::Info(BMPInfoHeader::load_from_reader(r)?),
             BMP_V4_INFO_HEADER_SIZE => {
                 BMPGenericInfoHeader::V4Info(BMPV4Header::load_from_reader(r)?)
@@ -611,6 +1276,9 @@ impl BMPInfo {
             BMP_V5_INFO_HEADER_SIZE => {
                 BMPGenericInfoHeader::V5Info(BMPV5Header::load_from_reader(r)?)
             }
+            _ if size >= BMP_OS2_V2_MIN_HEADER_SIZE && size <= BMP_OS2_V2_HEADER_SIZE && size % 4 == 0 => {
+                BMPGenericInfoHeader::OS2V2Info(BMPOS2V2Header::load_from_reader(r)?)
+            }
             _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::Other,
@@ -620,9 +1288,24 @@ impl BMPInfo {
         };
         let mut colors = Vec::<RGBQuad>::new();
         if header.get_bit_count() < 16 {
-            let palette_len = 2u64.pow(header.get_bit_count() as u32);
+            let mut palette_len = 2u64.pow(header.get_bit_count() as u32);
+            if header.get_colors_used() > 0 && (header.get_colors_used() as u64) < palette_len {
+                palette_len = header.get_colors_used() as u64;
+            }
             for _ in 0..palette_len {
-                colors.push(RGBQuad::load_from_reader(r)?);
+                colors.push(match header {
+                    // OS/2 1.x color table is made of 3 byte triples
+                    BMPGenericInfoHeader::Core(_) => RGBQuad::load_triple_from_reader(r)?,
+                    _ => RGBQuad::load_from_reader(r)?,
+                });
+            }
+        }
+        if let BMPGenericInfoHeader::Info(ref i) = header {
+            if let BMPCompression::BITFIELDS = i.bi_compression {
+                // red, green and blue masks follow BITMAPINFOHEADER
+                for _ in 0..3 {
+                    colors.push(RGBQuad::load_from_reader(r)?);
+                }
             }
         }
         Ok(BMPInfo {
@@ -635,12 +1318,55 @@ impl BMPInfo {
             BMPGenericInfoHeader::Info(ref info) => info.save_to_writer(w)?,
             BMPGenericInfoHeader::V4Info(ref info) => info.save_to_writer(w)?,
             BMPGenericInfoHeader::V5Info(ref info) => info.save_to_writer(w)?,
+            BMPGenericInfoHeader::Core(ref info) => info.save_to_writer(w)?,
+            BMPGenericInfoHeader::OS2V2Info(ref info) => info.save_to_writer(w)?,
         };
         for c in &self.bmi_colors {
-            c.save_to_writer(w)?;
+            match self.bmi_header {
+                BMPGenericInfoHeader::Core(_) => c.save_triple_to_writer(w)?,
+                _ => c.save_to_writer(w)?,
+            }
         }
         Ok(())
     }
+    /// Size of the info header, color table and masks, that is the offset
+    /// of the bitmap in packed DIB
+    pub fn get_size(&self) -> i32 {
+        let entry_size = match self.bmi_header {
+            BMPGenericInfoHeader::Core(_) => 3,
+            _ => 4,
+        };
+        self.bmi_header.get_size() + entry_size * self.bmi_colors.len() as i32
+    }
+    /// Red, green, blue and alpha masks of 16 and 32 bits per pixel bitmap
+    pub fn get_masks(&self) -> [u32; 4] {
+        let bitfields = match self.bmi_header.get_compression_type() {
+            BMPCompression::BITFIELDS => true,
+            _ => false,
+        };
+        let alpha = match self.bmi_header {
+            BMPGenericInfoHeader::Core(_) | BMPGenericInfoHeader::OS2V2Info(_) => 0,
+            BMPGenericInfoHeader::Info(_) => 0,
+            BMPGenericInfoHeader::V4Info(ref i) => i.bv4_alpha_mask as u32,
+            BMPGenericInfoHeader::V5Info(ref i) => i.bv5_alpha_mask as u32,
+        };
+        match self.bmi_header {
+            BMPGenericInfoHeader::Info(_) if bitfields && self.bmi_colors.len() >= 3 => [
+                self.bmi_colors[0].to_mask(),
+                self.bmi_colors[1].to_mask(),
+                self.bmi_colors[2].to_mask(),
+                0,
+            ],
+            BMPGenericInfoHeader::V4Info(ref i) if bitfields => [
+                i.bv4_red_mask as u32, i.bv4_green_mask as u32, i.bv4_blue_mask as u32, alpha,
+            ],
+            BMPGenericInfoHeader::V5Info(ref i) if bitfields => [
+                i.bv5_red_mask as u32, i.bv5_green_mask as u32, i.bv5_blue_mask as u32, alpha,
+            ],
+            _ if self.bmi_header.get_bit_count() == 16 => [0x7C00, 0x03E0, 0x001F, 0],
+            _ => [0x00FF_0000, 0x0000_FF00, 0x0000_00FF, alpha],
+        }
+    }
 }
 
 impl fmt::Display for BMPInfo {
@@ -748,6 +1474,139 @@ impl BMPInfoHeader {
     }
 }
 
+/// OS/2 1.x and Windows 2.x BITMAPCOREHEADER
+#[derive(Debug)]
+pub struct BMPCoreHeader {
+    /// The number of bytes required by this structure, always 12
+    bc_size: i32,
+    /// The width of the bitmap, in pixels
+    bc_width: u16,
+    /// The height of the bitmap, in pixels
+    bc_height: u16,
+    /// The number of planes for the target device. This value must be set to 1
+    bc_planes: i16,
+    /// The number of bits-per-pixel
+    bc_bit_count: i16,
+}
+
+impl BMPCoreHeader {
+    pub fn load_from_reader<R: ?Sized + BufRead>(r: &mut R) -> io::Result<BMPCoreHeader> {
+        Ok(BMPCoreHeader {
+            bc_size: r.read_i32::<LittleEndian>()?,
+            bc_width: r.read_u16::<LittleEndian>()?,
+            bc_height: r.read_u16::<LittleEndian>()?,
+            bc_planes: r.read_i16::<LittleEndian>()?,
+            bc_bit_count: r.read_i16::<LittleEndian>()?,
+        })
+    }
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_i32::<LittleEndian>(self.bc_size)?;
+        w.write_u16::<LittleEndian>(self.bc_width)?;
+        w.write_u16::<LittleEndian>(self.bc_height)?;
+        w.write_i16::<LittleEndian>(self.bc_planes)?;
+        w.write_i16::<LittleEndian>(self.bc_bit_count)?;
+        Ok(())
+    }
+}
+
+/// OS/2 2.x BITMAPINFOHEADER2. The first 40 bytes match BITMAPINFOHEADER,
+/// the header may be truncated, missing fields are zero.
+/// http://www.fileformat.info/format/os2bmp/egff.htm
+#[derive(Debug, Default)]
+pub struct BMPOS2V2Header {
+    size: i32,
+    width: i32,
+    height: i32,
+    planes: i16,
+    bit_count: i16,
+    compression: BMPCompression,
+    size_image: i32,
+    x_pels_per_meter: i32,
+    y_pels_per_meter: i32,
+    clr_used: i32,
+    clr_important: i32,
+    /// Units of the resolution, 0 is pixels per meter
+    units: u16,
+    reserved: u16,
+    /// Recording algorithm, 0 is bottom-up
+    recording: u16,
+    /// Halftoning algorithm
+    rendering: u16,
+    size1: u32,
+    size2: u32,
+    /// Color encoding, 0 is RGB
+    color_encoding: u32,
+    /// Reserved for application use
+    identifier: u32,
+}
+
+impl Default for BMPCompression {
+    fn default() -> BMPCompression {
+        BMPCompression::RGB
+    }
+}
+
+impl BMPOS2V2Header {
+    pub fn load_from_reader<R: ?Sized + BufRead>(r: &mut R) -> io::Result<BMPOS2V2Header> {
+        let size = r.read_i32::<LittleEndian>()?;
+        let mut data = vec![0u8; BMP_OS2_V2_HEADER_SIZE as usize - 4];
+        r.read_exact(&mut data[..size as usize - 4])?;
+        let r = &mut &data[..];
+        let mut h = BMPOS2V2Header::default();
+        h.size = size;
+        h.width = r.read_i32::<LittleEndian>()?;
+        h.height = r.read_i32::<LittleEndian>()?;
+        h.planes = r.read_i16::<LittleEndian>()?;
+        h.bit_count = r.read_i16::<LittleEndian>()?;
+        h.compression = match r.read_i32::<LittleEndian>()? {
+            // 3 is Huffman 1D and 4 is RLE24 in OS/2, not BITFIELDS and JPEG
+            c if c >= 0 && c <= 2 => BMPCompression::from_bytes(c)?,
+            c => return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Unsupported OS/2 compression format: {}", c),
+            )),
+        };
+        h.size_image = r.read_i32::<LittleEndian>()?;
+        h.x_pels_per_meter = r.read_i32::<LittleEndian>()?;
+        h.y_pels_per_meter = r.read_i32::<LittleEndian>()?;
+        h.clr_used = r.read_i32::<LittleEndian>()?;
+        h.clr_important = r.read_i32::<LittleEndian>()?;
+        h.units = r.read_u16::<LittleEndian>()?;
+        h.reserved = r.read_u16::<LittleEndian>()?;
+        h.recording = r.read_u16::<LittleEndian>()?;
+        h.rendering = r.read_u16::<LittleEndian>()?;
+        h.size1 = r.read_u32::<LittleEndian>()?;
+        h.size2 = r.read_u32::<LittleEndian>()?;
+        h.color_encoding = r.read_u32::<LittleEndian>()?;
+        h.identifier = r.read_u32::<LittleEndian>()?;
+        Ok(h)
+    }
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut data = Vec::with_capacity(BMP_OS2_V2_HEADER_SIZE as usize);
+        data.write_i32::<LittleEndian>(self.size)?;
+        data.write_i32::<LittleEndian>(self.width)?;
+        data.write_i32::<LittleEndian>(self.height)?;
+        data.write_i16::<LittleEndian>(self.planes)?;
+        data.write_i16::<LittleEndian>(self.bit_count)?;
+        data.write_i32::<LittleEndian>(BMPCompression::to_bytes(&self.compression))?;
+        data.write_i32::<LittleEndian>(self.size_image)?;
+        data.write_i32::<LittleEndian>(self.x_pels_per_meter)?;
+        data.write_i32::<LittleEndian>(self.y_pels_per_meter)?;
+        data.write_i32::<LittleEndian>(self.clr_used)?;
+        data.write_i32::<LittleEndian>(self.clr_important)?;
+        data.write_u16::<LittleEndian>(self.units)?;
+        data.write_u16::<LittleEndian>(self.reserved)?;
+        data.write_u16::<LittleEndian>(self.recording)?;
+        data.write_u16::<LittleEndian>(self.rendering)?;
+        data.write_u32::<LittleEndian>(self.size1)?;
+        data.write_u32::<LittleEndian>(self.size2)?;
+        data.write_u32::<LittleEndian>(self.color_encoding)?;
+        data.write_u32::<LittleEndian>(self.identifier)?;
+        // keep the original truncated size
+        w.write_all(&data[..self.size as usize])
+    }
+}
+
 /// In all versions of BMP files starting with Version 3 (Win3x),
 /// the color entries occupy 4 bytes each so that they can be efficiently
 /// read and written as single 32-bit values. Taken as a single value,
@@ -778,6 +1637,30 @@ impl RGBQuad {
             self.rgb_green = green;
             self.rgb_blue = blue;
     }
+    pub fn red(&self) -> u8 {
+        self.rgb_red
+    }
+    pub fn green(&self) -> u8 {
+        self.rgb_green
+    }
+    pub fn blue(&self) -> u8 {
+        self.rgb_blue
+    }
+    /// Color masks of BI_BITFIELDS bitmaps are stored in place of the color table
+    pub fn from_mask(mask: u32) -> RGBQuad {
+        RGBQuad {
+            rgb_blue: mask as u8,
+            rgb_green: (mask >> 8) as u8,
+            rgb_red: (mask >> 16) as u8,
+            rgb_reserved: (mask >> 24) as u8,
+        }
+    }
+    pub fn to_mask(&self) -> u32 {
+        self.rgb_blue as u32
+            | (self.rgb_green as u32) << 8
+            | (self.rgb_red as u32) << 16
+            | (self.rgb_reserved as u32) << 24
+    }
 
     pub fn load_from_reader<R: ?Sized + BufRead>(r: &mut R) -> io::Result<RGBQuad> {
         Ok(RGBQuad {
@@ -794,6 +1677,21 @@ impl RGBQuad {
         w.write_u8(self.rgb_reserved)?;
         Ok(())
     }
+    /// RGBTRIPLE of the OS/2 1.x color table, blue comes first
+    pub fn load_triple_from_reader<R: ?Sized + BufRead>(r: &mut R) -> io::Result<RGBQuad> {
+        Ok(RGBQuad {
+            rgb_blue: r.read_u8()?,
+            rgb_green: r.read_u8()?,
+            rgb_red: r.read_u8()?,
+            rgb_reserved: 0,
+        })
+    }
+    pub fn save_triple_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u8(self.rgb_blue)?;
+        w.write_u8(self.rgb_green)?;
+        w.write_u8(self.rgb_red)?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -821,6 +1719,32 @@ pub struct BMPV4Header {
 }
 
 impl BMPV4Header {
+    /// Uncompressed bitfields header with sRGB color space,
+    /// `masks` are red, green, blue and alpha masks
+    pub fn new(width: i32, height: i32, bpp: i16, size: i32, masks: [u32; 4]) -> BMPV4Header {
+        BMPV4Header {
+            bv4_size: BMP_V4_INFO_HEADER_SIZE,
+            bv4_width: width,
+            bv4_height: height,
+            bv4_planes: 1,
+            bv4_bit_count: bpp,
+            bv4_v4_compression: BMPCompression::BITFIELDS,
+            bv4_size_image: size,
+            bv4_x_pels_per_meter: 0,
+            bv4_y_pels_per_meter: 0,
+            bv4_clr_used: 0,
+            bv4_clr_important: 0,
+            bv4_red_mask: masks[0] as i32,
+            bv4_green_mask: masks[1] as i32,
+            bv4_blue_mask: masks[2] as i32,
+            bv4_alpha_mask: masks[3] as i32,
+            bv4_cs_type: LCS_SRGB,
+            bv4_endpoints: CIEXYZTriple::default(),
+            bv4_gamma_red: 0,
+            bv4_gamma_green: 0,
+            bv4_gamma_blue: 0,
+        }
+    }
     pub fn load_from_reader<R: ?Sized + BufRead>(r: &mut R) -> io::Result<BMPV4Header> {
         Ok(BMPV4Header {
             bv4_size: r.read_i32::<LittleEndian>()?,
@@ -870,7 +1794,7 @@ impl BMPV4Header {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CIEXYZTriple {
     ciexyz_red: CIEXYZ,
     ciexyz_green: CIEXYZ,
@@ -893,7 +1817,7 @@ impl CIEXYZTriple {
 }
 
 type Fxpt2Dot30 = u32;
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CIEXYZ {
     ciexyz_x: Fxpt2Dot30,
     ciexyz_y: Fxpt2Dot30,
@@ -1001,3 +1925,111 @@ impl BMPV5Header {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// File bytes as `save_to_file` writes them
+    fn save(image: &BMPImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        image.header.save_to_writer(&mut data).unwrap();
+        image.info.save_to_writer(&mut data).unwrap();
+        data.extend_from_slice(&image.bitmap.data);
+        assert_eq!(data.len(), image.header.bf_size as usize);
+        data
+    }
+
+    fn load(data: &[u8]) -> BMPImage {
+        BMPImage::load_from_reader(&mut Cursor::new(data)).unwrap()
+    }
+
+    fn indexed(width: u32, height: u32, colors: usize) -> Pixels {
+        let palette = (0..colors).map(|i| RGBQuad::new(i as u8, 255 - i as u8, (i * 7) as u8)).collect();
+        let indexes = (0..width * height).map(|i| (i / 8 % colors as u32) as u8).collect();
+        Pixels::new(width, height, PixelData::Indexed { palette: palette, indexes: indexes })
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let rgba: Vec<u8> = (0..5 * 3 * 4).map(|i| (i * 37 % 256) as u8).collect();
+        let rgba = Pixels::new(5, 3, PixelData::RGBA(rgba));
+        let images = vec![
+            (indexed(9, 3, 2), 1),
+            (indexed(7, 3, 4), 2),
+            (indexed(5, 4, 16), 4),
+            (indexed(6, 2, 200), 8),
+            (rgba.clone(), 24),
+            (rgba.clone(), 32),
+        ];
+        for (pixels, bit_count) in images {
+            let image = BMPImage::from_pixels_with_depth(&pixels, bit_count).unwrap();
+            let mut loaded = load(&save(&image));
+            assert_eq!(loaded.info.bmi_header.get_bit_count(), bit_count);
+            let loaded = loaded.to_pixels().unwrap();
+            if bit_count == 32 {
+                assert_eq!(loaded.to_rgba(), pixels.to_rgba());
+            } else {
+                assert_eq!(loaded.to_rgb(), pixels.to_rgb(), "{} bits", bit_count);
+            }
+        }
+
+        // 5 bits per channel keep the pixels after the first save
+        let mut image = BMPImage::from_pixels_with_depth(&rgba, 16).unwrap();
+        let first = image.to_pixels().unwrap();
+        let mut loaded = load(&save(&BMPImage::from_pixels_with_depth(&first, 16).unwrap()));
+        assert_eq!(loaded.to_pixels().unwrap().to_rgb(), first.to_rgb());
+        assert!(BMPImage::from_pixels_with_depth(&rgba, 8).is_err());
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        let pixels = Pixels::new(2, 2, PixelData::RGB(vec![0; 12]));
+        let data = save(&BMPImage::from_pixels(&pixels).unwrap());
+        let patched = |width: i32, height: i32, bit_count: i16, size: i32| {
+            let mut data = data.clone();
+            (&mut data[18..]).write_i32::<LittleEndian>(width).unwrap();
+            (&mut data[22..]).write_i32::<LittleEndian>(height).unwrap();
+            (&mut data[28..]).write_i16::<LittleEndian>(bit_count).unwrap();
+            (&mut data[34..]).write_i32::<LittleEndian>(size).unwrap();
+            BMPImage::load_from_reader(&mut Cursor::new(data))
+        };
+        for &(width, height, bit_count) in &[
+            (-4, 2, 24),
+            (0, -1426063359, 24),
+            (2, i32::MIN, 24),
+            // the stride times the rows is beyond usize
+            (0x7FFF_FFFF, 0x7FFF_FFFF, 64),
+        ] {
+            // the size implied by the header is beyond the data
+            let err = patched(width, height, bit_count, 0).and_then(|mut image| image.to_pixels()).unwrap_err();
+            assert!(err.kind() == io::ErrorKind::InvalidData || err.kind() == io::ErrorKind::UnexpectedEof);
+            let mut image = patched(width, height, bit_count, 16).unwrap();
+            assert_eq!(image.to_pixels().unwrap_err().kind(), io::ErrorKind::InvalidData, "{}x{}", width, height);
+        }
+        let mut image = patched(0x7FFF_FFFF, 1, 24, 16).unwrap();
+        assert_eq!(image.to_pixels().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(patched(2, 2, 24, -1).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rle_round_trip() {
+        for &(colors, compression) in &[(16, BMPCompression::RLE4), (200, BMPCompression::RLE8)] {
+            let pixels = indexed(13, 5, colors);
+            let mut image = BMPImage::from_pixels(&pixels).unwrap();
+            let raw_size = image.bitmap.data.len();
+            image.encode_bitmap(compression).unwrap();
+            assert!(image.bitmap.data.len() < raw_size);
+            let mut loaded = load(&save(&image));
+            assert_eq!(loaded.info.bmi_header.get_compression_type().to_string(), compression.to_string());
+            loaded.decode_bitmap().unwrap();
+            assert_eq!(BMPCompression::to_bytes(&loaded.info.bmi_header.get_compression_type()), 0);
+            assert_eq!(loaded.bitmap.data.len(), raw_size);
+            assert_eq!(loaded.to_pixels().unwrap().to_rgb(), pixels.to_rgb());
+        }
+        let mut image = BMPImage::from_pixels(&indexed(4, 4, 2)).unwrap();
+        assert_eq!(image.encode_bitmap(BMPCompression::RLE8).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        image.info.bmi_header.set_encoding(BMPCompression::JPEG);
+        assert_eq!(image.decode_bitmap().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
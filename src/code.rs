@@ -0,0 +1,448 @@
+//! # Source code export
+//! Image pixels as constant byte arrays of C headers or Rust modules
+//! for firmware, with width, height and row size constants.
+//! Rows are top-down, optionally padded and PackBits (ByteRun1) compressed:
+//! a control byte n of 0 to 127 copies the next n + 1 bytes,
+//! n of -127 to -1 repeats the next byte 1 - n times.
+//! https://en.wikipedia.org/wiki/PackBits
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bmp;
+use color::ColorMetric;
+use format;
+use ilbm;
+use mono;
+use quantize::{self, Dither};
+use xbm;
+
+/// Array values per line written
+const VALUES_PER_LINE: usize = 12;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Language {
+    C,
+    Rust,
+}
+
+pub const LANGUAGES: [&'static str; 2] = ["c", "rust"];
+
+impl FromStr for Language {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Language> {
+        match s.to_lowercase().as_ref() {
+            "c" | "h" => Ok(Language::C),
+            "rust" | "rs" => Ok(Language::Rust),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown language: {}", s),
+            )),
+        }
+    }
+}
+
+impl Language {
+    pub fn from_extension<P: AsRef<Path>>(p: P) -> io::Result<Language> {
+        match p.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("h") | Some("c") | Some("hpp") | Some("cpp") => Ok(Language::C),
+            Some("rs") => Ok(Language::Rust),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't guess language of {:?}, use --lang", p.as_ref()),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PixelFormat {
+    /// 16 bits: 5 red, 6 green and 5 blue
+    RGB565,
+    /// 24 bits: 8 red, 8 green and 8 blue
+    RGB888,
+    /// 8 bits: 3 red, 3 green and 2 blue
+    RGB332,
+    /// 8 bit palette indexes and palette of RGB888 colors
+    Indexed8,
+    /// 1 bit black and white, packed as the monochrome layout says
+    Mono,
+}
+
+pub const PIXEL_FORMATS: [&'static str; 5] = ["rgb565", "rgb888", "rgb332", "indexed8", "mono"];
+
+impl Default for PixelFormat {
+    fn default() -> PixelFormat {
+        PixelFormat::RGB565
+    }
+}
+
+impl fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            PixelFormat::RGB565 => "RGB565",
+            PixelFormat::RGB888 => "RGB888",
+            PixelFormat::RGB332 => "RGB332",
+            PixelFormat::Indexed8 => "8 bit indexed",
+            PixelFormat::Mono => "1 bit monochrome",
+        })
+    }
+}
+
+impl FromStr for PixelFormat {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<PixelFormat> {
+        match s.to_lowercase().as_ref() {
+            "rgb565" => Ok(PixelFormat::RGB565),
+            "rgb888" => Ok(PixelFormat::RGB888),
+            "rgb332" => Ok(PixelFormat::RGB332),
+            "indexed8" | "indexed" => Ok(PixelFormat::Indexed8),
+            "mono" => Ok(PixelFormat::Mono),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown pixel format: {}", s),
+            )),
+        }
+    }
+}
+
+/// Order of bytes of multi-byte pixels, little-endian RGB888 is BGR
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+pub const BYTE_ORDERS: [&'static str; 2] = ["little", "big"];
+
+impl Default for ByteOrder {
+    fn default() -> ByteOrder {
+        ByteOrder::Little
+    }
+}
+
+impl fmt::Display for ByteOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ByteOrder::Little => "little-endian",
+            ByteOrder::Big => "big-endian",
+        })
+    }
+}
+
+impl FromStr for ByteOrder {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<ByteOrder> {
+        match s.to_lowercase().as_ref() {
+            "little" | "le" => Ok(ByteOrder::Little),
+            "big" | "be" => Ok(ByteOrder::Big),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown byte order: {}", s),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Guessed from the destination extension if not set
+    pub language: Option<Language>,
+    pub pixel_format: PixelFormat,
+    pub byte_order: ByteOrder,
+    /// Rows are padded to a multiple of this number of bytes
+    pub row_padding: Option<usize>,
+    /// Prefix of the symbols, made of the source file name if not set
+    pub name: Option<String>,
+    /// PackBits compression of the data
+    pub rle: bool,
+    /// Brightness from which monochrome pixels are white, 128 if not set
+    pub threshold: Option<u8>,
+    pub dither: Dither,
+    pub metric: ColorMetric,
+    pub layout: mono::Layout,
+    pub invert: bool,
+}
+
+/// Image encoded for the source code
+#[derive(Debug, Clone)]
+pub struct Code {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes of a padded row, or of a page of the page-major monochrome
+    pub row_size: usize,
+    /// Uncompressed data size
+    pub size: usize,
+    /// RGB888 colors of 8 bit indexed pixels
+    pub palette: Vec<u8>,
+    pub data: Vec<u8>,
+    pub description: String,
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.chars().next().map_or(false, |c| c.is_ascii_digit())
+}
+
+/// Encode pixels with the `opts` pixel format, padding and compression
+pub fn encode(pixels: &bmp::Pixels, name: &str, opts: &Options) -> io::Result<Code> {
+    if !is_identifier(name) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Symbol name {:?} is not an identifier", name),
+        ));
+    }
+    let width = pixels.width as usize;
+    let big = opts.byte_order == ByteOrder::Big;
+    let mut palette = Vec::new();
+    let (data, row_len) = match opts.pixel_format {
+        PixelFormat::RGB565 => {
+            let data = pixels.to_rgb().chunks(3).flat_map(|px| {
+                let v = (px[0] as u16 >> 3) << 11 | (px[1] as u16 >> 2) << 5 | px[2] as u16 >> 3;
+                if big { vec![(v >> 8) as u8, v as u8] } else { vec![v as u8, (v >> 8) as u8] }
+            }).collect();
+            (data, width * 2)
+        },
+        PixelFormat::RGB888 => {
+            let data = pixels.to_rgb().chunks(3).flat_map(|px| {
+                if big { vec![px[0], px[1], px[2]] } else { vec![px[2], px[1], px[0]] }
+            }).collect();
+            (data, width * 3)
+        },
+        PixelFormat::RGB332 => {
+            let data = pixels.to_rgb().chunks(3).map(|px| px[0] >> 5 << 5 | px[1] >> 5 << 2 | px[2] >> 6).collect();
+            (data, width)
+        },
+        PixelFormat::Indexed8 => {
+            let indexed = match *pixels {
+                bmp::Pixels { data: bmp::PixelData::Indexed { .. }, .. } => pixels.clone(),
+                _ => quantize::quantize(pixels, 256, opts.metric, opts.dither),
+            };
+            match indexed.data {
+                bmp::PixelData::Indexed { palette: ref colors, ref indexes } => {
+                    for c in colors {
+                        palette.extend_from_slice(&[c.red(), c.green(), c.blue()]);
+                    }
+                    (indexes.clone(), width)
+                },
+                _ => unreachable!("pixels are quantized"),
+            }
+        },
+        PixelFormat::Mono => {
+            let threshold = opts.threshold.unwrap_or(128);
+            let mono_pixels = quantize::monochrome(pixels, threshold, opts.dither);
+            let row_len = match opts.layout {
+                mono::Layout::Rows => (width + 7) / 8,
+                mono::Layout::Pages => width,
+            };
+            (mono::pack(&mono_pixels, opts.layout, opts.invert), row_len)
+        },
+    };
+    let padding = opts.row_padding.unwrap_or(1).max(1);
+    let row_size = (row_len + padding - 1) / padding * padding;
+    let mut padded = Vec::with_capacity(data.len() / row_len.max(1) * row_size);
+    for row in data.chunks(row_len.max(1)) {
+        padded.extend_from_slice(row);
+        padded.resize(padded.len() + row_size - row.len(), 0);
+    }
+    let mut description = format!("{}x{} px, {}", pixels.width, pixels.height, opts.pixel_format);
+    match opts.pixel_format {
+        PixelFormat::RGB565 | PixelFormat::RGB888 => description.push_str(&format!(" {}", opts.byte_order)),
+        PixelFormat::Indexed8 => description.push_str(&format!(", {} colors", palette.len() / 3)),
+        PixelFormat::Mono => description.push_str(&format!(
+            ", {}{}",
+            if opts.layout == mono::Layout::Pages { "pages of 8 rows" } else { "rows" },
+            if opts.invert { ", set bits are black" } else { ", set bits are white" },
+        )),
+        PixelFormat::RGB332 => {},
+    }
+    let size = padded.len();
+    let data = if opts.rle {
+        description.push_str(", PackBits compressed");
+        ilbm::byterun1_encode(&padded)
+    } else {
+        padded
+    };
+    Ok(Code {
+        name: name.to_owned(),
+        width: pixels.width,
+        height: pixels.height,
+        row_size: row_size,
+        size: size,
+        palette: palette,
+        data: data,
+        description: description,
+    })
+}
+
+/// Array values as lines of hex numbers
+fn array_lines(data: &[u8]) -> String {
+    let lines: Vec<String> = data.chunks(VALUES_PER_LINE).map(|line| {
+        let values: Vec<String> = line.iter().map(|b| format!("0x{:02x}", b)).collect();
+        format!("    {},", values.join(", "))
+    }).collect();
+    lines.join("\n")
+}
+
+fn write_c<W: ?Sized + Write>(code: &Code, w: &mut W, source: &str) -> io::Result<()> {
+    let upper = code.name.to_uppercase();
+    writeln!(w, "/* {} from {}: {} */", code.name, source, code.description)?;
+    writeln!(w, "#ifndef {}_H", upper)?;
+    writeln!(w, "#define {}_H\n", upper)?;
+    writeln!(w, "#include <stdint.h>\n")?;
+    writeln!(w, "#define {}_WIDTH {}", upper, code.width)?;
+    writeln!(w, "#define {}_HEIGHT {}", upper, code.height)?;
+    writeln!(w, "#define {}_ROW_SIZE {}", upper, code.row_size)?;
+    writeln!(w, "#define {}_SIZE {}\n", upper, code.size)?;
+    if !code.palette.is_empty() {
+        writeln!(w, "#define {}_COLORS {}\n", upper, code.palette.len() / 3)?;
+        writeln!(w, "static const uint8_t {}_palette[{}] = {{", code.name, code.palette.len())?;
+        writeln!(w, "{}\n}};\n", array_lines(&code.palette))?;
+    }
+    writeln!(w, "static const uint8_t {}_data[{}] = {{", code.name, code.data.len())?;
+    writeln!(w, "{}\n}};\n", array_lines(&code.data))?;
+    writeln!(w, "#endif /* {}_H */", upper)
+}
+
+fn write_rust<W: ?Sized + Write>(code: &Code, w: &mut W, source: &str) -> io::Result<()> {
+    let upper = code.name.to_uppercase();
+    writeln!(w, "//! {} from {}: {}\n", code.name, source, code.description)?;
+    writeln!(w, "pub const {}_WIDTH: usize = {};", upper, code.width)?;
+    writeln!(w, "pub const {}_HEIGHT: usize = {};", upper, code.height)?;
+    writeln!(w, "pub const {}_ROW_SIZE: usize = {};", upper, code.row_size)?;
+    writeln!(w, "pub const {}_SIZE: usize = {};\n", upper, code.size)?;
+    if !code.palette.is_empty() {
+        writeln!(w, "pub const {}_COLORS: usize = {};\n", upper, code.palette.len() / 3)?;
+        writeln!(w, "pub static {}_PALETTE: [u8; {}] = [", upper, code.palette.len())?;
+        writeln!(w, "{}\n];\n", array_lines(&code.palette))?;
+    }
+    writeln!(w, "pub static {}_DATA: [u8; {}] = [", upper, code.data.len())?;
+    writeln!(w, "{}\n];", array_lines(&code.data))
+}
+
+pub fn save_to_writer<W: ?Sized + Write>(code: &Code, w: &mut W, language: Language, source: &str) -> io::Result<()> {
+    match language {
+        Language::C => write_c(code, w, source),
+        Language::Rust => write_rust(code, w, source),
+    }
+}
+
+/// Export the first page of `src` image as source code into `dst`
+pub fn export(src: &str, dst: &str, opts: &Options) -> io::Result<()> {
+    let language = match opts.language {
+        Some(l) => l,
+        None => Language::from_extension(dst)?,
+    };
+    let name = opts.name.clone().unwrap_or_else(|| xbm::identifier(src).to_lowercase());
+    let pixels = format::load_pixels(src, 0)?;
+    let code = encode(&pixels, &name, opts)?;
+    let source = Path::new(src).file_name().and_then(|s| s.to_str()).unwrap_or(src);
+    let mut f = BufWriter::new(File::create(dst)?);
+    save_to_writer(&code, &mut f, language, source)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values of the array declared on the line containing `decl`
+    fn array(source: &str, decl: &str) -> Vec<u8> {
+        let start = source.find(decl).unwrap();
+        let start = start + source[start..].find('\n').unwrap();
+        let end = start + source[start..].find(&['}', ']'][..]).unwrap();
+        source[start..end].split(',').map(|v| v.trim()).filter(|v| !v.is_empty())
+            .map(|v| u8::from_str_radix(&v[2..], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn option_names() {
+        for name in &LANGUAGES {
+            name.parse::<Language>().unwrap();
+        }
+        for name in &PIXEL_FORMATS {
+            name.parse::<PixelFormat>().unwrap();
+        }
+        for name in &BYTE_ORDERS {
+            name.parse::<ByteOrder>().unwrap();
+        }
+        assert_eq!(Language::from_extension("logo.hpp").unwrap(), Language::C);
+        assert_eq!(Language::from_extension("src/logo.rs").unwrap(), Language::Rust);
+        assert_eq!(Language::from_extension("logo.txt").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!("RGB565".parse::<PixelFormat>().unwrap(), PixelFormat::RGB565);
+        assert!("rgb444".parse::<PixelFormat>().is_err());
+        assert!(is_identifier("_logo2"));
+        assert!(!is_identifier("2logo") && !is_identifier("my-logo") && !is_identifier(""));
+    }
+
+    #[test]
+    fn encode_pixel_formats() {
+        let pixels = bmp::Pixels::new(3, 1, bmp::PixelData::RGB(vec![255, 128, 64, 0, 0, 0, 8, 252, 255]));
+        let code = |format: PixelFormat, byte_order: ByteOrder, row_padding: Option<usize>| {
+            let opts = Options {
+                pixel_format: format,
+                byte_order: byte_order,
+                row_padding: row_padding,
+                ..Options::default()
+            };
+            encode(&pixels, "logo", &opts).unwrap()
+        };
+        assert_eq!(code(PixelFormat::RGB565, ByteOrder::Little, None).data, vec![0x08, 0xFC, 0, 0, 0xFF, 0x0F]);
+        assert_eq!(code(PixelFormat::RGB565, ByteOrder::Big, None).data, vec![0xFC, 0x08, 0, 0, 0x0F, 0xFF]);
+        assert_eq!(code(PixelFormat::RGB888, ByteOrder::Little, None).data, vec![64, 128, 255, 0, 0, 0, 255, 252, 8]);
+        assert_eq!(code(PixelFormat::RGB888, ByteOrder::Big, None).data, vec![255, 128, 64, 0, 0, 0, 8, 252, 255]);
+        let rgb332 = code(PixelFormat::RGB332, ByteOrder::Little, Some(4));
+        assert_eq!((rgb332.row_size, rgb332.size), (4, 4));
+        assert_eq!(rgb332.data, vec![0xF1, 0x00, 0x1F, 0x00]);
+        let mono = code(PixelFormat::Mono, ByteOrder::Little, Some(2));
+        assert_eq!(mono.data, vec![0b1010_0000, 0]);
+        let indexed = code(PixelFormat::Indexed8, ByteOrder::Little, None);
+        assert_eq!(indexed.palette.len() % 3, 0);
+        for (i, &idx) in indexed.data.iter().enumerate() {
+            assert_eq!(&indexed.palette[idx as usize * 3..idx as usize * 3 + 3], &pixels.to_rgb()[i * 3..i * 3 + 3]);
+        }
+        assert!(encode(&pixels, "my logo", &Options::default()).is_err());
+    }
+
+    #[test]
+    fn source_round_trip() {
+        let mut seed = 3u32;
+        let rgb: Vec<u8> = (0..17 * 9 * 3).map(|i| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            // runs of a color and noise to compress both ways
+            if i % 51 < 30 { (i % 3 * 80) as u8 } else { (seed >> 16) as u8 }
+        }).collect();
+        let pixels = bmp::Pixels::new(17, 9, bmp::PixelData::RGB(rgb));
+        for &format in &[PixelFormat::RGB565, PixelFormat::RGB888, PixelFormat::Indexed8, PixelFormat::Mono] {
+            for &language in &[Language::C, Language::Rust] {
+                let mut opts = Options { pixel_format: format, row_padding: Some(4), ..Options::default() };
+                let plain = encode(&pixels, "logo", &opts).unwrap();
+                assert_eq!((plain.row_size % 4, plain.data.len()), (0, plain.row_size * 9));
+                opts.rle = true;
+                let code = encode(&pixels, "logo", &opts).unwrap();
+                assert_eq!(code.size, plain.data.len());
+                let mut source = Vec::new();
+                save_to_writer(&code, &mut source, language, "logo.bmp").unwrap();
+                let source = String::from_utf8(source).unwrap();
+                let (data, palette) = match language {
+                    Language::C => (array(&source, "logo_data["), "logo_palette["),
+                    Language::Rust => (array(&source, "LOGO_DATA:"), "LOGO_PALETTE:"),
+                };
+                assert_eq!(data, code.data);
+                assert_eq!(ilbm::byterun1_decode(&data, code.size), plain.data);
+                if format == PixelFormat::Indexed8 {
+                    assert_eq!(array(&source, palette), plain.palette);
+                } else {
+                    assert!(!source.contains(palette));
+                }
+                let upper = if language == Language::C { "#define LOGO_WIDTH 17" } else { "LOGO_WIDTH: usize = 17;" };
+                assert!(source.contains(upper));
+            }
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! # Color distance
+//! Metrics used to find the nearest palette color.
+//!
+//! https://en.wikipedia.org/wiki/Color_difference
+//! http://www.brucelindbloom.com/index.html?Eqn_RGB_to_XYZ.html
+//! http://www2.ece.rochester.edu/~gsharma/ciede2000/ciede2000noteCRNA.pdf
+
+use std::f64::consts::PI;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+const LUMA: (f64, f64, f64) = (0.2126/*R*/, 0.7152/*G*/, 0.0722/*B*/);
+
+/// D65 reference white
+const WHITE: (f64, f64, f64) = (0.95047, 1.0, 1.08883);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ColorMetric {
+    /// Squared RGB difference weighted with the luma coefficients
+    Luma,
+    /// Plain RGB euclidean distance
+    Euclidean,
+    /// Low-cost RGB approximation weighted by the mean red level
+    Redmean,
+    /// Euclidean distance in CIELAB
+    CIE76,
+    /// CIE 2000 color difference in CIELAB
+    CIEDE2000,
+}
+
+pub const COLOR_METRICS: [&'static str; 5] = ["luma", "euclidean", "redmean", "cie76", "ciede2000"];
+
+impl Default for ColorMetric {
+    fn default() -> ColorMetric {
+        ColorMetric::Luma
+    }
+}
+
+impl fmt::Display for ColorMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ColorMetric::Luma => "luma",
+            ColorMetric::Euclidean => "euclidean",
+            ColorMetric::Redmean => "redmean",
+            ColorMetric::CIE76 => "cie76",
+            ColorMetric::CIEDE2000 => "ciede2000",
+        })
+    }
+}
+
+impl FromStr for ColorMetric {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<ColorMetric> {
+        match s.to_lowercase().as_ref() {
+            "luma" => Ok(ColorMetric::Luma),
+            "euclidean" | "rgb" => Ok(ColorMetric::Euclidean),
+            "redmean" => Ok(ColorMetric::Redmean),
+            "cie76" => Ok(ColorMetric::CIE76),
+            "ciede2000" => Ok(ColorMetric::CIEDE2000),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown color metric: {}", s),
+            )),
+        }
+    }
+}
+
+impl ColorMetric {
+    /// Distance between two sRGB colors, smaller is closer
+    pub fn delta(&self, a: [u8; 3], b: [u8; 3]) -> f64 {
+        let dr = b[0] as f64 - a[0] as f64;
+        let dg = b[1] as f64 - a[1] as f64;
+        let db = b[2] as f64 - a[2] as f64;
+        match *self {
+            ColorMetric::Luma => LUMA.0 * dr * dr + LUMA.1 * dg * dg + LUMA.2 * db * db,
+            ColorMetric::Euclidean => (dr * dr + dg * dg + db * db).sqrt(),
+            ColorMetric::Redmean => {
+                let rmean = (a[0] as f64 + b[0] as f64) / 2.0;
+                ((2.0 + rmean / 256.0) * dr * dr
+                    + 4.0 * dg * dg
+                    + (2.0 + (255.0 - rmean) / 256.0) * db * db).sqrt()
+            }
+            ColorMetric::CIE76 => Lab::from_rgb(a).cie76(&Lab::from_rgb(b)),
+            ColorMetric::CIEDE2000 => Lab::from_rgb(a).ciede2000(&Lab::from_rgb(b)),
+        }
+    }
+}
+
+/// A color in the CIELAB space (D65 white point)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Lab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+fn srgb_to_linear(c: u8) -> f64 {
+    let c = c as f64 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> u8 {
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round().max(0.0).min(255.0) as u8
+}
+
+fn lab_f(t: f64) -> f64 {
+    let delta: f64 = 6.0 / 29.0;
+    if t > delta.powi(3) {
+        t.cbrt()
+    } else {
+        t / (3.0 * delta * delta) + 4.0 / 29.0
+    }
+}
+
+fn lab_f_inv(t: f64) -> f64 {
+    let delta: f64 = 6.0 / 29.0;
+    if t > delta {
+        t.powi(3)
+    } else {
+        3.0 * delta * delta * (t - 4.0 / 29.0)
+    }
+}
+
+impl Lab {
+    pub fn new(l: f64, a: f64, b: f64) -> Lab {
+        Lab { l: l, a: a, b: b }
+    }
+
+    /// Convert sRGB to CIE XYZ
+    pub fn rgb_to_xyz(rgb: [u8; 3]) -> (f64, f64, f64) {
+        let r = srgb_to_linear(rgb[0]);
+        let g = srgb_to_linear(rgb[1]);
+        let b = srgb_to_linear(rgb[2]);
+        (
+            0.4124564 * r + 0.3575761 * g + 0.1804375 * b,
+            0.2126729 * r + 0.7151522 * g + 0.0721750 * b,
+            0.0193339 * r + 0.1191920 * g + 0.9503041 * b,
+        )
+    }
+
+    /// Convert CIE XYZ to sRGB, out of gamut values are clamped
+    pub fn xyz_to_rgb(xyz: (f64, f64, f64)) -> [u8; 3] {
+        let (x, y, z) = xyz;
+        [
+            linear_to_srgb(3.2404542 * x - 1.5371385 * y - 0.4985314 * z),
+            linear_to_srgb(-0.9692660 * x + 1.8760108 * y + 0.0415560 * z),
+            linear_to_srgb(0.0556434 * x - 0.2040259 * y + 1.0572252 * z),
+        ]
+    }
+
+    pub fn from_rgb(rgb: [u8; 3]) -> Lab {
+        let (x, y, z) = Lab::rgb_to_xyz(rgb);
+        let fx = lab_f(x / WHITE.0);
+        let fy = lab_f(y / WHITE.1);
+        let fz = lab_f(z / WHITE.2);
+        Lab {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+
+    pub fn to_rgb(&self) -> [u8; 3] {
+        let fy = (self.l + 16.0) / 116.0;
+        let fx = fy + self.a / 500.0;
+        let fz = fy - self.b / 200.0;
+        Lab::xyz_to_rgb((
+            WHITE.0 * lab_f_inv(fx),
+            WHITE.1 * lab_f_inv(fy),
+            WHITE.2 * lab_f_inv(fz),
+        ))
+    }
+
+    pub fn cie76(&self, other: &Lab) -> f64 {
+        ((self.l - other.l).powi(2) + (self.a - other.a).powi(2) + (self.b - other.b).powi(2)).sqrt()
+    }
+
+    pub fn ciede2000(&self, other: &Lab) -> f64 {
+        let (l1, a1, b1) = (self.l, self.a, self.b);
+        let (l2, a2, b2) = (other.l, other.a, other.b);
+
+        let c_mean = ((a1 * a1 + b1 * b1).sqrt() + (a2 * a2 + b2 * b2).sqrt()) / 2.0;
+        let g = 0.5 * (1.0 - (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt());
+        let a1p = (1.0 + g) * a1;
+        let a2p = (1.0 + g) * a2;
+        let c1p = (a1p * a1p + b1 * b1).sqrt();
+        let c2p = (a2p * a2p + b2 * b2).sqrt();
+        let hue = |b: f64, ap: f64| {
+            if b == 0.0 && ap == 0.0 {
+                0.0
+            } else {
+                let h = b.atan2(ap).to_degrees();
+                if h < 0.0 { h + 360.0 } else { h }
+            }
+        };
+        let h1p = hue(b1, a1p);
+        let h2p = hue(b2, a2p);
+
+        let dlp = l2 - l1;
+        let dcp = c2p - c1p;
+        let dhp = if c1p * c2p == 0.0 {
+            0.0
+        } else if (h2p - h1p).abs() <= 180.0 {
+            h2p - h1p
+        } else if h2p - h1p > 180.0 {
+            h2p - h1p - 360.0
+        } else {
+            h2p - h1p + 360.0
+        };
+        let dhp = 2.0 * (c1p * c2p).sqrt() * (dhp / 2.0).to_radians().sin();
+
+        let lp_mean = (l1 + l2) / 2.0;
+        let cp_mean = (c1p + c2p) / 2.0;
+        let hp_mean = if c1p * c2p == 0.0 {
+            h1p + h2p
+        } else if (h1p - h2p).abs() <= 180.0 {
+            (h1p + h2p) / 2.0
+        } else if h1p + h2p < 360.0 {
+            (h1p + h2p + 360.0) / 2.0
+        } else {
+            (h1p + h2p - 360.0) / 2.0
+        };
+
+        let t = 1.0 - 0.17 * (hp_mean - 30.0).to_radians().cos()
+            + 0.24 * (2.0 * hp_mean).to_radians().cos()
+            + 0.32 * (3.0 * hp_mean + 6.0).to_radians().cos()
+            - 0.20 * (4.0 * hp_mean - 63.0).to_radians().cos();
+        let d_theta = 30.0 * (-((hp_mean - 275.0) / 25.0).powi(2)).exp();
+        let rc = 2.0 * (cp_mean.powi(7) / (cp_mean.powi(7) + 25f64.powi(7))).sqrt();
+        let sl = 1.0 + 0.015 * (lp_mean - 50.0).powi(2) / (20.0 + (lp_mean - 50.0).powi(2)).sqrt();
+        let sc = 1.0 + 0.045 * cp_mean;
+        let sh = 1.0 + 0.015 * cp_mean * t;
+        let rt = -(2.0 * d_theta * PI / 180.0).sin() * rc;
+
+        ((dlp / sl).powi(2)
+            + (dcp / sc).powi(2)
+            + (dhp / sh).powi(2)
+            + rt * (dcp / sc) * (dhp / sh)).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ciede2000_reference_pairs() {
+        // Sharma, Wu, Dalal test data
+        let pairs = [
+            (Lab::new(50.0, 2.6772, -79.7751), Lab::new(50.0, 0.0, -82.7485), 2.0425),
+            (Lab::new(50.0, 2.5, 0.0), Lab::new(73.0, 25.0, -18.0), 27.1492),
+            (Lab::new(60.2574, -34.0099, 36.2677), Lab::new(60.4626, -34.1751, 39.4387), 1.2644),
+            (Lab::new(2.0776, 0.0795, -1.1350), Lab::new(0.9033, -0.0636, -0.5514), 0.9082),
+        ];
+        for &(ref a, ref b, expected) in pairs.iter() {
+            assert!((a.ciede2000(b) - expected).abs() < 1e-4);
+            assert!((b.ciede2000(a) - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn lab_round_trip() {
+        for &rgb in [[0u8, 0, 0], [255, 255, 255], [12, 200, 77], [255, 0, 128]].iter() {
+            assert_eq!(Lab::from_rgb(rgb).to_rgb(), rgb);
+        }
+    }
+}
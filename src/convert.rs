@@ -0,0 +1,319 @@
+//! # Image conversion
+//! The source format is detected by magic bytes, the destination format
+//! is taken from `--to` or the destination file extension.
+//! Multi-page sources are converted from the first page.
+
+use std::io;
+use std::str::FromStr;
+
+use bmp;
+use color::ColorMetric;
+use dcx;
+use farbfeld;
+use format::{self, Format};
+use gif;
+use ico;
+use ilbm;
+use mono;
+use netpbm;
+use pcx;
+use png;
+use qoi;
+use quantize::{self, Dither};
+use ras;
+use sgi;
+use tga;
+use wbmp;
+use xbm;
+use xpm;
+
+/// Brightness threshold of WBMP and packed monochrome
+pub const DEFAULT_THRESHOLD: u8 = 128;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Compression {
+    None,
+    /// Run-length encoding of the destination format, RLE8 or RLE4 for BMP
+    RLE,
+    RLE8,
+    RLE4,
+    /// BI_PNG bitmap of BMP
+    PNG,
+}
+
+pub const COMPRESSIONS: [&'static str; 5] = ["none", "rle", "rle8", "rle4", "png"];
+
+impl FromStr for Compression {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Compression> {
+        match s.to_lowercase().as_ref() {
+            "none" => Ok(Compression::None),
+            "rle" => Ok(Compression::RLE),
+            "rle8" => Ok(Compression::RLE8),
+            "rle4" => Ok(Compression::RLE4),
+            "png" => Ok(Compression::PNG),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unsupported compression: {}", s),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Destination format, guessed from the destination extension if not set
+    pub to: Option<Format>,
+    /// Bits per pixel of the destination image
+    pub depth: Option<i16>,
+    /// Number of palette colors
+    pub colors: Option<usize>,
+    pub compression: Option<Compression>,
+    pub dither: Dither,
+    pub metric: ColorMetric,
+    /// Write plain (ASCII) Netpbm
+    pub plain: bool,
+    /// Maximum Netpbm sample value, 255 if not set
+    pub maxval: Option<u16>,
+    /// Write Adam7 interlaced PNG
+    pub interlace: bool,
+    /// Brightness from which pixels become white in black and white images,
+    /// median cut palette is used for 1 bit images if not set
+    pub threshold: Option<u8>,
+    /// Layout of packed monochrome
+    pub layout: mono::Layout,
+    /// Set bits of packed monochrome are black
+    pub invert: bool,
+}
+
+/// Smallest palette depth holding `colors`
+fn palette_depth(colors: usize) -> i16 {
+    if colors <= 2 {
+        1
+    } else if colors <= 16 {
+        4
+    } else {
+        8
+    }
+}
+
+/// Reduce pixels to the requested depth and number of colors
+fn reduce(pixels: bmp::Pixels, depth: Option<i16>, opts: &Options) -> io::Result<bmp::Pixels> {
+    let depth = match (depth, opts.colors) {
+        (Some(d), _) => d,
+        (None, Some(colors)) => palette_depth(colors),
+        (None, None) => return Ok(pixels),
+    };
+    if depth > 8 {
+        return Ok(pixels);
+    }
+    if let (1, Some(threshold)) = (depth, opts.threshold) {
+        return Ok(quantize::monochrome(&pixels, threshold, opts.dither));
+    }
+    let max_colors = 1usize << depth;
+    let colors = opts.colors.unwrap_or(max_colors);
+    if colors == 0 || colors > max_colors {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} colors don't fit into {} bits per pixel", colors, depth),
+        ));
+    }
+    if let bmp::PixelData::Indexed { ref palette, .. } = pixels.data {
+        if palette.len() <= colors && opts.dither == Dither::None {
+            return Ok(pixels);
+        }
+    }
+    Ok(quantize::quantize(&pixels, colors, opts.metric, opts.dither))
+}
+
+fn to_bmp(pixels: bmp::Pixels, opts: &Options) -> io::Result<bmp::BMPImage> {
+    let compression = match (opts.compression, opts.depth) {
+        (None, _) | (Some(Compression::None), _) => None,
+        (Some(Compression::RLE4), _) | (Some(Compression::RLE), Some(4)) => Some(bmp::BMPCompression::RLE4),
+        (Some(Compression::PNG), _) => Some(bmp::BMPCompression::PNG),
+        _ => Some(bmp::BMPCompression::RLE8),
+    };
+    let depth = match compression {
+        Some(bmp::BMPCompression::RLE8) => Some(opts.depth.unwrap_or(8)),
+        Some(bmp::BMPCompression::RLE4) => Some(opts.depth.unwrap_or(4)),
+        _ => opts.depth,
+    };
+    let pixels = reduce(pixels, depth, opts)?;
+    let mut image = match depth {
+        Some(d) => bmp::BMPImage::from_pixels_with_depth(&pixels, d)?,
+        None => bmp::BMPImage::from_pixels(&pixels)?,
+    };
+    if let Some(compression) = compression {
+        image.encode_bitmap(compression)?;
+    }
+    Ok(image)
+}
+
+/// Convert `src` image into `dst`
+pub fn convert(src: &str, dst: &str, opts: &Options) -> io::Result<()> {
+    let to = match opts.to {
+        Some(f) => f,
+        None => Format::from_extension(dst)?,
+    };
+    let compression_ok = match (to, opts.compression) {
+        (_, None) | (_, Some(Compression::None)) => true,
+        (Format::BMP, _) => true,
+        (Format::PNG, Some(Compression::PNG)) => true,
+        (Format::TGA, Some(Compression::RLE)) => true,
+        (Format::SunRaster, Some(Compression::RLE)) => true,
+        (Format::SGI, Some(Compression::RLE)) => true,
+        _ => false,
+    };
+    if !compression_ok {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Compression can't be chosen for {}", to),
+        ));
+    }
+    let pixels = format::load_pixels(src, 0)?;
+    match to {
+        Format::BMP => {
+            to_bmp(pixels, opts)?.save_to_file(dst)?;
+        },
+        Format::PCX => {
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            pcx::save_to_file(&pixels, dst)?;
+        },
+        Format::DCX => {
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            let mut data = Vec::new();
+            pcx::save_to_writer(&pixels, &mut data)?;
+            let mut dcx = dcx::DCXFile::new();
+            dcx.add_page(data)?;
+            dcx.save_to_file(dst)?;
+        },
+        Format::Netpbm(kind) => {
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            netpbm::save_to_file(&pixels, dst, kind, opts.plain, opts.maxval.unwrap_or(255))?;
+        },
+        Format::TGA => {
+            let depth = match opts.depth {
+                Some(d) if d <= 8 => Some(8),
+                d => d,
+            };
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            let rle = opts.compression == Some(Compression::RLE);
+            tga::save_to_file(&pixels, dst, depth.map(|d| d as u8), rle)?;
+        },
+        Format::ICO | Format::CUR => {
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            let (icon_type, hotspot) = match to {
+                Format::CUR => (ico::CUR_TYPE, Some((0, 0))),
+                _ => (ico::ICO_TYPE, None),
+            };
+            let mut icon = ico::IconFile::new(icon_type);
+            icon.entries.push(ico::IconEntry::from_pixels(&pixels, hotspot)?);
+            icon.save_to_file(dst)?;
+        },
+        Format::GIF => {
+            // GIF is always indexed
+            let pixels = match reduce(pixels, opts.depth, opts)? {
+                p @ bmp::Pixels { data: bmp::PixelData::Indexed { .. }, .. } => p,
+                p => quantize::quantize(&p, gif::GIF_MAX_COLORS, opts.metric, opts.dither),
+            };
+            gif::build(&[pixels], &gif::Options::default())?.save_to_file(dst)?;
+        },
+        Format::PNG => {
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            png::save_to_file(&pixels, dst, opts.interlace)?;
+        },
+        Format::QOI => {
+            let channels = match opts.depth {
+                Some(32) => Some(4),
+                Some(24) => Some(3),
+                _ => None,
+            };
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            qoi::save_to_file(&pixels, dst, channels)?;
+        },
+        Format::Farbfeld => {
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            farbfeld::save_to_file(&pixels, dst)?;
+        },
+        Format::XBM => {
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            xbm::save_to_file(&pixels, dst, None)?;
+        },
+        Format::XPM => {
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            xpm::save_to_file(&pixels, dst, None)?;
+        },
+        Format::ILBM => {
+            // ILBM is always indexed
+            let pixels = match reduce(pixels, opts.depth, opts)? {
+                p @ bmp::Pixels { data: bmp::PixelData::Indexed { .. }, .. } => p,
+                p => quantize::quantize(&p, 256, opts.metric, opts.dither),
+            };
+            ilbm::save_to_file(&pixels, dst)?;
+        },
+        Format::SunRaster => {
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            let rle = opts.compression == Some(Compression::RLE);
+            ras::save_to_file(&pixels, dst, opts.depth.map(|d| d as u8), rle)?;
+        },
+        Format::SGI => {
+            let channels = match opts.depth {
+                Some(32) => Some(4),
+                Some(24) => Some(3),
+                Some(16) => Some(2),
+                Some(8) => Some(1),
+                _ => None,
+            };
+            let pixels = reduce(pixels, opts.depth, opts)?;
+            let rle = opts.compression == Some(Compression::RLE);
+            sgi::save_to_file(&pixels, dst, channels, rle)?;
+        },
+        Format::WBMP => {
+            let pixels = quantize::monochrome(&pixels, opts.threshold.unwrap_or(DEFAULT_THRESHOLD), opts.dither);
+            wbmp::save_to_file(&pixels, dst)?;
+        },
+        Format::Mono => {
+            let pixels = quantize::monochrome(&pixels, opts.threshold.unwrap_or(DEFAULT_THRESHOLD), opts.dither);
+            mono::save_to_file(&pixels, dst, opts.layout, opts.invert)?;
+        },
+        Format::OS2(_) | Format::AVI => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Writing {} is not supported", to),
+        )),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn distinct(pixels: &bmp::Pixels) -> usize {
+        pixels.to_rgb().chunks(3).collect::<HashSet<_>>().len()
+    }
+
+    #[test]
+    fn reduce_palette_depth() {
+        // 7 colors of a 256 entry palette are used
+        let pixels = bmp::Pixels::new(16, 8, bmp::PixelData::Indexed {
+            palette: (0..256).map(|i| bmp::RGBQuad::new(i as u8, (i * 91) as u8, (i * 13) as u8)).collect(),
+            indexes: (0..128).map(|i| [3, 40, 77, 120, 160, 201, 250][(i % 16 / 3 + i / 16) % 7]).collect(),
+        });
+        for &dither in &[Dither::None, Dither::FloydSteinberg] {
+            let opts = Options { depth: Some(4), dither: dither, ..Options::default() };
+            let mut image = to_bmp(pixels.clone(), &opts).unwrap();
+            assert_eq!((image.info.bmi_header.get_bit_count(), image.info.bmi_colors.len()), (4, 16));
+            assert_eq!(image.to_pixels().unwrap().to_rgb(), pixels.to_rgb());
+        }
+        let opts = Options { depth: Some(4), colors: Some(3), ..Options::default() };
+        assert_eq!(distinct(&reduce(pixels.clone(), opts.depth, &opts).unwrap()), 3);
+        let opts = Options { colors: Some(17), ..Options::default() };
+        assert_eq!(distinct(&reduce(pixels.clone(), opts.depth, &opts).unwrap()), 7);
+        let opts = Options { depth: Some(1), threshold: Some(128), ..Options::default() };
+        let reduced = reduce(pixels.clone(), opts.depth, &opts).unwrap();
+        assert!(reduced.to_rgb().iter().all(|&v| v == 0 || v == 255));
+        let opts = Options { depth: Some(4), colors: Some(17), ..Options::default() };
+        assert_eq!(reduce(pixels, opts.depth, &opts).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
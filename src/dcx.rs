@@ -0,0 +1,199 @@
+//! # Format desciption
+//! DCX is a multi-page container of PCX images, mostly used by fax software.
+//! The file starts with a magic number followed by a table of up to 1023
+//! page offsets, the table is terminated by zero offset.
+//! https://www.fileformat.info/format/pcx/egff.htm
+//! http://fileformats.archiveteam.org/wiki/DCX
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+use format;
+use pcx;
+
+pub const DCX_MAGIC: u32 = 987_654_321;
+pub const DCX_MAX_PAGES: usize = 1023;
+/// Magic number and offsets table with the terminating zero offset
+pub const DCX_HEADER_SIZE: u32 = 4 + 4 * (DCX_MAX_PAGES as u32 + 1);
+
+#[derive(Debug)]
+pub struct DCXPage {
+    /// Offset of the page from the beginning of the DCX file
+    pub offset: u32,
+    /// Raw PCX file data
+    pub data: Vec<u8>,
+}
+
+impl DCXPage {
+    pub fn header(&self) -> io::Result<pcx::PCXHeader> {
+        pcx::PCXHeader::load_from_reader(&mut Cursor::new(&self.data[..]))
+    }
+    pub fn pixels(&self) -> io::Result<bmp::Pixels> {
+        pcx::load_from_reader(&mut Cursor::new(&self.data[..]))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DCXFile {
+    pub pages: Vec<DCXPage>,
+}
+
+impl DCXFile {
+    pub fn new() -> DCXFile {
+        DCXFile { pages: Vec::new() }
+    }
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<DCXFile> {
+        let mut f = BufReader::new(File::open(p)?);
+        DCXFile::load_from_reader(&mut f)
+    }
+    pub fn load_from_reader<R: ?Sized + Read + Seek>(r: &mut R) -> io::Result<DCXFile> {
+        let magic = r.read_u32::<LittleEndian>()?;
+        if magic != DCX_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid DCX magic number: {:#x}", magic),
+            ));
+        }
+        let mut offsets = Vec::new();
+        for _ in 0..(DCX_MAX_PAGES + 1) {
+            let offset = r.read_u32::<LittleEndian>()?;
+            if offset == 0 {
+                break;
+            }
+            offsets.push(offset);
+        }
+        let file_size = r.seek(SeekFrom::End(0))?;
+
+        let mut pages = Vec::with_capacity(offsets.len());
+        for &offset in &offsets {
+            // page ends where the next one starts, pages aren't required to be ordered
+            let end = offsets.iter()
+                .map(|&o| o as u64)
+                .filter(|&o| o > offset as u64)
+                .min()
+                .unwrap_or(file_size);
+            if end > file_size || offset as u64 >= end {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("DCX page offset {} is beyond the end of file", offset),
+                ));
+            }
+            let mut data = vec![0u8; (end - offset as u64) as usize];
+            r.seek(SeekFrom::Start(offset as u64))?;
+            r.read_exact(&mut data)?;
+            pages.push(DCXPage {
+                offset: offset,
+                data: data,
+            });
+        }
+        Ok(DCXFile { pages: pages })
+    }
+
+    pub fn page(&self, idx: usize) -> io::Result<&DCXPage> {
+        self.pages.get(idx).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No page {} in DCX, it has {} pages", idx + 1, self.pages.len()),
+        ))
+    }
+
+    /// Append PCX file data as a new page
+    pub fn add_page(&mut self, data: Vec<u8>) -> io::Result<()> {
+        if self.pages.len() >= DCX_MAX_PAGES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("DCX can't hold more than {} pages", DCX_MAX_PAGES),
+            ));
+        }
+        pcx::PCXHeader::load_from_reader(&mut Cursor::new(&data[..]))?;
+        let offset = match self.pages.last() {
+            Some(p) => p.offset + p.data.len() as u32,
+            None => DCX_HEADER_SIZE,
+        };
+        self.pages.push(DCXPage {
+            offset: offset,
+            data: data,
+        });
+        Ok(())
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u32::<LittleEndian>(DCX_MAGIC)?;
+        let mut offset = DCX_HEADER_SIZE;
+        for page in &self.pages {
+            w.write_u32::<LittleEndian>(offset)?;
+            offset += page.data.len() as u32;
+        }
+        for _ in self.pages.len()..(DCX_MAX_PAGES + 1) {
+            w.write_u32::<LittleEndian>(0)?;
+        }
+        for page in &self.pages {
+            w.write_all(&page.data)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, p: P) -> io::Result<()> {
+        let mut f = BufWriter::new(File::create(p)?);
+        self.save_to_writer(&mut f)?;
+        f.flush()
+    }
+}
+
+/// Build DCX from image files, non PCX pages are converted into PCX
+pub fn build(sources: &[&str]) -> io::Result<DCXFile> {
+    let mut dcx = DCXFile::new();
+    for src in sources {
+        let mut data = Vec::new();
+        if format::detect(src)? == format::Format::PCX {
+            File::open(src)?.read_to_end(&mut data)?;
+        } else {
+            let pixels = format::load_pixels(src, 0)?;
+            pcx::save_to_writer(&pixels, &mut data)?;
+        }
+        dcx.add_page(data)?;
+    }
+    Ok(dcx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcx_page(index: u8) -> Vec<u8> {
+        let pixels = bmp::Pixels::new(3, 2, bmp::PixelData::Indexed {
+            palette: vec![bmp::RGBQuad::new(0, 0, 0), bmp::RGBQuad::new(255, 255, 255)],
+            indexes: vec![index, 0, 1, 1, 0, index],
+        });
+        let mut data = Vec::new();
+        pcx::save_to_writer(&pixels, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let mut dcx = DCXFile::new();
+        dcx.add_page(pcx_page(0)).unwrap();
+        dcx.add_page(pcx_page(1)).unwrap();
+        let mut data = Vec::new();
+        dcx.save_to_writer(&mut data).unwrap();
+        let loaded = DCXFile::load_from_reader(&mut Cursor::new(data)).unwrap();
+        assert_eq!(loaded.pages.len(), 2);
+        assert_eq!(loaded.pages[1].data, pcx_page(1));
+        assert!(loaded.page(2).is_err());
+    }
+
+    #[test]
+    fn page_offset_beyond_end() {
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(DCX_MAGIC).unwrap();
+        data.write_u32::<LittleEndian>(12).unwrap();
+        data.write_u32::<LittleEndian>(1000).unwrap();
+        data.write_u32::<LittleEndian>(0).unwrap();
+        data.extend(pcx_page(0));
+        let err = DCXFile::load_from_reader(&mut Cursor::new(data)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
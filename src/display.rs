@@ -9,24 +9,41 @@ use self::gtk::{Window, HeaderBar, DrawingArea, WindowType};
 use self::gdk::ContextExt;
 use self::gdk_pixbuf::Pixbuf;
 
-use pcx;
+use bmp;
+use format;
 
-fn pixbuf_from_file(name: &str) -> io::Result<Pixbuf> {
-    let pb = Pixbuf::new_from_file(name);
-    if pb.is_ok() {
-        return Ok(pb.unwrap());
+fn pixbuf_from_file(name: &str, page: usize) -> io::Result<Pixbuf> {
+    if format::detect(name).is_ok() {
+        return format::load_pixels(name, page).map(pixbuf_from_pixels);
     }
-    if name.to_lowercase().ends_with(".pcx") {
-        return pcx::pixbuf_from_file(name);
-    }
-    Err(io::Error::new(
+    // formats unknown to bmper are left to gdk-pixbuf loaders
+    Pixbuf::new_from_file(name).map_err(|e| io::Error::new(
         io::ErrorKind::InvalidInput,
-        format!("Can't load image data: '{}'", pb.unwrap_err()),
+        format!("Can't load image data: '{}'", e),
     ))
 }
 
-pub fn image(name: &str) {
-    let image = pixbuf_from_file(name).unwrap_or_else(|e| {
+fn pixbuf_from_pixels(pixels: bmp::Pixels) -> Pixbuf {
+    let has_alpha = pixels.has_alpha();
+    let (data, bytes_per_pixel) = if has_alpha {
+        (pixels.to_rgba(), 4)
+    } else {
+        (pixels.to_rgb(), 3)
+    };
+    Pixbuf::new_from_vec(
+        data,                                   // vec
+        0 as gdk_pixbuf::Colorspace,            // GDK_COLORSPACE_RGB = 0 colorspace
+        has_alpha,                              // has_alpha
+        8,                                      // bits_per_sample (only 8 bps supported)
+        pixels.width as i32,
+        pixels.height as i32,
+        pixels.width as i32 * bytes_per_pixel,  // row_stride for pixbuf
+    )
+}
+
+/// Show image in a window, `page` selects a page of multi-page files
+pub fn image(name: &str, page: usize) {
+    let image = pixbuf_from_file(name, page).unwrap_or_else(|e| {
         eprintln!("Can't display: {}", e);
         process::exit(1);
     });
@@ -2,9 +2,16 @@
 //! For decoding/encoding implementations details
 //! see http://www.binaryessence.com/dct/en000073.htm
 //!
+//! # RLE4
+//! Same markers as RLE8, but runs and absolute blocks count 4 bit pixels,
+//! two pixels are packed into a byte.
+//! https://msdn.microsoft.com/en-us/library/dd183383(v=vs.85).aspx
+//!
 //! # BitFields
 //! http://www.fileformat.info/format/bmp/egff.htm
 
+use std::io;
+
 use bmp;
 
 /// Start marker
@@ -16,101 +23,355 @@ pub const RLE_EOB: u8 = 0x01;
 /// The delta marker indicates a jump relative to the current position.
 pub const RLE_DELTA: u8 = 0x02;
 
+/// Longest run or absolute block
+const RLE_MAX_RUN: usize = 255;
+/// Absolute block takes at least 3 pixels, shorter ones clash with markers
+const RLE_MIN_ABSOLUTE: usize = 3;
+
 pub trait Rle8 {
-    fn encode(&mut self, _width: i32, _height: i32);
-    fn decode(&mut self, width: i32, height: i32);
+    fn encode(&mut self, width: i32, height: i32);
+    fn decode(&mut self, width: i32, height: i32) -> io::Result<()>;
+}
+
+pub trait Rle4 {
+    fn encode(&mut self, width: i32, height: i32);
+    fn decode(&mut self, width: i32, height: i32) -> io::Result<()>;
 }
+
 impl Rle8 for bmp::Bitmap {
-    fn encode(&mut self, _width: i32, _height: i32) {
-        unimplemented!("bmp Rle8 bitmap encoding");
-        //self.decoded_from = None
-        //self.data = Vec::new();
+    fn encode(&mut self, width: i32, height: i32) {
+        let rows = unpack_rows(&self.data, width, height, 8);
+        self.data = rle_encode(&rows, 1);
+        self.decoded_from = None;
     }
-    fn decode(&mut self, width: i32, height: i32) {
+    fn decode(&mut self, width: i32, height: i32) -> io::Result<()> {
         if self.decoded_from.is_some() {
             // if alredy decoded, do no thing
-            return
+            return Ok(())
         }
-        assert!(height > 0);
-        let mut decoded_bm = Vec::new();
-        let mut x: i32 = 0;
-        let mut y = height - 1;
-        {
-            let mut it = self.data.iter();
-            loop {
-                let first;
-                let second;
-                match it.next() {
-                    Some(val) => first = *val,
-                    None => break,
-                };
-                match it.next() {
-                    Some(val) => second = *val,
-                    None => break,
-                };
+        self.data = rle_decode(&self.data, width, height, 1)?;
+        self.decoded_from = Some(bmp::BMPCompression::RLE8);
+        Ok(())
+    }
+}
 
-                match first {
-                    RLE_MARK => {
-                        match second {
-                            RLE_EOB => break,
-                            RLE_EOL => {
-                                let line_pad = width - x;
-                                if x > 0 && line_pad > 0 {
-                                    let len = decoded_bm.len();
-                                    decoded_bm.resize(len + line_pad as usize, 0u8);
-                                }
-                                x = 0;
-                                y -= 1;
-                            },
-                            RLE_DELTA => {
-                                let delta_x;
-                                let delta_y;
-                                match it.next() {
-                                    Some(val) => delta_x = *val as i32,
-                                    None => break,
-                                };
-                                match it.next() {
-                                    Some(val) => delta_y = *val as i32,
-                                    None => break,
-                                };
-                                let len = decoded_bm.len();
-                                let append = (delta_x + delta_y * width) as usize;
-                                x += delta_x;
-                                y -= delta_y;
-                                decoded_bm.resize(len + append, 0u8);
+impl Rle4 for bmp::Bitmap {
+    fn encode(&mut self, width: i32, height: i32) {
+        let rows = unpack_rows(&self.data, width, height, 4);
+        self.data = rle_encode(&rows, 2);
+        self.decoded_from = None;
+    }
+    fn decode(&mut self, width: i32, height: i32) -> io::Result<()> {
+        if self.decoded_from.is_some() {
+            // if alredy decoded, do no thing
+            return Ok(())
+        }
+        self.data = rle_decode(&self.data, width, height, 2)?;
+        self.decoded_from = Some(bmp::BMPCompression::RLE4);
+        Ok(())
+    }
+}
+
+/// Split uncompressed bitmap into rows of pixel values, in storage order
+fn unpack_rows(data: &[u8], width: i32, height: i32, bit_count: i16) -> Vec<Vec<u8>> {
+    let stride = bmp::row_stride(width, bit_count);
+    let width = width as usize;
+    data.chunks(stride).take(height as usize).map(|line| {
+        (0..width).map(|x| if bit_count == 8 {
+            line[x]
+        } else if x % 2 == 0 {
+            line[x / 2] >> 4
+        } else {
+            line[x / 2] & 0x0F
+        }).collect()
+    }).collect()
+}
+
+/// Split a row into runs of equal pixels and absolute blocks.
+/// Returns (is_run, start, length) triples.
+fn rle_blocks(row: &[u8]) -> Vec<(bool, usize, usize)> {
+    let mut blocks = Vec::new();
+    let mut idx = 0;
+    while idx < row.len() {
+        let mut run = 1;
+        while idx + run < row.len() && run < RLE_MAX_RUN && row[idx + run] == row[idx] {
+            run += 1;
+        }
+        if run > 1 {
+            blocks.push((true, idx, run));
+            idx += run;
+            continue;
+        }
+        // collect pixels until the next run starts
+        let mut len = 1;
+        while idx + len < row.len() && len < RLE_MAX_RUN {
+            if idx + len + 1 < row.len() && row[idx + len] == row[idx + len + 1] {
+                break;
+            }
+            len += 1;
+        }
+        if len < RLE_MIN_ABSOLUTE {
+            for i in 0..len {
+                blocks.push((true, idx + i, 1));
+            }
+        } else {
+            blocks.push((false, idx, len));
+        }
+        idx += len;
+    }
+    blocks
+}
+
+/// Encode rows of pixels, `pixels_per_byte` is 1 for RLE8 and 2 for RLE4
+fn rle_encode(rows: &[Vec<u8>], pixels_per_byte: usize) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for (y, row) in rows.iter().enumerate() {
+        for (is_run, start, len) in rle_blocks(row) {
+            if is_run {
+                let value = if pixels_per_byte == 1 { row[start] } else { row[start] << 4 | row[start] };
+                encoded.push(len as u8);
+                encoded.push(value);
+                continue;
+            }
+            encoded.push(RLE_MARK);
+            encoded.push(len as u8);
+            let block = &row[start..start + len];
+            let mut bytes: Vec<u8> = if pixels_per_byte == 1 {
+                block.to_vec()
+            } else {
+                block.chunks(2).map(|p| p[0] << 4 | p.get(1).cloned().unwrap_or(0)).collect()
+            };
+            // absolute blocks are word aligned
+            if bytes.len() % 2 == 1 {
+                bytes.push(0);
+            }
+            encoded.append(&mut bytes);
+        }
+        encoded.push(RLE_MARK);
+        encoded.push(if y + 1 == rows.len() { RLE_EOB } else { RLE_EOL });
+    }
+    encoded
+}
+
+/// Pixel `idx` of a run or absolute block value
+fn unpack_pixel(value: u8, idx: usize, pixels_per_byte: usize) -> u8 {
+    if pixels_per_byte == 1 {
+        value
+    } else if idx % 2 == 0 {
+        value >> 4
+    } else {
+        value & 0x0F
+    }
+}
+
+/// Decode RLE8 delta frame, as MS RLE video does, over the previous
+/// uncompressed frame. Skipped pixels keep the previous values.
+pub fn rle8_decode_delta(data: &[u8], previous: &[u8], width: i32, height: i32) -> io::Result<Vec<u8>> {
+    let mut decoded_bm = previous.to_vec();
+    rle_decode_into(data, &mut decoded_bm, width, height, 1)?;
+    Ok(decoded_bm)
+}
+
+/// Decode into uncompressed bitmap with padded rows,
+/// pixels skipped with delta or end of line markers are left zero.
+fn rle_decode(data: &[u8], width: i32, height: i32, pixels_per_byte: usize) -> io::Result<Vec<u8>> {
+    let mut decoded_bm = Vec::new();
+    rle_decode_into(data, &mut decoded_bm, width, height, pixels_per_byte)?;
+    Ok(decoded_bm)
+}
+
+/// Decode over the `decoded_bm` bitmap, which is resized to the bitmap size.
+/// RLE bitmaps are bottom-up and fit the 32 bit size fields of BMP headers.
+fn rle_decode_into(
+    data: &[u8], decoded_bm: &mut Vec<u8>,
+    width: i32, height: i32,
+    pixels_per_byte: usize,
+) -> io::Result<()> {
+    let bit_count = 8 / pixels_per_byte as i16;
+    if width <= 0 || height <= 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid RLE bitmap size: {}x{} px", width, height),
+        ));
+    }
+    let stride = bmp::row_stride(width, bit_count);
+    let size = stride.checked_mul(height as usize).filter(|&size| size <= i32::MAX as usize).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("RLE bitmap of {}x{} px is too large", width, height),
+        )
+    })?;
+    decoded_bm.resize(size, 0);
+    let (width, height) = (width as usize, height as usize);
+    let put = |bitmap: &mut [u8], x: usize, y: usize, value: u8| {
+        if x < width && y < height {
+            let pos = y * stride + x / pixels_per_byte;
+            if pixels_per_byte == 1 {
+                bitmap[pos] = value;
+            } else if x % 2 == 0 {
+                bitmap[pos] = bitmap[pos] & 0x0F | value << 4;
+            } else {
+                bitmap[pos] = bitmap[pos] & 0xF0 | value;
+            }
+        }
+    };
+
+    let mut x = 0usize;
+    let mut y = 0usize;
+    let mut it = data.iter();
+    loop {
+        let first;
+        let second;
+        match (it.next(), it.next()) {
+            (Some(a), Some(b)) => {
+                first = *a;
+                second = *b;
+            },
+            _ => break,
+        };
+        match first {
+            RLE_MARK => {
+                match second {
+                    RLE_EOB => break,
+                    RLE_EOL => {
+                        x = 0;
+                        y += 1;
+                    },
+                    RLE_DELTA => {
+                        match (it.next(), it.next()) {
+                            (Some(delta_x), Some(delta_y)) => {
+                                x += *delta_x as usize;
+                                y += *delta_y as usize;
                             },
-                            _ => { // absolute mode
-                                let with_word_pad = ((second + 1) / 2) * 2;
-                                for _ in 0..with_word_pad {
-                                    match it.next() {
-                                        Some(val) => {
-                                            decoded_bm.push(*val);
-                                        }
-                                        None => break,
-                                    };
-                                }
-                                x += second as i32;
+                            _ => break,
+                        };
+                    },
+                    _ => { // absolute mode
+                        let count = second as usize;
+                        let bytes = (count + pixels_per_byte - 1) / pixels_per_byte;
+                        let with_word_pad = (bytes + 1) / 2 * 2;
+                        let block: Vec<u8> = it.by_ref().take(with_word_pad).cloned().collect();
+                        for idx in 0..count {
+                            if let Some(value) = block.get(idx / pixels_per_byte) {
+                                put(decoded_bm, x, y, unpack_pixel(*value, idx, pixels_per_byte));
                             }
+                            x += 1;
                         }
                     }
-                    _ => { // encoded mode
-                        let len = decoded_bm.len();
-                        decoded_bm.resize(len + first as usize, second);
-                        x += first as i32;
-                        if x >= width {
-                            x = x % width;
-                            y -= 1;
-                        }
-                    }
+                }
+            }
+            _ => { // encoded mode
+                for idx in 0..(first as usize) {
+                    put(decoded_bm, x, y, unpack_pixel(second, idx, pixels_per_byte));
+                    x += 1;
                 }
             }
         }
-        let append = width - x + width * y;
-        if append > 0 {
-            let len = decoded_bm.len();
-            decoded_bm.resize(len + append as usize, 0u8);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitmap(data: Vec<u8>) -> bmp::Bitmap {
+        bmp::Bitmap {
+            data: data,
+            decoded_from: None,
+        }
+    }
+
+    #[test]
+    fn rle8_encode_blocks() {
+        // run, two single pixels and a run
+        let mut b = bitmap(vec![1, 1, 1, 1, 2, 3, 4, 4]);
+        Rle8::encode(&mut b, 8, 1);
+        assert_eq!(b.data, vec![4, 1, 1, 2, 1, 3, 2, 4, RLE_MARK, RLE_EOB]);
+        // absolute block is padded to a word
+        let mut b = bitmap(vec![1, 2, 3, 5, 5, 0, 0, 0]);
+        Rle8::encode(&mut b, 5, 1);
+        assert_eq!(b.data, vec![RLE_MARK, 3, 1, 2, 3, 0, 2, 5, RLE_MARK, RLE_EOB]);
+    }
+
+    #[test]
+    fn rle8_decode_reference() {
+        // example of the BMP compression documentation
+        let data = [
+            0x03, 0x04, 0x05, 0x06, 0x00, 0x03, 0x45, 0x56, 0x67, 0x00, 0x02, 0x78,
+            0x00, 0x02, 0x05, 0x01, 0x02, 0x78, 0x00, 0x00, 0x09, 0x1E, 0x00, 0x01,
+        ];
+        let mut b = bitmap(data.to_vec());
+        Rle8::decode(&mut b, 20, 3).unwrap();
+        let mut expected = vec![0u8; 60];
+        expected[..13].copy_from_slice(&[4, 4, 4, 6, 6, 6, 6, 6, 0x45, 0x56, 0x67, 0x78, 0x78]);
+        expected[38..40].copy_from_slice(&[0x78, 0x78]);
+        for px in &mut expected[40..49] {
+            *px = 0x1E;
+        }
+        assert_eq!(b.data, expected);
+    }
+
+    #[test]
+    fn rle4_decode_reference() {
+        let data = [0x03, 0x04, 0x00, 0x05, 0x12, 0x34, 0x50, 0x00, 0x00, 0x01];
+        let mut b = bitmap(data.to_vec());
+        Rle4::decode(&mut b, 8, 1).unwrap();
+        assert_eq!(b.data, vec![0x04, 0x01, 0x23, 0x45]);
+    }
+
+    #[test]
+    fn rle_decode_invalid_sizes() {
+        let data = [0x03, 0x04, 0x00, 0x01];
+        for &(width, height) in &[(4, -4), (4, 0), (0, 4), (-1, 4), (0x7FFF_FFFF, 0x7FFF_FFFF)] {
+            let mut b = bitmap(data.to_vec());
+            assert_eq!(Rle8::decode(&mut b, width, height).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            let mut b = bitmap(data.to_vec());
+            assert_eq!(Rle4::decode(&mut b, width, height).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert!(rle8_decode_delta(&data, &[], width, height).is_err());
+        }
+        assert_eq!(rle8_decode_delta(&data, &[9; 8], 4, 2).unwrap(), vec![4, 4, 4, 9, 9, 9, 9, 9]);
+    }
+
+    #[test]
+    fn rle8_round_trip() {
+        // odd width, long runs and absolute blocks over the limit of 255
+        let width = 601;
+        let height = 3;
+        let stride = bmp::row_stride(width, 8);
+        let mut data = vec![0u8; stride * height as usize];
+        for y in 0..height as usize {
+            for x in 0..width as usize {
+                data[y * stride + x] = match x {
+                    0..=299 => 7,
+                    300..=579 => (x * 7 + y) as u8,
+                    _ => (x / 2) as u8,
+                };
+            }
+        }
+        let mut b = bitmap(data.clone());
+        Rle8::encode(&mut b, width, height);
+        assert!(b.data.len() < data.len());
+        Rle8::decode(&mut b, width, height).unwrap();
+        assert_eq!(b.data, data);
+    }
+
+    #[test]
+    fn rle4_round_trip() {
+        for &width in [1, 2, 3, 9, 300].iter() {
+            let height = 4;
+            let stride = bmp::row_stride(width, 4);
+            let mut data = vec![0u8; stride * height as usize];
+            for y in 0..height as usize {
+                for x in 0..width as usize {
+                    let value = if x < width as usize / 2 { 0xA } else { (x * 3 + y) as u8 & 0x0F };
+                    data[y * stride + x / 2] |= if x % 2 == 0 { value << 4 } else { value };
+                }
+            }
+            let mut b = bitmap(data.clone());
+            Rle4::encode(&mut b, width, height);
+            Rle4::decode(&mut b, width, height).unwrap();
+            assert_eq!(b.data, data);
         }
-        self.decoded_from = Some(bmp::BMPCompression::RLE8);
-        self.data = decoded_bm;
     }
 }
@@ -0,0 +1,117 @@
+//! # Format desciption
+//! farbfeld: "farbfeld" magic, big-endian 32 bit width and height,
+//! then rows of 16 bit big-endian red, green, blue and alpha samples.
+//! https://tools.suckless.org/farbfeld/
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+
+pub const FARBFELD_MAGIC: &'static [u8; 8] = b"farbfeld";
+pub const FARBFELD_HEADER_SIZE: usize = 16;
+
+/// Read width and height after the magic
+pub fn load_size<R: ?Sized + Read>(r: &mut R) -> io::Result<(u32, u32)> {
+    let mut magic = [0u8; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != FARBFELD_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid farbfeld magic".to_owned()));
+    }
+    Ok((r.read_u32::<BigEndian>()?, r.read_u32::<BigEndian>()?))
+}
+
+/// Decode farbfeld, samples are scaled down to 8 bits.
+/// Opaque images become RGB pixels, the others RGBA.
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let (width, height) = load_size(r)?;
+    let size = (width as u64 * height as u64).checked_mul(8).ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("farbfeld image of {}x{} px is too large", width, height),
+    ))?;
+    let mut data = Vec::new();
+    r.take(size).read_to_end(&mut data)?;
+    if (data.len() as u64) < size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("farbfeld pixels are truncated: {} bytes, expected {}", data.len(), size),
+        ));
+    }
+    let rgba: Vec<u8> = data.chunks(2)
+        .map(|s| (((s[0] as u32) << 8 | s[1] as u32) * 255 + 32767) / 65535)
+        .map(|v| v as u8)
+        .collect();
+    let opaque = data.chunks(8).all(|px| px[6] == 0xFF && px[7] == 0xFF);
+    let pixel_data = if opaque {
+        let mut rgb = Vec::with_capacity(rgba.len() / 4 * 3);
+        for px in rgba.chunks(4) {
+            rgb.extend_from_slice(&px[..3]);
+        }
+        bmp::PixelData::RGB(rgb)
+    } else {
+        bmp::PixelData::RGBA(rgba)
+    };
+    Ok(bmp::Pixels::new(width, height, pixel_data))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// Encode pixels as farbfeld, 8 bit samples are scaled up to 16 bits
+pub fn save_to_writer<W: ?Sized + Write>(pixels: &bmp::Pixels, w: &mut W) -> io::Result<()> {
+    w.write_all(FARBFELD_MAGIC)?;
+    w.write_u32::<BigEndian>(pixels.width)?;
+    w.write_u32::<BigEndian>(pixels.height)?;
+    let mut data = Vec::with_capacity(pixels.width as usize * pixels.height as usize * 8);
+    for &sample in &pixels.to_rgba() {
+        data.write_u16::<BigEndian>(sample as u16 * 257)?;
+    }
+    w.write_all(&data)
+}
+
+pub fn save_to_file<P: AsRef<Path>>(pixels: &bmp::Pixels, p: P) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_load_round_trip() {
+        let rgb = bmp::Pixels::new(3, 2, bmp::PixelData::RGB((0..18).map(|i| i * 14).collect()));
+        let mut data = Vec::new();
+        save_to_writer(&rgb, &mut data).unwrap();
+        assert_eq!(data.len(), FARBFELD_HEADER_SIZE + 6 * 8);
+        assert_eq!(&data[16..24], &[0, 0, 0x0E, 0x0E, 0x1C, 0x1C, 0xFF, 0xFF]);
+        let loaded = load_from_reader(&mut &data[..]).unwrap();
+        assert!(!loaded.has_alpha());
+        assert_eq!(loaded.to_rgb(), rgb.to_rgb());
+
+        let rgba = bmp::Pixels::new(2, 2, bmp::PixelData::RGBA((0..16).map(|i| i * 16).collect()));
+        let mut data = Vec::new();
+        save_to_writer(&rgba, &mut data).unwrap();
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgba(), rgba.to_rgba());
+    }
+
+    #[test]
+    fn load_scaled_samples() {
+        let mut data = FARBFELD_MAGIC.to_vec();
+        data.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1, 0x80, 0x00, 0x00, 0xFF, 0x00, 0x7F, 0xFF, 0xFE]);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgba(), vec![128, 1, 0, 255]);
+        // the pixels are far shorter than the size says
+        let mut data = FARBFELD_MAGIC.to_vec();
+        data.extend_from_slice(&[0, 1, 0, 0, 0, 1, 0, 0]);
+        data.extend_from_slice(&[0; 16]);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        data[8..16].copy_from_slice(&[0xFF; 8]);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(load_from_reader(&mut &b"farbfelt\0\0\0\x01\0\0\0\x01"[..]).is_err());
+    }
+}
@@ -0,0 +1,387 @@
+//! # Format detection
+//! Image format is identified by the first bytes of the file,
+//! file extensions are used only to choose the output format.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+
+use avi;
+use bmp;
+use dcx;
+use farbfeld;
+use gif;
+use ico;
+use ilbm;
+use netpbm::{self, NetpbmType};
+use os2;
+use pcx;
+use png;
+use qoi;
+use ras;
+use sgi;
+use tga;
+use wbmp;
+use xbm;
+use xpm;
+
+/// Longest magic sequence checked by `sniff`, TGA header is checked as a whole
+pub const MAGIC_SIZE: usize = tga::TGA_HEADER_SIZE;
+
+/// OS/2 file types sharing the BMP file header layout
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OS2Type {
+    /// "BA" bitmap array
+    BitmapArray,
+    /// "CI" color icon
+    ColorIcon,
+    /// "CP" color pointer
+    ColorPointer,
+    /// "IC" monochrome icon
+    Icon,
+    /// "PT" monochrome pointer
+    Pointer,
+}
+
+impl fmt::Display for OS2Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            OS2Type::BitmapArray => "OS/2 bitmap array",
+            OS2Type::ColorIcon => "OS/2 color icon",
+            OS2Type::ColorPointer => "OS/2 color pointer",
+            OS2Type::Icon => "OS/2 icon",
+            OS2Type::Pointer => "OS/2 pointer",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    BMP,
+    OS2(OS2Type),
+    PCX,
+    DCX,
+    Netpbm(NetpbmType),
+    TGA,
+    ICO,
+    CUR,
+    AVI,
+    GIF,
+    PNG,
+    QOI,
+    Farbfeld,
+    XBM,
+    XPM,
+    ILBM,
+    SunRaster,
+    SGI,
+    WBMP,
+    /// Packed monochrome, write only
+    Mono,
+}
+
+/// Formats which can be written, names are used by `--to`
+pub const OUTPUT_FORMATS: [&'static str; 22] = [
+    "bmp", "pcx", "dcx", "pbm", "pgm", "ppm", "pnm", "pam", "tga", "ico", "cur", "gif", "png", "qoi", "ff",
+    "xbm", "xpm", "iff", "ras", "sgi", "wbmp", "mono",
+];
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Format::BMP => f.write_str("bmp"),
+            Format::OS2(t) => write!(f, "{}", t),
+            Format::PCX => f.write_str("pcx"),
+            Format::DCX => f.write_str("dcx"),
+            Format::Netpbm(t) => write!(f, "{}", t),
+            Format::TGA => f.write_str("tga"),
+            Format::ICO => f.write_str("ico"),
+            Format::CUR => f.write_str("cur"),
+            Format::AVI => f.write_str("avi"),
+            Format::GIF => f.write_str("gif"),
+            Format::PNG => f.write_str("png"),
+            Format::QOI => f.write_str("qoi"),
+            Format::Farbfeld => f.write_str("farbfeld"),
+            Format::XBM => f.write_str("xbm"),
+            Format::XPM => f.write_str("xpm"),
+            Format::ILBM => f.write_str("ilbm"),
+            Format::SunRaster => f.write_str("ras"),
+            Format::SGI => f.write_str("sgi"),
+            Format::WBMP => f.write_str("wbmp"),
+            Format::Mono => f.write_str("mono"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Format> {
+        match s.to_lowercase().as_ref() {
+            "bmp" | "dib" => Ok(Format::BMP),
+            "pcx" => Ok(Format::PCX),
+            "dcx" => Ok(Format::DCX),
+            "pbm" => Ok(Format::Netpbm(NetpbmType::PBM)),
+            "pgm" => Ok(Format::Netpbm(NetpbmType::PGM)),
+            "ppm" | "pnm" => Ok(Format::Netpbm(NetpbmType::PPM)),
+            "pam" => Ok(Format::Netpbm(NetpbmType::PAM)),
+            "tga" | "tpic" => Ok(Format::TGA),
+            "ico" => Ok(Format::ICO),
+            "cur" => Ok(Format::CUR),
+            "avi" => Ok(Format::AVI),
+            "gif" => Ok(Format::GIF),
+            "png" => Ok(Format::PNG),
+            "qoi" => Ok(Format::QOI),
+            "ff" | "farbfeld" => Ok(Format::Farbfeld),
+            "xbm" => Ok(Format::XBM),
+            "xpm" => Ok(Format::XPM),
+            "iff" | "ilbm" | "lbm" => Ok(Format::ILBM),
+            "ras" | "sun" => Ok(Format::SunRaster),
+            "sgi" | "rgb" | "rgba" | "bw" | "int" | "inta" => Ok(Format::SGI),
+            "wbmp" | "wbm" => Ok(Format::WBMP),
+            "mono" => Ok(Format::Mono),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown image format: {}", s),
+            )),
+        }
+    }
+}
+
+impl Format {
+    pub fn from_extension<P: AsRef<Path>>(p: P) -> io::Result<Format> {
+        match p.as_ref().extension().and_then(|e| e.to_str()) {
+            Some(ext) => ext.parse(),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can't guess image format of {:?}, use --to", p.as_ref()),
+            )),
+        }
+    }
+}
+
+/// Identify image format by its first bytes
+pub fn sniff(magic: &[u8]) -> Option<Format> {
+    if magic.len() >= 4 && (&magic[..4]).read_u32::<LittleEndian>().ok() == Some(dcx::DCX_MAGIC) {
+        return Some(Format::DCX);
+    }
+    if magic.len() >= 2 {
+        match &magic[..2] {
+            b"BM" => return Some(Format::BMP),
+            b"BA" => return Some(Format::OS2(OS2Type::BitmapArray)),
+            b"CI" => return Some(Format::OS2(OS2Type::ColorIcon)),
+            b"CP" => return Some(Format::OS2(OS2Type::ColorPointer)),
+            b"IC" => return Some(Format::OS2(OS2Type::Icon)),
+            b"PT" => return Some(Format::OS2(OS2Type::Pointer)),
+            _ => {},
+        }
+    }
+    if magic.starts_with(png::PNG_SIGNATURE) {
+        return Some(Format::PNG);
+    }
+    if magic.starts_with(qoi::QOI_MAGIC) {
+        return Some(Format::QOI);
+    }
+    if magic.starts_with(farbfeld::FARBFELD_MAGIC) {
+        return Some(Format::Farbfeld);
+    }
+    if magic.starts_with(xpm::XPM_MAGIC) {
+        return Some(Format::XPM);
+    }
+    if magic.starts_with(xbm::XBM_MAGIC) {
+        return Some(Format::XBM);
+    }
+    if magic.starts_with(gif::GIF87A_SIGNATURE) || magic.starts_with(gif::GIF89A_SIGNATURE) {
+        return Some(Format::GIF);
+    }
+    if magic.len() >= 4 && (&magic[..4]).read_u32::<BigEndian>().ok() == Some(ras::RAS_MAGIC) {
+        return Some(Format::SunRaster);
+    }
+    // magic, storage and bytes per channel
+    if magic.len() >= 4 && (&magic[..2]).read_u16::<BigEndian>().ok() == Some(sgi::SGI_MAGIC)
+        && magic[2] <= sgi::STORAGE_RLE && (magic[3] == 1 || magic[3] == 2) {
+        return Some(Format::SGI);
+    }
+    if magic.len() >= 12 && &magic[..4] == b"FORM" && &magic[8..12] == b"ILBM" {
+        return Some(Format::ILBM);
+    }
+    if magic.len() >= 12 && &magic[..4] == b"RIFF" && &magic[8..12] == b"AVI " {
+        return Some(Format::AVI);
+    }
+    // reserved zero, type and non-zero number of images
+    if magic.len() >= 6 && magic[..2] == [0, 0] && magic[3] == 0 && magic[4..6] != [0, 0] {
+        match magic[2] as u16 {
+            ico::ICO_TYPE => return Some(Format::ICO),
+            ico::CUR_TYPE => return Some(Format::CUR),
+            _ => {},
+        }
+    }
+    if magic.len() >= 3 && (magic[2] as char).is_whitespace() {
+        if let Some(t) = NetpbmType::from_magic(magic) {
+            return Some(Format::Netpbm(t));
+        }
+    }
+    // manufacturer, version and encoding bytes
+    if magic.len() >= 3 && magic[0] == pcx::PCX_MANUFACTURER && magic[1] <= 5 && magic[2] <= 1 {
+        return Some(Format::PCX);
+    }
+    // TGA has no magic, the header must be sane
+    if magic.len() >= tga::TGA_HEADER_SIZE && tga::TGAHeader::load_from_reader(&mut &magic[..]).is_ok() {
+        return Some(Format::TGA);
+    }
+    // WBMP has no magic, type 0 header without extensions must be sane
+    if magic.len() >= 4 && magic[..2] == [0, 0] && wbmp::WBMPHeader::load_from_reader(&mut &magic[..]).is_ok() {
+        return Some(Format::WBMP);
+    }
+    None
+}
+
+/// Identify format of the image file
+pub fn detect<P: AsRef<Path>>(p: P) -> io::Result<Format> {
+    let f = File::open(p.as_ref())?;
+    let mut magic = Vec::with_capacity(MAGIC_SIZE);
+    f.take(MAGIC_SIZE as u64).read_to_end(&mut magic)?;
+    sniff(&magic).ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unknown image format of {:?}", p.as_ref()),
+    ))
+}
+
+/// Load a page of the image of any supported format, pages of OS/2
+/// bitmap arrays and icons are their images, pages of AVI and GIF are frames. Single page formats have the only page 0.
+pub fn load_pixels<P: AsRef<Path>>(p: P, page: usize) -> io::Result<bmp::Pixels> {
+    let format = detect(p.as_ref())?;
+    let multi_page = match format {
+        Format::DCX | Format::OS2(OS2Type::BitmapArray) | Format::ICO | Format::CUR | Format::AVI | Format::GIF => true,
+        _ => false,
+    };
+    if !multi_page && page > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No page {}, {} has a single page", page + 1, format),
+        ));
+    }
+    match format {
+        Format::BMP => bmp::BMPImage::load_from_file(p)?.to_pixels(),
+        Format::PCX => pcx::load_from_file(p),
+        Format::DCX => dcx::DCXFile::load_from_file(p)?.page(page)?.pixels(),
+        Format::Netpbm(_) => netpbm::load_from_file(p),
+        Format::TGA => tga::load_from_file(p),
+        Format::OS2(_) => os2::OS2File::load_from_file(p)?.entry(page)?.image.to_pixels(),
+        Format::ICO | Format::CUR => ico::IconFile::load_from_file(p)?.entry(page)?.pixels(),
+        Format::AVI => avi::AVIFile::load_from_file(p)?.frame(page)?.to_pixels(),
+        Format::GIF => gif::GIFFile::load_from_file(p)?.frame(page),
+        Format::PNG => png::load_from_file(p),
+        Format::QOI => qoi::load_from_file(p),
+        Format::Farbfeld => farbfeld::load_from_file(p),
+        Format::XBM => xbm::load_from_file(p),
+        Format::XPM => xpm::load_from_file(p),
+        Format::ILBM => ilbm::load_from_file(p),
+        Format::SunRaster => ras::load_from_file(p),
+        Format::SGI => sgi::load_from_file(p),
+        Format::WBMP => wbmp::load_from_file(p),
+        Format::Mono => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} has no header to read, the size is unknown", format),
+        )),
+    }
+}
+
+/// Load image of any supported format as BMP, other formats are converted
+pub fn load_bmp<P: AsRef<Path>>(p: P) -> io::Result<bmp::BMPImage> {
+    match detect(p.as_ref())? {
+        Format::BMP => bmp::BMPImage::load_from_file(p),
+        _ => bmp::BMPImage::from_pixels(&load_pixels(p, 0)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_headers() {
+        let tga = [0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 24, 0];
+        for &(magic, format) in &[
+            (&b"BM\0\0\0\0"[..], Format::BMP),
+            (b"BA", Format::OS2(OS2Type::BitmapArray)),
+            (b"CI", Format::OS2(OS2Type::ColorIcon)),
+            (b"CP", Format::OS2(OS2Type::ColorPointer)),
+            (b"IC", Format::OS2(OS2Type::Icon)),
+            (b"PT", Format::OS2(OS2Type::Pointer)),
+            (&[0xB1, 0x68, 0xDE, 0x3A], Format::DCX),
+            (png::PNG_SIGNATURE, Format::PNG),
+            (b"qoif", Format::QOI),
+            (b"farbfeld", Format::Farbfeld),
+            (b"/* XPM */\nstatic", Format::XPM),
+            (b"#define a_width 8", Format::XBM),
+            (b"GIF87a", Format::GIF),
+            (b"GIF89a", Format::GIF),
+            (&[0x59, 0xA6, 0x6A, 0x95], Format::SunRaster),
+            (&[0x01, 0xDA, 0, 1], Format::SGI),
+            (&[0x01, 0xDA, 1, 2], Format::SGI),
+            (b"FORM\0\0\0\0ILBM", Format::ILBM),
+            (b"RIFF\0\0\0\0AVI ", Format::AVI),
+            (&[0, 0, 1, 0, 1, 0], Format::ICO),
+            (&[0, 0, 2, 0, 1, 0], Format::CUR),
+            (b"P1\n", Format::Netpbm(NetpbmType::PBM)),
+            (b"P4 ", Format::Netpbm(NetpbmType::PBM)),
+            (b"P5\t", Format::Netpbm(NetpbmType::PGM)),
+            (b"P6\r\n", Format::Netpbm(NetpbmType::PPM)),
+            (b"P7\n", Format::Netpbm(NetpbmType::PAM)),
+            (&[0x0A, 5, 1], Format::PCX),
+            (&[0x0A, 0, 0], Format::PCX),
+            (&tga, Format::TGA),
+            (&[0, 0, 8, 8], Format::WBMP),
+            // image type 8 is no TGA, the bitmap follows the WBMP header
+            (&[0, 0, 8, 8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0], Format::WBMP),
+            // 2x3 WBMP is a sane true color TGA header too, TGA is checked first
+            (&[0, 0, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 24, 0], Format::TGA),
+        ] {
+            assert_eq!(sniff(magic), Some(format), "{:?}", magic);
+        }
+    }
+
+    #[test]
+    fn sniff_unknown() {
+        let mut tga = [0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 24, 0];
+        tga[16] = 7;
+        for magic in &[
+            &b""[..],
+            b"B",
+            b"\x89PNG",
+            b"GIF8",
+            b"qoi",
+            b"FORM\0\0\0\0AIFF",
+            b"RIFF\0\0\0\0WAVE",
+            b"P8\n",
+            b"P6x",
+            b"P6",
+            &[0x01, 0xDA, 2, 1],
+            &[0x01, 0xDA, 0, 3],
+            &[0x0A, 6, 0],
+            &[0x0A, 5, 2],
+            &[0x0A, 5],
+            &[0, 0, 3, 0, 1, 0],
+            &[0, 0, 1, 0, 0, 0],
+            &[0, 0, 0, 8],
+            &[0, 0, 8],
+            &[0; MAGIC_SIZE],
+            &tga,
+            &tga[..MAGIC_SIZE - 1],
+        ] {
+            assert_eq!(sniff(magic), None, "{:?}", magic);
+        }
+
+        let mut seed = 7u32;
+        for _ in 0..256 {
+            let magic: Vec<u8> = (0..MAGIC_SIZE).map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            }).collect();
+            assert_eq!(sniff(&magic), None, "{:?}", magic);
+        }
+    }
+}
@@ -0,0 +1,809 @@
+//! # Format desciption
+//! GIF file is a header ("GIF87a" or "GIF89a"), logical screen descriptor
+//! with an optional global color table and a sequence of blocks:
+//!
+//! * 0x2C image descriptor, optional local color table and LZW compressed indexes
+//! * 0x21 extension: graphic control (delay, disposal, transparent index),
+//!   application (NETSCAPE2.0 loop count), comment or plain text
+//! * 0x3B trailer
+//!
+//! Data of images and extensions is split into sub-blocks upto 255 bytes
+//! prefixed with their length, an empty sub-block ends the data.
+//! Color tables have 2^n entries of RGB triples.
+//! https://www.w3.org/Graphics/GIF/spec-gif89a.txt
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+use color::ColorMetric;
+use format;
+use quantize::{self, Dither};
+
+pub const GIF87A_SIGNATURE: &'static [u8; 6] = b"GIF87a";
+pub const GIF89A_SIGNATURE: &'static [u8; 6] = b"GIF89a";
+pub const GIF_IMAGE_SEPARATOR: u8 = 0x2C;
+pub const GIF_EXTENSION_INTRODUCER: u8 = 0x21;
+pub const GIF_TRAILER: u8 = 0x3B;
+pub const GIF_GRAPHIC_CONTROL_LABEL: u8 = 0xF9;
+pub const GIF_APPLICATION_LABEL: u8 = 0xFF;
+pub const GIF_NETSCAPE_APPLICATION: &'static [u8; 11] = b"NETSCAPE2.0";
+/// Largest color table
+pub const GIF_MAX_COLORS: usize = 256;
+/// LZW codes are upto 12 bits
+const LZW_MAX_CODES: usize = 4096;
+const LZW_MAX_CODE_SIZE: u8 = 12;
+
+/// Disposal methods of graphic control extension
+pub const GIF_DISPOSE_NONE: u8 = 1;
+pub const GIF_DISPOSE_BACKGROUND: u8 = 2;
+pub const GIF_DISPOSE_PREVIOUS: u8 = 3;
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Number of bits of the color table able to hold `colors` entries, at least 1
+fn table_bits(colors: usize) -> u8 {
+    let mut bits = 1;
+    while (1 << bits) < colors {
+        bits += 1;
+    }
+    bits
+}
+
+fn load_color_table<R: ?Sized + Read>(r: &mut R, bits: u8) -> io::Result<Vec<bmp::RGBQuad>> {
+    let mut table = Vec::with_capacity(1 << bits);
+    for _ in 0..(1 << bits) {
+        let mut rgb = [0u8; 3];
+        r.read_exact(&mut rgb)?;
+        table.push(bmp::RGBQuad::new(rgb[0], rgb[1], rgb[2]));
+    }
+    Ok(table)
+}
+
+/// Write color table padded to 2^`bits` entries
+fn save_color_table<W: ?Sized + Write>(w: &mut W, table: &[bmp::RGBQuad], bits: u8) -> io::Result<()> {
+    for idx in 0..(1usize << bits) {
+        match table.get(idx) {
+            Some(c) => w.write_all(&[c.red(), c.green(), c.blue()])?,
+            None => w.write_all(&[0, 0, 0])?,
+        }
+    }
+    Ok(())
+}
+
+/// Read sub-blocks upto the terminating empty one
+fn load_sub_blocks<R: ?Sized + Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let len = r.read_u8()? as usize;
+        if len == 0 {
+            return Ok(data);
+        }
+        let start = data.len();
+        data.resize(start + len, 0);
+        r.read_exact(&mut data[start..])?;
+    }
+}
+
+fn save_sub_blocks<W: ?Sized + Write>(w: &mut W, data: &[u8]) -> io::Result<()> {
+    for block in data.chunks(255) {
+        w.write_u8(block.len() as u8)?;
+        w.write_all(block)?;
+    }
+    w.write_u8(0)
+}
+
+/// Decode LZW data into `count` indexes, missing ones are zero
+pub fn lzw_decode(data: &[u8], min_code_size: u8, count: usize) -> io::Result<Vec<u8>> {
+    if min_code_size < 2 || min_code_size >= LZW_MAX_CODE_SIZE {
+        return Err(invalid_data(format!("Invalid LZW minimum code size: {}", min_code_size)));
+    }
+    let clear = 1usize << min_code_size;
+    let end = clear + 1;
+    // every code is a prefix code followed by a byte
+    let mut prefix = [0u16; LZW_MAX_CODES];
+    let mut suffix = [0u8; LZW_MAX_CODES];
+    let mut first = [0u8; LZW_MAX_CODES];
+    let mut length = [0u16; LZW_MAX_CODES];
+    for code in 0..clear {
+        suffix[code] = code as u8;
+        first[code] = code as u8;
+        length[code] = 1;
+    }
+
+    let mut output = Vec::with_capacity(count);
+    let mut code_size = min_code_size + 1;
+    let mut next = end + 1;
+    let mut previous: Option<usize> = None;
+    let mut bits = 0u32;
+    let mut bit_count = 0u8;
+    let mut bytes = data.iter();
+    while output.len() < count {
+        while bit_count < code_size {
+            match bytes.next() {
+                Some(b) => {
+                    bits |= (*b as u32) << bit_count;
+                    bit_count += 8;
+                },
+                None => break,
+            }
+        }
+        if bit_count < code_size {
+            break;
+        }
+        let code = (bits & ((1 << code_size) - 1)) as usize;
+        bits >>= code_size;
+        bit_count -= code_size;
+
+        if code == clear {
+            code_size = min_code_size + 1;
+            next = end + 1;
+            previous = None;
+            continue;
+        }
+        if code == end {
+            break;
+        }
+        if let Some(prev) = previous {
+            let byte = if code < next {
+                first[code]
+            } else if code == next {
+                first[prev]
+            } else {
+                return Err(invalid_data(format!("Invalid LZW code {}, next is {}", code, next)));
+            };
+            if next < LZW_MAX_CODES {
+                prefix[next] = prev as u16;
+                suffix[next] = byte;
+                first[next] = first[prev];
+                length[next] = length[prev] + 1;
+                next += 1;
+                if next == 1 << code_size && code_size < LZW_MAX_CODE_SIZE {
+                    code_size += 1;
+                }
+            }
+        } else if code >= clear {
+            return Err(invalid_data(format!("Invalid first LZW code {}", code)));
+        }
+        // the string is collected from its end
+        let start = output.len();
+        output.resize(start + length[code] as usize, 0);
+        let mut c = code;
+        for pos in (start..output.len()).rev() {
+            output[pos] = suffix[c];
+            c = prefix[c] as usize;
+        }
+        previous = Some(code);
+    }
+    output.resize(count, 0);
+    Ok(output)
+}
+
+/// Writes variable size codes, least significant bits first
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u32,
+    bit_count: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: usize, size: u8) {
+        self.bits |= (code as u32) << self.bit_count;
+        self.bit_count += size;
+        while self.bit_count >= 8 {
+            self.data.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bit_count > 0 {
+            self.data.push(self.bits as u8);
+        }
+        self.data
+    }
+}
+
+/// Compress indexes with LZW, the table is cleared when it is full
+pub fn lzw_encode(indexes: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1usize << min_code_size;
+    let end = clear + 1;
+    let mut codes: HashMap<(usize, u8), usize> = HashMap::new();
+    let mut code_size = min_code_size + 1;
+    let mut next = end + 1;
+    let mut w = BitWriter {
+        data: Vec::with_capacity(indexes.len() / 2),
+        bits: 0,
+        bit_count: 0,
+    };
+    w.write(clear, code_size);
+    let mut pixels = indexes.iter();
+    let mut current = match pixels.next() {
+        Some(idx) => *idx as usize,
+        None => {
+            w.write(end, code_size);
+            return w.finish();
+        },
+    };
+    for &idx in pixels {
+        if let Some(&code) = codes.get(&(current, idx)) {
+            current = code;
+            continue;
+        }
+        w.write(current, code_size);
+        if next < LZW_MAX_CODES {
+            codes.insert((current, idx), next);
+            next += 1;
+            // the decoder adds codes a step later
+            if next > 1 << code_size && code_size < LZW_MAX_CODE_SIZE {
+                code_size += 1;
+            }
+        } else {
+            w.write(clear, code_size);
+            codes.clear();
+            code_size = min_code_size + 1;
+            next = end + 1;
+        }
+        current = idx as usize;
+    }
+    w.write(current, code_size);
+    w.write(end, code_size);
+    w.finish()
+}
+
+#[derive(Debug, Clone)]
+pub struct GIFFrame {
+    /// Position and size on the logical screen
+    pub left: u16,
+    pub top: u16,
+    pub width: u16,
+    pub height: u16,
+    /// Local color table, the global one is used if not set
+    pub palette: Option<Vec<bmp::RGBQuad>>,
+    /// Color table indexes, rows top-down in the natural order
+    pub indexes: Vec<u8>,
+    /// Stored with interlaced rows
+    pub interlaced: bool,
+    /// Delay after the frame in 1/100 of second
+    pub delay: u16,
+    /// What to do with the frame area before the next frame
+    pub disposal: u8,
+    pub transparent: Option<u8>,
+}
+
+/// Rows of interlaced image in the storage order
+fn interlaced_rows(height: usize) -> Vec<usize> {
+    let mut rows = Vec::with_capacity(height);
+    for &(start, step) in &[(0, 8), (4, 8), (2, 4), (1, 2)] {
+        rows.extend((start..height).step_by(step));
+    }
+    rows
+}
+
+#[derive(Debug)]
+pub struct GIFFile {
+    /// Logical screen size
+    pub width: u16,
+    pub height: u16,
+    /// Global color table
+    pub palette: Option<Vec<bmp::RGBQuad>>,
+    pub background: u8,
+    /// Animation repetitions from NETSCAPE2.0 extension, 0 is forever
+    pub loop_count: Option<u16>,
+    pub frames: Vec<GIFFrame>,
+}
+
+impl GIFFile {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<GIFFile> {
+        let mut f = BufReader::new(File::open(p)?);
+        GIFFile::load_from_reader(&mut f)
+    }
+
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<GIFFile> {
+        let mut signature = [0u8; 6];
+        r.read_exact(&mut signature)?;
+        if &signature != GIF87A_SIGNATURE && &signature != GIF89A_SIGNATURE {
+            return Err(invalid_data(format!("Invalid GIF signature: {:?}", String::from_utf8_lossy(&signature))));
+        }
+        let width = r.read_u16::<LittleEndian>()?;
+        let height = r.read_u16::<LittleEndian>()?;
+        let packed = r.read_u8()?;
+        let background = r.read_u8()?;
+        let _aspect = r.read_u8()?;
+        let palette = if packed & 0x80 != 0 {
+            Some(load_color_table(r, (packed & 0x07) + 1)?)
+        } else {
+            None
+        };
+
+        let mut gif = GIFFile {
+            width: width,
+            height: height,
+            palette: palette,
+            background: background,
+            loop_count: None,
+            frames: Vec::new(),
+        };
+        // graphic control applies to the next image
+        let mut delay = 0;
+        let mut disposal = 0;
+        let mut transparent = None;
+        loop {
+            // some writers omit the trailer
+            let block = match r.read_u8() {
+                Ok(b) => b,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && !gif.frames.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            match block {
+                GIF_TRAILER => break,
+                GIF_EXTENSION_INTRODUCER => {
+                    let label = r.read_u8()?;
+                    let data = load_sub_blocks(r)?;
+                    if label == GIF_GRAPHIC_CONTROL_LABEL && data.len() >= 4 {
+                        disposal = (data[0] >> 2) & 0x07;
+                        delay = (&data[1..3]).read_u16::<LittleEndian>()?;
+                        transparent = if data[0] & 0x01 != 0 { Some(data[3]) } else { None };
+                    } else if label == GIF_APPLICATION_LABEL && data.len() >= 14
+                        && &data[..11] == GIF_NETSCAPE_APPLICATION && data[11] == 1 {
+                        gif.loop_count = Some((&data[12..14]).read_u16::<LittleEndian>()?);
+                    }
+                },
+                GIF_IMAGE_SEPARATOR => {
+                    let left = r.read_u16::<LittleEndian>()?;
+                    let top = r.read_u16::<LittleEndian>()?;
+                    let frame_width = r.read_u16::<LittleEndian>()?;
+                    let frame_height = r.read_u16::<LittleEndian>()?;
+                    let packed = r.read_u8()?;
+                    let palette = if packed & 0x80 != 0 {
+                        Some(load_color_table(r, (packed & 0x07) + 1)?)
+                    } else {
+                        None
+                    };
+                    let interlaced = packed & 0x40 != 0;
+                    let min_code_size = r.read_u8()?;
+                    let data = load_sub_blocks(r)?;
+                    let (w, h) = (frame_width as usize, frame_height as usize);
+                    let mut indexes = lzw_decode(&data, min_code_size, w * h)?;
+                    if interlaced {
+                        let stored = indexes.clone();
+                        for (src, dst) in interlaced_rows(h).into_iter().enumerate() {
+                            indexes[dst * w..(dst + 1) * w].copy_from_slice(&stored[src * w..(src + 1) * w]);
+                        }
+                    }
+                    gif.frames.push(GIFFrame {
+                        left: left,
+                        top: top,
+                        width: frame_width,
+                        height: frame_height,
+                        palette: palette,
+                        indexes: indexes,
+                        interlaced: interlaced,
+                        delay: delay,
+                        disposal: disposal,
+                        transparent: transparent,
+                    });
+                    delay = 0;
+                    disposal = 0;
+                    transparent = None;
+                },
+                _ => return Err(invalid_data(format!("Unknown GIF block: {:#04X}", block))),
+            }
+        }
+        if gif.frames.is_empty() {
+            return Err(invalid_data("GIF has no images".to_owned()));
+        }
+        Ok(gif)
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(GIF89A_SIGNATURE)?;
+        w.write_u16::<LittleEndian>(self.width)?;
+        w.write_u16::<LittleEndian>(self.height)?;
+        match self.palette {
+            Some(ref palette) => {
+                let bits = table_bits(palette.len());
+                // 8 bits per primary color
+                w.write_u8(0x80 | 0x70 | (bits - 1))?;
+                w.write_u8(self.background)?;
+                w.write_u8(0)?;
+                save_color_table(w, palette, bits)?;
+            },
+            None => w.write_all(&[0x70, 0, 0])?,
+        }
+        if let Some(loop_count) = self.loop_count {
+            w.write_all(&[GIF_EXTENSION_INTRODUCER, GIF_APPLICATION_LABEL, 11])?;
+            w.write_all(GIF_NETSCAPE_APPLICATION)?;
+            w.write_all(&[3, 1])?;
+            w.write_u16::<LittleEndian>(loop_count)?;
+            w.write_u8(0)?;
+        }
+        for frame in &self.frames {
+            let colors = match (&frame.palette, &self.palette) {
+                (&Some(ref p), _) | (&None, &Some(ref p)) => p.len(),
+                (&None, &None) => return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "GIF frame has no color table".to_owned(),
+                )),
+            };
+            if frame.delay != 0 || frame.disposal != 0 || frame.transparent.is_some() {
+                w.write_all(&[GIF_EXTENSION_INTRODUCER, GIF_GRAPHIC_CONTROL_LABEL, 4])?;
+                w.write_u8(frame.disposal << 2 | if frame.transparent.is_some() { 1 } else { 0 })?;
+                w.write_u16::<LittleEndian>(frame.delay)?;
+                w.write_u8(frame.transparent.unwrap_or(0))?;
+                w.write_u8(0)?;
+            }
+            w.write_u8(GIF_IMAGE_SEPARATOR)?;
+            w.write_u16::<LittleEndian>(frame.left)?;
+            w.write_u16::<LittleEndian>(frame.top)?;
+            w.write_u16::<LittleEndian>(frame.width)?;
+            w.write_u16::<LittleEndian>(frame.height)?;
+            let interlace = if frame.interlaced { 0x40 } else { 0 };
+            match frame.palette {
+                Some(ref palette) => {
+                    let bits = table_bits(palette.len());
+                    w.write_u8(0x80 | interlace | (bits - 1))?;
+                    save_color_table(w, palette, bits)?;
+                },
+                None => w.write_u8(interlace)?,
+            }
+            let width = frame.width as usize;
+            let indexes = if frame.interlaced {
+                interlaced_rows(frame.height as usize).into_iter()
+                    .flat_map(|y| frame.indexes[y * width..(y + 1) * width].iter().cloned())
+                    .collect()
+            } else {
+                frame.indexes.clone()
+            };
+            let min_code_size = table_bits(colors).max(2);
+            w.write_u8(min_code_size)?;
+            save_sub_blocks(w, &lzw_encode(&indexes, min_code_size))?;
+        }
+        w.write_u8(GIF_TRAILER)
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, p: P) -> io::Result<()> {
+        let mut f = BufWriter::new(File::create(p)?);
+        self.save_to_writer(&mut f)?;
+        f.flush()
+    }
+
+    /// Logical screen after drawing frames upto `idx`. A frame covering
+    /// the whole screen without transparency keeps its palette.
+    pub fn frame(&self, idx: usize) -> io::Result<bmp::Pixels> {
+        let frame = self.frames.get(idx).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No frame {} in GIF, it has {} frames", idx + 1, self.frames.len()),
+        ))?;
+        let (width, height) = (self.width as usize, self.height as usize);
+        let covers_screen = frame.left == 0 && frame.top == 0
+            && frame.width == self.width && frame.height == self.height;
+        if covers_screen && frame.transparent.is_none() {
+            return Ok(bmp::Pixels::new(width as u32, height as u32, bmp::PixelData::Indexed {
+                palette: self.frame_palette(frame)?.to_vec(),
+                indexes: frame.indexes.clone(),
+            }));
+        }
+
+        // the screen starts transparent, background color is ignored as browsers do
+        let mut screen = vec![0u8; width * height * 4];
+        for (pos, frame) in self.frames[..idx + 1].iter().enumerate() {
+            let previous = if frame.disposal == GIF_DISPOSE_PREVIOUS { Some(screen.clone()) } else { None };
+            let palette = self.frame_palette(frame)?;
+            let frame_width = frame.width as usize;
+            for (i, &index) in frame.indexes.iter().enumerate() {
+                let x = frame.left as usize + i % frame_width;
+                let y = frame.top as usize + i / frame_width;
+                if x >= width || y >= height || frame.transparent == Some(index) {
+                    continue;
+                }
+                let c = palette.get(index as usize).cloned().unwrap_or(bmp::RGBQuad::new(0, 0, 0));
+                screen[(y * width + x) * 4..(y * width + x) * 4 + 4].copy_from_slice(&[c.red(), c.green(), c.blue(), 255]);
+            }
+            if pos == idx {
+                break;
+            }
+            match frame.disposal {
+                GIF_DISPOSE_BACKGROUND => {
+                    for y in frame.top as usize..(frame.top as usize + frame.height as usize).min(height) {
+                        for x in frame.left as usize..(frame.left as usize + frame_width).min(width) {
+                            screen[(y * width + x) * 4..(y * width + x) * 4 + 4].copy_from_slice(&[0, 0, 0, 0]);
+                        }
+                    }
+                },
+                GIF_DISPOSE_PREVIOUS => screen = previous.unwrap(),
+                _ => {},
+            }
+        }
+        Ok(bmp::Pixels::new(width as u32, height as u32, bmp::PixelData::RGBA(screen)))
+    }
+
+    fn frame_palette<'a>(&'a self, frame: &'a GIFFrame) -> io::Result<&'a [bmp::RGBQuad]> {
+        match (&frame.palette, &self.palette) {
+            (&Some(ref p), _) | (&None, &Some(ref p)) => Ok(p),
+            (&None, &None) => Err(invalid_data("GIF frame has no color table".to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for GIFFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{} px, {} frames", self.width, self.height, self.frames.len())?;
+        if let Some(ref palette) = self.palette {
+            write!(f, ", global palette of {} colors", palette.len())?;
+        }
+        match self.loop_count {
+            Some(0) => write!(f, ", loops forever"),
+            Some(n) => write!(f, ", loops {} times", n),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for GIFFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{} px at {}x{}, delay {} ms", self.width, self.height, self.left, self.top, self.delay as u32 * 10)?;
+        if let Some(ref palette) = self.palette {
+            write!(f, ", local palette of {} colors", palette.len())?;
+        }
+        if let Some(idx) = self.transparent {
+            write!(f, ", transparent index {}", idx)?;
+        }
+        if self.interlaced {
+            write!(f, ", interlaced")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Delay between frames in 1/100 of second
+    pub delay: u16,
+    /// Animation repetitions, 0 is forever
+    pub loop_count: Option<u16>,
+    pub transparent: Option<u8>,
+    /// Quantize every frame to its own palette instead of the global one
+    pub local_palettes: bool,
+    pub colors: usize,
+    pub metric: ColorMetric,
+    pub dither: Dither,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            delay: 10,
+            loop_count: Some(0),
+            transparent: None,
+            local_palettes: false,
+            colors: GIF_MAX_COLORS,
+            metric: ColorMetric::default(),
+            dither: Dither::None,
+        }
+    }
+}
+
+/// Build GIF from indexed or true color images, frames are placed
+/// at the top left corner of the screen as large as the largest frame.
+/// Palette images fitting into the palette are kept as they are.
+pub fn build(frames: &[bmp::Pixels], opts: &Options) -> io::Result<GIFFile> {
+    if frames.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No frames to build GIF from".to_owned()));
+    }
+    if opts.colors == 0 || opts.colors > GIF_MAX_COLORS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("GIF palette can't have {} colors, upto {} allowed", opts.colors, GIF_MAX_COLORS),
+        ));
+    }
+    for p in frames {
+        if p.width > u16::max_value() as u32 || p.height > u16::max_value() as u32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("GIF frame can't be {}x{} px", p.width, p.height),
+            ));
+        }
+    }
+    let fits = |p: &bmp::Pixels| match p.data {
+        bmp::PixelData::Indexed { ref palette, .. } => palette.len() <= opts.colors && opts.dither == Dither::None,
+        _ => false,
+    };
+    let same_palette = frames.windows(2).all(|pair| match (&pair[0].data, &pair[1].data) {
+        (&bmp::PixelData::Indexed { palette: ref a, .. }, &bmp::PixelData::Indexed { palette: ref b, .. }) => {
+            a.iter().map(|c| (c.red(), c.green(), c.blue())).eq(b.iter().map(|c| (c.red(), c.green(), c.blue())))
+        },
+        _ => false,
+    });
+
+    let indexed: Vec<bmp::Pixels> = if opts.local_palettes {
+        frames.iter().map(|p| if fits(p) {
+            p.clone()
+        } else {
+            quantize::quantize(p, opts.colors, opts.metric, opts.dither)
+        }).collect()
+    } else if same_palette && fits(&frames[0]) {
+        frames.to_vec()
+    } else {
+        let refs: Vec<&bmp::Pixels> = frames.iter().collect();
+        quantize::quantize_frames(&refs, opts.colors, opts.metric, opts.dither)
+    };
+
+    let mut gif = GIFFile {
+        width: frames.iter().map(|p| p.width).max().unwrap() as u16,
+        height: frames.iter().map(|p| p.height).max().unwrap() as u16,
+        palette: None,
+        background: 0,
+        loop_count: if frames.len() > 1 { opts.loop_count } else { None },
+        frames: Vec::with_capacity(frames.len()),
+    };
+    for p in indexed {
+        let (palette, indexes) = match p.data {
+            bmp::PixelData::Indexed { palette, indexes } => (palette, indexes),
+            _ => unreachable!("frames are quantized"),
+        };
+        if let Some(idx) = opts.transparent {
+            if idx as usize >= palette.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Transparent index {} is out of the palette of {} colors", idx, palette.len()),
+                ));
+            }
+        }
+        let local = if opts.local_palettes {
+            Some(palette)
+        } else {
+            if gif.palette.is_none() {
+                gif.palette = Some(palette);
+            }
+            None
+        };
+        gif.frames.push(GIFFrame {
+            left: 0,
+            top: 0,
+            width: p.width as u16,
+            height: p.height as u16,
+            palette: local,
+            indexes: indexes,
+            interlaced: false,
+            delay: if frames.len() > 1 { opts.delay } else { 0 },
+            // frames of different sizes don't leave traces
+            disposal: if frames.len() > 1 { GIF_DISPOSE_BACKGROUND } else { 0 },
+            transparent: opts.transparent,
+        });
+    }
+    Ok(gif)
+}
+
+/// Build GIF from image files, one frame for every file
+pub fn build_from_files(sources: &[&str], opts: &Options) -> io::Result<GIFFile> {
+    let mut frames = Vec::with_capacity(sources.len());
+    for src in sources {
+        frames.push(format::load_pixels(src, 0)?);
+    }
+    build(&frames, opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample image from the GIF89a specification walkthrough
+    const SAMPLE: [u8; 100] = [
+        1, 1, 1, 1, 1, 2, 2, 2, 2, 2,
+        1, 1, 1, 1, 1, 2, 2, 2, 2, 2,
+        1, 1, 1, 1, 1, 2, 2, 2, 2, 2,
+        1, 1, 1, 0, 0, 0, 0, 2, 2, 2,
+        1, 1, 1, 0, 0, 0, 0, 2, 2, 2,
+        2, 2, 2, 0, 0, 0, 0, 1, 1, 1,
+        2, 2, 2, 0, 0, 0, 0, 1, 1, 1,
+        2, 2, 2, 2, 2, 1, 1, 1, 1, 1,
+        2, 2, 2, 2, 2, 1, 1, 1, 1, 1,
+        2, 2, 2, 2, 2, 1, 1, 1, 1, 1,
+    ];
+    const SAMPLE_LZW: [u8; 22] = [
+        0x8C, 0x2D, 0x99, 0x87, 0x2A, 0x1C, 0xDC, 0x33, 0xA0, 0x02, 0x75,
+        0xEC, 0x95, 0xFA, 0xA8, 0xDE, 0x60, 0x8C, 0x04, 0x91, 0x4C, 0x01,
+    ];
+
+    #[test]
+    fn lzw_sample() {
+        // codes grow from 3 to 4 bits
+        assert_eq!(&lzw_decode(&SAMPLE_LZW, 2, 100).unwrap()[..], &SAMPLE[..]);
+        assert_eq!(lzw_encode(&SAMPLE, 2), SAMPLE_LZW.to_vec());
+    }
+
+    #[test]
+    fn lzw_decode_clear_code() {
+        // clear, 1, 1, clear, 2, end in 3 bit codes
+        let mut w = BitWriter { data: Vec::new(), bits: 0, bit_count: 0 };
+        for &code in [4, 1, 1, 4, 2, 5].iter() {
+            w.write(code, 3);
+        }
+        assert_eq!(lzw_decode(&w.finish(), 2, 3).unwrap(), vec![1, 1, 2]);
+        // missing indexes are zero
+        assert_eq!(lzw_decode(&[], 2, 2).unwrap(), vec![0, 0]);
+        assert!(lzw_decode(&[0x07], 2, 1).is_err());
+    }
+
+    #[test]
+    fn lzw_round_trip() {
+        // pseudo random data fills the table and makes the encoder clear it
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..50000).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }).collect();
+        for &bits in [2, 3, 5, 8].iter() {
+            let indexes: Vec<u8> = noise.iter().map(|&v| v & ((1 << bits) - 1) as u8).collect();
+            let data = lzw_encode(&indexes, bits);
+            assert_eq!(lzw_decode(&data, bits, indexes.len()).unwrap(), indexes);
+        }
+        assert_eq!(lzw_decode(&lzw_encode(&[], 2), 2, 0).unwrap(), Vec::<u8>::new());
+    }
+
+    fn colors(palette: &[bmp::RGBQuad]) -> Vec<(u8, u8, u8)> {
+        palette.iter().map(|c| (c.red(), c.green(), c.blue())).collect()
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let global = vec![
+            bmp::RGBQuad::new(0, 0, 0), bmp::RGBQuad::new(255, 0, 0),
+            bmp::RGBQuad::new(0, 255, 0), bmp::RGBQuad::new(0, 0, 255),
+        ];
+        let frame = |left, top, width, height, palette, indexes: Vec<u8>, interlaced| GIFFrame {
+            left: left,
+            top: top,
+            width: width,
+            height: height,
+            palette: palette,
+            indexes: indexes,
+            interlaced: interlaced,
+            delay: 20,
+            disposal: GIF_DISPOSE_BACKGROUND,
+            transparent: None,
+        };
+        let mut gif = GIFFile {
+            width: 10,
+            height: 10,
+            palette: Some(global.clone()),
+            background: 0,
+            loop_count: Some(3),
+            frames: vec![
+                frame(0, 0, 10, 10, None, SAMPLE.to_vec(), false),
+                frame(2, 1, 3, 11, Some(vec![bmp::RGBQuad::new(1, 2, 3), bmp::RGBQuad::new(4, 5, 6)]),
+                      (0..33).map(|i| (i / 3 % 2) as u8).collect(), true),
+            ],
+        };
+        gif.frames[0].disposal = 0;
+        gif.frames[1].transparent = Some(0);
+
+        let mut data = Vec::new();
+        gif.save_to_writer(&mut data).unwrap();
+        let loaded = GIFFile::load_from_reader(&mut &data[..]).unwrap();
+        assert_eq!((loaded.width, loaded.height, loaded.loop_count), (10, 10, Some(3)));
+        assert_eq!(colors(loaded.palette.as_ref().unwrap()), colors(&global));
+        assert_eq!(loaded.frames.len(), 2);
+        for (a, b) in loaded.frames.iter().zip(gif.frames.iter()) {
+            assert_eq!((a.left, a.top, a.width, a.height), (b.left, b.top, b.width, b.height));
+            assert_eq!((a.interlaced, a.delay, a.disposal, a.transparent), (b.interlaced, b.delay, b.disposal, b.transparent));
+            assert_eq!(a.palette.as_ref().map(|p| colors(p)), b.palette.as_ref().map(|p| colors(p)));
+            assert_eq!(a.indexes, b.indexes);
+        }
+
+        // second frame over the first one, its transparent pixels show the first
+        let screen = loaded.frame(1).unwrap().to_rgba();
+        let pixel = |x: usize, y: usize| &screen[(y * 10 + x) * 4..(y * 10 + x) * 4 + 4];
+        assert_eq!(pixel(2, 1), &[255, 0, 0, 255]);
+        assert_eq!(pixel(2, 2), &[4, 5, 6, 255]);
+        assert_eq!(pixel(3, 3), &[0, 0, 0, 255]);
+    }
+}
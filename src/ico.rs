@@ -0,0 +1,440 @@
+//! # Format desciption
+//! ICO and CUR files hold a directory of images of several sizes.
+//! Every image is a DIB without the file header: BITMAPINFOHEADER with
+//! the doubled height, color table, XOR bitmap and 1 bit per pixel AND mask.
+//! Cursors keep the hotspot in place of the planes and bit count of the entry.
+//! https://msdn.microsoft.com/en-us/library/ms997538.aspx
+//! https://en.wikipedia.org/wiki/ICO_(file_format)
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+use format;
+use png;
+
+pub const ICO_TYPE: u16 = 1;
+pub const CUR_TYPE: u16 = 2;
+pub const ICO_HEADER_SIZE: u32 = 6;
+pub const ICO_ENTRY_SIZE: u32 = 16;
+/// Largest icon side, stored as 0 in the directory
+pub const ICO_MAX_SIZE: u32 = 256;
+
+#[derive(Debug)]
+pub struct IconEntry {
+    /// Width in pixels, 0 means 256
+    pub width: u8,
+    /// Height in pixels, 0 means 256
+    pub height: u8,
+    /// Number of palette colors, 0 if more than 255
+    pub color_count: u8,
+    pub reserved: u8,
+    /// Color planes of icons, hotspot X of cursors
+    pub planes: u16,
+    /// Bits per pixel of icons, hotspot Y of cursors
+    pub bit_count: u16,
+    /// Offset of the image from the beginning of the file
+    pub offset: u32,
+    /// DIB or PNG image data
+    pub data: Vec<u8>,
+    /// Entry of a cursor, planes and bit count hold the hotspot
+    pub cursor: bool,
+}
+
+impl IconEntry {
+    pub fn get_width(&self) -> u32 {
+        if self.width == 0 { ICO_MAX_SIZE } else { self.width as u32 }
+    }
+
+    pub fn get_height(&self) -> u32 {
+        if self.height == 0 { ICO_MAX_SIZE } else { self.height as u32 }
+    }
+
+    pub fn is_png(&self) -> bool {
+        self.data.starts_with(png::PNG_SIGNATURE)
+    }
+
+    /// Hotspot of cursors
+    pub fn hotspot(&self) -> Option<(u16, u16)> {
+        if self.cursor { Some((self.planes, self.bit_count)) } else { None }
+    }
+
+    /// Bits per pixel taken from the image itself, directory values are often zero
+    pub fn image_bit_count(&self) -> io::Result<i16> {
+        if self.is_png() {
+            let header = png::PNGHeader::load_from_reader(&mut &self.data[..])?;
+            return Ok(header.bits_per_pixel() as i16);
+        }
+        let info = bmp::BMPInfo::load_from_reader(&mut Cursor::new(&self.data[..]))?;
+        Ok(info.bmi_header.get_bit_count())
+    }
+
+    /// Decode image, the AND mask becomes the alpha channel.
+    /// PNG compressed images are decoded as they are.
+    /// 32 bits per pixel images with non-zero alpha ignore the mask.
+    pub fn pixels(&self) -> io::Result<bmp::Pixels> {
+        if self.is_png() {
+            return png::load_from_reader(&mut &self.data[..]);
+        }
+        let mut r = Cursor::new(&self.data[..]);
+        let mut info = bmp::BMPInfo::load_from_reader(&mut r)?;
+        let width = info.bmi_header.get_width();
+        let height = info.bmi_header.get_height() / 2;
+        let bit_count = info.bmi_header.get_bit_count();
+        info.bmi_header.set_height(height);
+        // icon bitmaps are bottom-up and as large as the directory says
+        if width <= 0 || height <= 0 || width as u32 != self.get_width() || height as u32 != self.get_height() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Icon bitmap is {}x{} px, directory entry is {}x{} px",
+                    width, height, self.get_width(), self.get_height(),
+                ),
+            ));
+        }
+
+        let start = r.position() as usize;
+        let mask_stride = bmp::row_stride(width, 1);
+        let too_large = || io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Icon bitmap of {}x{} px, {} bpp is too large", width, height, bit_count),
+        );
+        let xor_size = bmp::row_stride(width, bit_count).checked_mul(height as usize).ok_or_else(too_large)?;
+        let mask_size = mask_stride.checked_mul(height as usize).ok_or_else(too_large)?;
+        let xor_end = start.checked_add(xor_size).ok_or_else(too_large)?;
+        let mask_end = xor_end.checked_add(mask_size).ok_or_else(too_large)?;
+        if self.data.len() < xor_end {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Icon bitmap is truncated: {} bytes, expected {}", self.data.len(), xor_end),
+            ));
+        }
+        let xor = self.data[start..xor_end].to_vec();
+        // some writers omit the mask of 32 bits per pixel images
+        let mask = &self.data[xor_end..mask_end.min(self.data.len())];
+
+        let mut image = bmp::BMPImage {
+            header: bmp::BMPFileHeader::new(0, 0),
+            info: info,
+            bitmap: bmp::Bitmap {
+                data: xor,
+                decoded_from: None,
+            },
+        };
+        let mut rgba = image.to_pixels()?.to_rgba();
+        let (width, height) = (width as usize, height as usize);
+        let stride = bmp::row_stride(width as i32, bit_count);
+        let own_alpha = bit_count == 32
+            && (0..width * height).any(|i| image.bitmap.data[(i / width) * stride + (i % width) * 4 + 3] != 0);
+        for y in 0..height {
+            // bitmaps are bottom-up
+            let row = height - 1 - y;
+            for x in 0..width {
+                let alpha = &mut rgba[(y * width + x) * 4 + 3];
+                if own_alpha {
+                    *alpha = image.bitmap.data[row * stride + x * 4 + 3];
+                } else if mask.get(row * mask_stride + x / 8).map_or(false, |bits| bits >> (7 - x % 8) & 1 == 1) {
+                    *alpha = 0;
+                }
+            }
+        }
+        Ok(bmp::Pixels::new(width as u32, height as u32, bmp::PixelData::RGBA(rgba)))
+    }
+
+    /// Encode pixels into icon DIB. Palette images keep the palette with
+    /// an empty mask, the others become 32 bits per pixel with alpha.
+    /// 256 pixel images are PNG compressed like Windows Vista icons.
+    pub fn from_pixels(pixels: &bmp::Pixels, hotspot: Option<(u16, u16)>) -> io::Result<IconEntry> {
+        if pixels.width == 0 || pixels.width > ICO_MAX_SIZE || pixels.height == 0 || pixels.height > ICO_MAX_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Icon can't be {}x{}, upto {} pixels allowed", pixels.width, pixels.height, ICO_MAX_SIZE),
+            ));
+        }
+        if pixels.width == ICO_MAX_SIZE || pixels.height == ICO_MAX_SIZE {
+            let mut data = Vec::new();
+            png::save_to_writer(pixels, &mut data, false)?;
+            let (planes, bit_count) = hotspot.unwrap_or((1, 32));
+            return Ok(IconEntry {
+                width: pixels.width as u8,
+                height: pixels.height as u8,
+                color_count: 0,
+                reserved: 0,
+                planes: planes,
+                bit_count: bit_count,
+                offset: 0,
+                data: data,
+                cursor: hotspot.is_some(),
+            });
+        }
+        let (width, height) = (pixels.width as i32, pixels.height as i32);
+        let image = match pixels.data {
+            bmp::PixelData::Indexed { .. } => bmp::BMPImage::from_pixels(pixels)?,
+            _ => bmp::BMPImage::from_rgba(width, height, &pixels.to_rgba())?,
+        };
+        let bit_count = image.info.bmi_header.get_bit_count();
+        let colors = image.info.bmi_colors.len();
+
+        let mask_stride = bmp::row_stride(width, 1);
+        let mut mask = vec![0u8; mask_stride * height as usize];
+        if pixels.has_alpha() {
+            for (y, row) in pixels.to_rgba().chunks(width as usize * 4).rev().enumerate() {
+                for (x, px) in row.chunks(4).enumerate() {
+                    if px[3] < 128 {
+                        mask[y * mask_stride + x / 8] |= 0x80 >> (x % 8);
+                    }
+                }
+            }
+        }
+
+        let info = bmp::BMPInfo {
+            bmi_header: bmp::BMPGenericInfoHeader::Info(bmp::BMPInfoHeader::new(
+                width, height * 2,
+                bit_count,
+                (image.bitmap.data.len() + mask.len()) as i32,
+                0, 0,
+                colors as i32, 0,
+            )),
+            bmi_colors: image.info.bmi_colors.clone(),
+        };
+        let mut data = Vec::new();
+        info.save_to_writer(&mut data)?;
+        data.extend_from_slice(&image.bitmap.data);
+        data.extend_from_slice(&mask);
+
+        let (planes, bit_count) = match hotspot {
+            Some((x, y)) => (x, y),
+            None => (1, bit_count as u16),
+        };
+        Ok(IconEntry {
+            width: pixels.width as u8,
+            height: pixels.height as u8,
+            color_count: if colors < 256 { colors as u8 } else { 0 },
+            reserved: 0,
+            planes: planes,
+            bit_count: bit_count,
+            offset: 0,
+            data: data,
+            cursor: hotspot.is_some(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct IconFile {
+    /// ICO_TYPE or CUR_TYPE
+    pub icon_type: u16,
+    pub entries: Vec<IconEntry>,
+}
+
+impl IconFile {
+    pub fn new(icon_type: u16) -> IconFile {
+        IconFile {
+            icon_type: icon_type,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn is_cursor(&self) -> bool {
+        self.icon_type == CUR_TYPE
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<IconFile> {
+        let mut f = BufReader::new(File::open(p)?);
+        IconFile::load_from_reader(&mut f)
+    }
+
+    pub fn load_from_reader<R: ?Sized + Read + Seek>(r: &mut R) -> io::Result<IconFile> {
+        let reserved = r.read_u16::<LittleEndian>()?;
+        let icon_type = r.read_u16::<LittleEndian>()?;
+        if reserved != 0 || (icon_type != ICO_TYPE && icon_type != CUR_TYPE) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid icon header: reserved {}, type {}", reserved, icon_type),
+            ));
+        }
+        let count = r.read_u16::<LittleEndian>()?;
+        let mut entries = Vec::with_capacity(count as usize);
+        let mut sizes = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let width = r.read_u8()?;
+            let height = r.read_u8()?;
+            let color_count = r.read_u8()?;
+            let reserved = r.read_u8()?;
+            let planes = r.read_u16::<LittleEndian>()?;
+            let bit_count = r.read_u16::<LittleEndian>()?;
+            sizes.push(r.read_u32::<LittleEndian>()?);
+            entries.push(IconEntry {
+                width: width,
+                height: height,
+                color_count: color_count,
+                reserved: reserved,
+                planes: planes,
+                bit_count: bit_count,
+                offset: r.read_u32::<LittleEndian>()?,
+                data: Vec::new(),
+                cursor: icon_type == CUR_TYPE,
+            });
+        }
+        for (entry, size) in entries.iter_mut().zip(sizes) {
+            r.seek(SeekFrom::Start(entry.offset as u64))?;
+            entry.data = vec![0u8; size as usize];
+            r.read_exact(&mut entry.data)?;
+        }
+        Ok(IconFile {
+            icon_type: icon_type,
+            entries: entries,
+        })
+    }
+
+    pub fn entry(&self, idx: usize) -> io::Result<&IconEntry> {
+        self.entries.get(idx).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No image {} in icon, it has {} images", idx + 1, self.entries.len()),
+        ))
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u16::<LittleEndian>(0)?;
+        w.write_u16::<LittleEndian>(self.icon_type)?;
+        w.write_u16::<LittleEndian>(self.entries.len() as u16)?;
+        let mut offset = ICO_HEADER_SIZE + ICO_ENTRY_SIZE * self.entries.len() as u32;
+        for e in &self.entries {
+            w.write_u8(e.width)?;
+            w.write_u8(e.height)?;
+            w.write_u8(e.color_count)?;
+            w.write_u8(e.reserved)?;
+            w.write_u16::<LittleEndian>(e.planes)?;
+            w.write_u16::<LittleEndian>(e.bit_count)?;
+            w.write_u32::<LittleEndian>(e.data.len() as u32)?;
+            w.write_u32::<LittleEndian>(offset)?;
+            offset += e.data.len() as u32;
+        }
+        for e in &self.entries {
+            w.write_all(&e.data)?;
+        }
+        Ok(())
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, p: P) -> io::Result<()> {
+        let mut f = BufWriter::new(File::create(p)?);
+        self.save_to_writer(&mut f)?;
+        f.flush()
+    }
+}
+
+/// Build icon or cursor from image files, one entry for every file
+pub fn build(sources: &[&str], icon_type: u16, hotspot: Option<(u16, u16)>) -> io::Result<IconFile> {
+    let mut icon = IconFile::new(icon_type);
+    let hotspot = if icon_type == CUR_TYPE { Some(hotspot.unwrap_or((0, 0))) } else { None };
+    for src in sources {
+        let pixels = format::load_pixels(src, 0)?;
+        icon.entries.push(IconEntry::from_pixels(&pixels, hotspot)?);
+    }
+    Ok(icon)
+}
+
+impl fmt::Display for IconEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{} px, ", self.get_width(), self.get_height())?;
+        if self.is_png() {
+            write!(f, "PNG, ")?;
+        } else if let Ok(bit_count) = self.image_bit_count() {
+            write!(f, "{} bits per pixel, ", bit_count)?;
+        }
+        write!(f, "{} bytes at {}", self.data.len(), self.offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgba(width: u32, height: u32) -> bmp::Pixels {
+        let data = (0..width * height * 4).map(|i| match i % 4 {
+            3 => if i / 4 % 3 == 0 { 0 } else { 255 },
+            c => (i / 4 * (c + 1) * 9) as u8,
+        }).collect();
+        bmp::Pixels::new(width, height, bmp::PixelData::RGBA(data))
+    }
+
+    /// Transparent pixels are black, as icons store them
+    fn visible(pixels: &bmp::Pixels) -> Vec<u8> {
+        pixels.to_rgba().chunks(4).flat_map(|px| if px[3] == 0 { vec![0, 0, 0, 0] } else { px.to_vec() }).collect()
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let indexed = bmp::Pixels::new(5, 3, bmp::PixelData::Indexed {
+            palette: (0..16).map(|i| bmp::RGBQuad::new(i * 16, 255 - i, i)).collect(),
+            indexes: (0..15).map(|i| (i * 7 % 16) as u8).collect(),
+        });
+        let images = [indexed, rgba(7, 4), rgba(256, 256)];
+        let mut icon = IconFile::new(ICO_TYPE);
+        for pixels in images.iter() {
+            icon.entries.push(IconEntry::from_pixels(pixels, None).unwrap());
+        }
+        let mut data = Vec::new();
+        icon.save_to_writer(&mut data).unwrap();
+        let loaded = IconFile::load_from_reader(&mut Cursor::new(&data[..])).unwrap();
+        assert!(!loaded.is_cursor());
+        assert_eq!(loaded.entries.len(), 3);
+        for (entry, pixels) in loaded.entries.iter().zip(images.iter()) {
+            assert_eq!((entry.get_width(), entry.get_height()), (pixels.width, pixels.height));
+            assert_eq!(visible(&entry.pixels().unwrap()), visible(pixels));
+        }
+        assert_eq!(loaded.entries[0].image_bit_count().unwrap(), 4);
+        assert_eq!(loaded.entries[1].image_bit_count().unwrap(), 32);
+        assert!(loaded.entries[2].is_png());
+        assert_eq!(loaded.entries[2].offset as usize, data.len() - loaded.entries[2].data.len());
+        assert!(loaded.entry(3).is_err());
+    }
+
+    #[test]
+    fn cursor_hotspot() {
+        let mut cursor = IconFile::new(CUR_TYPE);
+        cursor.entries.push(IconEntry::from_pixels(&rgba(4, 4), Some((3, 1))).unwrap());
+        let mut data = Vec::new();
+        cursor.save_to_writer(&mut data).unwrap();
+        let loaded = IconFile::load_from_reader(&mut Cursor::new(&data[..])).unwrap();
+        assert!(loaded.is_cursor());
+        assert_eq!(loaded.entries[0].hotspot(), Some((3, 1)));
+        let entry = IconEntry::from_pixels(&rgba(4, 4), None).unwrap();
+        assert_eq!(entry.hotspot(), None);
+        assert_eq!(entry.bit_count, 32);
+    }
+
+    #[test]
+    fn mask_makes_transparent() {
+        let pixels = rgba(9, 2);
+        let mut entry = IconEntry::from_pixels(&pixels, None).unwrap();
+        // without alpha in the bitmap after the info header the AND mask is used
+        for px in entry.data[40..40 + 9 * 2 * 4].chunks_mut(4) {
+            px[3] = 0;
+        }
+        assert_eq!(visible(&entry.pixels().unwrap()), visible(&pixels));
+    }
+
+    #[test]
+    fn invalid_bitmap_sizes() {
+        let entry = IconEntry::from_pixels(&rgba(9, 2), None).unwrap();
+        for &(width, height) in &[(9, -4), (-9, 4), (0, 4), (9, 0), (0x7FFF_FFFF, 4), (9, 0x7FFF_FFFF), (8, 4)] {
+            let mut entry = IconEntry::from_pixels(&rgba(9, 2), None).unwrap();
+            (&mut entry.data[4..]).write_i32::<LittleEndian>(width).unwrap();
+            (&mut entry.data[8..]).write_i32::<LittleEndian>(height).unwrap();
+            assert_eq!(entry.pixels().unwrap_err().kind(), io::ErrorKind::InvalidData, "{}x{}", width, height);
+        }
+        let mut other = IconEntry::from_pixels(&rgba(9, 2), None).unwrap();
+        other.height = 3;
+        assert_eq!(other.pixels().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        other.height = 2;
+        other.data.truncate(40 + 9 * 4 + 5);
+        assert_eq!(other.pixels().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        // the mask may be missing
+        other.data = entry.data[..40 + 9 * 2 * 4].to_vec();
+        assert_eq!(other.pixels().unwrap().to_rgba(), entry.pixels().unwrap().to_rgba());
+    }
+}
@@ -0,0 +1,499 @@
+//! # Format desciption
+//! IFF ILBM: "FORM" chunk of "ILBM" type holding BMHD bitmap header,
+//! CMAP palette, optional CAMG Amiga display mode and BODY.
+//! Chunks have big-endian sizes and are padded to even length.
+//! BODY rows are bitplanes of 16 bit aligned width, the first plane
+//! holds the least significant bits, optionally ByteRun1 compressed.
+//! Extra Half-Brite mode adds 32 colors of half brightness, Hold-And-Modify
+//! mode changes one component of the previous pixel.
+//! http://www.etwright.org/lwsdk/docs/filefmts/ilbm.html
+//! https://en.wikipedia.org/wiki/ILBM
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+use pcx;
+
+pub const ILBM_BMHD_SIZE: u32 = 20;
+
+/// Masking values
+pub const MASK_NONE: u8 = 0;
+pub const MASK_HAS_MASK: u8 = 1;
+pub const MASK_TRANSPARENT_COLOR: u8 = 2;
+pub const MASK_LASSO: u8 = 3;
+
+/// Compression values
+pub const COMPRESSION_NONE: u8 = 0;
+pub const COMPRESSION_BYTERUN1: u8 = 1;
+
+/// CAMG viewport mode flags
+pub const CAMG_EHB: u32 = 0x80;
+pub const CAMG_HAM: u32 = 0x800;
+
+/// Longest ByteRun1 run or literal
+const BYTERUN1_MAX: usize = 128;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// BMHD chunk
+#[derive(Debug, Clone)]
+pub struct ILBMHeader {
+    pub width: u16,
+    pub height: u16,
+    pub x: i16,
+    pub y: i16,
+    pub planes: u8,
+    pub masking: u8,
+    pub compression: u8,
+    pub pad: u8,
+    pub transparent_color: u16,
+    pub x_aspect: u8,
+    pub y_aspect: u8,
+    pub page_width: i16,
+    pub page_height: i16,
+}
+
+impl ILBMHeader {
+    pub fn new(width: u16, height: u16, planes: u8) -> ILBMHeader {
+        ILBMHeader {
+            width: width,
+            height: height,
+            x: 0,
+            y: 0,
+            planes: planes,
+            masking: MASK_NONE,
+            compression: COMPRESSION_BYTERUN1,
+            pad: 0,
+            transparent_color: 0,
+            x_aspect: 1,
+            y_aspect: 1,
+            page_width: width as i16,
+            page_height: height as i16,
+        }
+    }
+
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<ILBMHeader> {
+        Ok(ILBMHeader {
+            width: r.read_u16::<BigEndian>()?,
+            height: r.read_u16::<BigEndian>()?,
+            x: r.read_i16::<BigEndian>()?,
+            y: r.read_i16::<BigEndian>()?,
+            planes: r.read_u8()?,
+            masking: r.read_u8()?,
+            compression: r.read_u8()?,
+            pad: r.read_u8()?,
+            transparent_color: r.read_u16::<BigEndian>()?,
+            x_aspect: r.read_u8()?,
+            y_aspect: r.read_u8()?,
+            page_width: r.read_i16::<BigEndian>()?,
+            page_height: r.read_i16::<BigEndian>()?,
+        })
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u16::<BigEndian>(self.width)?;
+        w.write_u16::<BigEndian>(self.height)?;
+        w.write_i16::<BigEndian>(self.x)?;
+        w.write_i16::<BigEndian>(self.y)?;
+        w.write_u8(self.planes)?;
+        w.write_u8(self.masking)?;
+        w.write_u8(self.compression)?;
+        w.write_u8(self.pad)?;
+        w.write_u16::<BigEndian>(self.transparent_color)?;
+        w.write_u8(self.x_aspect)?;
+        w.write_u8(self.y_aspect)?;
+        w.write_i16::<BigEndian>(self.page_width)?;
+        w.write_i16::<BigEndian>(self.page_height)
+    }
+
+    /// Bytes of one plane row, rows are word aligned
+    pub fn plane_row_size(&self) -> usize {
+        (self.width as usize + 15) / 16 * 2
+    }
+}
+
+impl fmt::Display for ILBMHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let masking = match self.masking {
+            MASK_NONE => "no mask",
+            MASK_HAS_MASK => "mask plane",
+            MASK_TRANSPARENT_COLOR => "transparent color",
+            MASK_LASSO => "lasso",
+            _ => "unknown mask",
+        };
+        write!(
+            f,
+            "{}x{} px, {} planes, {}, {}",
+            self.width, self.height, self.planes, masking,
+            if self.compression == COMPRESSION_BYTERUN1 { "ByteRun1" } else { "uncompressed" },
+        )
+    }
+}
+
+/// Decoded FORM ILBM chunks
+#[derive(Debug)]
+pub struct ILBMFile {
+    pub header: ILBMHeader,
+    pub palette: Vec<bmp::RGBQuad>,
+    /// CAMG display mode, 0 if missing
+    pub mode: u32,
+    pub body: Vec<u8>,
+}
+
+impl ILBMFile {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<ILBMFile> {
+        let mut f = BufReader::new(File::open(p)?);
+        ILBMFile::load_from_reader(&mut f)
+    }
+
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<ILBMFile> {
+        let mut id = [0u8; 4];
+        r.read_exact(&mut id)?;
+        let size = r.read_u32::<BigEndian>()?;
+        let mut form_type = [0u8; 4];
+        r.read_exact(&mut form_type)?;
+        if &id != b"FORM" || &form_type != b"ILBM" {
+            return Err(invalid_data("Not an IFF ILBM file".to_owned()));
+        }
+        let mut data = Vec::new();
+        r.take(size.saturating_sub(4) as u64).read_to_end(&mut data)?;
+
+        let (mut header, mut palette, mut mode, mut body) = (None, Vec::new(), 0, None);
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let kind = &data[pos..pos + 4];
+            let len = (&data[pos + 4..pos + 8]).read_u32::<BigEndian>()? as usize;
+            let chunk = &data[pos + 8..(pos + 8 + len).min(data.len())];
+            match kind {
+                b"BMHD" => header = Some(ILBMHeader::load_from_reader(&mut &chunk[..])?),
+                b"CMAP" => palette = chunk.chunks(3).filter(|c| c.len() == 3)
+                    .map(|c| bmp::RGBQuad::new(c[0], c[1], c[2])).collect(),
+                b"CAMG" if chunk.len() >= 4 => mode = (&chunk[..]).read_u32::<BigEndian>()?,
+                b"BODY" => body = Some(chunk.to_vec()),
+                _ => {},
+            }
+            // chunks are padded to even size
+            pos += 8 + len + len % 2;
+        }
+        match (header, body) {
+            (Some(header), Some(body)) => Ok(ILBMFile {
+                header: header,
+                palette: palette,
+                mode: mode,
+                body: body,
+            }),
+            (None, _) => Err(invalid_data("ILBM has no BMHD chunk".to_owned())),
+            (_, None) => Err(invalid_data("ILBM has no BODY chunk".to_owned())),
+        }
+    }
+
+    pub fn is_ham(&self) -> bool {
+        self.mode & CAMG_HAM != 0 && (self.header.planes == 6 || self.header.planes == 8)
+    }
+
+    /// EHB is set in CAMG, files without CAMG have 6 planes and 32 colors
+    pub fn is_ehb(&self) -> bool {
+        !self.is_ham() && self.header.planes == 6
+            && (self.mode & CAMG_EHB != 0 || (self.mode == 0 && self.palette.len() == 32))
+    }
+
+    /// Uncompressed BODY rows of all planes and the mask plane
+    fn rows(&self) -> io::Result<Vec<u8>> {
+        let h = &self.header;
+        let planes = h.planes as usize + if h.masking == MASK_HAS_MASK { 1 } else { 0 };
+        let size = h.plane_row_size() * planes * h.height as usize;
+        let data = match h.compression {
+            COMPRESSION_NONE => self.body.clone(),
+            COMPRESSION_BYTERUN1 => byterun1_decode(&self.body, size),
+            c => return Err(invalid_data(format!("Unknown ILBM compression: {}", c))),
+        };
+        if data.len() < size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("ILBM body is truncated: {} bytes, expected {}", data.len(), size),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Decode pixels, upto 8 planes are palette images, HAM and 24 planes
+    /// are true color. The mask plane is skipped.
+    pub fn pixels(&self) -> io::Result<bmp::Pixels> {
+        let h = &self.header;
+        let (width, height, planes) = (h.width as usize, h.height as usize, h.planes as usize);
+        if width == 0 || height == 0 || (planes == 0 || planes > 8) && planes != 24 {
+            return Err(invalid_data(format!("Unsupported ILBM: {}", h)));
+        }
+        let row_size = h.plane_row_size();
+        let stride = row_size * (planes + if h.masking == MASK_HAS_MASK { 1 } else { 0 });
+        let data = self.rows()?;
+        let rows = (0..height).map(|y| &data[y * stride..y * stride + row_size * planes]);
+
+        if planes == 24 {
+            let mut rgb = Vec::with_capacity(width * height * 3);
+            for row in rows {
+                let components: Vec<Vec<u8>> = (0..3).map(|c| {
+                    pcx::planar_to_chunky(&row[c * 8 * row_size..(c + 1) * 8 * row_size], 1, 8, row_size, width)
+                }).collect();
+                for x in 0..width {
+                    rgb.extend_from_slice(&[components[0][x], components[1][x], components[2][x]]);
+                }
+            }
+            return Ok(bmp::Pixels::new(width as u32, height as u32, bmp::PixelData::RGB(rgb)));
+        }
+
+        let indexes: Vec<Vec<u8>> = rows.map(|row| pcx::planar_to_chunky(row, 1, planes as u8, row_size, width)).collect();
+        let mut palette = self.palette.clone();
+        if palette.is_empty() {
+            // gray levels if there is no CMAP
+            let max = (1usize << planes) - 1;
+            palette = (0..max + 1).map(|i| {
+                let g = (i * 255 / max) as u8;
+                bmp::RGBQuad::new(g, g, g)
+            }).collect();
+        }
+
+        if self.is_ham() {
+            // the control bits are the two most significant ones
+            let bits = planes - 2;
+            let mut rgb = Vec::with_capacity(width * height * 3);
+            for row in &indexes {
+                let background = palette[0].clone();
+                let mut color = [background.red(), background.green(), background.blue()];
+                for &px in row {
+                    let value = px & ((1 << bits) - 1);
+                    // spread the modify value over 8 bits
+                    let level = (value << (8 - bits)) | (value >> (2 * bits - 8));
+                    match px >> bits {
+                        0 => if let Some(c) = palette.get(value as usize) {
+                            color = [c.red(), c.green(), c.blue()];
+                        },
+                        1 => color[2] = level,
+                        2 => color[0] = level,
+                        _ => color[1] = level,
+                    }
+                    rgb.extend_from_slice(&color);
+                }
+            }
+            return Ok(bmp::Pixels::new(width as u32, height as u32, bmp::PixelData::RGB(rgb)));
+        }
+
+        if self.is_ehb() {
+            palette.truncate(32);
+            palette.resize(32, bmp::RGBQuad::new(0, 0, 0));
+            let half: Vec<bmp::RGBQuad> = palette.iter()
+                .map(|c| bmp::RGBQuad::new(c.red() / 2, c.green() / 2, c.blue() / 2))
+                .collect();
+            palette.extend(half);
+        }
+        let indexes: Vec<u8> = indexes.into_iter().flat_map(|row| row).collect();
+        if let Some(&idx) = indexes.iter().find(|&&i| i as usize >= palette.len()) {
+            return Err(invalid_data(format!("Color index {} is out of {} colors", idx, palette.len())));
+        }
+        Ok(bmp::Pixels::new(width as u32, height as u32, bmp::PixelData::Indexed {
+            palette: palette,
+            indexes: indexes,
+        }))
+    }
+}
+
+/// Decode ByteRun1 data upto `size` bytes
+pub fn byterun1_decode(data: &[u8], size: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 0;
+    while pos < data.len() && out.len() < size {
+        let n = data[pos] as i8;
+        pos += 1;
+        if n >= 0 {
+            let end = (pos + n as usize + 1).min(data.len());
+            out.extend_from_slice(&data[pos..end]);
+            pos = end;
+        } else if n != -128 {
+            if let Some(&byte) = data.get(pos) {
+                out.extend(::std::iter::repeat(byte).take((1 - n as isize) as usize));
+            }
+            pos += 1;
+        }
+    }
+    out.truncate(size);
+    out
+}
+
+/// ByteRun1 encode one row, runs of 3 and more bytes are replicated
+pub fn byterun1_encode(row: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(row.len() + row.len() / BYTERUN1_MAX + 1);
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let flush = |out: &mut Vec<u8>, literal: &[u8]| {
+        for chunk in literal.chunks(BYTERUN1_MAX) {
+            out.push((chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+    };
+    while pos < row.len() {
+        let mut run = 1;
+        while pos + run < row.len() && run < BYTERUN1_MAX && row[pos + run] == row[pos] {
+            run += 1;
+        }
+        if run >= 3 {
+            flush(&mut out, &row[literal_start..pos]);
+            out.push((1i16 - run as i16) as u8);
+            out.push(row[pos]);
+            pos += run;
+            literal_start = pos;
+        } else {
+            pos += run;
+        }
+    }
+    flush(&mut out, &row[literal_start..]);
+    out
+}
+
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    ILBMFile::load_from_reader(r)?.pixels()
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    ILBMFile::load_from_file(p)?.pixels()
+}
+
+fn write_chunk<W: ?Sized + Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(kind)?;
+    w.write_u32::<BigEndian>(data.len() as u32)?;
+    w.write_all(data)?;
+    if data.len() % 2 == 1 {
+        w.write_u8(0)?;
+    }
+    Ok(())
+}
+
+/// Encode palette pixels as ByteRun1 compressed ILBM with as many planes
+/// as the palette needs
+pub fn save_to_writer<W: ?Sized + Write>(pixels: &bmp::Pixels, w: &mut W) -> io::Result<()> {
+    let (palette, indexes) = match pixels.data {
+        bmp::PixelData::Indexed { ref palette, ref indexes } => (palette, indexes),
+        _ => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "ILBM requires a palette image".to_owned(),
+        )),
+    };
+    if pixels.width == 0 || pixels.width > 0xFFFF || pixels.height == 0 || pixels.height > 0xFFFF {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("ILBM can't be {}x{}", pixels.width, pixels.height),
+        ));
+    }
+    let mut planes = 1;
+    while (1usize << planes) < palette.len() {
+        planes += 1;
+    }
+    let h = ILBMHeader::new(pixels.width as u16, pixels.height as u16, planes);
+    let mut bmhd = Vec::with_capacity(ILBM_BMHD_SIZE as usize);
+    h.save_to_writer(&mut bmhd)?;
+    let mut cmap = Vec::with_capacity(palette.len() * 3);
+    for c in palette {
+        cmap.extend_from_slice(&[c.red(), c.green(), c.blue()]);
+    }
+    let row_size = h.plane_row_size();
+    let mut body = Vec::new();
+    for row in indexes.chunks(pixels.width as usize) {
+        // every plane row is compressed on its own
+        let planar = pcx::chunky_to_planar(row, 1, planes, row_size);
+        for plane in planar.chunks(row_size) {
+            body.extend(byterun1_encode(plane));
+        }
+    }
+
+    let padded = |len: usize| 8 + len + len % 2;
+    let size = 4 + padded(bmhd.len()) + padded(cmap.len()) + padded(body.len());
+    w.write_all(b"FORM")?;
+    w.write_u32::<BigEndian>(size as u32)?;
+    w.write_all(b"ILBM")?;
+    write_chunk(w, b"BMHD", &bmhd)?;
+    write_chunk(w, b"CMAP", &cmap)?;
+    write_chunk(w, b"BODY", &body)
+}
+
+pub fn save_to_file<P: AsRef<Path>>(pixels: &bmp::Pixels, p: P) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byterun1_reference() {
+        // the PackBits example, -128 is a no-op
+        let data = [
+            0xFE, 0xAA, 0x02, 0x80, 0x00, 0x2A, 0xFD, 0xAA, 0x80, 0x03, 0x80, 0x00, 0x2A, 0x22, 0xF7, 0xAA,
+        ];
+        let mut expected = vec![0xAA, 0xAA, 0xAA, 0x80, 0x00, 0x2A, 0xAA, 0xAA, 0xAA, 0xAA, 0x80, 0x00, 0x2A, 0x22];
+        expected.extend_from_slice(&[0xAA; 10]);
+        assert_eq!(byterun1_decode(&data, 24), expected);
+        assert_eq!(byterun1_decode(&data, 5), &expected[..5]);
+    }
+
+    #[test]
+    fn byterun1_encode_packets() {
+        assert_eq!(byterun1_encode(&[1, 2, 2, 3, 3, 3, 3]), vec![2, 1, 2, 2, 0xFD, 3]);
+        assert_eq!(byterun1_encode(&[9; 300]), vec![0x81, 9, 0x81, 9, 0xD5, 9]);
+        let literal: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let encoded = byterun1_encode(&literal);
+        assert_eq!((encoded[0], encoded[129]), (127, 71));
+        for row in [vec![], vec![5], vec![5, 5], literal, (0..1000).map(|i| (i / 7 % 3) as u8).collect()].iter() {
+            assert_eq!(&byterun1_decode(&byterun1_encode(row), row.len()), row);
+        }
+    }
+
+    fn planar_file(planes: u8, mode: u32, palette: Vec<bmp::RGBQuad>, indexes: &[u8]) -> ILBMFile {
+        let mut header = ILBMHeader::new(indexes.len() as u16, 1, planes);
+        header.compression = COMPRESSION_NONE;
+        let row_size = header.plane_row_size();
+        ILBMFile {
+            header: header,
+            palette: palette,
+            mode: mode,
+            body: pcx::chunky_to_planar(indexes, 1, planes, row_size),
+        }
+    }
+
+    #[test]
+    fn ham_pixels() {
+        let mut palette: Vec<bmp::RGBQuad> = (0..16).map(|_| bmp::RGBQuad::new(0, 0, 0)).collect();
+        palette[1] = bmp::RGBQuad::new(16, 32, 48);
+        // set color 1, modify blue, red and green
+        let ilbm = planar_file(6, CAMG_HAM, palette, &[0x01, 0x1F, 0x20, 0x3A]);
+        assert!(ilbm.is_ham());
+        assert_eq!(ilbm.pixels().unwrap().to_rgb(), vec![16, 32, 48, 16, 32, 255, 0, 32, 255, 0, 170, 255]);
+    }
+
+    #[test]
+    fn ehb_pixels() {
+        let palette: Vec<bmp::RGBQuad> = (0..32).map(|i| bmp::RGBQuad::new(i * 8, 100, 201)).collect();
+        let ilbm = planar_file(6, 0, palette, &[3, 35]);
+        assert!(ilbm.is_ehb());
+        assert_eq!(ilbm.pixels().unwrap().to_rgb(), vec![24, 100, 201, 12, 50, 100]);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        for &colors in [2u32, 5, 32, 33, 256].iter() {
+            let pixels = bmp::Pixels::new(21, 4, bmp::PixelData::Indexed {
+                palette: (0..colors).map(|i| bmp::RGBQuad::new(i as u8, (i * 3) as u8, 255 - i as u8)).collect(),
+                indexes: (0..84).map(|i| if i % 21 < 9 { 1 } else { (i * 11 % colors) as u8 }).collect(),
+            });
+            let mut data = Vec::new();
+            save_to_writer(&pixels, &mut data).unwrap();
+            assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), pixels.to_rgb());
+        }
+    }
+}
@@ -5,10 +5,38 @@ extern crate clap;
 
 pub mod bmp;
 pub mod pcx;
+pub mod dcx;
+pub mod os2;
+pub mod ico;
+pub mod avi;
+pub mod gif;
+pub mod png;
+pub mod qoi;
+pub mod farbfeld;
+pub mod xbm;
+pub mod xpm;
+pub mod ilbm;
+pub mod ras;
+pub mod sgi;
+pub mod wbmp;
+pub mod mono;
+pub mod metafile;
+pub mod zlib;
+pub mod netpbm;
+pub mod tga;
+pub mod format;
+pub mod color;
+pub mod quantize;
+pub mod convert;
+pub mod code;
+pub mod raw;
 pub mod encoding;
 pub mod display;
 mod args;
 
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
 use std::process;
 
 pub fn main() {
@@ -17,6 +45,201 @@ pub fn main() {
     if let Some(matches) = app.subcommand_matches("meta") {
         let filename = matches.value_of("FILE").unwrap();
         println!("Info from file {:?}", filename);
+        let image_format = format::detect(filename).unwrap_or_else(|e| {
+            eprintln!("Can't read metadata: {}", e);
+            process::exit(1);
+        });
+        if image_format == format::Format::PCX {
+            let pcx_header = pcx::PCXHeader::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            print_pcx_meta(&pcx_header, matches.is_present("raw"), matches.is_present("colors"));
+            return;
+        }
+        if image_format == format::Format::DCX {
+            let dcx = dcx::DCXFile::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("DCX with {} pages", dcx.pages.len());
+            for (idx, page) in dcx.pages.iter().enumerate() {
+                println!("Page {}:", idx + 1);
+                let pcx_header = page.header().expect(format!("Page {}", idx + 1).as_ref());
+                print_pcx_meta(&pcx_header, matches.is_present("raw"), matches.is_present("colors"));
+            }
+            return;
+        }
+        if image_format == format::Format::TGA {
+            let tga_header = tga::TGAHeader::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            if matches.is_present("raw") {
+                println!("{:?}", tga_header);
+            } else {
+                println!("{}", tga_header);
+            }
+            let tga_2 = File::open(filename).and_then(|mut f| tga::has_footer(&mut f)).unwrap_or(false);
+            println!("TGA 2.0 footer: {}", if tga_2 { "yes" } else { "no" });
+            return;
+        }
+        if let format::Format::Netpbm(_) = image_format {
+            let pixels = format::load_pixels(filename, 0)
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("Format: {}\nWidth: {} px\nHeight: {} px", image_format, pixels.width, pixels.height);
+            return;
+        }
+        if let format::Format::OS2(_) = image_format {
+            let os2_file = os2::OS2File::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("{} with {} images", image_format, os2_file.entries.len());
+            for (idx, entry) in os2_file.entries.iter().enumerate() {
+                println!("Image {}: {}", idx + 1, entry.image);
+                if entry.display_width != 0 || entry.display_height != 0 {
+                    println!("Display: {}x{}", entry.display_width, entry.display_height);
+                }
+                if matches.is_present("raw") {
+                    println!("{:?}", entry.image.header());
+                }
+            }
+            return;
+        }
+        if image_format == format::Format::ICO || image_format == format::Format::CUR {
+            let icon = ico::IconFile::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("{} with {} images", image_format, icon.entries.len());
+            for (idx, entry) in icon.entries.iter().enumerate() {
+                println!("Image {}: {}", idx + 1, entry);
+                if let Some((x, y)) = entry.hotspot() {
+                    println!("Hotspot: {}x{}", x, y);
+                }
+                if matches.is_present("raw") {
+                    println!("{:?}", entry);
+                }
+            }
+            return;
+        }
+        if image_format == format::Format::GIF {
+            let gif = gif::GIFFile::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("GIF: {}", gif);
+            for (idx, frame) in gif.frames.iter().enumerate() {
+                println!("Frame {}: {}", idx + 1, frame);
+            }
+            if matches.is_present("colors") {
+                println!("{:?}", gif.palette);
+            }
+            return;
+        }
+        if image_format == format::Format::PNG {
+            let png_header = png::PNGHeader::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            if matches.is_present("raw") {
+                println!("{:?}", png_header);
+            } else {
+                println!("PNG: {}", png_header);
+            }
+            if matches.is_present("colors") {
+                let pixels = png::load_from_file(filename)
+                    .expect(format!("Source file {}", filename).as_ref());
+                if let bmp::PixelData::Indexed { ref palette, .. } = pixels.data {
+                    println!("{:?}", palette);
+                }
+            }
+            return;
+        }
+        if image_format == format::Format::QOI {
+            let qoi_header = qoi::QOIHeader::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            if matches.is_present("raw") {
+                println!("{:?}", qoi_header);
+            } else {
+                println!("QOI: {}", qoi_header);
+            }
+            return;
+        }
+        if image_format == format::Format::Farbfeld {
+            let (width, height) = File::open(filename).and_then(|mut f| farbfeld::load_size(&mut f))
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("Format: {}\nWidth: {} px\nHeight: {} px", image_format, width, height);
+            return;
+        }
+        if image_format == format::Format::XBM {
+            let xbm_header = xbm::load_header_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("XBM: {}", xbm_header);
+            return;
+        }
+        if image_format == format::Format::XPM {
+            let xpm_header = xpm::load_header_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("XPM: {}", xpm_header);
+            if matches.is_present("colors") {
+                let pixels = xpm::load_from_file(filename)
+                    .expect(format!("Source file {}", filename).as_ref());
+                if let bmp::PixelData::Indexed { ref palette, .. } = pixels.data {
+                    println!("{:?}", palette);
+                }
+            }
+            return;
+        }
+        if image_format == format::Format::ILBM {
+            let ilbm = ilbm::ILBMFile::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            if matches.is_present("raw") {
+                println!("{:?}", ilbm.header);
+            } else {
+                println!("ILBM: {}", ilbm.header);
+            }
+            if ilbm.is_ham() {
+                println!("Mode: HAM{}", ilbm.header.planes);
+            } else if ilbm.is_ehb() {
+                println!("Mode: EHB");
+            }
+            if matches.is_present("colors") {
+                println!("{:?}", ilbm.palette);
+            }
+            return;
+        }
+        if image_format == format::Format::SunRaster {
+            let ras_header = ras::RasterHeader::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            if matches.is_present("raw") {
+                println!("{:?}", ras_header);
+            } else {
+                println!("Sun Raster: {}", ras_header);
+            }
+            if matches.is_present("colors") && ras_header.map_type == ras::RMT_EQUAL_RGB {
+                let pixels = ras::load_from_file(filename)
+                    .expect(format!("Source file {}", filename).as_ref());
+                if let bmp::PixelData::Indexed { ref palette, .. } = pixels.data {
+                    println!("{:?}", palette);
+                }
+            }
+            return;
+        }
+        if image_format == format::Format::SGI {
+            let sgi_header = sgi::SGIHeader::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            if matches.is_present("raw") {
+                println!("{:?}", sgi_header);
+            } else {
+                println!("SGI: {}", sgi_header);
+            }
+            return;
+        }
+        if image_format == format::Format::WBMP {
+            let wbmp_header = wbmp::WBMPHeader::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("WBMP: {}", wbmp_header);
+            return;
+        }
+        if image_format == format::Format::AVI {
+            let avi = avi::AVIFile::load_from_file(filename)
+                .expect(format!("Source file {}", filename).as_ref());
+            println!("AVI: {}", avi);
+            if matches.is_present("colors") {
+                if let Ok(info) = avi.info() {
+                    println!("{:?}", info.bmi_colors);
+                }
+            }
+            return;
+        }
         let bmp_info = bmp::BMPImage::meta_from_file(filename)
             .expect(format!("Source file {}", filename).as_ref());
         if matches.is_present("raw") {
@@ -31,7 +254,7 @@ pub fn main() {
     } else if let Some(matches) = app.subcommand_matches("grayscale") {
         let src = matches.value_of("SRC").unwrap();
         let dst = matches.value_of("DST").unwrap();
-        let mut image = bmp::BMPImage::load_from_file(src).expect(src);
+        let mut image = format::load_bmp(src).expect(src);
         image.grayscale();
         image.save_to_file(dst).expect(dst);
 
@@ -42,38 +265,379 @@ pub fn main() {
         if matches.is_present("width") {
             width = value_t_or_exit!(matches, "width", i16);
         }
-        let mut image = bmp::BMPImage::load_from_file(src).expect(src);
-        image.decode_bitmap();
-        image.border(width);
+        let mut image = format::load_bmp(src).expect(src);
+        image.border(width).unwrap_or_else(|e| {
+            eprintln!("Can't decode {}: {}", src, e);
+            process::exit(1);
+        });
         image.save_to_file(dst).expect(dst);
 
     } else if let Some(matches) = app.subcommand_matches("decode") {
         let src = matches.value_of("SRC").unwrap();
         let dst = matches.value_of("DST").unwrap();
-        let mut image = bmp::BMPImage::load_from_file(src).expect(src);
-        image.decode_bitmap();
+        let mut image = format::load_bmp(src).expect(src);
+        image.decode_bitmap().unwrap_or_else(|e| {
+            eprintln!("Can't decode {}: {}", src, e);
+            process::exit(1);
+        });
         image.save_to_file(dst).expect(dst);
 
     } else if let Some(matches) = app.subcommand_matches("convert") {
         let src = matches.value_of("SRC").unwrap();
         let dst = matches.value_of("DST").unwrap();
-        let mut image = pcx::pcx_256colors_to_bmp_16colors(src).unwrap_or_else(|e| {
-            eprintln!("Can't convert {} to 16 colors bmp {}: {}", src, dst, e);
+        let mut opts = convert::Options::default();
+        if matches.is_present("to") {
+            opts.to = Some(value_t_or_exit!(matches, "to", format::Format));
+        }
+        if matches.is_present("depth") {
+            opts.depth = Some(value_t_or_exit!(matches, "depth", i16));
+        }
+        if matches.is_present("colors") {
+            opts.colors = Some(value_t_or_exit!(matches, "colors", usize));
+        }
+        if matches.is_present("compression") {
+            opts.compression = Some(value_t_or_exit!(matches, "compression", convert::Compression));
+        }
+        if matches.is_present("dither") {
+            opts.dither = value_t_or_exit!(matches, "dither", quantize::Dither);
+        }
+        if matches.is_present("metric") {
+            opts.metric = value_t_or_exit!(matches, "metric", color::ColorMetric);
+        }
+        opts.plain = matches.is_present("plain");
+        if matches.is_present("maxval") {
+            opts.maxval = Some(value_t_or_exit!(matches, "maxval", u16));
+        }
+        opts.interlace = matches.is_present("interlace");
+        if matches.is_present("threshold") {
+            opts.threshold = Some(value_t_or_exit!(matches, "threshold", u8));
+        }
+        if matches.is_present("layout") {
+            opts.layout = value_t_or_exit!(matches, "layout", mono::Layout);
+        }
+        opts.invert = matches.is_present("invert");
+        convert::convert(src, dst, &opts).unwrap_or_else(|e| {
+            eprintln!("Can't convert {} to {}: {}", src, dst, e);
+            process::exit(1);
+        });
+
+    } else if let Some(matches) = app.subcommand_matches("export-code") {
+        let src = matches.value_of("SRC").unwrap();
+        let dst = matches.value_of("DST").unwrap();
+        let mut opts = code::Options::default();
+        if matches.is_present("lang") {
+            opts.language = Some(value_t_or_exit!(matches, "lang", code::Language));
+        }
+        if matches.is_present("pixel-format") {
+            opts.pixel_format = value_t_or_exit!(matches, "pixel-format", code::PixelFormat);
+        }
+        if matches.is_present("byte-order") {
+            opts.byte_order = value_t_or_exit!(matches, "byte-order", code::ByteOrder);
+        }
+        if matches.is_present("row-padding") {
+            opts.row_padding = Some(value_t_or_exit!(matches, "row-padding", usize));
+        }
+        opts.name = matches.value_of("name").map(|s| s.to_owned());
+        opts.rle = matches.is_present("rle");
+        if matches.is_present("threshold") {
+            opts.threshold = Some(value_t_or_exit!(matches, "threshold", u8));
+        }
+        if matches.is_present("dither") {
+            opts.dither = value_t_or_exit!(matches, "dither", quantize::Dither);
+        }
+        if matches.is_present("metric") {
+            opts.metric = value_t_or_exit!(matches, "metric", color::ColorMetric);
+        }
+        if matches.is_present("layout") {
+            opts.layout = value_t_or_exit!(matches, "layout", mono::Layout);
+        }
+        opts.invert = matches.is_present("invert");
+        code::export(src, dst, &opts).unwrap_or_else(|e| {
+            eprintln!("Can't export {} to {}: {}", src, dst, e);
             process::exit(1);
         });
-        image.save_to_file(dst).expect(dst);
+
+    } else if let Some(matches) = app.subcommand_matches("raw") {
+        if let Some(matches) = matches.subcommand_matches("import") {
+            let src = matches.value_of("SRC").unwrap();
+            let dst = matches.value_of("DST").unwrap();
+            let width = value_t_or_exit!(matches, "width", u32);
+            let height = value_t_or_exit!(matches, "height", u32);
+            let raw = raw_format(matches);
+            let mut image = raw::load_bmp_from_file(src, width, height, &raw).unwrap_or_else(|e| {
+                eprintln!("Can't import {} as {}: {}", src, raw, e);
+                process::exit(1);
+            });
+            image.save_to_file(dst).expect(dst);
+
+        } else if let Some(matches) = matches.subcommand_matches("export") {
+            let src = matches.value_of("SRC").unwrap();
+            let dst = matches.value_of("DST").unwrap();
+            let raw = raw_format(matches);
+            format::load_pixels(src, 0)
+                .and_then(|pixels| raw::save_to_file(&pixels, dst, &raw))
+                .unwrap_or_else(|e| {
+                    eprintln!("Can't export {} as {}: {}", src, raw, e);
+                    process::exit(1);
+                });
+        }
 
     } else if let Some(matches) = app.subcommand_matches("logo") {
         let src = matches.value_of("SRC").unwrap();
         let dst = matches.value_of("DST").unwrap();
         let logo = matches.value_of("LOGO").unwrap();
-        let mut image = bmp::BMPImage::load_from_file(src).expect(src);
+        let mut image = format::load_bmp(src).expect(src);
         image.add_logo(logo);
         image.save_to_file(dst).expect(dst);
 
+    } else if let Some(matches) = app.subcommand_matches("dcx") {
+        if let Some(matches) = matches.subcommand_matches("list") {
+            let filename = matches.value_of("FILE").unwrap();
+            let dcx = dcx::DCXFile::load_from_file(filename).expect(filename);
+            println!("{} pages in {:?}", dcx.pages.len(), filename);
+            for (idx, page) in dcx.pages.iter().enumerate() {
+                match page.header() {
+                    Ok(h) => println!(
+                        "Page {}: offset {}, {} bytes, {}x{} px, {} bits per pixel",
+                        idx + 1, page.offset, page.data.len(), h.get_width(), h.get_height(), h.bit_count(),
+                    ),
+                    Err(e) => println!(
+                        "Page {}: offset {}, {} bytes, invalid PCX: {}",
+                        idx + 1, page.offset, page.data.len(), e,
+                    ),
+                }
+            }
+
+        } else if let Some(matches) = matches.subcommand_matches("extract") {
+            let src = matches.value_of("SRC").unwrap();
+            let dst = matches.value_of("DST").unwrap();
+            let page_num = value_t_or_exit!(matches, "PAGE", usize);
+            let dcx = dcx::DCXFile::load_from_file(src).expect(src);
+            let page = dcx.page(page_num - 1).unwrap_or_else(|e| {
+                eprintln!("Can't extract page {}: {}", page_num, e);
+                process::exit(1);
+            });
+            if format::Format::from_extension(dst).ok() == Some(format::Format::PCX) {
+                File::create(dst).and_then(|mut f| f.write_all(&page.data)).expect(dst);
+            } else {
+                let mut image = page.pixels()
+                    .and_then(|p| bmp::BMPImage::from_pixels(&p))
+                    .unwrap_or_else(|e| {
+                        eprintln!("Can't convert page {} to bmp {}: {}", page_num, dst, e);
+                        process::exit(1);
+                    });
+                image.save_to_file(dst).expect(dst);
+            }
+
+        } else if let Some(matches) = matches.subcommand_matches("build") {
+            let dst = matches.value_of("DST").unwrap();
+            let sources: Vec<&str> = matches.values_of("SRC").unwrap().collect();
+            let dcx = dcx::build(&sources).unwrap_or_else(|e| {
+                eprintln!("Can't build dcx {}: {}", dst, e);
+                process::exit(1);
+            });
+            dcx.save_to_file(dst).expect(dst);
+        }
+
+    } else if let Some(matches) = app.subcommand_matches("os2") {
+        if let Some(matches) = matches.subcommand_matches("list") {
+            let filename = matches.value_of("FILE").unwrap();
+            let os2_file = os2::OS2File::load_from_file(filename).expect(filename);
+            println!("{} images in {:?}", os2_file.entries.len(), filename);
+            for (idx, entry) in os2_file.entries.iter().enumerate() {
+                println!(
+                    "Image {}: offset {}, display {}x{}, {}",
+                    idx + 1, entry.offset, entry.display_width, entry.display_height, entry.image,
+                );
+            }
+
+        } else if let Some(matches) = matches.subcommand_matches("extract") {
+            let src = matches.value_of("SRC").unwrap();
+            let dst = matches.value_of("DST").unwrap();
+            let image_num = value_t_or_exit!(matches, "IMAGE", usize);
+            let mut os2_file = os2::OS2File::load_from_file(src).expect(src);
+            let mut image = os2_file.entry(image_num - 1)
+                .and_then(|e| e.image.to_pixels())
+                .and_then(|p| bmp::BMPImage::from_pixels(&p))
+                .unwrap_or_else(|e| {
+                    eprintln!("Can't extract image {}: {}", image_num, e);
+                    process::exit(1);
+                });
+            image.save_to_file(dst).expect(dst);
+        }
+
+    } else if let Some(matches) = app.subcommand_matches("ico") {
+        if let Some(matches) = matches.subcommand_matches("list") {
+            let filename = matches.value_of("FILE").unwrap();
+            let icon = ico::IconFile::load_from_file(filename).expect(filename);
+            println!("{} images in {:?}", icon.entries.len(), filename);
+            for (idx, entry) in icon.entries.iter().enumerate() {
+                match entry.hotspot() {
+                    Some((x, y)) => println!("Image {}: {}, hotspot {}x{}", idx + 1, entry, x, y),
+                    None => println!("Image {}: {}", idx + 1, entry),
+                }
+            }
+
+        } else if let Some(matches) = matches.subcommand_matches("extract") {
+            let src = matches.value_of("SRC").unwrap();
+            let dst = matches.value_of("DST").unwrap();
+            let image_num = value_t_or_exit!(matches, "IMAGE", usize);
+            let icon = ico::IconFile::load_from_file(src).expect(src);
+            let mut image = icon.entry(image_num - 1)
+                .and_then(|e| e.pixels())
+                .and_then(|p| bmp::BMPImage::from_pixels(&p))
+                .unwrap_or_else(|e| {
+                    eprintln!("Can't extract image {}: {}", image_num, e);
+                    process::exit(1);
+                });
+            image.save_to_file(dst).expect(dst);
+
+        } else if let Some(matches) = matches.subcommand_matches("build") {
+            let dst = matches.value_of("DST").unwrap();
+            let sources: Vec<&str> = matches.values_of("SRC").unwrap().collect();
+            let icon_type = match format::Format::from_extension(dst) {
+                Ok(format::Format::CUR) => ico::CUR_TYPE,
+                _ => ico::ICO_TYPE,
+            };
+            let hotspot = matches.value_of("hotspot").map(|h| parse_hotspot(h).unwrap_or_else(|| {
+                eprintln!("Invalid hotspot {:?}, expected X,Y", h);
+                process::exit(1);
+            }));
+            let icon = ico::build(&sources, icon_type, hotspot).unwrap_or_else(|e| {
+                eprintln!("Can't build icon {}: {}", dst, e);
+                process::exit(1);
+            });
+            icon.save_to_file(dst).expect(dst);
+        }
+
+    } else if let Some(matches) = app.subcommand_matches("avi") {
+        if let Some(matches) = matches.subcommand_matches("build") {
+            let dst = matches.value_of("DST").unwrap();
+            let dir = matches.value_of("DIR").unwrap();
+            let mut fps = avi::AVI_DEFAULT_FPS;
+            if matches.is_present("fps") {
+                fps = value_t_or_exit!(matches, "fps", u32);
+            }
+            let rle = matches.value_of("compression") == Some("rle8");
+            let avi = avi::list_images(dir)
+                .and_then(|sources| avi::build(&sources, fps, rle))
+                .unwrap_or_else(|e| {
+                    eprintln!("Can't build avi {} from {}: {}", dst, dir, e);
+                    process::exit(1);
+                });
+            avi.save_to_file(dst).expect(dst);
+
+        } else if let Some(matches) = matches.subcommand_matches("extract") {
+            let src = matches.value_of("SRC").unwrap();
+            let dir = matches.value_of("DIR").unwrap();
+            let avi = avi::AVIFile::load_from_file(src).expect(src);
+            let images = avi.images().unwrap_or_else(|e| {
+                eprintln!("Can't decode frames of {}: {}", src, e);
+                process::exit(1);
+            });
+            fs::create_dir_all(dir).expect(dir);
+            for (idx, mut image) in images.into_iter().enumerate() {
+                let dst = Path::new(dir).join(format!("frame_{:05}.bmp", idx + 1));
+                image.save_to_file(&dst).expect(&dst.to_string_lossy());
+            }
+        }
+
+    } else if let Some(matches) = app.subcommand_matches("gif") {
+        if let Some(matches) = matches.subcommand_matches("build") {
+            let dst = matches.value_of("DST").unwrap();
+            let sources: Vec<&str> = matches.values_of("SRC").unwrap().collect();
+            let mut opts = gif::Options::default();
+            if matches.is_present("delay") {
+                opts.delay = value_t_or_exit!(matches, "delay", u16);
+            }
+            if matches.is_present("loop") {
+                opts.loop_count = Some(value_t_or_exit!(matches, "loop", u16));
+            }
+            if matches.is_present("transparent") {
+                opts.transparent = Some(value_t_or_exit!(matches, "transparent", u8));
+            }
+            opts.local_palettes = matches.is_present("local-palettes");
+            if matches.is_present("colors") {
+                opts.colors = value_t_or_exit!(matches, "colors", usize);
+            }
+            if matches.is_present("dither") {
+                opts.dither = value_t_or_exit!(matches, "dither", quantize::Dither);
+            }
+            if matches.is_present("metric") {
+                opts.metric = value_t_or_exit!(matches, "metric", color::ColorMetric);
+            }
+            let gif = gif::build_from_files(&sources, &opts).unwrap_or_else(|e| {
+                eprintln!("Can't build gif {}: {}", dst, e);
+                process::exit(1);
+            });
+            gif.save_to_file(dst).expect(dst);
+        }
+
+    } else if let Some(matches) = app.subcommand_matches("extract") {
+        let src = matches.value_of("SRC").unwrap();
+        let dir = matches.value_of("DIR").unwrap();
+        let metafile = metafile::Metafile::load_from_file(src).unwrap_or_else(|e| {
+            eprintln!("Can't read metafile {}: {}", src, e);
+            process::exit(1);
+        });
+        println!("{} bitmaps in {} {:?}", metafile.dibs.len(), metafile.kind, src);
+        fs::create_dir_all(dir).expect(dir);
+        for (idx, dib) in metafile.dibs.iter().enumerate() {
+            let mut image = match dib.image() {
+                Ok(image) => image,
+                Err(e) => {
+                    eprintln!("Can't extract bitmap {} of {} record at offset {}: {}", idx + 1, dib.record, dib.offset, e);
+                    continue;
+                },
+            };
+            let dst = Path::new(dir).join(format!("bitmap_{:05}.bmp", idx + 1));
+            println!(
+                "Bitmap {}: {} record at offset {}, {}x{} px, {} bpp",
+                idx + 1, dib.record, dib.offset, image.info.bmi_header.get_width(),
+                image.info.bmi_header.get_height().abs(), image.info.bmi_header.get_bit_count(),
+            );
+            image.save_to_file(&dst).expect(&dst.to_string_lossy());
+        }
+
     } else if let Some(matches) = app.subcommand_matches("display") {
         let image = matches.value_of("IMAGE").unwrap();
-        display::image(image);
+        let mut page: usize = 1;
+        if matches.is_present("page") {
+            page = value_t_or_exit!(matches, "page", usize);
+        }
+        display::image(image, page - 1);
+    }
+}
+
+/// Parse "X,Y" cursor hotspot
+fn parse_hotspot(s: &str) -> Option<(u16, u16)> {
+    let mut parts = s.splitn(2, ',');
+    let x = parts.next()?.trim().parse().ok()?;
+    let y = parts.next()?.trim().parse().ok()?;
+    Some((x, y))
+}
+
+/// Raw pixel layout of "raw" subcommand arguments
+fn raw_format(matches: &clap::ArgMatches) -> raw::RawFormat {
+    let mut raw = raw::RawFormat::new(value_t_or_exit!(matches, "format", raw::PixelFormat));
+    if matches.is_present("stride") {
+        raw.stride = Some(value_t_or_exit!(matches, "stride", usize));
+    }
+    if matches.is_present("byte-order") {
+        raw.byte_order = value_t_or_exit!(matches, "byte-order", code::ByteOrder);
+    }
+    raw.bottom_up = matches.is_present("bottom-up");
+    raw
+}
+
+fn print_pcx_meta(header: &pcx::PCXHeader, raw: bool, colors: bool) {
+    if raw {
+        println!("{:?}", header);
+    } else {
+        println!("{}", header);
+    }
+    if colors {
+        println!("{:?}", header.get_palette());
     }
 }
 
@@ -0,0 +1,474 @@
+//! # Format desciption
+//! Windows metafiles are streams of GDI drawing records. Bitmaps are drawn
+//! by records which carry a DIB: BITMAPINFO header and color table followed
+//! by the bitmap, as in the BMP file without its file header.
+//!
+//! * WMF - optional 22 byte placeable header, 18 byte metafile header, then
+//!   records of size in 16 bit words, function number and parameters.
+//!   DIB records keep the packed DIB after the fixed parameters.
+//! * EMF - records of type and size in bytes, the first is EMR_HEADER with
+//!   " EMF" signature. DIB records point to the header and the bitmap
+//!   by offsets from the record start.
+//!
+//! DIBs with the color table of logical palette indexes (DIB_PAL_COLORS)
+//! depend on the palette selected at playback time and can't be extracted.
+//! https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-wmf/
+//! https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-emf/
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use bmp;
+
+/// Key of the placeable WMF header
+pub const WMF_PLACEABLE_KEY: u32 = 0x9AC6_CDD7;
+pub const WMF_PLACEABLE_HEADER_SIZE: usize = 22;
+pub const WMF_HEADER_SIZE: usize = 18;
+/// Size, function and no parameters
+pub const WMF_RECORD_MIN_SIZE: usize = 6;
+
+pub const META_EOF: u16 = 0x0000;
+pub const META_DIBCREATEPATTERNBRUSH: u16 = 0x0142;
+pub const META_DIBBITBLT: u16 = 0x0940;
+pub const META_DIBSTRETCHBLT: u16 = 0x0B41;
+pub const META_SETDIBTODEV: u16 = 0x0D33;
+pub const META_STRETCHDIB: u16 = 0x0F43;
+
+/// Pattern brush of the old bitmap structure instead of DIB
+const BS_PATTERN: u16 = 3;
+
+/// " EMF" signature of EMR_HEADER
+pub const EMF_SIGNATURE: u32 = 0x464D_4520;
+pub const EMF_SIGNATURE_OFFSET: usize = 40;
+/// Type and size
+pub const EMF_RECORD_MIN_SIZE: usize = 8;
+
+pub const EMR_HEADER: u32 = 1;
+pub const EMR_EOF: u32 = 14;
+pub const EMR_BITBLT: u32 = 76;
+pub const EMR_STRETCHBLT: u32 = 77;
+pub const EMR_MASKBLT: u32 = 78;
+pub const EMR_PLGBLT: u32 = 79;
+pub const EMR_SETDIBITSTODEVICE: u32 = 80;
+pub const EMR_STRETCHDIBITS: u32 = 81;
+pub const EMR_CREATEMONOBRUSH: u32 = 93;
+pub const EMR_CREATEDIBPATTERNBRUSHPT: u32 = 94;
+
+/// Color table of RGBQUAD colors
+pub const DIB_RGB_COLORS: u32 = 0;
+/// Color table of 16 bit logical palette indexes
+pub const DIB_PAL_COLORS: u32 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MetafileType {
+    WMF,
+    EMF,
+}
+
+impl fmt::Display for MetafileType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            MetafileType::WMF => "WMF",
+            MetafileType::EMF => "EMF",
+        })
+    }
+}
+
+/// DIB of a metafile record
+#[derive(Debug)]
+pub struct MetafileDIB {
+    /// Name of the record
+    pub record: &'static str,
+    /// Offset of the record from the beginning of the file
+    pub offset: usize,
+    /// DIB_RGB_COLORS or DIB_PAL_COLORS
+    pub usage: u32,
+    /// Info header and color table
+    pub info: Vec<u8>,
+    pub bitmap: Vec<u8>,
+}
+
+impl MetafileDIB {
+    /// Standalone BMP image of the DIB
+    pub fn image(&self) -> io::Result<bmp::BMPImage> {
+        if self.usage == DIB_PAL_COLORS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "DIB colors are indexes of the metafile palette".to_owned(),
+            ));
+        }
+        let info = bmp::BMPInfo::load_from_reader(&mut Cursor::new(&self.info[..]))?;
+        let size = info.bmi_header.get_bitmap_size() as usize;
+        if self.bitmap.len() < size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("DIB bitmap is truncated: {} bytes, expected {}", self.bitmap.len(), size),
+            ));
+        }
+        let offset = bmp::BMP_FILE_HEADER_SIZE as i32 + info.get_size();
+        Ok(bmp::BMPImage {
+            header: bmp::BMPFileHeader::new(offset + size as i32, offset),
+            info: info,
+            bitmap: bmp::Bitmap {
+                data: self.bitmap[..size].to_vec(),
+                decoded_from: None,
+            },
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Metafile {
+    pub kind: MetafileType,
+    pub dibs: Vec<MetafileDIB>,
+}
+
+impl Metafile {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<Metafile> {
+        let mut f = BufReader::new(File::open(p)?);
+        Metafile::load_from_reader(&mut f)
+    }
+
+    /// Walk metafile records and collect their DIBs
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<Metafile> {
+        let mut data = Vec::new();
+        r.read_to_end(&mut data)?;
+        match detect(&data) {
+            Some(MetafileType::WMF) => Ok(Metafile {
+                kind: MetafileType::WMF,
+                dibs: wmf_dibs(&data)?,
+            }),
+            Some(MetafileType::EMF) => Ok(Metafile {
+                kind: MetafileType::EMF,
+                dibs: emf_dibs(&data)?,
+            }),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a WMF or EMF metafile".to_owned(),
+            )),
+        }
+    }
+}
+
+fn u16_at(data: &[u8], pos: usize) -> io::Result<u16> {
+    data.get(pos..pos + 2).ok_or_else(|| truncated(pos))?.read_u16::<LittleEndian>()
+}
+
+fn u32_at(data: &[u8], pos: usize) -> io::Result<u32> {
+    data.get(pos..pos + 4).ok_or_else(|| truncated(pos))?.read_u32::<LittleEndian>()
+}
+
+fn truncated(pos: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("Metafile is truncated at offset {}", pos),
+    )
+}
+
+fn invalid_record(kind: MetafileType, pos: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid {} record at offset {}", kind, pos),
+    )
+}
+
+pub fn detect(data: &[u8]) -> Option<MetafileType> {
+    if u32_at(data, 0).ok() == Some(EMR_HEADER) && u32_at(data, EMF_SIGNATURE_OFFSET).ok() == Some(EMF_SIGNATURE) {
+        return Some(MetafileType::EMF);
+    }
+    if u32_at(data, 0).ok() == Some(WMF_PLACEABLE_KEY) {
+        return Some(MetafileType::WMF);
+    }
+    // memory or disk metafile of 9 words header
+    match (u16_at(data, 0).ok(), u16_at(data, 2).ok()) {
+        (Some(1), Some(9)) | (Some(2), Some(9)) => Some(MetafileType::WMF),
+        _ => None,
+    }
+}
+
+/// DIBs of WMF records, the packed DIB follows the fixed parameters
+fn wmf_dibs(data: &[u8]) -> io::Result<Vec<MetafileDIB>> {
+    let mut pos = 0;
+    if u32_at(data, 0)? == WMF_PLACEABLE_KEY {
+        pos += WMF_PLACEABLE_HEADER_SIZE;
+    }
+    pos += u16_at(data, pos + 2)? as usize * 2;
+    let mut dibs = Vec::new();
+    while pos + WMF_RECORD_MIN_SIZE <= data.len() {
+        let size = u32_at(data, pos)? as usize * 2;
+        let function = u16_at(data, pos + 4)?;
+        if size < WMF_RECORD_MIN_SIZE || data.len() - pos < size {
+            return Err(invalid_record(MetafileType::WMF, pos));
+        }
+        if function == META_EOF {
+            break;
+        }
+        let params = &data[pos + WMF_RECORD_MIN_SIZE..pos + size];
+        // blits without bitmap have no DIB and the size of the function high byte
+        let has_bitmap = size / 2 != (function >> 8) as usize + 3;
+        let dib = match function {
+            META_SETDIBTODEV => Some(("SetDIBToDev", u16_at(params, 0)?, 18)),
+            META_STRETCHDIB => Some(("StretchDIB", u16_at(params, 4)?, 22)),
+            META_DIBBITBLT if has_bitmap => Some(("DIBBitBlt", DIB_RGB_COLORS as u16, 16)),
+            META_DIBSTRETCHBLT if has_bitmap => Some(("DIBStretchBlt", DIB_RGB_COLORS as u16, 20)),
+            META_DIBCREATEPATTERNBRUSH if u16_at(params, 0)? != BS_PATTERN => {
+                Some(("DIBCreatePatternBrush", u16_at(params, 2)?, 4))
+            },
+            _ => None,
+        };
+        if let Some((record, usage, start)) = dib {
+            if params.len() < start {
+                return Err(invalid_record(MetafileType::WMF, pos));
+            }
+            // color table of palette indexes isn't made of RGBQUADs, such DIB is kept whole
+            let info_size = if usage as u32 == DIB_PAL_COLORS {
+                params.len() - start
+            } else {
+                bmp::BMPInfo::load_from_reader(&mut Cursor::new(&params[start..]))?.get_size() as usize
+            };
+            dibs.push(MetafileDIB {
+                record: record,
+                offset: pos,
+                usage: usage as u32,
+                info: params[start..start + info_size].to_vec(),
+                bitmap: params[start + info_size..].to_vec(),
+            });
+        }
+        pos += size;
+    }
+    Ok(dibs)
+}
+
+/// DIBs of EMF records, offsets of the header and the bitmap are
+/// counted from the record start
+fn emf_dibs(data: &[u8]) -> io::Result<Vec<MetafileDIB>> {
+    let mut pos = 0;
+    let mut dibs = Vec::new();
+    while pos + EMF_RECORD_MIN_SIZE <= data.len() {
+        let kind = u32_at(data, pos)?;
+        let size = u32_at(data, pos + 4)? as usize;
+        if size < EMF_RECORD_MIN_SIZE || size % 4 != 0 || data.len() - pos < size {
+            return Err(invalid_record(MetafileType::EMF, pos));
+        }
+        if kind == EMR_EOF {
+            break;
+        }
+        let record = &data[pos..pos + size];
+        // name, offset of the usage and offset of the bitmap offsets
+        let dib = match kind {
+            EMR_BITBLT => Some(("BitBlt", 80, 84)),
+            EMR_STRETCHBLT => Some(("StretchBlt", 80, 84)),
+            EMR_MASKBLT => Some(("MaskBlt", 80, 84)),
+            EMR_PLGBLT => Some(("PlgBlt", 92, 96)),
+            EMR_SETDIBITSTODEVICE => Some(("SetDIBitsToDevice", 64, 48)),
+            EMR_STRETCHDIBITS => Some(("StretchDIBits", 64, 48)),
+            EMR_CREATEMONOBRUSH => Some(("CreateMonoBrush", 12, 16)),
+            EMR_CREATEDIBPATTERNBRUSHPT => Some(("CreateDIBPatternBrushPt", 12, 16)),
+            _ => None,
+        };
+        if let Some((name, usage_at, offsets_at)) = dib {
+            let usage = u32_at(record, usage_at)?;
+            let info_offset = u32_at(record, offsets_at)? as usize;
+            let info_size = u32_at(record, offsets_at + 4)? as usize;
+            let bitmap_offset = u32_at(record, offsets_at + 8)? as usize;
+            let bitmap_size = u32_at(record, offsets_at + 12)? as usize;
+            // blits of raster operations without source have no DIB
+            if info_size > 0 {
+                let info = record.get(info_offset..info_offset.saturating_add(info_size));
+                let bitmap = record.get(bitmap_offset..bitmap_offset.saturating_add(bitmap_size));
+                match (info, bitmap) {
+                    (Some(info), Some(bitmap)) => dibs.push(MetafileDIB {
+                        record: name,
+                        offset: pos,
+                        usage: usage,
+                        info: info.to_vec(),
+                        bitmap: bitmap.to_vec(),
+                    }),
+                    _ => return Err(invalid_record(MetafileType::EMF, pos)),
+                }
+            }
+        }
+        pos += size;
+    }
+    Ok(dibs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    /// Info header with color table and the bitmap
+    fn dib(width: i32, height: i32, bit_count: u16) -> (Vec<u8>, Vec<u8>) {
+        let stride = ((width * bit_count as i32 + 31) / 32 * 4) as usize;
+        let mut info = Vec::new();
+        info.write_u32::<LittleEndian>(40).unwrap();
+        info.write_i32::<LittleEndian>(width).unwrap();
+        info.write_i32::<LittleEndian>(height).unwrap();
+        info.write_u16::<LittleEndian>(1).unwrap();
+        info.write_u16::<LittleEndian>(bit_count).unwrap();
+        info.extend_from_slice(&[0; 24]);
+        if bit_count <= 8 {
+            for i in 0..1u32 << bit_count {
+                info.extend_from_slice(&[(i * 7) as u8, (i * 13) as u8, (i * 29) as u8, 0]);
+            }
+        }
+        let bitmap = (0..stride * height as usize).map(|i| (i * 31 + 7) as u8).collect();
+        (info, bitmap)
+    }
+
+    fn words(values: &[u16]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &v in values {
+            data.write_u16::<LittleEndian>(v).unwrap();
+        }
+        data
+    }
+
+    fn wmf_record(function: u16, params: &[u8]) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.write_u32::<LittleEndian>((WMF_RECORD_MIN_SIZE + params.len() + 1) as u32 / 2).unwrap();
+        record.write_u16::<LittleEndian>(function).unwrap();
+        record.extend_from_slice(params);
+        record.resize((WMF_RECORD_MIN_SIZE + params.len() + 1) / 2 * 2, 0);
+        record
+    }
+
+    fn emf_record(kind: u32, body: &[u8]) -> Vec<u8> {
+        let size = (EMF_RECORD_MIN_SIZE + body.len() + 3) / 4 * 4;
+        let mut record = Vec::new();
+        record.write_u32::<LittleEndian>(kind).unwrap();
+        record.write_u32::<LittleEndian>(size as u32).unwrap();
+        record.extend_from_slice(body);
+        record.resize(size, 0);
+        record
+    }
+
+    /// Offsets of the DIB header and the bitmap after the fixed part of the record
+    fn emf_offsets(fixed: usize, info: &[u8], bitmap: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &v in &[fixed, info.len(), fixed + info.len(), bitmap.len()] {
+            data.write_u32::<LittleEndian>(v as u32).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn wmf_records() {
+        let mut records = Vec::new();
+        let (info8, bitmap8) = dib(5, 3, 8);
+        let mut params = words(&[0x20, 0xCC, 0, 3, 5, 0, 0, 3, 5, 0, 0]);
+        params.extend_from_slice(&info8);
+        params.extend_from_slice(&bitmap8);
+        records.push(wmf_record(META_STRETCHDIB, &params));
+        let (info, bitmap) = dib(9, 4, 24);
+        let mut params = words(&[0x20, 0xCC, 4, 9, 0, 0, 4, 9, 0, 0]);
+        params.extend_from_slice(&info);
+        params.extend_from_slice(&bitmap);
+        records.push(wmf_record(META_DIBSTRETCHBLT, &params));
+        // no source bitmap, the record is as long as the function high byte says
+        records.push(wmf_record(META_DIBBITBLT, &words(&[0x42, 0, 0, 0, 0, 4, 4, 0, 0])));
+        let (info, bitmap) = dib(8, 8, 1);
+        let mut params = words(&[5, 0]);
+        params.extend_from_slice(&info);
+        params.extend_from_slice(&bitmap);
+        records.push(wmf_record(META_DIBCREATEPATTERNBRUSH, &params));
+        // the color table is of palette indexes
+        let (info, bitmap) = dib(4, 2, 4);
+        let mut params = words(&[DIB_PAL_COLORS as u16, 2, 0, 0, 0, 2, 4, 0, 0]);
+        params.extend_from_slice(&info[..40]);
+        params.extend_from_slice(&words(&(0..16).collect::<Vec<u16>>()));
+        params.extend_from_slice(&bitmap);
+        records.push(wmf_record(META_SETDIBTODEV, &params));
+        records.push(wmf_record(META_EOF, &[]));
+
+        let body: Vec<u8> = records.iter().flat_map(|r| r.iter().cloned()).collect();
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(WMF_PLACEABLE_KEY).unwrap();
+        data.extend_from_slice(&[0; WMF_PLACEABLE_HEADER_SIZE - 4]);
+        data.extend_from_slice(&words(&[1, 9, 0x300]));
+        data.write_u32::<LittleEndian>(((WMF_HEADER_SIZE + body.len()) / 2) as u32).unwrap();
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&body);
+
+        let wmf = Metafile::load_from_reader(&mut &data[..]).unwrap();
+        assert_eq!(wmf.kind, MetafileType::WMF);
+        let start = WMF_PLACEABLE_HEADER_SIZE + WMF_HEADER_SIZE;
+        let offsets: Vec<usize> = records.iter().scan(start, |pos, r| {
+            let offset = *pos;
+            *pos += r.len();
+            Some(offset)
+        }).collect();
+        let found: Vec<(&str, usize)> = wmf.dibs.iter().map(|d| (d.record, d.offset)).collect();
+        assert_eq!(found, vec![
+            ("StretchDIB", offsets[0]),
+            ("DIBStretchBlt", offsets[1]),
+            ("DIBCreatePatternBrush", offsets[3]),
+            ("SetDIBToDev", offsets[4]),
+        ]);
+        assert_eq!((&wmf.dibs[0].info, &wmf.dibs[0].bitmap), (&info8, &bitmap8));
+        let image = wmf.dibs[0].image().unwrap();
+        assert_eq!((image.info.bmi_header.get_width(), image.bitmap.data.len()), (5, 24));
+        assert_eq!(wmf.dibs[3].usage, DIB_PAL_COLORS);
+        assert!(wmf.dibs[3].image().is_err());
+
+        // the record goes past the end
+        let mut short = data.clone();
+        short.truncate(start + records[0].len() - 2);
+        assert!(Metafile::load_from_reader(&mut &short[..]).is_err());
+    }
+
+    #[test]
+    fn emf_records() {
+        let mut header = vec![0u8; 100];
+        header[EMF_SIGNATURE_OFFSET - 8..EMF_SIGNATURE_OFFSET - 4].copy_from_slice(&[0x20, 0x45, 0x4D, 0x46]);
+        let mut records = vec![emf_record(EMR_HEADER, &header)];
+        let (info8, bitmap8) = dib(7, 5, 8);
+        let mut body = vec![0u8; 40];
+        body.extend_from_slice(&emf_offsets(80, &info8, &bitmap8));
+        body.extend_from_slice(&[0, 0, 0, 0, 0x20, 0, 0xCC, 0, 7, 0, 0, 0, 5, 0, 0, 0]);
+        body.extend_from_slice(&info8);
+        body.extend_from_slice(&bitmap8);
+        records.push(emf_record(EMR_STRETCHDIBITS, &body));
+        let (info, bitmap) = dib(3, 3, 32);
+        let mut body = vec![0u8; 76];
+        body.extend_from_slice(&emf_offsets(108, &info, &bitmap));
+        body.extend_from_slice(&[3, 0, 0, 0, 3, 0, 0, 0]);
+        body.extend_from_slice(&info);
+        body.extend_from_slice(&bitmap);
+        records.push(emf_record(EMR_STRETCHBLT, &body));
+        // no source, no DIB
+        records.push(emf_record(EMR_BITBLT, &[0u8; 92]));
+        let (info, bitmap) = dib(8, 8, 4);
+        let mut body = vec![1, 0, 0, 0, 0, 0, 0, 0];
+        body.extend_from_slice(&emf_offsets(32, &info, &bitmap));
+        body.extend_from_slice(&info);
+        body.extend_from_slice(&bitmap);
+        records.push(emf_record(EMR_CREATEDIBPATTERNBRUSHPT, &body));
+        records.push(emf_record(EMR_EOF, &[0; 12]));
+        let data: Vec<u8> = records.iter().flat_map(|r| r.iter().cloned()).collect();
+
+        let emf = Metafile::load_from_reader(&mut &data[..]).unwrap();
+        assert_eq!(emf.kind, MetafileType::EMF);
+        let found: Vec<(&str, usize)> = emf.dibs.iter().map(|d| (d.record, d.offset)).collect();
+        let second = records[0].len() + records[1].len();
+        assert_eq!(found, vec![
+            ("StretchDIBits", records[0].len()),
+            ("StretchBlt", second),
+            ("CreateDIBPatternBrushPt", second + records[2].len() + records[3].len()),
+        ]);
+        assert_eq!((&emf.dibs[0].info, &emf.dibs[0].bitmap), (&info8, &bitmap8));
+        let image = emf.dibs[1].image().unwrap();
+        assert_eq!((image.info.bmi_header.get_bit_count(), image.bitmap.data.len()), (32, 36));
+
+        // the bitmap is out of the record
+        let mut broken = data.clone();
+        let at = records[0].len() + 8 + 40 + 8;
+        broken[at] = 0xFF;
+        assert!(Metafile::load_from_reader(&mut &broken[..]).is_err());
+        assert_eq!(detect(&data[4..]), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! # Format desciption
+//! Packed monochrome: headerless 1 bit per pixel stream as display
+//! controllers and e-paper drivers take it, set bits are white (lit) pixels.
+//! Row-major layout packs every row from the most significant bit and pads
+//! it to a byte. Page-major layout, as SSD1306 and similar OLED controllers
+//! expect, splits the image into pages of 8 rows and stores every page
+//! column as a byte with the top pixel in the least significant bit.
+//! https://cdn-shop.adafruit.com/datasheets/SSD1306.pdf
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bmp;
+
+/// Rows of a page-major layout page
+pub const PAGE_HEIGHT: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Layout {
+    /// Rows of horizontally packed pixels
+    Rows,
+    /// Pages of 8 rows of vertically packed pixels
+    Pages,
+}
+
+pub const LAYOUTS: [&'static str; 2] = ["rows", "pages"];
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout::Rows
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Layout::Rows => "rows",
+            Layout::Pages => "pages",
+        })
+    }
+}
+
+impl FromStr for Layout {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Layout> {
+        match s.to_lowercase().as_ref() {
+            "rows" | "row-major" => Ok(Layout::Rows),
+            "pages" | "page-major" | "ssd1306" => Ok(Layout::Pages),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown monochrome layout: {}", s),
+            )),
+        }
+    }
+}
+
+/// Pack pixels into bits, light pixels are set unless `invert` is given
+pub fn pack(pixels: &bmp::Pixels, layout: Layout, invert: bool) -> Vec<u8> {
+    let (width, height) = (pixels.width as usize, pixels.height as usize);
+    let bits: Vec<u8> = pixels.to_rgb().chunks(3)
+        .map(|px| ((px[0] as u32 + px[1] as u32 + px[2] as u32) / 3 >= 128) as u8 ^ invert as u8)
+        .collect();
+    match layout {
+        Layout::Rows => bits.chunks(width).flat_map(|row| bmp::pack_row(row, 1)).collect(),
+        Layout::Pages => {
+            let pages = (height + PAGE_HEIGHT - 1) / PAGE_HEIGHT;
+            let mut data = vec![0u8; pages * width];
+            for (y, row) in bits.chunks(width).enumerate() {
+                for (x, &bit) in row.iter().enumerate() {
+                    data[y / PAGE_HEIGHT * width + x] |= bit << (y % PAGE_HEIGHT);
+                }
+            }
+            data
+        },
+    }
+}
+
+pub fn save_to_writer<W: ?Sized + Write>(
+    pixels: &bmp::Pixels, w: &mut W,
+    layout: Layout,
+    invert: bool,
+) -> io::Result<()> {
+    w.write_all(&pack(pixels, layout, invert))
+}
+
+pub fn save_to_file<P: AsRef<Path>>(
+    pixels: &bmp::Pixels, p: P,
+    layout: Layout,
+    invert: bool,
+) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f, layout, invert)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Light pixels of both layouts back as bits
+    fn unpack(data: &[u8], width: usize, height: usize, layout: Layout) -> Vec<u8> {
+        let row_size = (width + 7) / 8;
+        let mut bits = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                bits.push(match layout {
+                    Layout::Rows => data[y * row_size + x / 8] >> (7 - x % 8) & 1,
+                    Layout::Pages => data[y / PAGE_HEIGHT * width + x] >> (y % PAGE_HEIGHT) & 1,
+                });
+            }
+        }
+        bits
+    }
+
+    #[test]
+    fn layout_names() {
+        assert_eq!(Layout::default(), Layout::Rows);
+        for name in &LAYOUTS {
+            assert_eq!(name.parse::<Layout>().unwrap().to_string(), *name);
+        }
+        assert_eq!("SSD1306".parse::<Layout>().unwrap(), Layout::Pages);
+        assert_eq!("row-major".parse::<Layout>().unwrap(), Layout::Rows);
+        assert_eq!("columns".parse::<Layout>().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn pack_layouts() {
+        // a white diagonal on black
+        let rgb: Vec<u8> = (0..10 * 10).flat_map(|i| vec![if i % 10 == i / 10 { 255 } else { 0 }; 3]).collect();
+        let pixels = bmp::Pixels::new(10, 10, bmp::PixelData::RGB(rgb));
+        let rows = pack(&pixels, Layout::Rows, false);
+        assert_eq!(&rows[..6], &[0x80, 0x00, 0x40, 0x00, 0x20, 0x00]);
+        assert_eq!(&rows[16..], &[0x00, 0x80, 0x00, 0x40]);
+        let pages = pack(&pixels, Layout::Pages, false);
+        assert_eq!(pages, vec![
+            0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02,
+        ]);
+        // the last page has only 2 rows
+        assert!(pack(&pixels, Layout::Pages, true).iter().zip(&pages).all(|(a, b)| a ^ b == 0xFF || a ^ b == 0x03));
+    }
+
+    #[test]
+    fn save_unpack_round_trip() {
+        let mut seed = 7u32;
+        let bits: Vec<u8> = (0..13 * 19).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16 & 1) as u8
+        }).collect();
+        let pixels = bmp::Pixels::new(13, 19, bmp::PixelData::Indexed {
+            palette: vec![bmp::RGBQuad::new(0, 0, 0), bmp::RGBQuad::new(255, 255, 255)],
+            indexes: bits.clone(),
+        });
+        for &layout in &[Layout::Rows, Layout::Pages] {
+            for &invert in &[false, true] {
+                let mut data = Vec::new();
+                save_to_writer(&pixels, &mut data, layout, invert).unwrap();
+                assert_eq!(data.len(), if layout == Layout::Rows { 2 * 19 } else { 13 * 3 });
+                let unpacked: Vec<u8> = unpack(&data, 13, 19, layout).iter().map(|b| b ^ invert as u8).collect();
+                assert_eq!(unpacked, bits);
+            }
+        }
+    }
+}
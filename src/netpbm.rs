@@ -0,0 +1,462 @@
+//! # Format desciption
+//! Netpbm family: PBM (bitmap), PGM (graymap), PPM (pixmap) and PAM.
+//! P1, P2 and P3 are the plain (ASCII) versions of P4, P5 and P6,
+//! P7 is PAM with arbitrary channels. Samples above 255 maxval take two
+//! big endian bytes.
+//! http://netpbm.sourceforge.net/doc/pbm.html
+//! http://netpbm.sourceforge.net/doc/pgm.html
+//! http://netpbm.sourceforge.net/doc/ppm.html
+//! http://netpbm.sourceforge.net/doc/pam.html
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use bmp;
+
+/// Plain format lines should not be longer than 70 characters
+const PLAIN_LINE_WIDTH: usize = 70;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NetpbmType {
+    PBM,
+    PGM,
+    PPM,
+    PAM,
+}
+
+impl fmt::Display for NetpbmType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            NetpbmType::PBM => "pbm",
+            NetpbmType::PGM => "pgm",
+            NetpbmType::PPM => "ppm",
+            NetpbmType::PAM => "pam",
+        })
+    }
+}
+
+impl NetpbmType {
+    /// Type of the file with `P1` - `P7` magic
+    pub fn from_magic(magic: &[u8]) -> Option<NetpbmType> {
+        if magic.len() < 2 || magic[0] != b'P' {
+            return None;
+        }
+        match magic[1] {
+            b'1' | b'4' => Some(NetpbmType::PBM),
+            b'2' | b'5' => Some(NetpbmType::PGM),
+            b'3' | b'6' => Some(NetpbmType::PPM),
+            b'7' => Some(NetpbmType::PAM),
+            _ => None,
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Header and plain raster tokenizer
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Skip whitespace and comments, comments run to the end of line
+    fn skip_space(&mut self) {
+        while self.pos < self.data.len() {
+            match self.data[self.pos] {
+                b'#' => {
+                    while self.pos < self.data.len() && self.data[self.pos] != b'\n' {
+                        self.pos += 1;
+                    }
+                },
+                c if (c as char).is_whitespace() => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    fn token(&mut self) -> io::Result<&'a [u8]> {
+        self.skip_space();
+        let start = self.pos;
+        while self.pos < self.data.len() && !(self.data[self.pos] as char).is_whitespace() {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated Netpbm data"));
+        }
+        Ok(&self.data[start..self.pos])
+    }
+
+    fn number(&mut self) -> io::Result<u32> {
+        let token = self.token()?;
+        String::from_utf8_lossy(token).parse().map_err(|_| invalid_data(
+            format!("Invalid number: {:?}", String::from_utf8_lossy(token)),
+        ))
+    }
+
+    /// Plain PBM bits may be written without separators
+    fn bit(&mut self) -> io::Result<u32> {
+        self.skip_space();
+        let bit = match self.data.get(self.pos) {
+            Some(&b'0') => 0,
+            Some(&b'1') => 1,
+            Some(c) => return Err(invalid_data(format!("Invalid PBM bit: {:?}", *c as char))),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated PBM data")),
+        };
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    fn line(&mut self) -> &'a [u8] {
+        let start = self.pos;
+        while self.pos < self.data.len() && self.data[self.pos] != b'\n' {
+            self.pos += 1;
+        }
+        let line = &self.data[start..self.pos];
+        self.pos += 1;
+        line
+    }
+
+    /// Binary raster starts after a single whitespace byte
+    fn raster(&mut self, size: usize) -> io::Result<&'a [u8]> {
+        let start = self.pos + 1;
+        if self.data.len().saturating_sub(start) < size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated Netpbm raster"));
+        }
+        self.pos = start + size;
+        Ok(&self.data[start..start + size])
+    }
+}
+
+/// Scale sample to 8 bits
+fn scale_sample(value: u32, maxval: u32) -> u8 {
+    ((value.min(maxval) * 255 + maxval / 2) / maxval) as u8
+}
+
+/// Product of image dimensions, an error if it doesn't fit into memory size
+fn checked_size(factors: &[usize]) -> io::Result<usize> {
+    factors.iter().try_fold(1usize, |size, &f| size.checked_mul(f))
+        .ok_or_else(|| invalid_data(format!("Netpbm image is too large: {:?}", factors)))
+}
+
+/// Read `count` binary samples, two bytes each if maxval is above 255
+fn binary_samples(p: &mut Parser, count: usize, maxval: u32) -> io::Result<Vec<u32>> {
+    if maxval > 255 {
+        Ok(p.raster(checked_size(&[count, 2])?)?.chunks(2).map(|s| (s[0] as u32) << 8 | s[1] as u32).collect())
+    } else {
+        Ok(p.raster(count)?.iter().map(|&s| s as u32).collect())
+    }
+}
+
+/// Build pixels from samples with `depth` channels: gray, gray with alpha, RGB or RGBA.
+/// Gray images become palette ones.
+fn to_pixels(width: u32, height: u32, depth: u32, maxval: u32, samples: &[u32]) -> io::Result<bmp::Pixels> {
+    let data = match depth {
+        1 => {
+            // one palette entry per gray level while they fit into 8 bits
+            let (levels, indexes) = if maxval <= 255 {
+                (maxval + 1, samples.iter().map(|&s| s.min(maxval) as u8).collect())
+            } else {
+                (256, samples.iter().map(|&s| scale_sample(s, maxval)).collect())
+            };
+            let palette = (0..levels).map(|l| {
+                let gray = if levels == 256 { l as u8 } else { scale_sample(l, maxval) };
+                bmp::RGBQuad::new(gray, gray, gray)
+            }).collect();
+            bmp::PixelData::Indexed {
+                palette: palette,
+                indexes: indexes,
+            }
+        },
+        2 => bmp::PixelData::RGBA(samples.chunks(2).flat_map(|s| {
+            let gray = scale_sample(s[0], maxval);
+            vec![gray, gray, gray, scale_sample(s[1], maxval)]
+        }).collect()),
+        3 => bmp::PixelData::RGB(samples.iter().map(|&s| scale_sample(s, maxval)).collect()),
+        4 => bmp::PixelData::RGBA(samples.iter().map(|&s| scale_sample(s, maxval)).collect()),
+        _ => return Err(invalid_data(format!("Unsupported PAM depth: {}", depth))),
+    };
+    Ok(bmp::Pixels::new(width, height, data))
+}
+
+/// PBM stores black as 1
+fn pbm_pixels(width: u32, height: u32, bits: Vec<u8>) -> bmp::Pixels {
+    bmp::Pixels::new(width, height, bmp::PixelData::Indexed {
+        palette: vec![bmp::RGBQuad::new(255, 255, 255), bmp::RGBQuad::new(0, 0, 0)],
+        indexes: bits,
+    })
+}
+
+fn load_pam(p: &mut Parser) -> io::Result<bmp::Pixels> {
+    let (mut width, mut height, mut depth, mut maxval) = (0, 0, 0, 0);
+    loop {
+        let token = p.token()?;
+        match token {
+            b"ENDHDR" => break,
+            b"WIDTH" => width = p.number()?,
+            b"HEIGHT" => height = p.number()?,
+            b"DEPTH" => depth = p.number()?,
+            b"MAXVAL" => maxval = p.number()?,
+            // depth tells the channels, tuple type is informational
+            b"TUPLTYPE" => { p.line(); },
+            _ => return Err(invalid_data(
+                format!("Unknown PAM header field: {:?}", String::from_utf8_lossy(token)),
+            )),
+        }
+    }
+    if width == 0 || height == 0 || depth == 0 || maxval == 0 || maxval > 65535 {
+        return Err(invalid_data(format!(
+            "Invalid PAM header: {}x{}, depth {}, maxval {}", width, height, depth, maxval,
+        )));
+    }
+    let count = checked_size(&[width as usize, height as usize, depth as usize])?;
+    let samples = binary_samples(p, count, maxval)?;
+    to_pixels(width, height, depth, maxval, &samples)
+}
+
+/// Decode Netpbm image, PBM and PGM become palette images
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    let mut p = Parser { data: &data, pos: 0 };
+    let magic = p.token()?;
+    if NetpbmType::from_magic(magic).is_none() || magic.len() != 2 {
+        return Err(invalid_data(
+            format!("Invalid Netpbm magic: {:?}", String::from_utf8_lossy(magic)),
+        ));
+    }
+    let kind = magic[1];
+    if kind == b'7' {
+        return load_pam(&mut p);
+    }
+
+    let width = p.number()?;
+    let height = p.number()?;
+    let maxval = if kind == b'1' || kind == b'4' { 1 } else { p.number()? };
+    if width == 0 || height == 0 || maxval == 0 || maxval > 65535 {
+        return Err(invalid_data(
+            format!("Invalid Netpbm header: {}x{}, maxval {}", width, height, maxval),
+        ));
+    }
+    let count = checked_size(&[width as usize, height as usize])?;
+    match kind {
+        b'1' => {
+            let bits = (0..count).map(|_| p.bit().map(|b| b as u8)).collect::<io::Result<_>>()?;
+            Ok(pbm_pixels(width, height, bits))
+        },
+        b'4' => {
+            let stride = (width as usize + 7) / 8;
+            let raster = p.raster(checked_size(&[stride, height as usize])?)?;
+            let mut bits = Vec::with_capacity(count);
+            for row in raster.chunks(stride) {
+                for x in 0..width as usize {
+                    bits.push(row[x / 8] >> (7 - x % 8) & 1);
+                }
+            }
+            Ok(pbm_pixels(width, height, bits))
+        },
+        b'2' | b'3' => {
+            let depth = if kind == b'2' { 1 } else { 3 };
+            let samples = (0..checked_size(&[count, depth as usize])?).map(|_| p.number()).collect::<io::Result<Vec<_>>>()?;
+            to_pixels(width, height, depth, maxval, &samples)
+        },
+        _ => {
+            let depth = if kind == b'5' { 1 } else { 3 };
+            let samples = binary_samples(&mut p, checked_size(&[count, depth as usize])?, maxval)?;
+            to_pixels(width, height, depth, maxval, &samples)
+        },
+    }
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// Palette images with gray colors only are written as graymaps by PAM
+fn is_gray(pixels: &bmp::Pixels) -> bool {
+    match pixels.data {
+        bmp::PixelData::Indexed { ref palette, .. } => {
+            palette.iter().all(|c| c.red() == c.green() && c.green() == c.blue())
+        },
+        _ => false,
+    }
+}
+
+/// Write samples as numbers, wrapping lines and starting a new line for every row
+fn write_plain<W: ?Sized + Write>(w: &mut W, samples: &[u32], row_len: usize) -> io::Result<()> {
+    for row in samples.chunks(row_len) {
+        let mut line = String::new();
+        for s in row {
+            let token = s.to_string();
+            if !line.is_empty() && line.len() + 1 + token.len() > PLAIN_LINE_WIDTH {
+                writeln!(w, "{}", line)?;
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&token);
+        }
+        writeln!(w, "{}", line)?;
+    }
+    Ok(())
+}
+
+fn write_binary<W: ?Sized + Write>(w: &mut W, samples: &[u32], maxval: u32) -> io::Result<()> {
+    let data: Vec<u8> = if maxval > 255 {
+        samples.iter().flat_map(|&s| vec![(s >> 8) as u8, s as u8]).collect()
+    } else {
+        samples.iter().map(|&s| s as u8).collect()
+    };
+    w.write_all(&data)
+}
+
+/// Encode pixels into Netpbm of the given type. `plain` selects P1 - P3 ASCII formats,
+/// `maxval` is the maximum sample value for PGM, PPM and PAM, 8 bit samples are scaled to it.
+pub fn save_to_writer<W: ?Sized + Write>(
+    pixels: &bmp::Pixels, w: &mut W,
+    kind: NetpbmType,
+    plain: bool,
+    maxval: u16,
+) -> io::Result<()> {
+    if maxval == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Netpbm maxval can't be zero"));
+    }
+    let (width, height) = (pixels.width, pixels.height);
+    let maxval = maxval as u32;
+    let scale = |v: u8| (v as u32 * maxval + 127) / 255;
+    match kind {
+        NetpbmType::PBM => {
+            // dark pixels are black
+            let bits: Vec<u32> = pixels.to_rgb().chunks(3)
+                .map(|px| if (px[0] as u32 + px[1] as u32 + px[2] as u32) / 3 < 128 { 1 } else { 0 })
+                .collect();
+            if plain {
+                write!(w, "P1\n{} {}\n", width, height)?;
+                return write_plain(w, &bits, width as usize);
+            }
+            write!(w, "P4\n{} {}\n", width, height)?;
+            let stride = (width as usize + 7) / 8;
+            let mut data = vec![0u8; stride * height as usize];
+            for (y, row) in bits.chunks(width as usize).enumerate() {
+                for (x, &bit) in row.iter().enumerate() {
+                    data[y * stride + x / 8] |= (bit as u8) << (7 - x % 8);
+                }
+            }
+            w.write_all(&data)
+        },
+        NetpbmType::PGM => {
+            let samples: Vec<u32> = pixels.to_rgb().chunks(3)
+                .map(|px| scale(((px[0] as u32 + px[1] as u32 + px[2] as u32) / 3) as u8))
+                .collect();
+            write!(w, "{}\n{} {}\n{}\n", if plain { "P2" } else { "P5" }, width, height, maxval)?;
+            if plain {
+                write_plain(w, &samples, width as usize)
+            } else {
+                write_binary(w, &samples, maxval)
+            }
+        },
+        NetpbmType::PPM => {
+            let samples: Vec<u32> = pixels.to_rgb().iter().map(|&s| scale(s)).collect();
+            write!(w, "{}\n{} {}\n{}\n", if plain { "P3" } else { "P6" }, width, height, maxval)?;
+            if plain {
+                write_plain(w, &samples, width as usize * 3)
+            } else {
+                write_binary(w, &samples, maxval)
+            }
+        },
+        NetpbmType::PAM => {
+            if plain {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "PAM has no plain format"));
+            }
+            let alpha = pixels.has_alpha();
+            let gray = is_gray(pixels);
+            let (depth, tupltype, samples): (u32, &str, Vec<u8>) = match (gray, alpha) {
+                (true, _) => (1, "GRAYSCALE", pixels.to_rgb().chunks(3).map(|px| px[0]).collect()),
+                (false, true) => (4, "RGB_ALPHA", pixels.to_rgba()),
+                (false, false) => (3, "RGB", pixels.to_rgb()),
+            };
+            write!(
+                w, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+                width, height, depth, maxval, tupltype,
+            )?;
+            let samples: Vec<u32> = samples.iter().map(|&s| scale(s)).collect();
+            write_binary(w, &samples, maxval)
+        },
+    }
+}
+
+pub fn save_to_file<P: AsRef<Path>>(
+    pixels: &bmp::Pixels, p: P,
+    kind: NetpbmType,
+    plain: bool,
+    maxval: u16,
+) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f, kind, plain, maxval)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray_palette() -> Vec<bmp::RGBQuad> {
+        (0..256).map(|l| bmp::RGBQuad::new(l as u8, l as u8, l as u8)).collect()
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let rgb = bmp::Pixels::new(5, 3, bmp::PixelData::RGB((0..45).map(|i| (i * 37 % 256) as u8).collect()));
+        let rgba = bmp::Pixels::new(3, 2, bmp::PixelData::RGBA((0..24).map(|i| (i * 11) as u8).collect()));
+        let gray = bmp::Pixels::new(4, 2, bmp::PixelData::Indexed {
+            palette: gray_palette(),
+            indexes: vec![0, 17, 128, 255, 1, 2, 3, 4],
+        });
+        let bw = bmp::Pixels::new(11, 2, bmp::PixelData::Indexed {
+            palette: vec![bmp::RGBQuad::new(0, 0, 0), bmp::RGBQuad::new(255, 255, 255)],
+            indexes: (0..22).map(|i| (i % 3 == 0) as u8).collect(),
+        });
+        let cases = vec![
+            (&bw, NetpbmType::PBM),
+            (&gray, NetpbmType::PGM),
+            (&rgb, NetpbmType::PPM),
+            (&rgba, NetpbmType::PAM),
+            (&gray, NetpbmType::PAM),
+        ];
+        for (pixels, kind) in cases {
+            for &plain in [false, true].iter() {
+                if plain && kind == NetpbmType::PAM {
+                    continue;
+                }
+                for &maxval in [255u16, 65535].iter() {
+                    let mut data = Vec::new();
+                    save_to_writer(pixels, &mut data, kind, plain, maxval).unwrap();
+                    let loaded = load_from_reader(&mut &data[..]).unwrap();
+                    assert_eq!((loaded.width, loaded.height), (pixels.width, pixels.height));
+                    assert_eq!(loaded.to_rgba(), pixels.to_rgba(), "{} plain {} maxval {}", kind, plain, maxval);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn load_scaled_samples() {
+        let pixels = load_from_reader(&mut &b"P2 3 1 4\n0 2 4\n"[..]).unwrap();
+        assert_eq!(pixels.to_rgb(), vec![0, 0, 0, 128, 128, 128, 255, 255, 255]);
+    }
+
+    #[test]
+    fn huge_header_is_rejected() {
+        for header in [&b"P5 70000 70000 255\n\0"[..], &b"P6 4294967295 4294967295 65535\n\0"[..]].iter() {
+            assert!(load_from_reader(&mut &header[..]).is_err());
+        }
+        let pam = b"P7\nWIDTH 4294967295\nHEIGHT 4294967295\nDEPTH 4294967295\nMAXVAL 255\nENDHDR\n";
+        assert!(load_from_reader(&mut &pam[..]).is_err());
+    }
+}
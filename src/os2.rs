@@ -0,0 +1,344 @@
+//! # Format desciption
+//! OS/2 bitmap files share the BMP file header, the file type tells the kind:
+//!
+//! * BA - bitmap array, a linked list of images for several display resolutions
+//! * IC, PT - monochrome icon and pointer
+//! * CI, CP - color icon and pointer
+//!
+//! Icons and pointers hold a monochrome bitmap twice as high as the image,
+//! the lower half is the AND mask and the upper half is the XOR mask.
+//! Color ones are followed by the second file header with the color bitmap.
+//! http://www.fileformat.info/format/os2bmp/egff.htm
+//! http://fileformats.archiveteam.org/wiki/OS/2_Icon
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use bmp;
+
+/// Bitmap array header: type, size, next header offset and display size
+pub const BA_HEADER_SIZE: u32 = 14;
+/// Guard against cycles in the list of array entries
+pub const BA_MAX_ENTRIES: usize = 1024;
+
+#[derive(Debug)]
+pub enum OS2Image {
+    Bitmap(bmp::BMPImage),
+    Icon(OS2Icon),
+}
+
+impl OS2Image {
+    /// Load image from the current position, dispatching by its file type
+    pub fn load_from_reader<R: ?Sized + BufRead + Seek>(r: &mut R) -> io::Result<OS2Image> {
+        let start = r.seek(SeekFrom::Current(0))?;
+        let mut file_type = [0u8; 2];
+        r.read_exact(&mut file_type)?;
+        r.seek(SeekFrom::Start(start))?;
+        match &file_type {
+            b"BM" => Ok(OS2Image::Bitmap(bmp::BMPImage::load_from_reader(r)?)),
+            b"CI" | b"CP" | b"IC" | b"PT" => Ok(OS2Image::Icon(OS2Icon::load_from_reader(r)?)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected OS/2 image type: {:?}", String::from_utf8_lossy(&file_type)),
+            )),
+        }
+    }
+
+    pub fn header(&self) -> &bmp::BMPFileHeader {
+        match *self {
+            OS2Image::Bitmap(ref b) => &b.header,
+            OS2Image::Icon(ref i) => &i.mask.header,
+        }
+    }
+
+    pub fn get_width(&self) -> i32 {
+        match *self {
+            OS2Image::Bitmap(ref b) => b.info.bmi_header.get_width(),
+            OS2Image::Icon(ref i) => i.get_width(),
+        }
+    }
+
+    pub fn get_height(&self) -> i32 {
+        match *self {
+            OS2Image::Bitmap(ref b) => b.info.bmi_header.get_height().abs(),
+            OS2Image::Icon(ref i) => i.get_height(),
+        }
+    }
+
+    pub fn bit_count(&self) -> i16 {
+        match *self {
+            OS2Image::Bitmap(ref b) => b.info.bmi_header.get_bit_count(),
+            OS2Image::Icon(ref i) => i.bit_count(),
+        }
+    }
+
+    pub fn to_pixels(&mut self) -> io::Result<bmp::Pixels> {
+        match *self {
+            OS2Image::Bitmap(ref mut b) => b.to_pixels(),
+            OS2Image::Icon(ref mut i) => i.to_pixels(),
+        }
+    }
+}
+
+impl fmt::Display for OS2Image {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "{} {}x{} px, {} bits per pixel",
+            self.header().file_type(), self.get_width(), self.get_height(), self.bit_count(),
+        )?;
+        if let Some((x, y)) = self.header().hotspot() {
+            write!(f, ", hotspot {}x{}", x, y)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct OS2Icon {
+    /// Monochrome AND and XOR masks
+    pub mask: bmp::BMPImage,
+    /// Color bitmap of CI and CP files
+    pub color: Option<bmp::BMPImage>,
+}
+
+impl OS2Icon {
+    pub fn load_from_reader<R: ?Sized + BufRead + Seek>(r: &mut R) -> io::Result<OS2Icon> {
+        let mut mask = bmp::BMPImage::meta_from_reader(r)?;
+        let is_color = match mask.header.file_type().as_ref() {
+            "CI" | "CP" => true,
+            _ => false,
+        };
+        // color bitmap headers follow the mask headers
+        let mut color = if is_color {
+            Some(bmp::BMPImage::meta_from_reader(r)?)
+        } else {
+            None
+        };
+        if mask.info.bmi_header.get_bit_count() != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Icon mask has {} bits per pixel", mask.info.bmi_header.get_bit_count()),
+            ));
+        }
+        mask.load_bitmap_from_reader(r)?;
+        if let Some(ref mut c) = color {
+            c.load_bitmap_from_reader(r)?;
+        }
+        Ok(OS2Icon {
+            mask: mask,
+            color: color,
+        })
+    }
+
+    pub fn get_width(&self) -> i32 {
+        self.mask.info.bmi_header.get_width()
+    }
+
+    /// Image height is a half of the mask height
+    pub fn get_height(&self) -> i32 {
+        self.mask.info.bmi_header.get_height().abs() / 2
+    }
+
+    pub fn bit_count(&self) -> i16 {
+        match self.color {
+            Some(ref c) => c.info.bmi_header.get_bit_count(),
+            None => 1,
+        }
+    }
+
+    /// Apply masks to the image. Transparent and screen inverting pixels
+    /// are both transparent, the screen is unknown.
+    pub fn to_pixels(&mut self) -> io::Result<bmp::Pixels> {
+        let width = self.get_width() as usize;
+        let height = self.get_height() as usize;
+        let mask = self.mask.to_pixels()?;
+        let mask_bits = match mask.data {
+            bmp::PixelData::Indexed { ref indexes, .. } => indexes.clone(),
+            _ => unreachable!("1 bit per pixel bitmap is always indexed"),
+        };
+        let color = match self.color {
+            Some(ref mut c) => {
+                let pixels = c.to_pixels()?;
+                if pixels.width as usize != width || pixels.height as usize != height {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Color bitmap {}x{} doesn't match the mask {}x{}",
+                            pixels.width, pixels.height, width, height,
+                        ),
+                    ));
+                }
+                Some(pixels.to_rgb())
+            },
+            None => None,
+        };
+        // top-down rows: XOR mask goes first, AND mask is the lower half
+        let (xor_mask, and_mask) = mask_bits.split_at(width * height);
+        let mut rgba = Vec::with_capacity(width * height * 4);
+        for idx in 0..width * height {
+            if and_mask[idx] != 0 {
+                rgba.extend_from_slice(&[0, 0, 0, 0]);
+                continue;
+            }
+            match color {
+                Some(ref rgb) => rgba.extend_from_slice(&rgb[idx * 3..idx * 3 + 3]),
+                None => {
+                    let value = if xor_mask[idx] != 0 { 255 } else { 0 };
+                    rgba.extend_from_slice(&[value, value, value]);
+                },
+            }
+            rgba.push(255);
+        }
+        Ok(bmp::Pixels::new(width as u32, height as u32, bmp::PixelData::RGBA(rgba)))
+    }
+}
+
+#[derive(Debug)]
+pub struct OS2Entry {
+    /// Offset of the bitmap array header, or of the image itself outside of arrays
+    pub offset: u64,
+    /// Display resolution the image is designed for, zero is any
+    pub display_width: u16,
+    pub display_height: u16,
+    pub image: OS2Image,
+}
+
+/// OS/2 bitmap file, a single image is a one entry array
+#[derive(Debug)]
+pub struct OS2File {
+    pub entries: Vec<OS2Entry>,
+}
+
+impl OS2File {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<OS2File> {
+        let mut f = BufReader::new(File::open(p)?);
+        OS2File::load_from_reader(&mut f)
+    }
+
+    pub fn load_from_reader<R: ?Sized + BufRead + Seek>(r: &mut R) -> io::Result<OS2File> {
+        let mut file_type = [0u8; 2];
+        r.read_exact(&mut file_type)?;
+        r.seek(SeekFrom::Start(0))?;
+        if &file_type != b"BA" {
+            return Ok(OS2File {
+                entries: vec![OS2Entry {
+                    offset: 0,
+                    display_width: 0,
+                    display_height: 0,
+                    image: OS2Image::load_from_reader(r)?,
+                }],
+            });
+        }
+
+        let mut entries = Vec::new();
+        let mut offset = 0u64;
+        loop {
+            if entries.len() >= BA_MAX_ENTRIES {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Bitmap array has more than {} entries", BA_MAX_ENTRIES),
+                ));
+            }
+            r.seek(SeekFrom::Start(offset))?;
+            r.read_exact(&mut file_type)?;
+            if &file_type != b"BA" {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid bitmap array header at {}", offset),
+                ));
+            }
+            let _size = r.read_u32::<LittleEndian>()?;
+            let next = r.read_u32::<LittleEndian>()? as u64;
+            let display_width = r.read_u16::<LittleEndian>()?;
+            let display_height = r.read_u16::<LittleEndian>()?;
+            entries.push(OS2Entry {
+                offset: offset,
+                display_width: display_width,
+                display_height: display_height,
+                image: OS2Image::load_from_reader(r)?,
+            });
+            // entries are linked forward, zero ends the list
+            if next <= offset {
+                break;
+            }
+            offset = next;
+        }
+        Ok(OS2File { entries: entries })
+    }
+
+    pub fn entry(&mut self, idx: usize) -> io::Result<&mut OS2Entry> {
+        let len = self.entries.len();
+        self.entries.get_mut(idx).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("No image {} in OS/2 file, it has {} images", idx + 1, len),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// File header of `kind` with the hotspot and the bitmap offset
+    fn file_header(kind: &[u8; 2], hotspot: (u8, u8), offset: u8) -> Vec<u8> {
+        vec![kind[0], kind[1], 0, 0, 0, 0, hotspot.0, 0, hotspot.1, 0, offset, 0, 0, 0]
+    }
+
+    /// BITMAPCOREHEADER and RGBTRIPLE colors
+    fn core_header(width: u8, height: u8, bit_count: u8, colors: &[u8]) -> Vec<u8> {
+        let mut data = vec![12, 0, 0, 0, width, 0, height, 0, 1, 0, bit_count, 0];
+        data.extend_from_slice(colors);
+        data
+    }
+
+    /// 2x2 icon masks, AND mask [0, 1, 0, 0] and XOR mask [1, 0, 0, 1]
+    /// in bottom-up rows of the double height bitmap
+    const MASK_BITS: [u8; 16] = [0, 0, 0, 0, 0x40, 0, 0, 0, 0x40, 0, 0, 0, 0x80, 0, 0, 0];
+    const BLACK_WHITE: [u8; 6] = [0, 0, 0, 255, 255, 255];
+
+    #[test]
+    fn bitmap_array() {
+        // BM entry at 0, the icon entry at 64
+        let mut data = vec![b'B', b'A', 14, 0, 0, 0, 64, 0, 0, 0, 0x80, 2, 0xE0, 1];
+        data.extend(file_header(b"BM", (0, 0), 14 + 14 + 12));
+        data.extend(core_header(2, 1, 24, &[]));
+        data.extend_from_slice(&[3, 2, 1, 6, 5, 4, 0, 0]);
+        data.resize(64, 0);
+        data.extend_from_slice(&[b'B', b'A', 14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend(file_header(b"IC", (1, 2), 64 + 14 + 14 + 18));
+        data.extend(core_header(2, 4, 1, &BLACK_WHITE));
+        data.extend_from_slice(&MASK_BITS);
+
+        let mut os2 = OS2File::load_from_reader(&mut Cursor::new(&data[..])).unwrap();
+        assert_eq!(os2.entries.len(), 2);
+        assert_eq!((os2.entries[0].offset, os2.entries[0].display_width, os2.entries[0].display_height), (0, 640, 480));
+        assert_eq!(os2.entries[1].offset, 64);
+        assert_eq!(os2.entry(0).unwrap().image.to_pixels().unwrap().to_rgb(), vec![1, 2, 3, 4, 5, 6]);
+        let icon = &mut os2.entry(1).unwrap().image;
+        assert_eq!((icon.get_width(), icon.get_height(), icon.bit_count()), (2, 2, 1));
+        assert_eq!(icon.header().hotspot(), Some((1, 2)));
+        assert_eq!(icon.to_pixels().unwrap().to_rgba(), vec![255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 255]);
+        assert!(os2.entry(2).is_err());
+    }
+
+    #[test]
+    fn color_icon() {
+        let mask_offset = 14 + 12 + 6 + 14 + 12;
+        let mut data = file_header(b"CI", (0, 0), mask_offset);
+        data.extend(core_header(2, 4, 1, &BLACK_WHITE));
+        data.extend(file_header(b"CI", (0, 0), mask_offset + 16));
+        data.extend(core_header(2, 2, 24, &[]));
+        data.extend_from_slice(&MASK_BITS);
+        data.extend_from_slice(&[90, 80, 70, 120, 110, 100, 0, 0, 30, 20, 10, 60, 50, 40, 0, 0]);
+
+        let mut os2 = OS2File::load_from_reader(&mut Cursor::new(&data[..])).unwrap();
+        assert_eq!(os2.entries.len(), 1);
+        let icon = &mut os2.entry(0).unwrap().image;
+        assert_eq!(icon.bit_count(), 24);
+        assert_eq!(icon.to_pixels().unwrap().to_rgba(), vec![10, 20, 30, 255, 0, 0, 0, 0, 70, 80, 90, 255, 100, 110, 120, 255]);
+    }
+}
@@ -2,21 +2,19 @@
 //! https://en.wikipedia.org/wiki/PCX#PCX_file_format
 //! https://www.fileformat.info/format/pcx/egff.htm
 
-extern crate gdk_pixbuf;
-
-use std::collections::{HashMap, HashSet};
-use std::io::{self, BufRead, BufReader, SeekFrom, Seek};
-use byteorder::{LittleEndian, ReadBytesExt};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, SeekFrom, Seek, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::fs::File;
-use self::gdk_pixbuf::Pixbuf;
+use std::path::Path;
 
 use bmp;
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq)]
 pub struct RGBTriple {
-    red: u8,
-    green: u8,
-    blue: u8,
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
 }
 
 impl ::std::cmp::Eq for RGBTriple {}
@@ -36,78 +34,402 @@ impl RGBTriple {
             blue: r.read_u8()?,
         })
     }
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u8(self.red)?;
+        w.write_u8(self.green)?;
+        w.write_u8(self.blue)?;
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
+/// Default 16 color EGA palette, used by files without palette information
+pub const EGA_PALETTE: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00), (0x00, 0x00, 0xAA), (0x00, 0xAA, 0x00), (0x00, 0xAA, 0xAA),
+    (0xAA, 0x00, 0x00), (0xAA, 0x00, 0xAA), (0xAA, 0x55, 0x00), (0xAA, 0xAA, 0xAA),
+    (0x55, 0x55, 0x55), (0x55, 0x55, 0xFF), (0x55, 0xFF, 0x55), (0x55, 0xFF, 0xFF),
+    (0xFF, 0x55, 0x55), (0xFF, 0x55, 0xFF), (0xFF, 0xFF, 0x55), (0xFF, 0xFF, 0xFF),
+];
+
+/// Default CGA palette 1 (black, cyan, magenta, white)
+const CGA_PALETTE: [usize; 4] = [0, 3, 5, 7];
+
+pub const PCX_MANUFACTURER: u8 = 0x0A;
+pub const PCX_HEADER_SIZE: u64 = 128;
+/// Marker byte in front of the trailing 256 color VGA palette
+pub const VGA_PALETTE_MARKER: u8 = 12;
+
 pub struct PCXHeader {
+    /// Constant flag, 10 = ZSoft .pcx
+    manufacturer: u8,
     version: u8,
+    /// 1 = PCX run length encoding
+    encoding: u8,
+    /// Bits per pixel in each color plane
     bitsperpixel: u8,
+    /// Image window dimensions, inclusive
+    xstart: i16,
+    ystart: i16,
+    xend: i16,
+    yend: i16,
+    horizdpi: u16,
+    vertdpi: u16,
     colorplanes: u8,
     bytesperline: u16,
+    /// 1 = color/bw, 2 = grayscale
+    palettetype: u16,
     height: i16,
     width: i16,
+    /// Colors used by the image, taken from header or VGA palette
     palette: Vec<RGBTriple>,
+    /// The 256 color VGA palette follows the bitmap
+    vga_palette: bool,
+}
+
+impl fmt::Debug for PCXHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // palette is printed separately
+        f.debug_struct("PCXHeader")
+            .field("manufacturer", &self.manufacturer)
+            .field("version", &self.version)
+            .field("encoding", &self.encoding)
+            .field("bitsperpixel", &self.bitsperpixel)
+            .field("xstart", &self.xstart)
+            .field("ystart", &self.ystart)
+            .field("xend", &self.xend)
+            .field("yend", &self.yend)
+            .field("horizdpi", &self.horizdpi)
+            .field("vertdpi", &self.vertdpi)
+            .field("colorplanes", &self.colorplanes)
+            .field("bytesperline", &self.bytesperline)
+            .field("palettetype", &self.palettetype)
+            .field("vga_palette", &self.vga_palette)
+            .finish()
+    }
+}
+
+impl fmt::Display for PCXHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Manufacturer: {}\n\
+             Version: {} ({})\n\
+             Encoding: {}\n\
+             Width: {} px\nHeight: {} px\n\
+             Window: ({}, {}) - ({}, {})\n\
+             Bits Per Pixel: {} x {} planes\n\
+             Bytes Per Line: {}\n\
+             DPI: {}x{}\n\
+             Palette Type: {}\n\
+             VGA Palette: {}\n\
+             Max Colors: {}",
+            match self.manufacturer {
+                PCX_MANUFACTURER => "ZSoft".to_owned(),
+                m => format!("Unknown ({})", m),
+            },
+            self.version,
+            match self.version {
+                0 => "PC Paintbrush 2.5",
+                2 => "PC Paintbrush 2.8 with palette",
+                3 => "PC Paintbrush 2.8 without palette",
+                4 => "PC Paintbrush for Windows",
+                5 => "PC Paintbrush 3.0 and later",
+                _ => "unknown",
+            },
+            match self.encoding {
+                0 => "Uncompressed",
+                1 => "Run-Length Encoded (RLE)",
+                _ => "Unknown",
+            },
+            self.width,
+            self.height,
+            self.xstart, self.ystart, self.xend, self.yend,
+            self.bitsperpixel,
+            self.colorplanes,
+            self.bytesperline,
+            self.horizdpi, self.vertdpi,
+            match self.palettetype {
+                1 => "Color/BW",
+                2 => "Grayscale",
+                _ => "Unspecified",
+            },
+            if self.vga_palette { "yes" } else { "no" },
+            2u64.pow(self.bit_count() as u32),
+        )
+    }
 }
 
 impl PCXHeader {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<PCXHeader> {
+        let mut f = BufReader::new(File::open(p)?);
+        PCXHeader::load_from_reader(&mut f)
+    }
     pub fn load_from_reader<R: ?Sized + BufRead + Seek>(r: &mut R) -> io::Result<PCXHeader> {
-        r.seek(SeekFrom::Start(1))?; // manufacturer
+        r.seek(SeekFrom::Start(0))?;
+        let manufacturer = r.read_u8()?;
+        if manufacturer != PCX_MANUFACTURER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid PCX manufacturer: {}", manufacturer),
+            ));
+        }
         let version = r.read_u8()?;
-        r.seek(SeekFrom::Current(1))?; // encoding
+        let encoding = r.read_u8()?;
         let bitsperpixel = r.read_u8()?;
         let xstart = r.read_i16::<LittleEndian>()?;
         let ystart = r.read_i16::<LittleEndian>()?;
         let xend = r.read_i16::<LittleEndian>()?;
         let yend = r.read_i16::<LittleEndian>()?;
-        let height = yend - ystart + 1;
-        let width = xend - xstart + 1;
-        // skip u16(horizdpi) and u16(vertdpi) + 16 (u24)colors palette + 1(u8) reserved
-        r.seek(SeekFrom::Current(53))?;
+        let height = yend as i32 - ystart as i32 + 1;
+        let width = xend as i32 - xstart as i32 + 1;
+        if width > ::std::i16::MAX as i32 || height > ::std::i16::MAX as i32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid PCX window: ({}, {}) - ({}, {})", xstart, ystart, xend, yend),
+            ));
+        }
+        let horizdpi = r.read_u16::<LittleEndian>()?;
+        let vertdpi = r.read_u16::<LittleEndian>()?;
+        let mut header_palette = Vec::with_capacity(16);
+        for _ in 0..16 {
+            header_palette.push(RGBTriple::load_from_reader(r)?);
+        }
+        r.seek(SeekFrom::Current(1))?; // reserved
         let colorplanes = r.read_u8()?;
         let bytesperline = r.read_u16::<LittleEndian>()?;
+        let palettetype = r.read_u16::<LittleEndian>()?;
 
-        let mut bpp = 8;
-        if version == 5 && bitsperpixel == 8 && colorplanes == 3 {
-            bpp = 24;
+        // https://en.wikipedia.org/wiki/PCX#PCX_file_format
+        let file_size = r.seek(SeekFrom::End(0))?;
+        let mut vga_palette = false;
+        if file_size >= PCX_HEADER_SIZE + 769 {
+            r.seek(SeekFrom::End(-769))?; // try find 256 color palette
+            vga_palette = r.read_u8()? == VGA_PALETTE_MARKER;
         }
 
         let mut palette = Vec::new();
-        if bpp == 8 {
-            if colorplanes != 1 || bitsperpixel != 8 {
+        match (bitsperpixel, colorplanes) {
+            (8, 1) => {
+                if !vga_palette {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("PCX 8bpp without 256 color palette no supported!"),
+                    ));
+                }
+                for _ in 0..256 {
+                    palette.push(RGBTriple::load_from_reader(r)?);
+                }
+            },
+            (8, 3) | (8, 4) => {}, // true color (with alpha plane)
+            (1, 1) => {
+                // monochrome: most writers leave the header palette zeroed
+                if version == 3 || header_palette[0] == header_palette[1] {
+                    palette.push(RGBTriple::new(0, 0, 0));
+                    palette.push(RGBTriple::new(255, 255, 255));
+                } else {
+                    palette.extend_from_slice(&header_palette[..2]);
+                }
+            },
+            (2, 1) => {
+                // CGA 4 colors
+                if version == 3 {
+                    for &idx in CGA_PALETTE.iter() {
+                        let (red, green, blue) = EGA_PALETTE[idx];
+                        palette.push(RGBTriple::new(red, green, blue));
+                    }
+                } else {
+                    palette.extend_from_slice(&header_palette[..4]);
+                }
+            },
+            (1, 2) | (1, 3) | (1, 4) | (4, 1) => {
+                // EGA 16 colors, version 0 and 3 carry no palette information
+                let colors = 1 << (bitsperpixel * colorplanes);
+                if version == 0 || version == 3 {
+                    for &(red, green, blue) in EGA_PALETTE[..colors].iter() {
+                        palette.push(RGBTriple::new(red, green, blue));
+                    }
+                } else {
+                    palette.extend_from_slice(&header_palette[..colors]);
+                }
+            },
+            _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::Other,
                     format!("PCX bitsperpixel={}/colorplanes={} not supported",
                             bitsperpixel, colorplanes),
                 ));
-            }
-            // https://en.wikipedia.org/wiki/PCX#PCX_file_format
-            r.seek(SeekFrom::End(-769))?; // try find 256 color palette
-            if r.read_u8()? != 12u8 {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("PCX 8bpp without 256 color palette no supported!"),
-                ));
-            }
-            for _ in 0..256 {
-                palette.push(RGBTriple::load_from_reader(r)?);
-            }
+            },
         }
 
         Ok(PCXHeader {
+            manufacturer: manufacturer,
             version: version,
-            bitsperpixel: bpp,
+            encoding: encoding,
+            bitsperpixel: bitsperpixel,
+            xstart: xstart,
+            ystart: ystart,
+            xend: xend,
+            yend: yend,
+            horizdpi: horizdpi,
+            vertdpi: vertdpi,
             colorplanes: colorplanes,
             bytesperline: bytesperline,
+            palettetype: palettetype,
+            height: height as i16,
+            width: width as i16,
+            palette: palette,
+            vga_palette: vga_palette,
+        })
+    }
+
+    /// Header of version 5 RLE encoded image, `bytesperline` is padded to an even number
+    pub fn new(width: i16, height: i16, bitsperpixel: u8, colorplanes: u8, palette: Vec<RGBTriple>) -> io::Result<PCXHeader> {
+        let bytesperline = (width as u32 * bitsperpixel as u32 + 15) / 16 * 2;
+        if width <= 0 || height <= 0 || bytesperline > ::std::u16::MAX as u32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Image {}x{} of {} bits per pixel doesn't fit PCX", width, height, bitsperpixel),
+            ));
+        }
+        Ok(PCXHeader {
+            manufacturer: PCX_MANUFACTURER,
+            version: 5,
+            encoding: 1,
+            bitsperpixel: bitsperpixel,
+            xstart: 0,
+            ystart: 0,
+            xend: width - 1,
+            yend: height - 1,
+            horizdpi: 72,
+            vertdpi: 72,
+            colorplanes: colorplanes,
+            bytesperline: bytesperline as u16,
+            palettetype: 1,
             height: height,
             width: width,
+            vga_palette: bitsperpixel == 8 && colorplanes == 1,
             palette: palette,
         })
     }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u8(self.manufacturer)?;
+        w.write_u8(self.version)?;
+        w.write_u8(self.encoding)?;
+        w.write_u8(self.bitsperpixel)?;
+        w.write_i16::<LittleEndian>(self.xstart)?;
+        w.write_i16::<LittleEndian>(self.ystart)?;
+        w.write_i16::<LittleEndian>(self.xend)?;
+        w.write_i16::<LittleEndian>(self.yend)?;
+        w.write_u16::<LittleEndian>(self.horizdpi)?;
+        w.write_u16::<LittleEndian>(self.vertdpi)?;
+        // 16 color palette lives in the header, bigger one is appended to the bitmap
+        for idx in 0..16 {
+            match self.palette.get(idx) {
+                Some(c) if !self.vga_palette => c.save_to_writer(w)?,
+                _ => RGBTriple::new(0, 0, 0).save_to_writer(w)?,
+            }
+        }
+        w.write_u8(0)?; // reserved
+        w.write_u8(self.colorplanes)?;
+        w.write_u16::<LittleEndian>(self.bytesperline)?;
+        w.write_u16::<LittleEndian>(self.palettetype)?;
+        w.write_all(&[0u8; 58])?; // screen size and filler
+        Ok(())
+    }
+
+    pub fn get_palette(&self) -> &Vec<RGBTriple> {
+        &self.palette
+    }
+    pub fn get_width(&self) -> i16 {
+        self.width
+    }
+    pub fn get_height(&self) -> i16 {
+        self.height
+    }
+
+    /// Total bits per pixel over all color planes
+    pub fn bit_count(&self) -> u8 {
+        self.bitsperpixel * self.colorplanes
+    }
+
+    /// Palette images store color indexes, the others are true color
+    pub fn is_indexed(&self) -> bool {
+        !self.palette.is_empty()
+    }
+}
+
+/// Convert a planar scanline into one value per pixel.
+/// Every plane takes `bytesperline` bytes and contributes `bitsperpixel`
+/// bits of the pixel value, the first plane holds the least significant bits.
+pub fn planar_to_chunky(
+    scanline: &[u8],
+    bitsperpixel: u8,
+    planes: u8,
+    bytesperline: usize,
+    width: usize,
+) -> Vec<u8> {
+    let bpp = bitsperpixel as usize;
+    let pixels_per_byte = 8 / bpp;
+    let mask = ((1u16 << bpp) - 1) as u8;
+    let mut pixels = vec![0u8; width];
+    for plane in 0..(planes as usize) {
+        let line = &scanline[plane * bytesperline..(plane + 1) * bytesperline];
+        for (x, pixel) in pixels.iter_mut().enumerate() {
+            let shift = (pixels_per_byte - 1 - x % pixels_per_byte) * bpp;
+            let value = (line[x / pixels_per_byte] >> shift) & mask;
+            *pixel |= value << (plane * bpp);
+        }
+    }
+    pixels
 }
 
-pub fn decode_line<R: ?Sized + BufRead + Seek>(r: &mut R, row_stride: u16) -> io::Result<Vec<u8>> {
-    let mut scanline = Vec::with_capacity(row_stride as usize);
-    let mut idx = row_stride as i32;
+/// Pack one value per pixel into color planes, reverse of `planar_to_chunky`
+pub fn chunky_to_planar(
+    pixels: &[u8],
+    bitsperpixel: u8,
+    planes: u8,
+    bytesperline: usize,
+) -> Vec<u8> {
+    let bpp = bitsperpixel as usize;
+    let pixels_per_byte = 8 / bpp;
+    let mask = ((1u16 << bpp) - 1) as u8;
+    let mut scanline = vec![0u8; bytesperline * planes as usize];
+    for plane in 0..(planes as usize) {
+        let line = &mut scanline[plane * bytesperline..(plane + 1) * bytesperline];
+        for (x, pixel) in pixels.iter().enumerate() {
+            let shift = (pixels_per_byte - 1 - x % pixels_per_byte) * bpp;
+            let value = (pixel >> (plane * bpp)) & mask;
+            line[x / pixels_per_byte] |= value << shift;
+        }
+    }
+    scanline
+}
+
+/// RLE encode scanline with the scheme `decode_line` reads:
+/// a byte with two high bits set is a counter for the next byte.
+pub fn encode_line(scanline: &[u8]) -> Vec<u8> {
+    let counter_marker = 0b1100_0000u8;
+    let max_run = 0b0011_1111usize;
+    let mut encoded = Vec::with_capacity(scanline.len());
+    let mut idx = 0;
+    while idx < scanline.len() {
+        let value = scanline[idx];
+        let mut run = 1;
+        while idx + run < scanline.len() && run < max_run && scanline[idx + run] == value {
+            run += 1;
+        }
+        if run > 1 || (value & counter_marker) == counter_marker {
+            encoded.push(counter_marker | run as u8);
+        }
+        encoded.push(value);
+        idx += run;
+    }
+    encoded
+}
+
+pub fn decode_line<R: ?Sized + BufRead + Seek>(r: &mut R, row_stride: usize) -> io::Result<Vec<u8>> {
+    let mut scanline = Vec::with_capacity(row_stride);
+    let mut idx = row_stride as i64;
     let counter_marker = 0b1100_0000u8;
     while idx > 0 {
         let byte = r.read_u8()?;
@@ -117,242 +439,212 @@ pub fn decode_line<R: ?Sized + BufRead + Seek>(r: &mut R, row_stride: u16) -> io
             (1, byte)
         };
         let current_len = scanline.len();
-        idx -= run_count as i32;
+        idx -= run_count as i64;
         scanline.resize(current_len + run_count as usize, run_value);
     }
     Ok(scanline)
 }
 
-pub fn pixbuf_from_file(name: &str) -> io::Result<Pixbuf> {
-    let mut f = BufReader::new(File::open(name)?);
-    let header = PCXHeader::load_from_reader(&mut f)?;
-    f.seek(SeekFrom::Start(128))?; // skip header
-
-    let pcx_row_stride = header.colorplanes as u16 * header.bytesperline;
-    let last_row_len = header.width * 3;
-    let cap = (header.height - 1) as usize * pcx_row_stride as usize + last_row_len as usize;
-    let mut data = Vec::with_capacity(cap);
-    for _ in 0..header.height {
-        let scanline = decode_line(&mut f, pcx_row_stride)?;
-        for pixel_idx in 0..header.width {
-            if header.bitsperpixel == 24 {
-                data.push(scanline[pixel_idx as usize]);                      // red
-                data.push(scanline[(header.width + pixel_idx) as usize]);   // green
-                data.push(scanline[(header.width*2 + pixel_idx) as usize]); // blue
+/// Write pixels as RLE encoded PCX: true color goes into three 8 bit planes,
+/// palette images become monochrome, 16 color EGA or 256 color VGA.
+pub fn save_to_writer<W: ?Sized + Write>(pixels: &bmp::Pixels, w: &mut W) -> io::Result<()> {
+    if pixels.width > ::std::i16::MAX as u32 || pixels.height > ::std::i16::MAX as u32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Image {}x{} is too large for PCX", pixels.width, pixels.height),
+        ));
+    }
+    let width = pixels.width as usize;
+    match pixels.data {
+        bmp::PixelData::Indexed { ref palette, ref indexes } => {
+            let (bitsperpixel, colorplanes) = if palette.len() <= 2 {
+                (1, 1)
+            } else if palette.len() <= 16 {
+                (1, 4)
             } else {
-                let palette_idx = scanline[pixel_idx as usize] as usize;
-                let rgb = header.palette[palette_idx];
-                data.push(rgb.red);
-                data.push(rgb.green);
-                data.push(rgb.blue);
+                (8, 1)
+            };
+            let mut pcx_palette: Vec<RGBTriple> = palette.iter()
+                .map(|c| RGBTriple::new(c.red(), c.green(), c.blue()))
+                .collect();
+            pcx_palette.resize(1 << (bitsperpixel * colorplanes), RGBTriple::new(0, 0, 0));
+            let header = PCXHeader::new(
+                pixels.width as i16, pixels.height as i16, bitsperpixel, colorplanes, pcx_palette,
+            )?;
+            header.save_to_writer(w)?;
+            for row in indexes.chunks(width) {
+                let scanline = chunky_to_planar(
+                    row, bitsperpixel, colorplanes, header.bytesperline as usize,
+                );
+                w.write_all(&encode_line(&scanline))?;
             }
-        }
+            if header.vga_palette {
+                w.write_u8(VGA_PALETTE_MARKER)?;
+                for c in &header.palette {
+                    c.save_to_writer(w)?;
+                }
+            }
+        },
+        _ => {
+            let header = PCXHeader::new(pixels.width as i16, pixels.height as i16, 8, 3, Vec::new())?;
+            header.save_to_writer(w)?;
+            let bytesperline = header.bytesperline as usize;
+            for row in pixels.to_rgb().chunks(width * 3) {
+                let mut scanline = vec![0u8; bytesperline * 3];
+                for (x, px) in row.chunks(3).enumerate() {
+                    scanline[x] = px[0];
+                    scanline[bytesperline + x] = px[1];
+                    scanline[bytesperline * 2 + x] = px[2];
+                }
+                w.write_all(&encode_line(&scanline))?;
+            }
+        },
     }
-
-    let pixbuf = Pixbuf::new_from_vec(
-        data,                                   // vec
-        0 as gdk_pixbuf::Colorspace,            // GDK_COLORSPACE_RGB = 0 colorspace
-        false,                                  // has_alpha
-        8,                                      // bits_per_sample (only 8 bps supported)
-        header.width as i32,
-        header.height as i32,
-        last_row_len as i32,                           // row_stride for pixbuf
-    );
-    Ok(pixbuf)
+    Ok(())
 }
 
-
-
-const LUMA: (f64, f64, f64)  =  (0.2126/*R*/, 0.7152/*G*/, 0.0722/*B*/);
-
-#[derive(Debug)]
-struct Cube {
-    color: RGBTriple,
-    colors: Vec<RGBTriple>,
+pub fn save_to_file<P: AsRef<Path>>(pixels: &bmp::Pixels, p: P) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f)?;
+    f.flush()
 }
 
-impl Cube {
-    fn new() -> Cube {
-        Cube{
-            color: RGBTriple::new(0, 0, 0),
-            colors: Vec::new(),
-        }
+/// Decode PCX into pixels: palette images keep color indexes,
+/// 24 and 32 bit images become RGB and RGBA.
+pub fn load_from_reader<R: ?Sized + BufRead + Seek>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let header = PCXHeader::load_from_reader(r)?;
+    r.seek(SeekFrom::Start(PCX_HEADER_SIZE))?; // skip header
+
+    if header.width <= 0 || header.height <= 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid PCX image size: {}x{} px", header.width, header.height),
+        ));
     }
-    fn split(&mut self) -> Cube {
-        let mut min_red = 0_u8;
-        let mut min_green = 0_u8;
-        let mut min_blue = 0_u8;
-        let mut max_red = 0_u8;
-        let mut max_green = 0_u8;
-        let mut max_blue = 0_u8;
-
-        for c in &self.colors {
-            min_red = min_red.min(c.red);
-            max_red = max_red.max(c.red);
-
-            min_green = min_green.min(c.green);
-            max_green = max_green.max(c.green);
-
-            min_blue = min_blue.min(c.blue);
-            max_blue = max_blue.max(c.blue);
-        }
-
-        let red = (max_red - min_red) as f64;
-        let green = (max_green - min_green) as f64;
-        let blue = (max_blue - min_blue) as f64;
-        let max = red.max(green).max(blue);
-
-        self.colors.sort_by(|a, b| {
-            if max == red {
-                (a.red, a.green, a.blue).cmp(&(b.red, b.green, b.blue))
-            } else if max == green {
-                (a.green, a.red, a.blue).cmp(&(b.green, b.red, b.blue))
-            } else {
-                (a.blue, a.green, a.red).cmp(&(b.blue, b.green, b.red))
+    let width = header.width as usize;
+    let height = header.height as usize;
+    let bytesperline = header.bytesperline as usize;
+    if bytesperline * 8 < width * header.bitsperpixel as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("PCX line of {} bytes is too short for {} px", bytesperline, width),
+        ));
+    }
+    let pcx_row_stride = header.colorplanes as usize * bytesperline;
+    let planes = header.colorplanes as usize;
+    let mut data = Vec::with_capacity(width * height * planes);
+    for _ in 0..height {
+        let scanline = if header.encoding == 0 {
+            let mut scanline = vec![0u8; pcx_row_stride];
+            r.read_exact(&mut scanline)?;
+            scanline
+        } else {
+            decode_line(r, pcx_row_stride)?
+        };
+        if header.is_indexed() {
+            data.append(&mut planar_to_chunky(
+                &scanline, header.bitsperpixel, header.colorplanes, bytesperline, width,
+            ));
+        } else {
+            for pixel_idx in 0..width {
+                for plane in 0..planes {
+                    data.push(scanline[bytesperline * plane + pixel_idx]);
+                }
             }
-        });
-        let split_at = self.colors.len()/2;
-        let colors = self.colors.split_off(split_at);
-        Cube{
-            color: RGBTriple::new(0, 0, 0),
-            colors: colors,
         }
     }
-    fn set_color(&mut self, freq: &HashMap<RGBTriple, f64>) {
-        let (mut red, mut green, mut blue) = (0_f64, 0_f64, 0_f64);
-        let total = self.colors.iter().fold(0f64, |acc, x| acc + freq.get(x).unwrap());
-        for color in &self.colors {
-            red += color.red as f64 * freq.get(&color).unwrap();
-            green += color.green as f64 * freq.get(&color).unwrap();
-            blue += color.blue as f64 * freq.get(&color).unwrap();
+
+    let data = if header.is_indexed() {
+        bmp::PixelData::Indexed {
+            palette: header.palette.iter()
+                .map(|c| bmp::RGBQuad::new(c.red, c.green, c.blue))
+                .collect(),
+            indexes: data,
         }
-        red /= total;
-        green /= total;
-        blue /= total;
-        self.color = RGBTriple::new(red.round() as u8, green.round() as u8, blue.round() as u8);
-    }
+    } else if planes == 4 {
+        bmp::PixelData::RGBA(data)
+    } else {
+        bmp::PixelData::RGB(data)
+    };
+    Ok(bmp::Pixels::new(header.width as u32, header.height as u32, data))
 }
 
-#[derive(Debug)]
-struct Palette {
-    bit_count: usize,
-    cubes: Vec<Cube>,
-    palette: Vec<RGBTriple>,
-    frequency: HashMap<RGBTriple, f64>,
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
 }
 
-impl Palette {
-    fn from_pcx_palette(palette: &Vec<RGBTriple>, bit_count: usize) -> Palette {
-        Palette{
-            bit_count: bit_count,
-            cubes: Vec::with_capacity(bit_count),
-            palette: palette.clone(),
-            frequency: HashMap::with_capacity(palette.len()),
-        }
-    }
-
-    fn compute_frequency(&mut self, bitmap: &Vec<u8>, row_stride: usize, h: &PCXHeader) {
-        let mut counter = vec![0f64; h.palette.len()];
-        for line_idx in 0..(h.height as usize) {
-            for pixel_idx in 0..(h.width as usize) {
-                counter[bitmap[row_stride * line_idx + pixel_idx] as usize] += 1f64;
-            }
-        }
-        for idx in 0..counter.len() {
-            let prev = match self.frequency.get(&h.palette[idx]) {
-                Some(&x) => x,
-                None => 0f64,
-            };
-            self.frequency.insert(h.palette[idx], counter[idx] + prev);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn encode_line_round_trip() {
+        let mut scanline = vec![7u8; 200];
+        scanline.extend_from_slice(&[0xC0, 0xC1, 0xFF, 0x3F, 0x40, 0xC0, 0xC0, 1, 2, 3]);
+        scanline.extend(vec![0xFFu8; 63]);
+        scanline.extend(vec![0u8; 64]);
+        let encoded = encode_line(&scanline);
+        assert!(encoded.len() < scanline.len());
+        let decoded = decode_line(&mut Cursor::new(encoded), scanline.len()).unwrap();
+        assert_eq!(decoded, scanline);
     }
 
-    fn color_delta(a: &RGBTriple, b: &RGBTriple) -> f64 {
-       LUMA.0*(((b.red as i16   - a.red as i16).abs() as f64) * ((b.red as i16   - a.red as i16).abs() as f64)) +
-       LUMA.1*(((b.green as i16 - a.green as i16).abs() as f64) * ((b.green as i16 - a.green as i16).abs() as f64)) +
-       LUMA.2*(((b.blue as i16  - a.blue as i16).abs() as f64) * (b.blue as i16  - a.blue as i16).abs() as f64)
+    #[test]
+    fn encode_line_escapes_marker_bytes() {
+        assert_eq!(encode_line(&[0x3F, 0xC0, 0xFF]), vec![0x3F, 0xC1, 0xC0, 0xC1, 0xFF]);
+        // runs are split at 63 bytes
+        assert_eq!(encode_line(&[5; 64]), vec![0xFF, 5, 5]);
     }
 
-    fn round_pcx_palette(&mut self) {
-        let mut unique_colors = HashSet::new();
-        for c in &self.palette {
-            unique_colors.insert(c);
+    #[test]
+    fn save_load_round_trip() {
+        let palette = |n: usize| (0..n).map(|i| bmp::RGBQuad::new(i as u8, 255 - i as u8, (i * 7) as u8)).collect();
+        let images = vec![
+            bmp::Pixels::new(13, 3, bmp::PixelData::Indexed {
+                palette: palette(2),
+                indexes: (0..39).map(|i| (i % 3 == 0) as u8).collect(),
+            }),
+            bmp::Pixels::new(9, 4, bmp::PixelData::Indexed {
+                palette: palette(16),
+                indexes: (0..36).map(|i| (i * 5 % 16) as u8).collect(),
+            }),
+            bmp::Pixels::new(7, 5, bmp::PixelData::Indexed {
+                palette: palette(256),
+                indexes: (0..35).map(|i| (i * 37 % 256) as u8).collect(),
+            }),
+            bmp::Pixels::new(5, 3, bmp::PixelData::RGB((0..45).map(|i| (i * 53 % 256) as u8).collect())),
+        ];
+        for pixels in images {
+            let mut data = Vec::new();
+            save_to_writer(&pixels, &mut data).unwrap();
+            let loaded = load_from_reader(&mut Cursor::new(data)).unwrap();
+            assert_eq!((loaded.width, loaded.height), (pixels.width, pixels.height));
+            assert_eq!(loaded.to_rgb(), pixels.to_rgb());
         }
-        let mut cube = Cube::new();
-        cube.colors.append(&mut unique_colors.iter().map(|c|{*(*c)}).collect());
-        self.cubes.push(cube);
-
-        for step in 1..(self.bit_count+1) {
-            let mut new_cubes = Vec::with_capacity(step);
-            for c in &mut self.cubes {
-                new_cubes.push(c.split());
-            }
-            self.cubes.append(&mut new_cubes);
-        }
-        for c in &mut self.cubes {
-            c.set_color(&self.frequency);
-        }
-    }
-
-    fn cube_color(&self, c: RGBTriple) -> RGBTriple {
-        let mut nearest_cube = 0;
-        let mut delta = Palette::color_delta(&c, &self.cubes[0].color);
-
-        for idx in 1..self.cubes.len() {
-            let next_delta = Palette::color_delta(&c, &self.cubes[idx].color);
-            if delta > next_delta {
-                delta = next_delta;
-                nearest_cube = idx;
-            }
-        }
-        self.cubes[nearest_cube].color
     }
-}
 
-pub fn pcx_256colors_to_bmp_16colors(src_file: &str) -> io::Result<bmp::BMPImage> {
-    let mut src = BufReader::new(File::open(src_file)?);
-    let header = PCXHeader::load_from_reader(&mut src)?;
-    src.seek(SeekFrom::Start(128))?; // skip header
-
-    // https://en.wikipedia.org/wiki/BMP_file_format
-    let bmp_row_stride = ((8 * header.width + 31)/32*4) as usize;
-
-    let file_header_size = 14 /*header size*/ + 40 /*info header size*/ + 4*256 /*palette size*/;
-    let bitmap_size = bmp_row_stride as i32 * header.height as i32;
-
-    let mut dst_bmp = bmp::BMPImage {
-        header: bmp::BMPFileHeader::new(file_header_size + bitmap_size, file_header_size),
-        info: bmp::BMPInfo {
-            bmi_header: bmp::BMPGenericInfoHeader::Info(bmp::BMPInfoHeader::new(
-                header.width as i32,
-                header.height as i32,
-                8, // bits per pixel
-                bitmap_size,
-                0, 0,  // x, y pixels per meter (ignored)
-                256, 16,  // colors used, important
-            )),
-            bmi_colors: Vec::with_capacity(header.palette.len()),
-        },
-        bitmap: bmp::Bitmap::with_capacity(bmp_row_stride * header.height as usize),
-    };
-    let pcx_row_stride = header.colorplanes as u16 * header.bytesperline;
-    for _ in 0..(header.height as usize) {
-        let mut scanline = decode_line(&mut src, pcx_row_stride)?;
-        scanline.resize(bmp_row_stride, 0u8);
-        scanline.reverse();
-        dst_bmp.bitmap.data.append(&mut scanline);
+    #[test]
+    fn load_uncompressed() {
+        let mut header = PCXHeader::new(3, 2, 8, 3, Vec::new()).unwrap();
+        header.encoding = 0;
+        let mut data = Vec::new();
+        header.save_to_writer(&mut data).unwrap();
+        // planes of red, green and blue padded to 4 bytes
+        data.extend_from_slice(&[0xC1, 2, 3, 0, 4, 5, 6, 0, 7, 8, 0xFF, 0]);
+        data.extend_from_slice(&[9, 10, 11, 0, 12, 13, 14, 0, 15, 16, 17, 0]);
+        let pixels = load_from_reader(&mut Cursor::new(data)).unwrap();
+        assert_eq!(pixels.to_rgb(), vec![
+            0xC1, 4, 7, 2, 5, 8, 3, 6, 0xFF,
+            9, 12, 15, 10, 13, 16, 11, 14, 17,
+        ]);
     }
-    dst_bmp.bitmap.data.reverse();
-
-    let mut palette = Palette::from_pcx_palette(&header.palette, 4);
-    palette.compute_frequency(&dst_bmp.bitmap.data, bmp_row_stride, &header);
-    palette.round_pcx_palette();
 
-    for idx in 0..header.palette.len() {
-        dst_bmp.info.bmi_colors.push({
-            let c = palette.cube_color(header.palette[idx]);
-            bmp::RGBQuad::new(c.red, c.green, c.blue)
-        });
+    #[test]
+    fn wide_image_fits_bytesperline() {
+        let header = PCXHeader::new(9000, 1, 8, 3, Vec::new()).unwrap();
+        assert_eq!(header.bytesperline, 9000);
+        assert!(PCXHeader::new(0, 1, 8, 1, Vec::new()).is_err());
     }
-    Ok(dst_bmp)
 }
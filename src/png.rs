@@ -0,0 +1,689 @@
+//! # Format desciption
+//! PNG: 8 byte signature and chunks of big-endian length, 4 letter type,
+//! data and CRC-32 of the type and data. IHDR goes first, PLTE holds
+//! the palette, tRNS the palette alpha or the transparent color, IDAT
+//! chunks the zlib compressed image and IEND ends the file.
+//! Every row is filtered by one of 5 filters before compression,
+//! interlaced images are stored as 7 Adam7 passes.
+//! https://www.w3.org/TR/PNG/
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+use zlib;
+
+pub const PNG_SIGNATURE: &'static [u8; 8] = b"\x89PNG\r\n\x1a\n";
+/// Size of the IHDR chunk data
+pub const PNG_HEADER_SIZE: u32 = 13;
+
+/// Color types
+pub const PNG_GRAYSCALE: u8 = 0;
+pub const PNG_TRUE_COLOR: u8 = 2;
+pub const PNG_INDEXED: u8 = 3;
+pub const PNG_GRAYSCALE_ALPHA: u8 = 4;
+pub const PNG_TRUE_COLOR_ALPHA: u8 = 6;
+
+pub const PNG_INTERLACE_NONE: u8 = 0;
+pub const PNG_INTERLACE_ADAM7: u8 = 1;
+
+/// Row filter types
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+const FILTER_UP: u8 = 2;
+const FILTER_AVERAGE: u8 = 3;
+const FILTER_PAETH: u8 = 4;
+
+/// Longest IDAT chunk written
+const IDAT_MAX_SIZE: usize = 65536;
+
+/// Adam7 passes: first column, first row, column and row steps
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2),
+];
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Clone)]
+pub struct PNGHeader {
+    pub width: u32,
+    pub height: u32,
+    /// Bits per sample or palette index: 1, 2, 4, 8 or 16
+    pub bit_depth: u8,
+    pub color_type: u8,
+    /// Always 0, deflate
+    pub compression: u8,
+    /// Always 0, adaptive filtering with 5 filter types
+    pub filter: u8,
+    pub interlace: u8,
+}
+
+impl PNGHeader {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<PNGHeader> {
+        let mut f = BufReader::new(File::open(p)?);
+        PNGHeader::load_from_reader(&mut f)
+    }
+
+    /// Read the signature and IHDR chunk
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<PNGHeader> {
+        let mut signature = [0u8; 8];
+        r.read_exact(&mut signature)?;
+        if &signature != PNG_SIGNATURE {
+            return Err(invalid_data("Invalid PNG signature".to_owned()));
+        }
+        let (kind, data) = read_chunk(r)?;
+        if &kind != b"IHDR" || data.len() != PNG_HEADER_SIZE as usize {
+            return Err(invalid_data(format!("PNG starts with {} chunk instead of IHDR", String::from_utf8_lossy(&kind))));
+        }
+        let mut r = &data[..];
+        let h = PNGHeader {
+            width: r.read_u32::<BigEndian>()?,
+            height: r.read_u32::<BigEndian>()?,
+            bit_depth: r.read_u8()?,
+            color_type: r.read_u8()?,
+            compression: r.read_u8()?,
+            filter: r.read_u8()?,
+            interlace: r.read_u8()?,
+        };
+        if !h.is_valid() {
+            return Err(invalid_data(format!("Invalid PNG header: {:?}", h)));
+        }
+        Ok(h)
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut data = Vec::with_capacity(PNG_HEADER_SIZE as usize);
+        data.write_u32::<BigEndian>(self.width)?;
+        data.write_u32::<BigEndian>(self.height)?;
+        data.write_u8(self.bit_depth)?;
+        data.write_u8(self.color_type)?;
+        data.write_u8(self.compression)?;
+        data.write_u8(self.filter)?;
+        data.write_u8(self.interlace)?;
+        w.write_all(PNG_SIGNATURE)?;
+        write_chunk(w, b"IHDR", &data)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let depth_ok = match self.color_type {
+            PNG_GRAYSCALE => [1, 2, 4, 8, 16].contains(&self.bit_depth),
+            PNG_INDEXED => [1, 2, 4, 8].contains(&self.bit_depth),
+            PNG_TRUE_COLOR | PNG_GRAYSCALE_ALPHA | PNG_TRUE_COLOR_ALPHA => [8, 16].contains(&self.bit_depth),
+            _ => false,
+        };
+        depth_ok && self.width > 0 && self.height > 0 && self.compression == 0 && self.filter == 0
+            && self.interlace <= PNG_INTERLACE_ADAM7
+    }
+
+    /// Samples per pixel
+    pub fn channels(&self) -> usize {
+        match self.color_type {
+            PNG_TRUE_COLOR => 3,
+            PNG_GRAYSCALE_ALPHA => 2,
+            PNG_TRUE_COLOR_ALPHA => 4,
+            _ => 1,
+        }
+    }
+
+    pub fn bits_per_pixel(&self) -> usize {
+        self.channels() * self.bit_depth as usize
+    }
+
+    pub fn is_interlaced(&self) -> bool {
+        self.interlace == PNG_INTERLACE_ADAM7
+    }
+
+    fn row_size(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel() + 7) / 8
+    }
+}
+
+impl fmt::Display for PNGHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let color_type = match self.color_type {
+            PNG_GRAYSCALE => "grayscale",
+            PNG_TRUE_COLOR => "true color",
+            PNG_INDEXED => "indexed",
+            PNG_GRAYSCALE_ALPHA => "grayscale with alpha",
+            PNG_TRUE_COLOR_ALPHA => "true color with alpha",
+            _ => "unknown",
+        };
+        write!(
+            f,
+            "{}x{} px, {} {} bit samples, {}",
+            self.width, self.height, color_type, self.bit_depth,
+            if self.is_interlaced() { "Adam7 interlaced" } else { "not interlaced" },
+        )
+    }
+}
+
+/// Read chunk type and data, checking the CRC
+fn read_chunk<R: ?Sized + Read>(r: &mut R) -> io::Result<([u8; 4], Vec<u8>)> {
+    let len = r.read_u32::<BigEndian>()?;
+    if len > 0x7FFF_FFFF {
+        return Err(invalid_data(format!("Invalid PNG chunk length: {}", len)));
+    }
+    // type and data are read together for the CRC
+    let mut data = Vec::new();
+    r.take(4 + len as u64).read_to_end(&mut data)?;
+    if data.len() != 4 + len as usize {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "PNG chunk is truncated".to_owned()));
+    }
+    let crc = r.read_u32::<BigEndian>()?;
+    let mut kind = [0u8; 4];
+    kind.copy_from_slice(&data[..4]);
+    if zlib::crc32(&data) != crc {
+        return Err(invalid_data(format!("CRC mismatch of PNG chunk {}", String::from_utf8_lossy(&kind))));
+    }
+    Ok((kind, data.split_off(4)))
+}
+
+fn write_chunk<W: ?Sized + Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_u32::<BigEndian>(data.len() as u32)?;
+    let mut crc_data = Vec::with_capacity(4 + data.len());
+    crc_data.extend_from_slice(kind);
+    crc_data.extend_from_slice(data);
+    w.write_all(&crc_data)?;
+    w.write_u32::<BigEndian>(zlib::crc32(&crc_data))
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Undo the filter of `row` given the previous unfiltered row,
+/// `bpp` is the number of bytes per complete pixel, at least 1
+fn unfilter(filter: u8, row: &mut [u8], prev: &[u8], bpp: usize) -> io::Result<()> {
+    match filter {
+        FILTER_NONE => {},
+        FILTER_SUB => for i in bpp..row.len() {
+            row[i] = row[i].wrapping_add(row[i - bpp]);
+        },
+        FILTER_UP => for i in 0..row.len() {
+            row[i] = row[i].wrapping_add(prev[i]);
+        },
+        FILTER_AVERAGE => for i in 0..row.len() {
+            let left = if i >= bpp { row[i - bpp] as u16 } else { 0 };
+            row[i] = row[i].wrapping_add(((left + prev[i] as u16) / 2) as u8);
+        },
+        FILTER_PAETH => for i in 0..row.len() {
+            let (left, up_left) = if i >= bpp { (row[i - bpp], prev[i - bpp]) } else { (0, 0) };
+            row[i] = row[i].wrapping_add(paeth(left, prev[i], up_left));
+        },
+        _ => return Err(invalid_data(format!("Invalid PNG filter type: {}", filter))),
+    }
+    Ok(())
+}
+
+/// Filter `row` given the previous row
+fn filter_row(filter: u8, row: &[u8], prev: &[u8], bpp: usize) -> Vec<u8> {
+    (0..row.len()).map(|i| {
+        let (left, up, up_left) = if i >= bpp { (row[i - bpp], prev[i], prev[i - bpp]) } else { (0, prev[i], 0) };
+        row[i].wrapping_sub(match filter {
+            FILTER_SUB => left,
+            FILTER_UP => up,
+            FILTER_AVERAGE => ((left as u16 + up as u16) / 2) as u8,
+            FILTER_PAETH => paeth(left, up, up_left),
+            _ => 0,
+        })
+    }).collect()
+}
+
+/// Sample `idx` of the unfiltered row
+fn sample(row: &[u8], idx: usize, depth: u8) -> u16 {
+    match depth {
+        16 => (row[idx * 2] as u16) << 8 | row[idx * 2 + 1] as u16,
+        8 => row[idx] as u16,
+        _ => {
+            let bit = idx * depth as usize;
+            (row[bit / 8] >> (8 - depth as usize - bit % 8)) as u16 & ((1 << depth) - 1)
+        },
+    }
+}
+
+/// Scale sample of `depth` bits to 8 bits
+fn scale_sample(value: u16, depth: u8) -> u8 {
+    let max = (1u32 << depth) - 1;
+    ((value as u32 * 255 + max / 2) / max) as u8
+}
+
+/// Pass sizes of the image, the only pass of non-interlaced images
+fn passes(h: &PNGHeader) -> Vec<(usize, usize, usize, usize, usize, usize)> {
+    let (width, height) = (h.width as usize, h.height as usize);
+    if !h.is_interlaced() {
+        return vec![(0, 0, 1, 1, width, height)];
+    }
+    ADAM7.iter().map(|&(x0, y0, dx, dy)| {
+        let pass_width = if width > x0 { (width - x0 + dx - 1) / dx } else { 0 };
+        let pass_height = if height > y0 { (height - y0 + dy - 1) / dy } else { 0 };
+        (x0, y0, dx, dy, pass_width, pass_height)
+    }).collect()
+}
+
+/// Unfilter image data into samples, rows are top-down
+fn decode_samples(h: &PNGHeader, data: &[u8]) -> io::Result<Vec<u16>> {
+    let channels = h.channels();
+    let width = h.width as usize;
+    let bpp = (h.bits_per_pixel() / 8).max(1);
+    let mut samples = vec![0u16; width * h.height as usize * channels];
+    let mut pos = 0;
+    for (x0, y0, dx, dy, pass_width, pass_height) in passes(h) {
+        // empty passes have no filter bytes
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+        let row_size = h.row_size(pass_width);
+        let mut prev = vec![0u8; row_size];
+        for y in 0..pass_height {
+            if data.len() < pos + 1 + row_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("PNG image data is truncated: {} bytes", data.len()),
+                ));
+            }
+            let filter = data[pos];
+            let mut row = data[pos + 1..pos + 1 + row_size].to_vec();
+            pos += 1 + row_size;
+            unfilter(filter, &mut row, &prev, bpp)?;
+            for x in 0..pass_width {
+                let dst = ((y0 + y * dy) * width + x0 + x * dx) * channels;
+                for c in 0..channels {
+                    samples[dst + c] = sample(&row, x * channels + c, h.bit_depth);
+                }
+            }
+            prev = row;
+        }
+    }
+    Ok(samples)
+}
+
+/// Decode PNG of any color type, bit depth and interlacing.
+/// Palette and grayscale images upto 8 bits become palette pixels,
+/// tRNS chunk makes them RGBA. 16 bit samples are scaled down to 8 bits.
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let h = PNGHeader::load_from_reader(r)?;
+    let mut palette = Vec::new();
+    let mut trns = Vec::new();
+    let mut data = Vec::new();
+    loop {
+        let (kind, chunk) = read_chunk(r)?;
+        match &kind {
+            b"PLTE" => {
+                if chunk.len() % 3 != 0 || chunk.len() > 256 * 3 {
+                    return Err(invalid_data(format!("Invalid PNG palette size: {}", chunk.len())));
+                }
+                palette = chunk.chunks(3).map(|c| bmp::RGBQuad::new(c[0], c[1], c[2])).collect();
+            },
+            b"tRNS" => trns = chunk,
+            b"IDAT" => data.extend_from_slice(&chunk),
+            b"IEND" => break,
+            // ancillary chunks have the lowercase first letter
+            _ if kind[0] & 0x20 == 0 => {
+                return Err(invalid_data(format!("Unknown critical PNG chunk {}", String::from_utf8_lossy(&kind))));
+            },
+            _ => {},
+        }
+    }
+    let samples = decode_samples(&h, &zlib::decompress(&data)?)?;
+    let (width, height, depth) = (h.width, h.height, h.bit_depth);
+    let scale = |v: u16| scale_sample(v, depth);
+    let pixel_data = match h.color_type {
+        PNG_INDEXED => {
+            if palette.is_empty() {
+                return Err(invalid_data("Indexed PNG has no palette".to_owned()));
+            }
+            if let Some(&idx) = samples.iter().find(|&&i| i as usize >= palette.len()) {
+                return Err(invalid_data(format!("Palette index {} is out of {} colors", idx, palette.len())));
+            }
+            if trns.is_empty() {
+                bmp::PixelData::Indexed {
+                    palette: palette,
+                    indexes: samples.iter().map(|&i| i as u8).collect(),
+                }
+            } else {
+                // colors without tRNS entry are opaque
+                bmp::PixelData::RGBA(samples.iter().flat_map(|&i| {
+                    let c = &palette[i as usize];
+                    vec![c.red(), c.green(), c.blue(), trns.get(i as usize).cloned().unwrap_or(255)]
+                }).collect())
+            }
+        },
+        PNG_GRAYSCALE if trns.len() >= 2 => {
+            let key = (&trns[..]).read_u16::<BigEndian>()?;
+            bmp::PixelData::RGBA(samples.iter().flat_map(|&v| {
+                let g = scale(v);
+                vec![g, g, g, if v == key { 0 } else { 255 }]
+            }).collect())
+        },
+        PNG_GRAYSCALE if depth <= 8 => bmp::PixelData::Indexed {
+            palette: (0..1u16 << depth).map(|v| {
+                let g = scale(v);
+                bmp::RGBQuad::new(g, g, g)
+            }).collect(),
+            indexes: samples.iter().map(|&v| v as u8).collect(),
+        },
+        PNG_GRAYSCALE => bmp::PixelData::Indexed {
+            palette: (0..256).map(|g| bmp::RGBQuad::new(g as u8, g as u8, g as u8)).collect(),
+            indexes: samples.iter().map(|&v| scale(v)).collect(),
+        },
+        PNG_TRUE_COLOR if trns.len() >= 6 => {
+            let mut t = &trns[..];
+            let key = [t.read_u16::<BigEndian>()?, t.read_u16::<BigEndian>()?, t.read_u16::<BigEndian>()?];
+            bmp::PixelData::RGBA(samples.chunks(3).flat_map(|px| {
+                vec![scale(px[0]), scale(px[1]), scale(px[2]), if px == key { 0 } else { 255 }]
+            }).collect())
+        },
+        PNG_TRUE_COLOR => bmp::PixelData::RGB(samples.iter().map(|&v| scale(v)).collect()),
+        PNG_GRAYSCALE_ALPHA => bmp::PixelData::RGBA(samples.chunks(2).flat_map(|px| {
+            let g = scale(px[0]);
+            vec![g, g, g, scale(px[1])]
+        }).collect()),
+        _ => bmp::PixelData::RGBA(samples.iter().map(|&v| scale(v)).collect()),
+    };
+    Ok(bmp::Pixels::new(width, height, pixel_data))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// Smallest bit depth holding `values` levels
+fn sample_depth(values: usize) -> u8 {
+    match values {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    }
+}
+
+/// Pack samples of one row into bytes
+fn pack_row(samples: &[u8], depth: u8) -> Vec<u8> {
+    if depth == 8 {
+        return samples.to_vec();
+    }
+    bmp::pack_row(samples, depth as i16)
+}
+
+/// Encode pixels as PNG. Palette pixels are written as indexed or as
+/// grayscale of the smallest bit depth if all colors are gray levels,
+/// RGB as true color and RGBA as true color with alpha.
+pub fn save_to_writer<W: ?Sized + Write>(pixels: &bmp::Pixels, w: &mut W, interlace: bool) -> io::Result<()> {
+    if pixels.width == 0 || pixels.height == 0 || pixels.width > 0x7FFF_FFFF || pixels.height > 0x7FFF_FFFF {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("PNG can't be {}x{}", pixels.width, pixels.height),
+        ));
+    }
+    let mut h = PNGHeader {
+        width: pixels.width,
+        height: pixels.height,
+        bit_depth: 8,
+        color_type: PNG_TRUE_COLOR,
+        compression: 0,
+        filter: 0,
+        interlace: if interlace { PNG_INTERLACE_ADAM7 } else { PNG_INTERLACE_NONE },
+    };
+    let mut plte = Vec::new();
+    // one byte per sample before packing
+    let samples: Vec<u8> = match pixels.data {
+        bmp::PixelData::Indexed { ref palette, ref indexes } => {
+            let max_index = indexes.iter().cloned().max().unwrap_or(0) as usize;
+            if max_index >= palette.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Palette index {} is out of {} colors", max_index, palette.len()),
+                ));
+            }
+            let used = &palette[..max_index + 1];
+            // gray levels of 1, 2 and 4 bits are multiples of 255, 85 and 17
+            let gray_depth = [1u8, 2, 4, 8].iter().cloned().find(|&d| used.iter().all(|c| {
+                c.red() == c.green() && c.green() == c.blue() && c.red() as u32 % (255 / ((1u32 << d) - 1)) == 0
+            }));
+            if let Some(depth) = gray_depth {
+                h.color_type = PNG_GRAYSCALE;
+                h.bit_depth = depth;
+                let step = 255 / ((1u32 << depth) - 1);
+                indexes.iter().map(|&i| (palette[i as usize].red() as u32 / step) as u8).collect()
+            } else {
+                h.color_type = PNG_INDEXED;
+                h.bit_depth = sample_depth(used.len());
+                for c in used {
+                    plte.extend_from_slice(&[c.red(), c.green(), c.blue()]);
+                }
+                indexes.clone()
+            }
+        },
+        bmp::PixelData::RGB(ref rgb) => rgb.clone(),
+        bmp::PixelData::RGBA(ref rgba) => {
+            h.color_type = PNG_TRUE_COLOR_ALPHA;
+            rgba.clone()
+        },
+    };
+
+    let channels = h.channels();
+    let width = h.width as usize;
+    let bpp = (h.bits_per_pixel() / 8).max(1);
+    let mut filtered = Vec::new();
+    for (x0, y0, dx, dy, pass_width, pass_height) in passes(&h) {
+        if pass_width == 0 || pass_height == 0 {
+            continue;
+        }
+        let mut prev = vec![0u8; h.row_size(pass_width)];
+        for y in 0..pass_height {
+            let mut row_samples = Vec::with_capacity(pass_width * channels);
+            for x in 0..pass_width {
+                let src = ((y0 + y * dy) * width + x0 + x * dx) * channels;
+                row_samples.extend_from_slice(&samples[src..src + channels]);
+            }
+            let row = pack_row(&row_samples, h.bit_depth);
+            // palette and low depth images compress best unfiltered,
+            // the others take the filter of the smallest absolute sum
+            let (filter, data) = if h.color_type == PNG_INDEXED || h.bit_depth < 8 {
+                (FILTER_NONE, row.clone())
+            } else {
+                (FILTER_NONE..FILTER_PAETH + 1)
+                    .map(|f| (f, filter_row(f, &row, &prev, bpp)))
+                    .min_by_key(|&(_, ref data)| data.iter().map(|&b| (b as i8 as i32).abs() as u32).sum::<u32>())
+                    .unwrap()
+            };
+            filtered.push(filter);
+            filtered.extend_from_slice(&data);
+            prev = row;
+        }
+    }
+
+    h.save_to_writer(w)?;
+    if !plte.is_empty() {
+        write_chunk(w, b"PLTE", &plte)?;
+    }
+    for chunk in zlib::compress(&filtered).chunks(IDAT_MAX_SIZE) {
+        write_chunk(w, b"IDAT", chunk)?;
+    }
+    write_chunk(w, b"IEND", &[])
+}
+
+pub fn save_to_file<P: AsRef<Path>>(pixels: &bmp::Pixels, p: P, interlace: bool) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f, interlace)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(color_type: u8, bit_depth: u8, interlace: u8) -> PNGHeader {
+        PNGHeader {
+            width: 7,
+            height: 5,
+            bit_depth: bit_depth,
+            color_type: color_type,
+            compression: 0,
+            filter: 0,
+            interlace: interlace,
+        }
+    }
+
+    /// Encode samples unfiltered, the chunks are written before IDAT
+    fn encode(h: &PNGHeader, samples: &[u16], chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let channels = h.channels();
+        let width = h.width as usize;
+        let mut raw = Vec::new();
+        for (x0, y0, dx, dy, pass_width, pass_height) in passes(h) {
+            if pass_width == 0 || pass_height == 0 {
+                continue;
+            }
+            for y in 0..pass_height {
+                let mut row = Vec::new();
+                for x in 0..pass_width {
+                    let src = ((y0 + y * dy) * width + x0 + x * dx) * channels;
+                    row.extend_from_slice(&samples[src..src + channels]);
+                }
+                raw.push(FILTER_NONE);
+                if h.bit_depth == 16 {
+                    for v in row {
+                        raw.write_u16::<BigEndian>(v).unwrap();
+                    }
+                } else {
+                    let row: Vec<u8> = row.iter().map(|&v| v as u8).collect();
+                    raw.extend(pack_row(&row, h.bit_depth));
+                }
+            }
+        }
+        let mut data = Vec::new();
+        h.save_to_writer(&mut data).unwrap();
+        for &(kind, chunk) in chunks {
+            write_chunk(&mut data, kind, chunk).unwrap();
+        }
+        write_chunk(&mut data, b"IDAT", &zlib::compress(&raw)).unwrap();
+        write_chunk(&mut data, b"IEND", &[]).unwrap();
+        data
+    }
+
+    #[test]
+    fn load_color_types_and_depths() {
+        let types = [
+            (PNG_GRAYSCALE, &[1u8, 2, 4, 8, 16][..]),
+            (PNG_INDEXED, &[1, 2, 4, 8][..]),
+            (PNG_TRUE_COLOR, &[8, 16][..]),
+            (PNG_GRAYSCALE_ALPHA, &[8, 16][..]),
+            (PNG_TRUE_COLOR_ALPHA, &[8, 16][..]),
+        ];
+        for &(color_type, depths) in types.iter() {
+            for &depth in depths {
+                for &interlace in [PNG_INTERLACE_NONE, PNG_INTERLACE_ADAM7].iter() {
+                    let h = header(color_type, depth, interlace);
+                    let max = (1u32 << depth.min(8)) - 1;
+                    // 8 bit values, 16 bit samples repeat them in both bytes
+                    let values: Vec<u32> = (0..35 * h.channels() as u32).map(|i| (i * 37 + 11) % (max + 1)).collect();
+                    let samples: Vec<u16> = values.iter().map(|&v| if depth == 16 { v as u16 * 257 } else { v as u16 }).collect();
+                    let level = |v: u32| (v * (255 / max)) as u8;
+                    let palette: Vec<u8> = (0..max + 1).flat_map(|i| vec![i as u8, 255 - i as u8, (i * 3) as u8]).collect();
+                    let expected: Vec<u8> = match color_type {
+                        PNG_GRAYSCALE => values.iter().flat_map(|&v| vec![level(v), level(v), level(v), 255]).collect(),
+                        PNG_INDEXED => values.iter().flat_map(|&v| {
+                            let c = &palette[v as usize * 3..v as usize * 3 + 3];
+                            vec![c[0], c[1], c[2], 255]
+                        }).collect(),
+                        PNG_TRUE_COLOR => values.chunks(3).flat_map(|c| vec![level(c[0]), level(c[1]), level(c[2]), 255]).collect(),
+                        PNG_GRAYSCALE_ALPHA => values.chunks(2).flat_map(|c| vec![level(c[0]), level(c[0]), level(c[0]), level(c[1])]).collect(),
+                        _ => values.iter().map(|&v| level(v)).collect(),
+                    };
+                    let data = encode(&h, &samples, &[(b"PLTE", &palette)]);
+                    let pixels = load_from_reader(&mut &data[..]).unwrap();
+                    assert_eq!((pixels.width, pixels.height), (7, 5));
+                    assert_eq!(pixels.to_rgba(), expected, "color type {}, {} bits, interlace {}", color_type, depth, interlace);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn load_transparency() {
+        let mut h = header(PNG_GRAYSCALE, 4, PNG_INTERLACE_NONE);
+        h.width = 2;
+        h.height = 1;
+        let data = encode(&h, &[3, 15], &[(b"tRNS", &[0, 3])]);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgba(), vec![51, 51, 51, 0, 255, 255, 255, 255]);
+
+        h.color_type = PNG_TRUE_COLOR;
+        h.bit_depth = 8;
+        let data = encode(&h, &[1, 2, 3, 1, 2, 4], &[(b"tRNS", &[0, 1, 0, 2, 0, 3])]);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgba(), vec![1, 2, 3, 0, 1, 2, 4, 255]);
+
+        // palette entries without alpha are opaque
+        h.color_type = PNG_INDEXED;
+        let data = encode(&h, &[0, 1], &[(b"PLTE", &[10, 20, 30, 40, 50, 60]), (b"tRNS", &[128])]);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgba(), vec![10, 20, 30, 128, 40, 50, 60, 255]);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let (width, height) = (13, 6);
+        let gray = |levels: u32| (0..levels).map(|i| {
+            let g = (i * 255 / (levels - 1)) as u8;
+            bmp::RGBQuad::new(g, g, g)
+        }).collect::<Vec<_>>();
+        let colors = |count: u32| (0..count).map(|i| bmp::RGBQuad::new(i as u8, 7, 200 - i as u8)).collect::<Vec<_>>();
+        let indexed = |palette: Vec<bmp::RGBQuad>| {
+            let count = palette.len() as u32;
+            bmp::Pixels::new(width, height, bmp::PixelData::Indexed {
+                palette: palette,
+                indexes: (0..width * height).map(|i| (i * 7 % count) as u8).collect(),
+            })
+        };
+        let images = [
+            (indexed(gray(2)), PNG_GRAYSCALE, 1),
+            (indexed(gray(4)), PNG_GRAYSCALE, 2),
+            (indexed(gray(16)), PNG_GRAYSCALE, 4),
+            (indexed(gray(256)), PNG_GRAYSCALE, 8),
+            (indexed(colors(2)), PNG_INDEXED, 1),
+            (indexed(colors(3)), PNG_INDEXED, 2),
+            (indexed(colors(16)), PNG_INDEXED, 4),
+            (indexed(colors(200)), PNG_INDEXED, 8),
+            (bmp::Pixels::new(width, height, bmp::PixelData::RGB((0..width * height * 3).map(|i| (i * 13) as u8).collect())),
+             PNG_TRUE_COLOR, 8),
+            (bmp::Pixels::new(width, height, bmp::PixelData::RGBA((0..width * height * 4).map(|i| (i * 29) as u8).collect())),
+             PNG_TRUE_COLOR_ALPHA, 8),
+        ];
+        for &(ref pixels, color_type, depth) in images.iter() {
+            for &interlace in [false, true].iter() {
+                let mut data = Vec::new();
+                save_to_writer(pixels, &mut data, interlace).unwrap();
+                let h = PNGHeader::load_from_reader(&mut &data[..]).unwrap();
+                assert_eq!((h.color_type, h.bit_depth, h.is_interlaced()), (color_type, depth, interlace));
+                assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgba(), pixels.to_rgba());
+            }
+        }
+    }
+
+    #[test]
+    fn bmp_png_bitmap_from_rgba() {
+        let rgba: Vec<u8> = (0..5 * 3 * 4).map(|i| (i * 17) as u8).collect();
+        let mut image = bmp::BMPImage::from_rgba(5, 3, &rgba).unwrap();
+        image.encode_bitmap(bmp::BMPCompression::PNG).unwrap();
+        let compression = image.info.bmi_header.get_compression_type();
+        assert_eq!(bmp::BMPCompression::to_bytes(&compression), bmp::BMPCompression::to_bytes(&bmp::BMPCompression::PNG));
+        assert_eq!(image.to_pixels().unwrap().to_rgba(), rgba);
+        // alpha channel needs bitfields
+        image.decode_bitmap().unwrap();
+        let compression = image.info.bmi_header.get_compression_type();
+        assert_eq!(bmp::BMPCompression::to_bytes(&compression), bmp::BMPCompression::to_bytes(&bmp::BMPCompression::BITFIELDS));
+        assert_eq!(image.to_pixels().unwrap().to_rgba(), rgba);
+    }
+}
@@ -0,0 +1,297 @@
+//! # Format desciption
+//! QOI: 14 byte header with "qoif" magic, big-endian width and height,
+//! number of channels and colorspace, then pixel chunks and 8 byte end marker.
+//! Every chunk is a run of the previous pixel, an index into the array of
+//! 64 recently seen pixels, a small difference to the previous pixel
+//! or the full RGB or RGBA value.
+//! https://qoiformat.org/qoi-specification.pdf
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+
+pub const QOI_MAGIC: &'static [u8; 4] = b"qoif";
+pub const QOI_HEADER_SIZE: usize = 14;
+pub const QOI_END_MARKER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 1];
+
+/// Colorspace values, informative only
+pub const QOI_SRGB: u8 = 0;
+pub const QOI_LINEAR: u8 = 1;
+
+/// Chunk tags, 8 bit tags take precedence over 2 bit ones
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RUN: u8 = 0xC0;
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+const OP_MASK: u8 = 0xC0;
+
+/// Longest run, 63 and 64 would collide with OP_RGB and OP_RGBA
+const MAX_RUN: u8 = 62;
+/// Pixels in the decoder limit of the reference implementation
+const MAX_PIXELS: u64 = 400_000_000;
+
+#[derive(Debug, Clone)]
+pub struct QOIHeader {
+    pub width: u32,
+    pub height: u32,
+    /// 3 for RGB, 4 for RGBA
+    pub channels: u8,
+    pub colorspace: u8,
+}
+
+impl QOIHeader {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<QOIHeader> {
+        let mut f = BufReader::new(File::open(p)?);
+        QOIHeader::load_from_reader(&mut f)
+    }
+
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<QOIHeader> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != QOI_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid QOI magic".to_owned()));
+        }
+        let h = QOIHeader {
+            width: r.read_u32::<BigEndian>()?,
+            height: r.read_u32::<BigEndian>()?,
+            channels: r.read_u8()?,
+            colorspace: r.read_u8()?,
+        };
+        if !h.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid QOI header: {:?}", h),
+            ));
+        }
+        Ok(h)
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(QOI_MAGIC)?;
+        w.write_u32::<BigEndian>(self.width)?;
+        w.write_u32::<BigEndian>(self.height)?;
+        w.write_u8(self.channels)?;
+        w.write_u8(self.colorspace)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.width > 0 && self.height > 0 && (self.width as u64 * self.height as u64) < MAX_PIXELS
+            && (self.channels == 3 || self.channels == 4) && self.colorspace <= QOI_LINEAR
+    }
+}
+
+impl fmt::Display for QOIHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} px, {}, {}",
+            self.width, self.height,
+            if self.channels == 4 { "RGBA" } else { "RGB" },
+            if self.colorspace == QOI_LINEAR { "linear" } else { "sRGB with linear alpha" },
+        )
+    }
+}
+
+fn hash(px: [u8; 4]) -> usize {
+    (px[0] as usize * 3 + px[1] as usize * 5 + px[2] as usize * 7 + px[3] as usize * 11) % 64
+}
+
+/// Decode QOI into RGB or RGBA pixels, as the header channels say
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let h = QOIHeader::load_from_reader(r)?;
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    let count = h.width as usize * h.height as usize;
+    let channels = h.channels as usize;
+    let mut pixels = Vec::with_capacity(count * channels);
+    let mut index = [[0u8; 4]; 64];
+    let mut px = [0u8, 0, 0, 255];
+    let mut pos = 0;
+    let mut decoded = 0;
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "QOI data is truncated".to_owned());
+    while decoded < count {
+        let tag = *data.get(pos).ok_or_else(&truncated)?;
+        pos += 1;
+        let mut run = 1;
+        if tag == OP_RGB || tag == OP_RGBA {
+            let size = if tag == OP_RGB { 3 } else { 4 };
+            if data.len() < pos + size {
+                return Err(truncated());
+            }
+            px[..size].copy_from_slice(&data[pos..pos + size]);
+            pos += size;
+        } else {
+            match tag & OP_MASK {
+                OP_INDEX => px = index[tag as usize],
+                OP_DIFF => {
+                    px[0] = px[0].wrapping_add((tag >> 4 & 3).wrapping_sub(2));
+                    px[1] = px[1].wrapping_add((tag >> 2 & 3).wrapping_sub(2));
+                    px[2] = px[2].wrapping_add((tag & 3).wrapping_sub(2));
+                },
+                OP_LUMA => {
+                    let next = *data.get(pos).ok_or_else(&truncated)?;
+                    pos += 1;
+                    let dg = (tag & 0x3F).wrapping_sub(32);
+                    px[0] = px[0].wrapping_add(dg.wrapping_add(next >> 4).wrapping_sub(8));
+                    px[1] = px[1].wrapping_add(dg);
+                    px[2] = px[2].wrapping_add(dg.wrapping_add(next & 0x0F).wrapping_sub(8));
+                },
+                _ => run = (tag & 0x3F) as usize + 1,
+            }
+        }
+        index[hash(px)] = px;
+        for _ in 0..run.min(count - decoded) {
+            pixels.extend_from_slice(&px[..channels]);
+        }
+        decoded += run;
+    }
+    let data = if channels == 4 { bmp::PixelData::RGBA(pixels) } else { bmp::PixelData::RGB(pixels) };
+    Ok(bmp::Pixels::new(h.width, h.height, data))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// Encode pixels as QOI, `channels` is 3 or 4, by default 4 for RGBA pixels
+/// and 3 for the others
+pub fn save_to_writer<W: ?Sized + Write>(pixels: &bmp::Pixels, w: &mut W, channels: Option<u8>) -> io::Result<()> {
+    let channels = channels.unwrap_or(if pixels.has_alpha() { 4 } else { 3 });
+    let h = QOIHeader {
+        width: pixels.width,
+        height: pixels.height,
+        channels: channels,
+        colorspace: QOI_SRGB,
+    };
+    if !h.is_valid() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("QOI can't be {}x{} with {} channels", h.width, h.height, h.channels),
+        ));
+    }
+    let rgba = pixels.to_rgba();
+    let mut data = Vec::with_capacity(rgba.len() / 2);
+    let mut index = [[0u8; 4]; 64];
+    let mut prev = [0u8, 0, 0, 255];
+    let mut run = 0u8;
+    let count = rgba.len() / 4;
+    for (i, chunk) in rgba.chunks(4).enumerate() {
+        let mut px = [chunk[0], chunk[1], chunk[2], chunk[3]];
+        if channels == 3 {
+            px[3] = 255;
+        }
+        if px == prev {
+            run += 1;
+            if run == MAX_RUN || i + 1 == count {
+                data.push(OP_RUN | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+        if run > 0 {
+            data.push(OP_RUN | (run - 1));
+            run = 0;
+        }
+        let idx = hash(px);
+        if index[idx] == px {
+            data.push(OP_INDEX | idx as u8);
+        } else {
+            index[idx] = px;
+            if px[3] == prev[3] {
+                let dr = px[0].wrapping_sub(prev[0]) as i8;
+                let dg = px[1].wrapping_sub(prev[1]) as i8;
+                let db = px[2].wrapping_sub(prev[2]) as i8;
+                let (dr_dg, db_dg) = (dr.wrapping_sub(dg), db.wrapping_sub(dg));
+                if dr >= -2 && dr <= 1 && dg >= -2 && dg <= 1 && db >= -2 && db <= 1 {
+                    data.push(OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8);
+                } else if dg >= -32 && dg <= 31 && dr_dg >= -8 && dr_dg <= 7 && db_dg >= -8 && db_dg <= 7 {
+                    data.push(OP_LUMA | (dg + 32) as u8);
+                    data.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+                } else {
+                    data.extend_from_slice(&[OP_RGB, px[0], px[1], px[2]]);
+                }
+            } else {
+                data.extend_from_slice(&[OP_RGBA, px[0], px[1], px[2], px[3]]);
+            }
+        }
+        prev = px;
+    }
+    h.save_to_writer(w)?;
+    w.write_all(&data)?;
+    w.write_all(&QOI_END_MARKER)
+}
+
+pub fn save_to_file<P: AsRef<Path>>(pixels: &bmp::Pixels, p: P, channels: Option<u8>) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f, channels)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: [u8; 14] = [b'q', b'o', b'i', b'f', 0, 0, 0, 6, 0, 0, 0, 1, 3, 0];
+    /// Run, diff, luma, RGB and index operations
+    const OPS: [u8; 9] = [0xC1, 0x76, 0x9C, 0xFF, 0xFE, 100, 100, 100, 0x33];
+    const RGB: [u8; 18] = [0, 0, 0, 0, 0, 0, 1, 255, 0, 4, 251, 3, 100, 100, 100, 1, 255, 0];
+
+    #[test]
+    fn encode_ops() {
+        let pixels = bmp::Pixels::new(6, 1, bmp::PixelData::RGB(RGB.to_vec()));
+        let mut data = Vec::new();
+        save_to_writer(&pixels, &mut data, None).unwrap();
+        assert_eq!(&data[..14], &HEADER[..]);
+        assert_eq!(&data[14..data.len() - 8], &OPS[..]);
+        assert_eq!(&data[data.len() - 8..], &QOI_END_MARKER[..]);
+    }
+
+    #[test]
+    fn decode_ops() {
+        let mut data = HEADER.to_vec();
+        data.extend_from_slice(&OPS[..]);
+        data.extend_from_slice(&QOI_END_MARKER);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), RGB.to_vec());
+        // the last operation is cut
+        let mut data = HEADER.to_vec();
+        data.extend_from_slice(&OPS[..7]);
+        assert!(load_from_reader(&mut &data[..]).is_err());
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        let pixels = bmp::Pixels::new(150, 1, bmp::PixelData::RGB(vec![0; 450]));
+        let mut data = Vec::new();
+        save_to_writer(&pixels, &mut data, None).unwrap();
+        assert_eq!(&data[14..data.len() - 8], &[0xFD, 0xFD, 0xC0 | 25]);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), vec![0; 450]);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let rgba: Vec<u8> = (0..33 * 9 * 4).map(|i: u32| match i % 4 {
+            3 => if i / 4 % 5 == 0 { 128 } else { 255 },
+            c => ((i / 4 / 3) * (c + 1) * 5 + i / 400) as u8,
+        }).collect();
+        let rgba = bmp::Pixels::new(33, 9, bmp::PixelData::RGBA(rgba));
+        let rgb = bmp::Pixels::new(33, 9, bmp::PixelData::RGB((0..33 * 9 * 3).map(|i| (i * i / 7) as u8).collect()));
+        for &(pixels, channels) in [(&rgba, None), (&rgba, Some(4)), (&rgb, None), (&rgb, Some(4))].iter() {
+            let mut data = Vec::new();
+            save_to_writer(pixels, &mut data, channels).unwrap();
+            let loaded = load_from_reader(&mut &data[..]).unwrap();
+            assert_eq!(loaded.has_alpha(), channels == Some(4) || pixels.has_alpha());
+            assert_eq!(loaded.to_rgba(), pixels.to_rgba());
+        }
+        // 3 channels drop the alpha
+        let mut data = Vec::new();
+        save_to_writer(&rgba, &mut data, Some(3)).unwrap();
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), rgba.to_rgb());
+    }
+}
@@ -0,0 +1,392 @@
+//! # Color quantization
+//! Median cut palette reduction with optional Floyd-Steinberg dithering,
+//! black and white reduction by a brightness threshold.
+//! https://en.wikipedia.org/wiki/Median_cut
+//! https://en.wikipedia.org/wiki/Floyd%E2%80%93Steinberg_dithering
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use bmp;
+use color::ColorMetric;
+use pcx::RGBTriple;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Dither {
+    /// Every pixel takes the nearest palette color
+    None,
+    /// Quantization error is diffused to the neighbour pixels
+    FloydSteinberg,
+}
+
+pub const DITHER_MODES: [&'static str; 2] = ["none", "floyd-steinberg"];
+
+impl Default for Dither {
+    fn default() -> Dither {
+        Dither::None
+    }
+}
+
+impl fmt::Display for Dither {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Dither::None => "none",
+            Dither::FloydSteinberg => "floyd-steinberg",
+        })
+    }
+}
+
+impl FromStr for Dither {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<Dither> {
+        match s.to_lowercase().as_ref() {
+            "none" => Ok(Dither::None),
+            "floyd-steinberg" | "fs" => Ok(Dither::FloydSteinberg),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown dither mode: {}", s),
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Cube {
+    color: RGBTriple,
+    colors: Vec<RGBTriple>,
+}
+
+impl Cube {
+    fn new() -> Cube {
+        Cube{
+            color: RGBTriple::new(0, 0, 0),
+            colors: Vec::new(),
+        }
+    }
+    fn split(&mut self) -> Cube {
+        let mut min_red = 255_u8;
+        let mut min_green = 255_u8;
+        let mut min_blue = 255_u8;
+        let mut max_red = 0_u8;
+        let mut max_green = 0_u8;
+        let mut max_blue = 0_u8;
+
+        for c in &self.colors {
+            min_red = min_red.min(c.red);
+            max_red = max_red.max(c.red);
+
+            min_green = min_green.min(c.green);
+            max_green = max_green.max(c.green);
+
+            min_blue = min_blue.min(c.blue);
+            max_blue = max_blue.max(c.blue);
+        }
+
+        let red = (max_red - min_red) as f64;
+        let green = (max_green - min_green) as f64;
+        let blue = (max_blue - min_blue) as f64;
+        let max = red.max(green).max(blue);
+
+        self.colors.sort_by(|a, b| {
+            if max == red {
+                (a.red, a.green, a.blue).cmp(&(b.red, b.green, b.blue))
+            } else if max == green {
+                (a.green, a.red, a.blue).cmp(&(b.green, b.red, b.blue))
+            } else {
+                (a.blue, a.green, a.red).cmp(&(b.blue, b.green, b.red))
+            }
+        });
+        let split_at = self.colors.len()/2;
+        let colors = self.colors.split_off(split_at);
+        Cube{
+            color: RGBTriple::new(0, 0, 0),
+            colors: colors,
+        }
+    }
+    fn set_color(&mut self, freq: &HashMap<RGBTriple, f64>) {
+        let (mut red, mut green, mut blue) = (0_f64, 0_f64, 0_f64);
+        let total = self.colors.iter().fold(0f64, |acc, x| acc + freq.get(x).unwrap());
+        for color in &self.colors {
+            red += color.red as f64 * freq.get(color).unwrap();
+            green += color.green as f64 * freq.get(color).unwrap();
+            blue += color.blue as f64 * freq.get(color).unwrap();
+        }
+        red /= total;
+        green /= total;
+        blue /= total;
+        self.color = RGBTriple::new(red.round() as u8, green.round() as u8, blue.round() as u8);
+    }
+}
+
+#[derive(Debug)]
+struct Palette {
+    colors: usize,
+    cubes: Vec<Cube>,
+    frequency: HashMap<RGBTriple, f64>,
+    metric: ColorMetric,
+}
+
+impl Palette {
+    fn new(colors: usize, metric: ColorMetric) -> Palette {
+        Palette{
+            colors: colors,
+            cubes: Vec::with_capacity(colors),
+            frequency: HashMap::new(),
+            metric: metric,
+        }
+    }
+
+    /// Count colors of the pixels, unused palette colors are left out
+    /// as they would take palette entries from the used ones
+    fn compute_frequency(&mut self, pixels: &bmp::Pixels) {
+        for px in pixels.to_rgb().chunks(3) {
+            *self.frequency.entry(RGBTriple::new(px[0], px[1], px[2])).or_insert(0f64) += 1f64;
+        }
+    }
+
+    fn color_delta(&self, a: &RGBTriple, b: &RGBTriple) -> f64 {
+        self.metric.delta([a.red, a.green, a.blue], [b.red, b.green, b.blue])
+    }
+
+    fn round_palette(&mut self) {
+        let mut unique_colors: Vec<RGBTriple> = self.frequency.keys().cloned().collect();
+        unique_colors.sort_by_key(|c| (c.red, c.green, c.blue));
+        if unique_colors.len() <= self.colors {
+            // every used color keeps its own palette entry
+            self.cubes = unique_colors.into_iter().map(|c| Cube { color: c, colors: vec![c] }).collect();
+            return;
+        }
+        let mut cube = Cube::new();
+        cube.colors.append(&mut unique_colors);
+        self.cubes.push(cube);
+
+        // split the most populated cube until we get enough colors
+        while self.cubes.len() < self.colors {
+            let (idx, len) = self.cubes.iter()
+                .map(|c| c.colors.len())
+                .enumerate()
+                .max_by_key(|&(idx, len)| (len, -(idx as isize)))
+                .unwrap();
+            if len < 2 {
+                break; // less unique colors than requested
+            }
+            let new_cube = self.cubes[idx].split();
+            self.cubes.push(new_cube);
+        }
+        for c in &mut self.cubes {
+            c.set_color(&self.frequency);
+        }
+    }
+
+    fn cube_index(&self, c: RGBTriple) -> usize {
+        let mut nearest_cube = 0;
+        let mut delta = self.color_delta(&c, &self.cubes[0].color);
+
+        for idx in 1..self.cubes.len() {
+            let next_delta = self.color_delta(&c, &self.cubes[idx].color);
+            if delta > next_delta {
+                delta = next_delta;
+                nearest_cube = idx;
+            }
+        }
+        nearest_cube
+    }
+}
+
+/// Reduce image colors with median cut, the result has exactly `colors` palette entries
+pub fn quantize(pixels: &bmp::Pixels, colors: usize, metric: ColorMetric, dither: Dither) -> bmp::Pixels {
+    quantize_frames(&[pixels], colors, metric, dither).pop().unwrap()
+}
+
+/// Reduce colors of several images to the common palette of `colors` entries
+pub fn quantize_frames(frames: &[&bmp::Pixels], colors: usize, metric: ColorMetric, dither: Dither) -> Vec<bmp::Pixels> {
+    let mut palette = Palette::new(colors, metric);
+    for pixels in frames {
+        palette.compute_frequency(pixels);
+    }
+    palette.round_palette();
+
+    let mut bmi_colors: Vec<bmp::RGBQuad> = palette.cubes.iter()
+        .map(|c| bmp::RGBQuad::new(c.color.red, c.color.green, c.color.blue))
+        .collect();
+    // keep the requested palette size even if the image has less unique colors
+    bmi_colors.resize(colors, bmp::RGBQuad::new(0, 0, 0));
+
+    let mut remap = HashMap::new();
+    frames.iter().map(|pixels| {
+        let indexes = remap_pixels(pixels, &palette, &mut remap, dither);
+        bmp::Pixels::new(pixels.width, pixels.height, bmp::PixelData::Indexed {
+            palette: bmi_colors.clone(),
+            indexes: indexes,
+        })
+    }).collect()
+}
+
+/// Palette indexes of the pixels, `remap` caches the nearest palette colors
+fn remap_pixels(pixels: &bmp::Pixels, palette: &Palette, remap: &mut HashMap<RGBTriple, u8>, dither: Dither) -> Vec<u8> {
+    if pixels.width == 0 || pixels.height == 0 {
+        return Vec::new();
+    }
+    let rgb = pixels.to_rgb();
+    let mut nearest = |c: RGBTriple| *remap.entry(c).or_insert_with(|| palette.cube_index(c) as u8);
+    match dither {
+        Dither::None => rgb.chunks(3).map(|px| nearest(RGBTriple::new(px[0], px[1], px[2]))).collect(),
+        Dither::FloydSteinberg => {
+            let width = pixels.width as usize;
+            let mut indexes = Vec::with_capacity(rgb.len() / 3);
+            // error of the current and the next rows, shifted by one pixel
+            let mut error = vec![[0f64; 3]; width + 2];
+            let mut next_error = vec![[0f64; 3]; width + 2];
+            for row in rgb.chunks(width * 3) {
+                for (x, px) in row.chunks(3).enumerate() {
+                    let mut wanted = [0f64; 3];
+                    for ch in 0..3 {
+                        wanted[ch] = (px[ch] as f64 + error[x + 1][ch]).max(0f64).min(255f64);
+                    }
+                    let idx = nearest(RGBTriple::new(
+                        wanted[0].round() as u8, wanted[1].round() as u8, wanted[2].round() as u8,
+                    ));
+                    let c = palette.cubes[idx as usize].color;
+                    let got = [c.red as f64, c.green as f64, c.blue as f64];
+                    for ch in 0..3 {
+                        let e = wanted[ch] - got[ch];
+                        error[x + 2][ch] += e * 7f64 / 16f64;
+                        next_error[x][ch] += e * 3f64 / 16f64;
+                        next_error[x + 1][ch] += e * 5f64 / 16f64;
+                        next_error[x + 2][ch] += e / 16f64;
+                    }
+                    indexes.push(idx);
+                }
+                ::std::mem::swap(&mut error, &mut next_error);
+                for e in next_error.iter_mut() {
+                    *e = [0f64; 3];
+                }
+            }
+            indexes
+        },
+    }
+}
+
+/// Reduce image to black and white, pixels of average brightness at
+/// the `threshold` and above become white. Palette is black and white.
+pub fn monochrome(pixels: &bmp::Pixels, threshold: u8, dither: Dither) -> bmp::Pixels {
+    let width = pixels.width as usize;
+    let gray: Vec<f64> = pixels.to_rgb().chunks(3)
+        .map(|px| ((px[0] as u32 + px[1] as u32 + px[2] as u32) / 3) as f64)
+        .collect();
+    let indexes = match dither {
+        _ if pixels.width == 0 || pixels.height == 0 => Vec::new(),
+        Dither::None => gray.iter().map(|&g| (g >= threshold as f64) as u8).collect(),
+        Dither::FloydSteinberg => {
+            let mut indexes = Vec::with_capacity(gray.len());
+            // error of the current and the next rows, shifted by one pixel
+            let mut error = vec![0f64; width + 2];
+            let mut next_error = vec![0f64; width + 2];
+            for row in gray.chunks(width) {
+                for (x, &g) in row.iter().enumerate() {
+                    let wanted = g + error[x + 1];
+                    let white = wanted >= threshold as f64;
+                    let e = wanted - if white { 255f64 } else { 0f64 };
+                    error[x + 2] += e * 7f64 / 16f64;
+                    next_error[x] += e * 3f64 / 16f64;
+                    next_error[x + 1] += e * 5f64 / 16f64;
+                    next_error[x + 2] += e / 16f64;
+                    indexes.push(white as u8);
+                }
+                ::std::mem::swap(&mut error, &mut next_error);
+                for e in next_error.iter_mut() {
+                    *e = 0f64;
+                }
+            }
+            indexes
+        },
+    };
+    bmp::Pixels::new(pixels.width, pixels.height, bmp::PixelData::Indexed {
+        palette: vec![bmp::RGBQuad::new(0, 0, 0), bmp::RGBQuad::new(255, 255, 255)],
+        indexes: indexes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn distinct(pixels: &bmp::Pixels) -> usize {
+        pixels.to_rgb().chunks(3).collect::<HashSet<_>>().len()
+    }
+
+    /// Gray gradient of every level, left to right
+    fn gradient() -> bmp::Pixels {
+        bmp::Pixels::new(256, 4, bmp::PixelData::RGB((0..256 * 4).flat_map(|i| vec![(i % 256) as u8; 3]).collect()))
+    }
+
+    #[test]
+    fn used_colors_survive() {
+        // 7 colors of a 256 entry palette are used
+        let palette: Vec<bmp::RGBQuad> = (0..256).map(|i| bmp::RGBQuad::new(i as u8, (i * 91) as u8, (i * 13) as u8)).collect();
+        let used = [3, 40, 77, 120, 160, 201, 250];
+        let indexed = bmp::Pixels::new(16, 8, bmp::PixelData::Indexed {
+            palette: palette,
+            indexes: (0..128).map(|i| used[(i % 16 / 3 + i / 16) % 7]).collect(),
+        });
+        let rgb = bmp::Pixels::new(4, 4, bmp::PixelData::RGB((0..48).map(|i| (i * 16) as u8).collect()));
+        for &(ref pixels, colors) in &[(indexed, 16), (rgb.clone(), 16), (rgb, 20)] {
+            for &dither in &[Dither::None, Dither::FloydSteinberg] {
+                let reduced = quantize(pixels, colors, ColorMetric::default(), dither);
+                match reduced.data {
+                    bmp::PixelData::Indexed { ref palette, .. } => assert_eq!(palette.len(), colors),
+                    _ => panic!("quantized pixels are not indexed"),
+                }
+                assert_eq!(reduced.to_rgb(), pixels.to_rgb(), "{} colors, {} dither", colors, dither);
+            }
+        }
+    }
+
+    #[test]
+    fn median_cut() {
+        let pixels = gradient();
+        for &colors in &[2, 16, 200] {
+            let reduced = quantize(&pixels, colors, ColorMetric::default(), Dither::None);
+            assert_eq!(distinct(&reduced), colors);
+            // nearest colors keep the gradient order
+            let row: Vec<u8> = reduced.to_rgb().chunks(3).take(256).map(|px| px[0]).collect();
+            assert!(row.windows(2).all(|w| w[0] <= w[1]));
+            assert!(row.iter().zip(0..256).all(|(&v, l)| (v as i32 - l).abs() <= 256 / colors as i32));
+        }
+    }
+
+    #[test]
+    fn dithered_gradient() {
+        let pixels = gradient();
+        let mean = |p: &bmp::Pixels| p.to_rgb().iter().map(|&v| v as f64).sum::<f64>() / (256 * 4 * 3) as f64;
+        let reduced = quantize(&pixels, 2, ColorMetric::default(), Dither::FloydSteinberg);
+        assert_eq!(distinct(&reduced), 2);
+        assert!((mean(&reduced) - mean(&pixels)).abs() < 4f64);
+        // dithered pixels mix both colors between them, the plain ones are split in halves
+        let plain = quantize(&pixels, 2, ColorMetric::default(), Dither::None);
+        let changes = |p: &bmp::Pixels| p.to_rgb().chunks(3).take(256).collect::<Vec<_>>().windows(2)
+            .filter(|w| w[0] != w[1]).count();
+        assert_eq!(changes(&plain), 1);
+        assert!(changes(&reduced) > 20);
+
+        let mono = monochrome(&pixels, 128, Dither::FloydSteinberg);
+        assert!((mean(&mono) - mean(&pixels)).abs() < 4f64);
+        let mono = monochrome(&pixels, 100, Dither::None);
+        let white = mono.to_rgb().chunks(3).filter(|px| px[0] == 255).count();
+        assert_eq!(white, 156 * 4);
+    }
+
+    #[test]
+    fn empty_pixels() {
+        for &(width, height) in &[(0, 3), (3, 0), (0, 0)] {
+            let pixels = bmp::Pixels::new(width, height, bmp::PixelData::RGB(Vec::new()));
+            for &dither in &[Dither::None, Dither::FloydSteinberg] {
+                assert!(quantize(&pixels, 4, ColorMetric::default(), dither).to_rgb().is_empty());
+                assert!(monochrome(&pixels, 128, dither).to_rgb().is_empty());
+            }
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! # Format desciption
+//! Sun Raster: 32 byte big-endian header with magic, size, depth, length
+//! of the image data, type and colormap, then the colormap of all red,
+//! all green and all blue values, then rows padded to 16 bits.
+//! 24 and 32 bit pixels are BGR and XBGR, RGB and XRGB in the RGB type.
+//! Byte-encoded type compresses the whole image data with 0x80 escapes.
+//! https://www.fileformat.info/format/sunraster/egff.htm
+//! http://fileformats.archiveteam.org/wiki/Sun_Raster
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+
+pub const RAS_MAGIC: u32 = 0x59A6_6A95;
+pub const RAS_HEADER_SIZE: usize = 32;
+
+/// Image types
+pub const RT_OLD: u32 = 0;
+pub const RT_STANDARD: u32 = 1;
+pub const RT_BYTE_ENCODED: u32 = 2;
+pub const RT_FORMAT_RGB: u32 = 3;
+pub const RT_FORMAT_TIFF: u32 = 4;
+pub const RT_FORMAT_IFF: u32 = 5;
+
+/// Colormap types
+pub const RMT_NONE: u32 = 0;
+pub const RMT_EQUAL_RGB: u32 = 1;
+pub const RMT_RAW: u32 = 2;
+
+/// Escape byte of the byte-encoded type
+const RLE_ESCAPE: u8 = 0x80;
+/// Longest run of the byte-encoded type
+const RLE_MAX: usize = 256;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Clone)]
+pub struct RasterHeader {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+    /// Size of the image data, may be zero in old files
+    pub length: u32,
+    pub kind: u32,
+    pub map_type: u32,
+    pub map_length: u32,
+}
+
+impl RasterHeader {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<RasterHeader> {
+        let mut f = BufReader::new(File::open(p)?);
+        RasterHeader::load_from_reader(&mut f)
+    }
+
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<RasterHeader> {
+        if r.read_u32::<BigEndian>()? != RAS_MAGIC {
+            return Err(invalid_data("Invalid Sun Raster magic".to_owned()));
+        }
+        let h = RasterHeader {
+            width: r.read_u32::<BigEndian>()?,
+            height: r.read_u32::<BigEndian>()?,
+            depth: r.read_u32::<BigEndian>()?,
+            length: r.read_u32::<BigEndian>()?,
+            kind: r.read_u32::<BigEndian>()?,
+            map_type: r.read_u32::<BigEndian>()?,
+            map_length: r.read_u32::<BigEndian>()?,
+        };
+        if !h.is_valid() {
+            return Err(invalid_data(format!("Invalid Sun Raster header: {:?}", h)));
+        }
+        Ok(h)
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u32::<BigEndian>(RAS_MAGIC)?;
+        w.write_u32::<BigEndian>(self.width)?;
+        w.write_u32::<BigEndian>(self.height)?;
+        w.write_u32::<BigEndian>(self.depth)?;
+        w.write_u32::<BigEndian>(self.length)?;
+        w.write_u32::<BigEndian>(self.kind)?;
+        w.write_u32::<BigEndian>(self.map_type)?;
+        w.write_u32::<BigEndian>(self.map_length)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.width > 0 && self.height > 0 && [1, 8, 24, 32].contains(&self.depth)
+            && self.kind <= RT_FORMAT_IFF && self.map_type <= RMT_RAW
+            && (self.map_type != RMT_EQUAL_RGB || self.map_length % 3 == 0)
+    }
+
+    pub fn is_rle(&self) -> bool {
+        self.kind == RT_BYTE_ENCODED
+    }
+
+    /// Row size in bytes, padded to 16 bits
+    pub fn row_size(&self) -> io::Result<usize> {
+        (self.width as usize).checked_mul(self.depth as usize).and_then(|bits| bits.checked_add(15))
+            .map(|bits| bits / 16 * 2)
+            .ok_or_else(|| invalid_data(format!("Sun Raster row of {} px is too large", self.width)))
+    }
+}
+
+impl fmt::Display for RasterHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            RT_OLD => "old",
+            RT_STANDARD => "standard",
+            RT_BYTE_ENCODED => "byte-encoded",
+            RT_FORMAT_RGB => "RGB",
+            RT_FORMAT_TIFF => "TIFF",
+            _ => "IFF",
+        };
+        write!(f, "{}x{} px, {} bits, {} type", self.width, self.height, self.depth, kind)?;
+        match self.map_type {
+            RMT_EQUAL_RGB => write!(f, ", {} colors", self.map_length / 3),
+            RMT_RAW => write!(f, ", raw colormap of {} bytes", self.map_length),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Decode the byte-encoded image data of `size` bytes
+pub fn rle_decode(data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+    // the size comes from the header, no byte of data makes more than a run
+    let mut out = Vec::with_capacity(size.min(data.len().saturating_mul(RLE_MAX)));
+    let mut pos = 0;
+    while out.len() < size && pos < data.len() {
+        let b = data[pos];
+        pos += 1;
+        if b != RLE_ESCAPE {
+            out.push(b);
+            continue;
+        }
+        match data.get(pos) {
+            Some(&0) => {
+                out.push(RLE_ESCAPE);
+                pos += 1;
+            },
+            Some(&count) => {
+                let value = *data.get(pos + 1).ok_or_else(|| io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Sun Raster run is truncated".to_owned(),
+                ))?;
+                for _ in 0..count as usize + 1 {
+                    out.push(value);
+                }
+                pos += 2;
+            },
+            None => break,
+        }
+    }
+    if out.len() < size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Sun Raster data is truncated: {} bytes, expected {}", out.len(), size),
+        ));
+    }
+    out.truncate(size);
+    Ok(out)
+}
+
+/// Byte-encode image data, runs of 3 and more bytes and every escape byte
+/// become escape sequences
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut pos = 0;
+    while pos < data.len() {
+        let value = data[pos];
+        let run = data[pos..].iter().take(RLE_MAX).take_while(|&&b| b == value).count();
+        if run >= 3 || (value == RLE_ESCAPE && run == 2) {
+            out.extend_from_slice(&[RLE_ESCAPE, (run - 1) as u8, value]);
+            pos += run;
+        } else {
+            if value == RLE_ESCAPE {
+                out.extend_from_slice(&[RLE_ESCAPE, 0]);
+            } else {
+                out.push(value);
+            }
+            pos += 1;
+        }
+    }
+    out
+}
+
+/// Decode Sun Raster, colormap images become palette pixels, 8 bit images
+/// without colormap get a gray palette and 1 bit ones are black on white.
+/// The pad byte of 32 bit pixels is ignored.
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let h = RasterHeader::load_from_reader(r)?;
+    if h.kind == RT_FORMAT_TIFF || h.kind == RT_FORMAT_IFF {
+        return Err(invalid_data("Sun Raster of TIFF and IFF types are not supported".to_owned()));
+    }
+    let mut map = Vec::new();
+    r.take(h.map_length as u64).read_to_end(&mut map)?;
+    if map.len() < h.map_length as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Sun Raster colormap is truncated: {} bytes, expected {}", map.len(), h.map_length),
+        ));
+    }
+    let (width, height) = (h.width as usize, h.height as usize);
+    let row_size = h.row_size()?;
+    let size = row_size.checked_mul(height)
+        .ok_or_else(|| invalid_data(format!("Sun Raster image of {}x{} px is too large", width, height)))?;
+    let mut data = Vec::new();
+    if h.is_rle() && h.length > 0 {
+        r.take(h.length as u64).read_to_end(&mut data)?;
+    } else if h.is_rle() {
+        r.read_to_end(&mut data)?;
+    } else {
+        r.take(size as u64).read_to_end(&mut data)?;
+    }
+    let data = if h.is_rle() {
+        rle_decode(&data, size)?
+    } else if data.len() < size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Sun Raster data is truncated: {} bytes, expected {}", data.len(), size),
+        ));
+    } else {
+        data
+    };
+    let rows = data.chunks(row_size);
+    let pixel_data = match h.depth {
+        1 | 8 => {
+            let palette = if h.map_type == RMT_EQUAL_RGB && h.map_length > 0 {
+                let colors = map.len() / 3;
+                (0..colors).map(|i| bmp::RGBQuad::new(map[i], map[colors + i], map[2 * colors + i])).collect()
+            } else if h.depth == 1 {
+                vec![bmp::RGBQuad::new(255, 255, 255), bmp::RGBQuad::new(0, 0, 0)]
+            } else {
+                (0..256).map(|l| bmp::RGBQuad::new(l as u8, l as u8, l as u8)).collect()
+            };
+            let mut indexes = Vec::with_capacity(width * height);
+            for row in rows {
+                if h.depth == 1 {
+                    indexes.extend((0..width).map(|x| row[x / 8] >> (7 - x % 8) & 1));
+                } else {
+                    indexes.extend_from_slice(&row[..width]);
+                }
+            }
+            bmp::PixelData::Indexed {
+                palette: palette,
+                indexes: indexes,
+            }
+        },
+        _ => {
+            let bytes = h.depth as usize / 8;
+            let mut rgb = Vec::with_capacity(width * height * 3);
+            for row in rows {
+                for px in row[..width * bytes].chunks(bytes) {
+                    let px = &px[bytes - 3..];
+                    if h.kind == RT_FORMAT_RGB {
+                        rgb.extend_from_slice(px);
+                    } else {
+                        rgb.extend_from_slice(&[px[2], px[1], px[0]]);
+                    }
+                }
+            }
+            bmp::PixelData::RGB(rgb)
+        },
+    };
+    Ok(bmp::Pixels::new(h.width, h.height, pixel_data))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// Encode pixels as Sun Raster of the standard or byte-encoded type.
+/// Palette pixels are 8 bits with colormap by default, 1 bit requires
+/// at most 2 colors. Other pixels are 24 bit BGR, alpha is dropped.
+pub fn save_to_writer<W: ?Sized + Write>(
+    pixels: &bmp::Pixels, w: &mut W,
+    depth: Option<u8>,
+    rle: bool,
+) -> io::Result<()> {
+    let mut h = RasterHeader {
+        width: pixels.width,
+        height: pixels.height,
+        depth: 24,
+        length: 0,
+        kind: if rle { RT_BYTE_ENCODED } else { RT_STANDARD },
+        map_type: RMT_NONE,
+        map_length: 0,
+    };
+    let width = pixels.width as usize;
+    let mut map = Vec::new();
+    let mut data = Vec::new();
+    match (&pixels.data, depth) {
+        (&bmp::PixelData::Indexed { ref palette, ref indexes }, None) |
+        (&bmp::PixelData::Indexed { ref palette, ref indexes }, Some(1)) |
+        (&bmp::PixelData::Indexed { ref palette, ref indexes }, Some(8)) => {
+            h.depth = match depth {
+                Some(1) if palette.len() <= 2 => 1,
+                Some(1) => return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} colors don't fit into 1 bit Sun Raster", palette.len()),
+                )),
+                _ => 8,
+            };
+            h.map_type = RMT_EQUAL_RGB;
+            h.map_length = palette.len() as u32 * 3;
+            map.extend(palette.iter().map(|c| c.red()));
+            map.extend(palette.iter().map(|c| c.green()));
+            map.extend(palette.iter().map(|c| c.blue()));
+            let row_size = h.row_size()?;
+            for row in indexes.chunks(width) {
+                let mut packed = vec![0u8; row_size];
+                if h.depth == 1 {
+                    for (x, &i) in row.iter().enumerate() {
+                        packed[x / 8] |= (i & 1) << (7 - x % 8);
+                    }
+                } else {
+                    packed[..width].copy_from_slice(row);
+                }
+                data.extend_from_slice(&packed);
+            }
+        },
+        (_, Some(1)) | (_, Some(8)) => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "1 and 8 bit Sun Raster requires a palette image".to_owned(),
+        )),
+        (_, None) | (_, Some(24)) | (_, Some(32)) => {
+            h.depth = depth.unwrap_or(24) as u32;
+            let row_size = h.row_size()?;
+            for row in pixels.to_rgb().chunks(width * 3) {
+                let mut packed = Vec::with_capacity(row_size);
+                for px in row.chunks(3) {
+                    if h.depth == 32 {
+                        packed.push(0);
+                    }
+                    packed.extend_from_slice(&[px[2], px[1], px[0]]);
+                }
+                packed.resize(row_size, 0);
+                data.extend_from_slice(&packed);
+            }
+        },
+        (_, Some(bits)) => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported Sun Raster bits per pixel: {}", bits),
+        )),
+    }
+    let data = if rle { rle_encode(&data) } else { data };
+    h.length = data.len() as u32;
+    h.save_to_writer(w)?;
+    w.write_all(&map)?;
+    w.write_all(&data)
+}
+
+pub fn save_to_file<P: AsRef<Path>>(
+    pixels: &bmp::Pixels, p: P,
+    depth: Option<u8>,
+    rle: bool,
+) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f, depth, rle)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rle_escapes() {
+        let data = [1, 2, 2, 2, 0x80, 0x80, 0x80, 5, 0x80, 6, 6, 0x80, 0x80];
+        let encoded = vec![1, 0x80, 2, 2, 0x80, 2, 0x80, 5, 0x80, 0, 6, 6, 0x80, 1, 0x80];
+        assert_eq!(rle_encode(&data), encoded);
+        assert_eq!(rle_decode(&encoded, data.len()).unwrap(), data.to_vec());
+        // runs are upto 256 bytes
+        assert_eq!(rle_encode(&[7; 300]), vec![0x80, 255, 7, 0x80, 43, 7]);
+        assert_eq!(rle_decode(&[0x80, 255, 7, 0x80, 43, 7], 300).unwrap(), vec![7; 300]);
+        assert!(rle_decode(&[0x80, 3], 4).is_err());
+        assert!(rle_decode(&[1, 2], 3).is_err());
+    }
+
+    #[test]
+    fn load_rgb_format() {
+        let h = RasterHeader {
+            width: 2,
+            height: 1,
+            depth: 24,
+            length: 6,
+            kind: RT_FORMAT_RGB,
+            map_type: RMT_NONE,
+            map_length: 0,
+        };
+        let mut data = Vec::new();
+        h.save_to_writer(&mut data).unwrap();
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 0, 0]);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn huge_sizes_are_rejected() {
+        let load = |width: u32, height: u32, kind: u32, map_type: u32, map_length: u32| {
+            let h = RasterHeader {
+                width: width,
+                height: height,
+                depth: 32,
+                length: 0,
+                kind: kind,
+                map_type: map_type,
+                map_length: map_length,
+            };
+            let mut data = Vec::new();
+            h.save_to_writer(&mut data).unwrap();
+            data.extend_from_slice(&[0x80, 255, 7, 1, 2, 3]);
+            load_from_reader(&mut &data[..]).unwrap_err().kind()
+        };
+        assert_eq!(load(1310733, 4278190208, RT_BYTE_ENCODED, RMT_NONE, 0), io::ErrorKind::UnexpectedEof);
+        assert_eq!(load(1310733, 4278190208, RT_STANDARD, RMT_NONE, 0), io::ErrorKind::UnexpectedEof);
+        assert_eq!(load(u32::MAX, u32::MAX, RT_BYTE_ENCODED, RMT_NONE, 0), io::ErrorKind::InvalidData);
+        assert_eq!(load(2, 2, RT_STANDARD, RMT_RAW, u32::MAX), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let indexed = |colors: u32| bmp::Pixels::new(13, 3, bmp::PixelData::Indexed {
+            palette: (0..colors).map(|i| bmp::RGBQuad::new(i as u8, 0x80, 255 - i as u8)).collect(),
+            indexes: (0..39).map(|i| if i < 20 { 1 } else { (i * 5 % colors) as u8 }).collect(),
+        });
+        let rgb = bmp::Pixels::new(13, 3, bmp::PixelData::RGB((0..117).map(|i| if i < 50 { 0x80 } else { i as u8 }).collect()));
+        let images = [(indexed(2), Some(1)), (indexed(2), None), (indexed(200), Some(8)), (rgb.clone(), None), (rgb, Some(32))];
+        for &rle in [false, true].iter() {
+            for &(ref pixels, depth) in images.iter() {
+                let mut data = Vec::new();
+                save_to_writer(pixels, &mut data, depth, rle).unwrap();
+                let h = RasterHeader::load_from_reader(&mut &data[..]).unwrap();
+                assert_eq!(h.is_rle(), rle);
+                assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), pixels.to_rgb());
+            }
+        }
+    }
+}
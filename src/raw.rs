@@ -0,0 +1,426 @@
+//! # Format desciption
+//! Raw framebuffer: headerless pixels of 8, 16, 24 or 32 bits, rows of
+//! `stride` bytes, top-down unless told otherwise.
+//! Pixel format names list the channels from the most significant bits
+//! of the pixel value followed by the channel sizes, as DRM formats do:
+//! RGB565, BGR888, RGBA8888, XRGB1555, GRAY8, GRAY16, ARGB4444.
+//! R, G, B and A are the color and alpha channels, X is unused,
+//! GRAY (or L) is the gray level. Pixel values are little-endian unless
+//! big-endian byte order is given, so XRGB8888 pixels are B, G, R, X bytes.
+//! https://docs.kernel.org/gpu/drm-kms.html#drm-format-handling
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use bmp;
+use code::ByteOrder;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+    Alpha,
+    Gray,
+    /// Padding bits
+    Unused,
+}
+
+/// Channels and their sizes in bits, the most significant first
+#[derive(Debug, Clone, PartialEq)]
+pub struct PixelFormat {
+    pub channels: Vec<(Channel, u32)>,
+}
+
+impl PixelFormat {
+    pub fn bits(&self) -> u32 {
+        self.channels.iter().map(|&(_, size)| size).sum()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    /// Bits of `channel` in the pixel value, 0 if there is no such channel
+    pub fn mask(&self, channel: Channel) -> u32 {
+        let mut shift = self.bits();
+        for &(c, size) in &self.channels {
+            shift -= size;
+            if c == channel {
+                return (((1u64 << size) - 1) << shift) as u32;
+            }
+        }
+        0
+    }
+
+    /// Red, green, blue and alpha masks
+    pub fn masks(&self) -> [u32; 4] {
+        [self.mask(Channel::Red), self.mask(Channel::Green), self.mask(Channel::Blue), self.mask(Channel::Alpha)]
+    }
+
+    pub fn is_gray(&self) -> bool {
+        self.mask(Channel::Gray) != 0
+    }
+}
+
+impl fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(c, _) in &self.channels {
+            f.write_str(match c {
+                Channel::Red => "R",
+                Channel::Green => "G",
+                Channel::Blue => "B",
+                Channel::Alpha => "A",
+                Channel::Gray => "GRAY",
+                Channel::Unused => "X",
+            })?;
+        }
+        let wide = self.channels.iter().any(|&(_, size)| size > 9);
+        for &(_, size) in &self.channels {
+            if wide {
+                write!(f, "{:02}", size)?;
+            } else {
+                write!(f, "{}", size)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PixelFormat {
+    type Err = io::Error;
+    fn from_str(s: &str) -> io::Result<PixelFormat> {
+        let invalid = || io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid pixel format: {}", s),
+        );
+        let name = s.to_uppercase();
+        let split = name.find(|c: char| c.is_ascii_digit()).ok_or_else(&invalid)?;
+        let (mut letters, digits) = name.split_at(split);
+        let mut kinds = Vec::new();
+        while !letters.is_empty() {
+            if letters.starts_with("GRAY") || letters.starts_with("GREY") {
+                kinds.push(Channel::Gray);
+                letters = &letters[4..];
+                continue;
+            }
+            kinds.push(match letters.as_bytes()[0] {
+                b'R' => Channel::Red,
+                b'G' => Channel::Green,
+                b'B' => Channel::Blue,
+                b'A' => Channel::Alpha,
+                b'L' | b'Y' => Channel::Gray,
+                b'X' => Channel::Unused,
+                _ => return Err(invalid()),
+            });
+            letters = &letters[1..];
+        }
+        if !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        // one digit per channel, or two digits for 16 bit channels
+        let sizes: Vec<u32> = if digits.len() == kinds.len() {
+            digits.chars().map(|c| c as u32 - '0' as u32).collect()
+        } else if digits.len() == 2 * kinds.len() {
+            digits.as_bytes().chunks(2).map(|d| ((d[0] - b'0') * 10 + d[1] - b'0') as u32).collect()
+        } else {
+            return Err(invalid());
+        };
+        let format = PixelFormat {
+            channels: kinds.into_iter().zip(sizes).collect(),
+        };
+        let colors = [Channel::Red, Channel::Green, Channel::Blue].iter().filter(|&&c| format.mask(c) != 0).count();
+        let valid = [8, 16, 24, 32].contains(&format.bits())
+            && format.channels.iter().all(|&(_, size)| (1..=16).contains(&size))
+            && (colors == 3 || colors == 0 && format.is_gray())
+            && !(colors == 3 && format.is_gray());
+        if !valid {
+            return Err(invalid());
+        }
+        Ok(format)
+    }
+}
+
+/// Layout of the raw pixels
+#[derive(Debug, Clone)]
+pub struct RawFormat {
+    pub format: PixelFormat,
+    pub byte_order: ByteOrder,
+    /// Bytes per row, rows are not padded if not set
+    pub stride: Option<usize>,
+    /// The first row is the bottom one
+    pub bottom_up: bool,
+}
+
+impl RawFormat {
+    pub fn new(format: PixelFormat) -> RawFormat {
+        RawFormat {
+            format: format,
+            byte_order: ByteOrder::Little,
+            stride: None,
+            bottom_up: false,
+        }
+    }
+
+    pub fn row_size(&self, width: u32) -> usize {
+        width as usize * self.format.bytes()
+    }
+
+    pub fn stride(&self, width: u32) -> usize {
+        self.stride.unwrap_or_else(|| self.row_size(width))
+    }
+}
+
+impl fmt::Display for RawFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.format, self.byte_order)?;
+        if let Some(stride) = self.stride {
+            write!(f, ", stride {} bytes", stride)?;
+        }
+        if self.bottom_up {
+            write!(f, ", bottom-up")?;
+        }
+        Ok(())
+    }
+}
+
+/// Read top-down pixel values
+fn load_values<R: ?Sized + Read>(r: &mut R, width: u32, height: u32, raw: &RawFormat) -> io::Result<Vec<u32>> {
+    let bytes = raw.format.bytes();
+    let too_large = || io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Raw image of {}x{} px is too large", width, height),
+    );
+    let row_size = (width as usize).checked_mul(bytes).ok_or_else(&too_large)?;
+    let stride = raw.stride.unwrap_or(row_size);
+    if width == 0 || height == 0 || stride < row_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid raw image of {}x{} px and stride {} bytes", width, height, stride),
+        ));
+    }
+    // the last row needs no padding
+    let size = stride.checked_mul(height as usize - 1)
+        .and_then(|s| s.checked_add(row_size))
+        .ok_or_else(&too_large)?;
+    // the data is read as it comes, the size may be far beyond the input
+    let mut data = Vec::new();
+    r.take(size as u64).read_to_end(&mut data)?;
+    if data.len() < size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Raw pixels are truncated: {} bytes, expected {}", data.len(), size),
+        ));
+    }
+    let mut values = Vec::with_capacity(width as usize * height as usize);
+    for y in 0..height as usize {
+        let row = if raw.bottom_up { height as usize - 1 - y } else { y };
+        let line = &data[row * stride..row * stride + row_size];
+        for px in line.chunks(bytes) {
+            values.push(match raw.byte_order {
+                ByteOrder::Little => px.iter().rev().fold(0u32, |v, &b| v << 8 | b as u32),
+                ByteOrder::Big => px.iter().fold(0u32, |v, &b| v << 8 | b as u32),
+            });
+        }
+    }
+    Ok(values)
+}
+
+fn to_pixels(values: &[u32], width: u32, height: u32, format: &PixelFormat) -> bmp::Pixels {
+    let [red, green, blue, alpha] = format.masks();
+    let data = if format.is_gray() {
+        let gray = format.mask(Channel::Gray);
+        if alpha == 0 {
+            bmp::PixelData::Indexed {
+                palette: (0..256).map(|l| bmp::RGBQuad::new(l as u8, l as u8, l as u8)).collect(),
+                indexes: values.iter().map(|&v| bmp::mask_channel(v, gray)).collect(),
+            }
+        } else {
+            bmp::PixelData::RGBA(values.iter().flat_map(|&v| {
+                let l = bmp::mask_channel(v, gray);
+                vec![l, l, l, bmp::mask_channel(v, alpha)]
+            }).collect())
+        }
+    } else if alpha == 0 {
+        bmp::PixelData::RGB(values.iter().flat_map(|&v| {
+            vec![bmp::mask_channel(v, red), bmp::mask_channel(v, green), bmp::mask_channel(v, blue)]
+        }).collect())
+    } else {
+        bmp::PixelData::RGBA(values.iter().flat_map(|&v| {
+            vec![
+                bmp::mask_channel(v, red), bmp::mask_channel(v, green),
+                bmp::mask_channel(v, blue), bmp::mask_channel(v, alpha),
+            ]
+        }).collect())
+    };
+    bmp::Pixels::new(width, height, data)
+}
+
+/// Decode raw pixels of `width` by `height` pixels
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R, width: u32, height: u32, raw: &RawFormat) -> io::Result<bmp::Pixels> {
+    let values = load_values(r, width, height, raw)?;
+    Ok(to_pixels(&values, width, height, &raw.format))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P, width: u32, height: u32, raw: &RawFormat) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f, width, height, raw)
+}
+
+/// Decode raw pixels as BMP. 16 and 32 bit color pixels keep their values
+/// in a bitfields bitmap, the others are converted.
+pub fn load_bmp_from_reader<R: ?Sized + Read>(r: &mut R, width: u32, height: u32, raw: &RawFormat) -> io::Result<bmp::BMPImage> {
+    let values = load_values(r, width, height, raw)?;
+    let bits = raw.format.bits();
+    if (bits == 16 || bits == 32) && !raw.format.is_gray() {
+        bmp::BMPImage::from_bitfields(width as i32, height as i32, bits as i16, raw.format.masks(), &values)
+    } else {
+        bmp::BMPImage::from_pixels(&to_pixels(&values, width, height, &raw.format))
+    }
+}
+
+pub fn load_bmp_from_file<P: AsRef<Path>>(p: P, width: u32, height: u32, raw: &RawFormat) -> io::Result<bmp::BMPImage> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_bmp_from_reader(&mut f, width, height, raw)
+}
+
+/// Scale 8 bit sample into `mask`, the smallest value `bmp::mask_channel`
+/// decodes back to the sample, so imported pixels are exported unchanged
+fn unmask_channel(sample: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let max = (mask >> shift) as u64;
+    (((sample as u64 * max + 254) / 255) as u32) << shift
+}
+
+/// Encode pixels as raw pixels, unused bits and row padding are zero
+pub fn save_to_writer<W: ?Sized + Write>(pixels: &bmp::Pixels, w: &mut W, raw: &RawFormat) -> io::Result<()> {
+    let (row_size, stride) = (raw.row_size(pixels.width), raw.stride(pixels.width));
+    if stride < row_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Stride {} bytes is less than {} bytes of the row", stride, row_size),
+        ));
+    }
+    let [red, green, blue, alpha] = raw.format.masks();
+    let gray = raw.format.mask(Channel::Gray);
+    let bytes = raw.format.bytes();
+    let mut rows: Vec<Vec<u8>> = pixels.to_rgba().chunks(pixels.width as usize * 4).map(|row| {
+        let mut line = Vec::with_capacity(stride);
+        for px in row.chunks(4) {
+            let l = ((px[0] as u32 + px[1] as u32 + px[2] as u32) / 3) as u8;
+            let value = unmask_channel(px[0], red) | unmask_channel(px[1], green) | unmask_channel(px[2], blue)
+                | unmask_channel(px[3], alpha) | unmask_channel(l, gray);
+            for i in 0..bytes {
+                let shift = match raw.byte_order {
+                    ByteOrder::Little => 8 * i,
+                    ByteOrder::Big => 8 * (bytes - 1 - i),
+                };
+                line.push((value >> shift) as u8);
+            }
+        }
+        line.resize(stride, 0);
+        line
+    }).collect();
+    if raw.bottom_up {
+        rows.reverse();
+    }
+    for row in rows {
+        w.write_all(&row)?;
+    }
+    Ok(())
+}
+
+pub fn save_to_file<P: AsRef<Path>>(pixels: &bmp::Pixels, p: P, raw: &RawFormat) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f, raw)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(name: &str) -> PixelFormat {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn pixel_format_from_str() {
+        assert_eq!(format("rgb565").masks(), [0xF800, 0x7E0, 0x1F, 0]);
+        assert_eq!(format("XRGB1555").masks(), [0x7C00, 0x3E0, 0x1F, 0]);
+        assert_eq!(format("ARGB4444").masks(), [0xF00, 0xF0, 0xF, 0xF000]);
+        assert_eq!(format("BGRX8888").masks(), [0xFF00, 0xFF_0000, 0xFF00_0000, 0]);
+        let gray = format("gray16");
+        assert!(gray.is_gray());
+        assert_eq!((gray.bits(), gray.mask(Channel::Gray)), (16, 0xFFFF));
+        assert_eq!(format("LA88").masks(), [0, 0, 0, 0xFF]);
+        assert_eq!(format("grey8"), format("Y8"));
+        for name in &["RGB565", "XRGB1555", "GRAY16", "BGRA8888"] {
+            assert_eq!(&format(name).to_string(), name);
+        }
+        for name in &["", "rgb", "565", "rgb56", "rgb5650", "rgbz565", "rgb999", "rgb444", "ra88", "rgbl4444", "rgba16161616"] {
+            assert!(name.parse::<PixelFormat>().is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn load_layouts() {
+        let mut raw = RawFormat::new(format("RGB565"));
+        let data = [0x00, 0xF8, 0xE0, 0x07];
+        assert_eq!(load_from_reader(&mut &data[..], 2, 1, &raw).unwrap().to_rgb(), vec![255, 0, 0, 0, 255, 0]);
+        raw.byte_order = ByteOrder::Big;
+        let data = [0xF8, 0x00, 0x07, 0xE0];
+        assert_eq!(load_from_reader(&mut &data[..], 2, 1, &raw).unwrap().to_rgb(), vec![255, 0, 0, 0, 255, 0]);
+
+        // padded rows from the bottom, the last row has no padding
+        let mut raw = RawFormat::new(format("GRAY8"));
+        raw.stride = Some(3);
+        raw.bottom_up = true;
+        let data = [1, 2, 0, 3, 4];
+        assert_eq!(load_from_reader(&mut &data[..], 2, 2, &raw).unwrap().to_rgb(), vec![3, 3, 3, 4, 4, 4, 1, 1, 1, 2, 2, 2]);
+        assert!(load_from_reader(&mut &data[..4], 2, 2, &raw).is_err());
+    }
+
+    #[test]
+    fn huge_size_is_rejected() {
+        let mut raw = RawFormat::new(format("XRGB8888"));
+        raw.stride = Some(::std::usize::MAX);
+        let err = load_from_reader(&mut &[0u8; 16][..], 2, 3, &raw).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // the size fits, but there is no such data
+        raw.stride = Some(1 << 40);
+        let err = load_from_reader(&mut &[0u8; 16][..], 2, 3, &raw).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let rgba = bmp::Pixels::new(5, 3, bmp::PixelData::RGBA((0..60).map(|i| (i * 17) as u8).collect()));
+        for name in &["RGB565", "XRGB1555", "ARGB4444", "BGR888", "RGBA8888", "GRAY8", "GRAY16", "LA88", "RGB332"] {
+            for &(big, stride, bottom_up) in &[(false, None, false), (true, Some(50), true)] {
+                let mut raw = RawFormat::new(format(name));
+                if big {
+                    raw.byte_order = ByteOrder::Big;
+                }
+                raw.stride = stride;
+                raw.bottom_up = bottom_up;
+                // the first pass quantizes, the next ones keep the values
+                let mut data = Vec::new();
+                save_to_writer(&rgba, &mut data, &raw).unwrap();
+                let loaded = load_from_reader(&mut &data[..], 5, 3, &raw).unwrap();
+                let mut again = Vec::new();
+                save_to_writer(&loaded, &mut again, &raw).unwrap();
+                assert_eq!(again, data, "{}", raw);
+                if name.ends_with("8888") {
+                    assert_eq!(loaded.to_rgba(), rgba.to_rgba());
+                }
+            }
+        }
+    }
+}
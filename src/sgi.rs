@@ -0,0 +1,448 @@
+//! # Format desciption
+//! SGI image: 512 byte big-endian header with magic, storage, bytes per
+//! channel, dimension, size and number of channels, then every channel
+//! as a separate plane of rows from bottom to top. Verbatim storage keeps
+//! the samples as they are, RLE storage has tables of row offsets and
+//! lengths followed by rows of literal and repeat packets.
+//! Channels are gray, gray with alpha, RGB or RGBA.
+//! https://paulbourke.net/dataformats/sgirgb/sgiversion.html
+//! https://en.wikipedia.org/wiki/Silicon_Graphics_Image
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+
+pub const SGI_MAGIC: u16 = 474;
+pub const SGI_HEADER_SIZE: usize = 512;
+
+/// Storage values
+pub const STORAGE_VERBATIM: u8 = 0;
+pub const STORAGE_RLE: u8 = 1;
+
+/// Colormap values, all but normal are obsolete
+pub const COLORMAP_NORMAL: u32 = 0;
+pub const COLORMAP_DITHERED: u32 = 1;
+pub const COLORMAP_SCREEN: u32 = 2;
+pub const COLORMAP_COLORMAP: u32 = 3;
+
+const IMAGE_NAME_SIZE: usize = 80;
+/// Longest literal or repeat packet
+const RLE_MAX: usize = 127;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "SGI data is truncated".to_owned())
+}
+
+#[derive(Debug, Clone)]
+pub struct SGIHeader {
+    pub storage: u8,
+    /// Bytes per channel sample, 1 or 2
+    pub bpc: u8,
+    pub dimension: u16,
+    pub width: u16,
+    pub height: u16,
+    pub channels: u16,
+    pub pixmin: u32,
+    pub pixmax: u32,
+    pub name: String,
+    pub colormap: u32,
+}
+
+impl SGIHeader {
+    pub fn new(width: u16, height: u16, channels: u16) -> SGIHeader {
+        SGIHeader {
+            storage: STORAGE_VERBATIM,
+            bpc: 1,
+            dimension: if channels > 1 { 3 } else if height > 1 { 2 } else { 1 },
+            width: width,
+            height: height,
+            channels: channels,
+            pixmin: 0,
+            pixmax: 255,
+            name: String::new(),
+            colormap: COLORMAP_NORMAL,
+        }
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<SGIHeader> {
+        let mut f = BufReader::new(File::open(p)?);
+        SGIHeader::load_from_reader(&mut f)
+    }
+
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<SGIHeader> {
+        if r.read_u16::<BigEndian>()? != SGI_MAGIC {
+            return Err(invalid_data("Invalid SGI magic".to_owned()));
+        }
+        let storage = r.read_u8()?;
+        let bpc = r.read_u8()?;
+        let dimension = r.read_u16::<BigEndian>()?;
+        let mut h = SGIHeader {
+            storage: storage,
+            bpc: bpc,
+            dimension: dimension,
+            width: r.read_u16::<BigEndian>()?,
+            height: r.read_u16::<BigEndian>()?,
+            channels: r.read_u16::<BigEndian>()?,
+            pixmin: r.read_u32::<BigEndian>()?,
+            pixmax: r.read_u32::<BigEndian>()?,
+            name: String::new(),
+            colormap: 0,
+        };
+        r.read_u32::<BigEndian>()?;
+        let mut name = [0u8; IMAGE_NAME_SIZE];
+        r.read_exact(&mut name)?;
+        let len = name.iter().position(|&b| b == 0).unwrap_or(IMAGE_NAME_SIZE);
+        h.name = String::from_utf8_lossy(&name[..len]).into_owned();
+        h.colormap = r.read_u32::<BigEndian>()?;
+        let mut dummy = [0u8; SGI_HEADER_SIZE - 108];
+        r.read_exact(&mut dummy)?;
+        // dimension 1 and 2 images have a single row and channel
+        match h.dimension {
+            1 => {
+                h.height = 1;
+                h.channels = 1;
+            },
+            2 => h.channels = 1,
+            _ => {},
+        }
+        if !h.is_valid() {
+            return Err(invalid_data(format!("Invalid SGI header: {:?}", h)));
+        }
+        Ok(h)
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u16::<BigEndian>(SGI_MAGIC)?;
+        w.write_u8(self.storage)?;
+        w.write_u8(self.bpc)?;
+        w.write_u16::<BigEndian>(self.dimension)?;
+        w.write_u16::<BigEndian>(self.width)?;
+        w.write_u16::<BigEndian>(self.height)?;
+        w.write_u16::<BigEndian>(self.channels)?;
+        w.write_u32::<BigEndian>(self.pixmin)?;
+        w.write_u32::<BigEndian>(self.pixmax)?;
+        w.write_u32::<BigEndian>(0)?;
+        let mut name = [0u8; IMAGE_NAME_SIZE];
+        let len = self.name.len().min(IMAGE_NAME_SIZE - 1);
+        name[..len].copy_from_slice(&self.name.as_bytes()[..len]);
+        w.write_all(&name)?;
+        w.write_u32::<BigEndian>(self.colormap)?;
+        w.write_all(&[0u8; SGI_HEADER_SIZE - 108])
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.storage <= STORAGE_RLE && (self.bpc == 1 || self.bpc == 2)
+            && self.dimension >= 1 && self.dimension <= 3
+            && self.width > 0 && self.height > 0 && self.channels >= 1 && self.channels <= 4
+    }
+
+    pub fn is_rle(&self) -> bool {
+        self.storage == STORAGE_RLE
+    }
+}
+
+impl fmt::Display for SGIHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let channels = match self.channels {
+            1 => "gray",
+            2 => "gray with alpha",
+            3 => "RGB",
+            _ => "RGBA",
+        };
+        write!(
+            f,
+            "{}x{} px, {}, {} bits, {}",
+            self.width, self.height, channels, self.bpc as u32 * 8,
+            if self.is_rle() { "RLE" } else { "verbatim" },
+        )?;
+        if self.colormap != COLORMAP_NORMAL {
+            write!(f, ", colormap {}", self.colormap)?;
+        }
+        if !self.name.is_empty() {
+            write!(f, ", name {:?}", self.name)?;
+        }
+        Ok(())
+    }
+}
+
+fn read_sample(data: &[u8], pos: usize, bpc: usize) -> io::Result<u16> {
+    match data.get(pos..pos + bpc) {
+        Some(s) if bpc == 2 => Ok((s[0] as u16) << 8 | s[1] as u16),
+        Some(s) => Ok(s[0] as u16),
+        None => Err(truncated()),
+    }
+}
+
+/// Decode a RLE row starting at `pos` of the data following the header
+fn rle_decode_row(data: &[u8], mut pos: usize, bpc: usize, width: usize) -> io::Result<Vec<u16>> {
+    let mut row = Vec::with_capacity(width);
+    while row.len() < width {
+        let packet = read_sample(data, pos, bpc)?;
+        pos += bpc;
+        let count = (packet & 0x7F) as usize;
+        if count == 0 {
+            break;
+        }
+        if packet & 0x80 != 0 {
+            for _ in 0..count {
+                row.push(read_sample(data, pos, bpc)?);
+                pos += bpc;
+            }
+        } else {
+            let value = read_sample(data, pos, bpc)?;
+            pos += bpc;
+            for _ in 0..count {
+                row.push(value);
+            }
+        }
+    }
+    if row.len() < width {
+        return Err(invalid_data(format!("SGI RLE row is short: {} samples, expected {}", row.len(), width)));
+    }
+    row.truncate(width);
+    Ok(row)
+}
+
+/// RLE encode a row of 8 bit samples, literal packets stop at runs of 3
+fn rle_encode_row(row: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(row.len() + row.len() / RLE_MAX + 2);
+    let mut pos = 0;
+    while pos < row.len() {
+        let start = pos;
+        while pos < row.len() && !(pos + 2 < row.len() && row[pos] == row[pos + 1] && row[pos] == row[pos + 2]) {
+            pos += 1;
+        }
+        for literal in row[start..pos].chunks(RLE_MAX) {
+            out.push(0x80 | literal.len() as u8);
+            out.extend_from_slice(literal);
+        }
+        if pos < row.len() {
+            let value = row[pos];
+            let run = row[pos..].iter().take(RLE_MAX).take_while(|&&b| b == value).count();
+            out.extend_from_slice(&[run as u8, value]);
+            pos += run;
+        }
+    }
+    out.push(0);
+    out
+}
+
+/// Decode SGI image, gray images become palette pixels with gray palette,
+/// the others RGB or RGBA. 16 bit samples are scaled down to 8 bits.
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let h = SGIHeader::load_from_reader(r)?;
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    let (width, height, channels) = (h.width as usize, h.height as usize, h.channels as usize);
+    let bpc = h.bpc as usize;
+    // planes[z][y] is the row y from the bottom of channel z
+    let mut planes = Vec::with_capacity(channels);
+    if h.is_rle() {
+        let tables = height * channels;
+        let mut offsets = Vec::with_capacity(tables);
+        for i in 0..tables {
+            let offset = read_u32(&data, i * 4)? as usize;
+            if offset < SGI_HEADER_SIZE {
+                return Err(invalid_data(format!("Invalid SGI row offset: {}", offset)));
+            }
+            offsets.push(offset - SGI_HEADER_SIZE);
+        }
+        for z in 0..channels {
+            let mut rows = Vec::with_capacity(height);
+            for y in 0..height {
+                rows.push(rle_decode_row(&data, offsets[z * height + y], bpc, width)?);
+            }
+            planes.push(rows);
+        }
+    } else {
+        if data.len() < width * height * channels * bpc {
+            return Err(truncated());
+        }
+        for z in 0..channels {
+            let mut rows = Vec::with_capacity(height);
+            for y in 0..height {
+                let start = ((z * height + y) * width) * bpc;
+                let row: io::Result<Vec<u16>> = (0..width).map(|x| read_sample(&data, start + x * bpc, bpc)).collect();
+                rows.push(row?);
+            }
+            planes.push(rows);
+        }
+    }
+    let scale = |v: u16| if bpc == 2 { ((v as u32 * 255 + 32767) / 65535) as u8 } else { v as u8 };
+    let mut samples = Vec::with_capacity(width * height * channels);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            for plane in &planes {
+                samples.push(scale(plane[y][x]));
+            }
+        }
+    }
+    let pixel_data = match channels {
+        1 => bmp::PixelData::Indexed {
+            palette: (0..256).map(|l| bmp::RGBQuad::new(l as u8, l as u8, l as u8)).collect(),
+            indexes: samples,
+        },
+        2 => bmp::PixelData::RGBA(samples.chunks(2).flat_map(|s| vec![s[0], s[0], s[0], s[1]]).collect()),
+        3 => bmp::PixelData::RGB(samples),
+        _ => bmp::PixelData::RGBA(samples),
+    };
+    Ok(bmp::Pixels::new(h.width as u32, h.height as u32, pixel_data))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> io::Result<u32> {
+    data.get(pos..pos + 4).ok_or_else(truncated)?.read_u32::<BigEndian>()
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// Encode pixels as 8 bit SGI image, `channels` is 1 to 4, by default
+/// 1 for gray palette pixels, 4 for RGBA pixels and 3 for the others.
+/// Colors are averaged into gray levels for 1 and 2 channels.
+pub fn save_to_writer<W: ?Sized + Write>(
+    pixels: &bmp::Pixels, w: &mut W,
+    channels: Option<u8>,
+    rle: bool,
+) -> io::Result<()> {
+    if pixels.width > 0xFFFF || pixels.height > 0xFFFF {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}x{} image is too large for SGI", pixels.width, pixels.height),
+        ));
+    }
+    let channels = match channels {
+        Some(c) => c as usize,
+        None => match pixels.data {
+            bmp::PixelData::Indexed { ref palette, .. }
+                if palette.iter().all(|c| c.red() == c.green() && c.green() == c.blue()) => 1,
+            _ if pixels.has_alpha() => 4,
+            _ => 3,
+        },
+    };
+    let mut h = SGIHeader::new(pixels.width as u16, pixels.height as u16, channels as u16);
+    if !h.is_valid() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("SGI can't be {}x{} with {} channels", h.width, h.height, h.channels),
+        ));
+    }
+    let (width, height) = (pixels.width as usize, pixels.height as usize);
+    let mut planes = vec![Vec::with_capacity(width * height); channels];
+    for px in pixels.to_rgba().chunks(4) {
+        let gray = ((px[0] as u32 + px[1] as u32 + px[2] as u32) / 3) as u8;
+        let values = match channels {
+            1 => [gray, 0, 0, 0],
+            2 => [gray, px[3], 0, 0],
+            _ => [px[0], px[1], px[2], px[3]],
+        };
+        for (plane, &v) in planes.iter_mut().zip(values.iter()) {
+            plane.push(v);
+        }
+    }
+    // rows of every plane from the bottom
+    let rows: Vec<&[u8]> = planes.iter().flat_map(|plane| plane.chunks(width).rev()).collect();
+    if !rle {
+        h.save_to_writer(w)?;
+        for row in rows {
+            w.write_all(row)?;
+        }
+        return Ok(());
+    }
+    h.storage = STORAGE_RLE;
+    let encoded: Vec<Vec<u8>> = rows.iter().map(|row| rle_encode_row(row)).collect();
+    h.save_to_writer(w)?;
+    let mut offset = (SGI_HEADER_SIZE + encoded.len() * 8) as u32;
+    for row in &encoded {
+        w.write_u32::<BigEndian>(offset)?;
+        offset += row.len() as u32;
+    }
+    for row in &encoded {
+        w.write_u32::<BigEndian>(row.len() as u32)?;
+    }
+    for row in &encoded {
+        w.write_all(row)?;
+    }
+    Ok(())
+}
+
+pub fn save_to_file<P: AsRef<Path>>(
+    pixels: &bmp::Pixels, p: P,
+    channels: Option<u8>,
+    rle: bool,
+) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f, channels, rle)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rle_rows() {
+        let encoded = rle_encode_row(&[1, 2, 3, 3, 3, 4]);
+        assert_eq!(encoded, vec![0x82, 1, 2, 3, 3, 0x81, 4, 0]);
+        assert_eq!(rle_decode_row(&encoded, 0, 1, 6).unwrap(), vec![1, 2, 3, 3, 3, 4]);
+        // packets are upto 127 samples
+        assert_eq!(rle_encode_row(&[5; 300]), vec![127, 5, 127, 5, 46, 5, 0]);
+        let literal: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let encoded = rle_encode_row(&literal);
+        assert_eq!((encoded[0], encoded[128], encoded.len()), (0xFF, 0x80 | 73, 203));
+        let decoded: Vec<u8> = rle_decode_row(&encoded, 0, 1, 200).unwrap().iter().map(|&v| v as u8).collect();
+        assert_eq!(decoded, literal);
+        assert!(rle_decode_row(&[0x81, 1, 0], 0, 1, 3).is_err());
+    }
+
+    #[test]
+    fn rle_row_of_16_bit_samples() {
+        let data = [0xEE, 0x00, 0x82, 0x12, 0x34, 0xAB, 0xCD, 0x00, 0x02, 0xFF, 0xFF, 0x00, 0x00];
+        assert_eq!(rle_decode_row(&data, 1, 2, 4).unwrap(), vec![0x1234, 0xABCD, 0xFFFF, 0xFFFF]);
+    }
+
+    #[test]
+    fn load_16_bit_samples() {
+        let mut h = SGIHeader::new(2, 2, 1);
+        h.bpc = 2;
+        let mut data = Vec::new();
+        h.save_to_writer(&mut data).unwrap();
+        // rows from the bottom
+        data.extend_from_slice(&[0xFF, 0xFF, 0x80, 0x00, 0x00, 0x00, 0x01, 0x01]);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), vec![0, 0, 0, 1, 1, 1, 255, 255, 255, 128, 128, 128]);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let (width, height) = (11, 3);
+        let gray = bmp::Pixels::new(width, height, bmp::PixelData::Indexed {
+            palette: (0..256).map(|l| bmp::RGBQuad::new(l as u8, l as u8, l as u8)).collect(),
+            indexes: (0..width * height).map(|i| if i < 8 { 9 } else { (i * 7) as u8 }).collect(),
+        });
+        let gray_alpha = bmp::Pixels::new(width, height, bmp::PixelData::RGBA((0..width * height).flat_map(|i| {
+            let g = (i / 4 * 20) as u8;
+            vec![g, g, g, (i * 9) as u8]
+        }).collect()));
+        let rgb = bmp::Pixels::new(width, height, bmp::PixelData::RGB((0..width * height * 3).map(|i| (i / 5 * 3) as u8).collect()));
+        let rgba = bmp::Pixels::new(width, height, bmp::PixelData::RGBA((0..width * height * 4).map(|i| (i * i) as u8).collect()));
+        let images = [(&gray, None, 1), (&gray_alpha, Some(2), 2), (&rgb, None, 3), (&rgba, None, 4)];
+        for &rle in [false, true].iter() {
+            for &(pixels, channels, expected) in images.iter() {
+                let mut data = Vec::new();
+                save_to_writer(pixels, &mut data, channels, rle).unwrap();
+                let h = SGIHeader::load_from_reader(&mut &data[..]).unwrap();
+                assert_eq!((h.channels, h.is_rle()), (expected, rle));
+                assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgba(), pixels.to_rgba());
+            }
+        }
+    }
+}
@@ -0,0 +1,552 @@
+//! # Format desciption
+//! Truevision TGA: 18 byte header, image ID, optional color map and pixels,
+//! raw or run-length encoded. TGA 2.0 files end with a footer holding
+//! the extension and developer area offsets and the signature.
+//! http://www.dca.fee.unicamp.br/~martino/disciplinas/ea978/tgaffs.pdf
+//! https://en.wikipedia.org/wiki/Truevision_TGA
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use bmp;
+
+pub const TGA_HEADER_SIZE: usize = 18;
+pub const TGA_FOOTER_SIZE: usize = 26;
+pub const TGA_SIGNATURE: &'static [u8; 18] = b"TRUEVISION-XFILE.\0";
+
+/// Image types
+pub const TGA_NO_IMAGE: u8 = 0;
+pub const TGA_COLOR_MAPPED: u8 = 1;
+pub const TGA_TRUE_COLOR: u8 = 2;
+pub const TGA_GRAYSCALE: u8 = 3;
+/// Added to the image type of run-length encoded images
+pub const TGA_RLE: u8 = 8;
+
+/// Image descriptor bits
+const DESCRIPTOR_ALPHA_BITS: u8 = 0x0F;
+const DESCRIPTOR_RIGHT_TO_LEFT: u8 = 0x10;
+const DESCRIPTOR_TOP_TO_BOTTOM: u8 = 0x20;
+
+/// Longest run-length packet
+const RLE_MAX_PACKET: usize = 128;
+
+#[derive(Debug, Clone)]
+pub struct TGAHeader {
+    /// Length of the image ID field following the header
+    pub id_length: u8,
+    /// 1 if the file has a color map
+    pub color_map_type: u8,
+    pub image_type: u8,
+    /// Index of the first color map entry
+    pub color_map_first: u16,
+    pub color_map_length: u16,
+    /// Bits per color map entry: 15, 16, 24 or 32
+    pub color_map_entry_size: u8,
+    pub x_origin: u16,
+    pub y_origin: u16,
+    pub width: u16,
+    pub height: u16,
+    pub pixel_depth: u8,
+    /// Alpha channel bits and the image origin
+    pub descriptor: u8,
+}
+
+impl TGAHeader {
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<TGAHeader> {
+        let mut f = BufReader::new(File::open(p)?);
+        TGAHeader::load_from_reader(&mut f)
+    }
+
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<TGAHeader> {
+        let h = TGAHeader {
+            id_length: r.read_u8()?,
+            color_map_type: r.read_u8()?,
+            image_type: r.read_u8()?,
+            color_map_first: r.read_u16::<LittleEndian>()?,
+            color_map_length: r.read_u16::<LittleEndian>()?,
+            color_map_entry_size: r.read_u8()?,
+            x_origin: r.read_u16::<LittleEndian>()?,
+            y_origin: r.read_u16::<LittleEndian>()?,
+            width: r.read_u16::<LittleEndian>()?,
+            height: r.read_u16::<LittleEndian>()?,
+            pixel_depth: r.read_u8()?,
+            descriptor: r.read_u8()?,
+        };
+        if !h.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid TGA header: image type {}, color map type {}, {} bits per pixel",
+                    h.image_type, h.color_map_type, h.pixel_depth,
+                ),
+            ));
+        }
+        Ok(h)
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u8(self.id_length)?;
+        w.write_u8(self.color_map_type)?;
+        w.write_u8(self.image_type)?;
+        w.write_u16::<LittleEndian>(self.color_map_first)?;
+        w.write_u16::<LittleEndian>(self.color_map_length)?;
+        w.write_u8(self.color_map_entry_size)?;
+        w.write_u16::<LittleEndian>(self.x_origin)?;
+        w.write_u16::<LittleEndian>(self.y_origin)?;
+        w.write_u16::<LittleEndian>(self.width)?;
+        w.write_u16::<LittleEndian>(self.height)?;
+        w.write_u8(self.pixel_depth)?;
+        w.write_u8(self.descriptor)?;
+        Ok(())
+    }
+
+    /// TGA has no magic number, header fields are checked for sane values
+    pub fn is_valid(&self) -> bool {
+        let base_type = self.image_type & !TGA_RLE;
+        let color_map_ok = match self.color_map_type {
+            0 => base_type != TGA_COLOR_MAPPED,
+            1 => [15, 16, 24, 32].contains(&self.color_map_entry_size),
+            _ => false,
+        };
+        let depth_ok = match base_type {
+            TGA_COLOR_MAPPED => self.pixel_depth == 8 || self.pixel_depth == 16,
+            TGA_TRUE_COLOR => [15, 16, 24, 32].contains(&self.pixel_depth),
+            TGA_GRAYSCALE => self.pixel_depth == 8 || self.pixel_depth == 16,
+            _ => false,
+        };
+        color_map_ok && depth_ok && self.image_type & !(TGA_RLE | 3) == 0
+    }
+
+    pub fn is_rle(&self) -> bool {
+        self.image_type & TGA_RLE != 0
+    }
+
+    pub fn alpha_bits(&self) -> u8 {
+        self.descriptor & DESCRIPTOR_ALPHA_BITS
+    }
+
+    pub fn is_top_to_bottom(&self) -> bool {
+        self.descriptor & DESCRIPTOR_TOP_TO_BOTTOM != 0
+    }
+
+    pub fn is_right_to_left(&self) -> bool {
+        self.descriptor & DESCRIPTOR_RIGHT_TO_LEFT != 0
+    }
+}
+
+impl fmt::Display for TGAHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let image_type = match self.image_type & !TGA_RLE {
+            TGA_COLOR_MAPPED => "Color-mapped",
+            TGA_TRUE_COLOR => "True-color",
+            TGA_GRAYSCALE => "Grayscale",
+            _ => "No image",
+        };
+        write!(
+            f,
+            "Image type: {} ({}){}\n\
+             Width: {} px\nHeight: {} px\n\
+             Bit Per Pixel: {}\n\
+             Alpha bits: {}\n\
+             Origin: {} {}",
+            self.image_type, image_type, if self.is_rle() { ", run-length encoded" } else { "" },
+            self.width, self.height,
+            self.pixel_depth,
+            self.alpha_bits(),
+            if self.is_top_to_bottom() { "top" } else { "bottom" },
+            if self.is_right_to_left() { "right" } else { "left" },
+        )?;
+        if self.color_map_type == 1 {
+            write!(
+                f, "\nColor map: {} entries from {}, {} bits each",
+                self.color_map_length, self.color_map_first, self.color_map_entry_size,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Bytes taken by a pixel or a color map entry of `bits`
+fn bytes_per(bits: u8) -> usize {
+    (bits as usize + 7) / 8
+}
+
+/// Decode a little endian color of 15, 16, 24 or 32 bits into RGBA
+fn decode_color(data: &[u8], bits: u8, alpha: bool) -> [u8; 4] {
+    match bits {
+        15 | 16 => {
+            let v = data[0] as u16 | (data[1] as u16) << 8;
+            let c5 = |c: u16| ((c & 0x1F) * 255 / 31) as u8;
+            let a = if alpha && bits == 16 && v & 0x8000 == 0 { 0 } else { 255 };
+            [c5(v >> 10), c5(v >> 5), c5(v), a]
+        },
+        24 => [data[2], data[1], data[0], 255],
+        _ => [data[2], data[1], data[0], if alpha { data[3] } else { 255 }],
+    }
+}
+
+/// Read `count` pixels of `size` bytes, run-length encoded or raw
+fn read_pixels<R: ?Sized + Read>(r: &mut R, count: usize, size: usize, rle: bool) -> io::Result<Vec<u8>> {
+    let mut data = vec![0u8; count * size];
+    if !rle {
+        r.read_exact(&mut data)?;
+        return Ok(data);
+    }
+    // packets may cross scanlines
+    let mut pos = 0;
+    let mut pixel = vec![0u8; size];
+    while pos < data.len() {
+        let packet = r.read_u8()?;
+        let len = ((packet & 0x7F) as usize + 1) * size;
+        let len = len.min(data.len() - pos);
+        if packet & 0x80 != 0 {
+            r.read_exact(&mut pixel)?;
+            for chunk in data[pos..pos + len].chunks_mut(size) {
+                chunk.copy_from_slice(&pixel);
+            }
+        } else {
+            r.read_exact(&mut data[pos..pos + len])?;
+        }
+        pos += len;
+    }
+    Ok(data)
+}
+
+/// Decode TGA into top-down pixels. Color-mapped images and 8 bit
+/// grayscale keep indexes, the others are RGB or RGBA.
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let h = TGAHeader::load_from_reader(r)?;
+    let mut id = vec![0u8; h.id_length as usize];
+    r.read_exact(&mut id)?;
+    let mut color_map = Vec::new();
+    if h.color_map_type == 1 {
+        let entry_size = bytes_per(h.color_map_entry_size);
+        let mut data = vec![0u8; h.color_map_length as usize * entry_size];
+        r.read_exact(&mut data)?;
+        color_map = data.chunks(entry_size)
+            .map(|c| decode_color(c, h.color_map_entry_size, h.alpha_bits() > 0))
+            .collect();
+    }
+
+    let (width, height) = (h.width as usize, h.height as usize);
+    let size = bytes_per(h.pixel_depth);
+    let data = read_pixels(r, width * height, size, h.is_rle())?;
+    // reorder into top-down, left to right pixels
+    let mut order: Vec<usize> = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = if h.is_top_to_bottom() { y } else { height - 1 - y };
+        for x in 0..width {
+            let col = if h.is_right_to_left() { width - 1 - x } else { x };
+            order.push(row * width + col);
+        }
+    }
+    let pixel = |idx: usize| &data[order[idx] * size..(order[idx] + 1) * size];
+    let count = width * height;
+
+    let pixels_data = match h.image_type & !TGA_RLE {
+        TGA_COLOR_MAPPED => {
+            let first = h.color_map_first as usize;
+            let index = |idx: usize| {
+                let p = pixel(idx);
+                if size == 1 { p[0] as usize } else { p[0] as usize | (p[1] as usize) << 8 }
+            };
+            let has_alpha = color_map.iter().any(|c| c[3] != 255);
+            if size == 1 && first + color_map.len() <= 256 && !has_alpha {
+                // indexes below the first entry are black
+                let mut palette = vec![bmp::RGBQuad::new(0, 0, 0); first];
+                palette.extend(color_map.iter().map(|c| bmp::RGBQuad::new(c[0], c[1], c[2])));
+                bmp::PixelData::Indexed {
+                    palette: palette,
+                    indexes: (0..count).map(|i| index(i) as u8).collect(),
+                }
+            } else {
+                let mut rgba = Vec::with_capacity(count * 4);
+                for i in 0..count {
+                    let c = index(i).checked_sub(first)
+                        .and_then(|idx| color_map.get(idx))
+                        .cloned()
+                        .unwrap_or([0, 0, 0, 255]);
+                    rgba.extend_from_slice(&c);
+                }
+                bmp::PixelData::RGBA(rgba)
+            }
+        },
+        TGA_GRAYSCALE if size == 1 => bmp::PixelData::Indexed {
+            palette: (0..256).map(|l| bmp::RGBQuad::new(l as u8, l as u8, l as u8)).collect(),
+            indexes: (0..count).map(|i| pixel(i)[0]).collect(),
+        },
+        TGA_GRAYSCALE => {
+            // gray and alpha bytes
+            let mut rgba = Vec::with_capacity(count * 4);
+            for i in 0..count {
+                let p = pixel(i);
+                rgba.extend_from_slice(&[p[0], p[0], p[0], p[1]]);
+            }
+            bmp::PixelData::RGBA(rgba)
+        },
+        _ => {
+            let alpha = h.alpha_bits() > 0 && (h.pixel_depth == 16 || h.pixel_depth == 32);
+            let mut rgba = Vec::with_capacity(count * 4);
+            for i in 0..count {
+                rgba.extend_from_slice(&decode_color(pixel(i), h.pixel_depth, alpha));
+            }
+            if alpha {
+                bmp::PixelData::RGBA(rgba)
+            } else {
+                bmp::PixelData::RGB(rgba.chunks(4).flat_map(|px| px[..3].to_vec()).collect())
+            }
+        },
+    };
+    Ok(bmp::Pixels::new(h.width as u32, h.height as u32, pixels_data))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// Encode pixels of `size` bytes into run-length packets, a run takes at least 2 pixels
+fn rle_encode(data: &[u8], size: usize, width: usize) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len());
+    // packets don't cross scanlines as TGA 2.0 recommends
+    for row in data.chunks(width * size) {
+        let pixels: Vec<&[u8]> = row.chunks(size).collect();
+        let mut idx = 0;
+        while idx < pixels.len() {
+            let mut run = 1;
+            while idx + run < pixels.len() && run < RLE_MAX_PACKET && pixels[idx + run] == pixels[idx] {
+                run += 1;
+            }
+            if run > 1 {
+                encoded.push(0x80 | (run - 1) as u8);
+                encoded.extend_from_slice(pixels[idx]);
+                idx += run;
+                continue;
+            }
+            let mut len = 1;
+            while idx + len < pixels.len() && len < RLE_MAX_PACKET {
+                if idx + len + 1 < pixels.len() && pixels[idx + len] == pixels[idx + len + 1] {
+                    break;
+                }
+                len += 1;
+            }
+            encoded.push((len - 1) as u8);
+            for p in &pixels[idx..idx + len] {
+                encoded.extend_from_slice(p);
+            }
+            idx += len;
+        }
+    }
+    encoded
+}
+
+/// Encode pixels as TGA with the top-left origin and TGA 2.0 footer.
+/// Palette images with gray colors only are written as grayscale,
+/// `bit_count` chooses 16 (5-5-5 with 1 bit alpha), 24 or 32 bits for true color.
+pub fn save_to_writer<W: ?Sized + Write>(
+    pixels: &bmp::Pixels, w: &mut W,
+    bit_count: Option<u8>,
+    rle: bool,
+) -> io::Result<()> {
+    if pixels.width > 0xFFFF || pixels.height > 0xFFFF {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}x{} image is too large for TGA", pixels.width, pixels.height),
+        ));
+    }
+    let mut h = TGAHeader {
+        id_length: 0,
+        color_map_type: 0,
+        image_type: TGA_TRUE_COLOR,
+        color_map_first: 0,
+        color_map_length: 0,
+        color_map_entry_size: 0,
+        x_origin: 0,
+        y_origin: 0,
+        width: pixels.width as u16,
+        height: pixels.height as u16,
+        pixel_depth: 24,
+        descriptor: DESCRIPTOR_TOP_TO_BOTTOM,
+    };
+    let mut color_map = Vec::new();
+    let data: Vec<u8> = match (&pixels.data, bit_count) {
+        (&bmp::PixelData::Indexed { ref palette, ref indexes }, None) |
+        (&bmp::PixelData::Indexed { ref palette, ref indexes }, Some(8)) => {
+            h.pixel_depth = 8;
+            if palette.iter().all(|c| c.red() == c.green() && c.green() == c.blue()) {
+                h.image_type = TGA_GRAYSCALE;
+                indexes.iter().map(|&i| palette.get(i as usize).map_or(0, |c| c.red())).collect()
+            } else {
+                h.image_type = TGA_COLOR_MAPPED;
+                h.color_map_type = 1;
+                h.color_map_length = palette.len() as u16;
+                h.color_map_entry_size = 24;
+                for c in palette {
+                    color_map.extend_from_slice(&[c.blue(), c.green(), c.red()]);
+                }
+                indexes.clone()
+            }
+        },
+        (_, Some(8)) => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "8 bits per pixel TGA requires a palette image".to_owned(),
+        )),
+        (_, Some(16)) => {
+            h.pixel_depth = 16;
+            let alpha = pixels.has_alpha();
+            if alpha {
+                h.descriptor |= 1;
+            }
+            pixels.to_rgba().chunks(4).flat_map(|px| {
+                let a = if !alpha || px[3] >= 128 { 0x8000 } else { 0 };
+                let v = a | (px[0] as u16 >> 3) << 10 | (px[1] as u16 >> 3) << 5 | px[2] as u16 >> 3;
+                vec![v as u8, (v >> 8) as u8]
+            }).collect()
+        },
+        (_, Some(32)) => {
+            h.pixel_depth = 32;
+            h.descriptor |= 8;
+            pixels.to_rgba().chunks(4).flat_map(|px| vec![px[2], px[1], px[0], px[3]]).collect()
+        },
+        (_, None) if pixels.has_alpha() => {
+            h.pixel_depth = 32;
+            h.descriptor |= 8;
+            pixels.to_rgba().chunks(4).flat_map(|px| vec![px[2], px[1], px[0], px[3]]).collect()
+        },
+        (_, None) | (_, Some(24)) => {
+            pixels.to_rgb().chunks(3).flat_map(|px| vec![px[2], px[1], px[0]]).collect()
+        },
+        (_, Some(bits)) => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported TGA bits per pixel: {}", bits),
+        )),
+    };
+    if rle {
+        h.image_type |= TGA_RLE;
+    }
+    h.save_to_writer(w)?;
+    w.write_all(&color_map)?;
+    if rle {
+        w.write_all(&rle_encode(&data, bytes_per(h.pixel_depth), pixels.width as usize))?;
+    } else {
+        w.write_all(&data)?;
+    }
+    // TGA 2.0 footer without extension and developer areas
+    w.write_u32::<LittleEndian>(0)?;
+    w.write_u32::<LittleEndian>(0)?;
+    w.write_all(TGA_SIGNATURE)
+}
+
+pub fn save_to_file<P: AsRef<Path>>(
+    pixels: &bmp::Pixels, p: P,
+    bit_count: Option<u8>,
+    rle: bool,
+) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f, bit_count, rle)?;
+    f.flush()
+}
+
+/// TGA 2.0 files end with the signature
+pub fn has_footer<R: ?Sized + Read + Seek>(r: &mut R) -> io::Result<bool> {
+    if r.seek(SeekFrom::End(0))? < (TGA_HEADER_SIZE + TGA_FOOTER_SIZE) as u64 {
+        return Ok(false);
+    }
+    r.seek(SeekFrom::End(-(TGA_SIGNATURE.len() as i64)))?;
+    let mut signature = [0u8; 18];
+    r.read_exact(&mut signature)?;
+    Ok(&signature == TGA_SIGNATURE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(image_type: u8, width: u16, height: u16, pixel_depth: u8, descriptor: u8) -> TGAHeader {
+        TGAHeader {
+            id_length: 0,
+            color_map_type: 0,
+            image_type: image_type,
+            color_map_first: 0,
+            color_map_length: 0,
+            color_map_entry_size: 0,
+            x_origin: 0,
+            y_origin: 0,
+            width: width,
+            height: height,
+            pixel_depth: pixel_depth,
+            descriptor: descriptor,
+        }
+    }
+
+    fn load(h: &TGAHeader, data: &[u8]) -> bmp::Pixels {
+        let mut file = Vec::new();
+        h.save_to_writer(&mut file).unwrap();
+        file.extend_from_slice(data);
+        load_from_reader(&mut &file[..]).unwrap()
+    }
+
+    #[test]
+    fn rle_packets_cross_scanlines() {
+        let h = header(TGA_TRUE_COLOR | TGA_RLE, 3, 2, 24, DESCRIPTOR_TOP_TO_BOTTOM);
+        // run of 4 pixels, then raw packet of 2 pixels, colors are BGR
+        let data = [0x83, 3, 2, 1, 0x01, 6, 5, 4, 9, 8, 7];
+        let pixels = load(&h, &data);
+        assert_eq!(pixels.to_rgb(), vec![1, 2, 3, 1, 2, 3, 1, 2, 3, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn origin_flags() {
+        let data = [1, 1, 1, 2, 2, 2, 3, 3, 3, 4, 4, 4];
+        let cases = [
+            (DESCRIPTOR_TOP_TO_BOTTOM, [1, 2, 3, 4]),
+            (0, [3, 4, 1, 2]),
+            (DESCRIPTOR_TOP_TO_BOTTOM | DESCRIPTOR_RIGHT_TO_LEFT, [2, 1, 4, 3]),
+            (DESCRIPTOR_RIGHT_TO_LEFT, [4, 3, 2, 1]),
+        ];
+        for &(descriptor, order) in cases.iter() {
+            let pixels = load(&header(TGA_TRUE_COLOR, 2, 2, 24, descriptor), &data);
+            let rgb: Vec<u8> = order.iter().flat_map(|&v| vec![v, v, v]).collect();
+            assert_eq!(pixels.to_rgb(), rgb);
+        }
+    }
+
+    #[test]
+    fn color_map_first_entry() {
+        let mut h = header(TGA_COLOR_MAPPED, 3, 1, 8, DESCRIPTOR_TOP_TO_BOTTOM);
+        h.color_map_type = 1;
+        h.color_map_first = 2;
+        h.color_map_length = 2;
+        h.color_map_entry_size = 24;
+        // entries 2 and 3, then indexes, index 0 is below the color map
+        let data = [30, 20, 10, 60, 50, 40, 3, 2, 0];
+        let pixels = load(&h, &data);
+        assert_eq!(pixels.to_rgb(), vec![40, 50, 60, 10, 20, 30, 0, 0, 0]);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let rgba: Vec<u8> = (0..7 * 3 * 4).map(|i| if i % 4 == 3 { 255 * (i / 4 % 2) as u8 } else { (i * 8) as u8 }).collect();
+        let rgba = bmp::Pixels::new(7, 3, bmp::PixelData::RGBA(rgba));
+        let rgb = bmp::Pixels::new(131, 2, bmp::PixelData::RGB((0..131 * 6).map(|i| if i < 400 { 5 } else { i as u8 }).collect()));
+        let indexed = bmp::Pixels::new(5, 2, bmp::PixelData::Indexed {
+            palette: vec![bmp::RGBQuad::new(255, 0, 0), bmp::RGBQuad::new(0, 255, 0), bmp::RGBQuad::new(0, 0, 255)],
+            indexes: vec![0, 1, 2, 2, 2, 2, 2, 1, 0, 0],
+        });
+        for &rle in [false, true].iter() {
+            for &(pixels, bits) in [(&rgba, None), (&rgba, Some(32)), (&rgb, Some(24)), (&indexed, Some(8))].iter() {
+                let mut data = Vec::new();
+                save_to_writer(pixels, &mut data, bits, rle).unwrap();
+                let loaded = load_from_reader(&mut &data[..]).unwrap();
+                assert_eq!(loaded.to_rgba(), pixels.to_rgba());
+            }
+            // 16 bit colors keep 5 bits per channel
+            let mut data = Vec::new();
+            save_to_writer(&indexed, &mut data, Some(16), rle).unwrap();
+            assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), indexed.to_rgb());
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! # Format desciption
+//! WBMP: Wireless Application Protocol bitmap. Type 0 is the only one
+//! defined, a header of multi-byte integers for type, width and height,
+//! then rows of 1 bit pixels padded to a byte, the most significant bit
+//! is the leftmost pixel, set bits are white. Multi-byte integers keep
+//! 7 bits in every byte, the high bit marks a continuation.
+//! https://www.wapforum.org/what/technical/SPEC-WAESpec-19990524.pdf
+//! https://en.wikipedia.org/wiki/Wireless_Application_Protocol_Bitmap_Format
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use bmp;
+
+/// B/W, uncompressed bitmap
+pub const WBMP_TYPE_0: u32 = 0;
+/// Extension headers follow the fixed header
+const FIX_HEADER_EXT: u8 = 0x80;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Read multi-byte integer, at most 32 bits
+pub fn read_mbi<R: ?Sized + Read>(r: &mut R) -> io::Result<u32> {
+    let mut value = 0u32;
+    for _ in 0..5 {
+        let b = r.read_u8()?;
+        value = value << 7 | (b & 0x7F) as u32;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("WBMP multi-byte integer is too long".to_owned()))
+}
+
+pub fn write_mbi<W: ?Sized + Write>(w: &mut W, value: u32) -> io::Result<()> {
+    let mut shift = 28;
+    while shift > 0 && value >> shift == 0 {
+        shift -= 7;
+    }
+    while shift > 0 {
+        w.write_u8((value >> shift) as u8 & 0x7F | 0x80)?;
+        shift -= 7;
+    }
+    w.write_u8(value as u8 & 0x7F)
+}
+
+#[derive(Debug, Clone)]
+pub struct WBMPHeader {
+    pub kind: u32,
+    pub fix_header: u8,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl WBMPHeader {
+    pub fn new(width: u32, height: u32) -> WBMPHeader {
+        WBMPHeader {
+            kind: WBMP_TYPE_0,
+            fix_header: 0,
+            width: width,
+            height: height,
+        }
+    }
+
+    pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<WBMPHeader> {
+        let mut f = BufReader::new(File::open(p)?);
+        WBMPHeader::load_from_reader(&mut f)
+    }
+
+    pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<WBMPHeader> {
+        let kind = read_mbi(r)?;
+        let fix_header = r.read_u8()?;
+        let h = WBMPHeader {
+            kind: kind,
+            fix_header: fix_header,
+            width: read_mbi(r)?,
+            height: read_mbi(r)?,
+        };
+        if !h.is_valid() {
+            return Err(invalid_data(format!("Invalid or unsupported WBMP header: {:?}", h)));
+        }
+        Ok(h)
+    }
+
+    pub fn save_to_writer<W: ?Sized + Write>(&self, w: &mut W) -> io::Result<()> {
+        write_mbi(w, self.kind)?;
+        w.write_u8(self.fix_header)?;
+        write_mbi(w, self.width)?;
+        write_mbi(w, self.height)
+    }
+
+    /// Type 0 has no extension headers
+    pub fn is_valid(&self) -> bool {
+        self.kind == WBMP_TYPE_0 && self.fix_header & FIX_HEADER_EXT == 0 && self.width > 0 && self.height > 0
+    }
+
+    pub fn row_size(&self) -> usize {
+        (self.width as usize + 7) / 8
+    }
+}
+
+impl fmt::Display for WBMPHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{} px, type {}", self.width, self.height, self.kind)
+    }
+}
+
+/// Decode WBMP into 1 bit palette pixels of black and white
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let h = WBMPHeader::load_from_reader(r)?;
+    let (width, height) = (h.width as usize, h.height as usize);
+    let row_size = h.row_size();
+    let mut data = Vec::new();
+    r.take((row_size * height) as u64).read_to_end(&mut data)?;
+    if data.len() < row_size * height {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("WBMP data is truncated: {} bytes, expected {}", data.len(), row_size * height),
+        ));
+    }
+    let mut indexes = Vec::with_capacity(width * height);
+    for row in data.chunks(row_size) {
+        indexes.extend((0..width).map(|x| row[x / 8] >> bmp::pixel_shift(x, 1) & 1));
+    }
+    let palette = vec![bmp::RGBQuad::new(0, 0, 0), bmp::RGBQuad::new(255, 255, 255)];
+    Ok(bmp::Pixels::new(h.width, h.height, bmp::PixelData::Indexed {
+        palette: palette,
+        indexes: indexes,
+    }))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// Encode pixels as type 0 WBMP, light pixels become white
+pub fn save_to_writer<W: ?Sized + Write>(pixels: &bmp::Pixels, w: &mut W) -> io::Result<()> {
+    WBMPHeader::new(pixels.width, pixels.height).save_to_writer(w)?;
+    let bits: Vec<u8> = pixels.to_rgb().chunks(3)
+        .map(|px| ((px[0] as u32 + px[1] as u32 + px[2] as u32) / 3 >= 128) as u8)
+        .collect();
+    for row in bits.chunks(pixels.width as usize) {
+        w.write_all(&bmp::pack_row(row, 1))?;
+    }
+    Ok(())
+}
+
+pub fn save_to_file<P: AsRef<Path>>(pixels: &bmp::Pixels, p: P) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p)?);
+    save_to_writer(pixels, &mut f)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_byte_integers() {
+        for &(value, bytes) in &[
+            (0u32, &[0x00][..]),
+            (0x7F, &[0x7F][..]),
+            (0x80, &[0x81, 0x00][..]),
+            (0xA0, &[0x81, 0x20][..]),
+            (0x3FFF, &[0xFF, 0x7F][..]),
+            (0xFFFF_FFFF, &[0x8F, 0xFF, 0xFF, 0xFF, 0x7F][..]),
+        ] {
+            let mut data = Vec::new();
+            write_mbi(&mut data, value).unwrap();
+            assert_eq!(&data[..], bytes, "{:#X}", value);
+            assert_eq!(read_mbi(&mut &data[..]).unwrap(), value);
+        }
+        assert_eq!(read_mbi(&mut &[0x80; 5][..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read_mbi(&mut &[0x81][..]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn load_header() {
+        let h = WBMPHeader::load_from_reader(&mut &[0, 0, 0x81, 0x20, 2][..]).unwrap();
+        assert_eq!((h.width, h.height, h.row_size()), (160, 2, 20));
+        for invalid in &[&[1, 0, 8, 8][..], &[0, 0x80, 8, 8], &[0, 0, 0, 8], &[0, 0, 8, 0]] {
+            assert_eq!(WBMPHeader::load_from_reader(&mut &invalid[..]).unwrap_err().kind(),
+                io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let rgb: Vec<u8> = (0..11 * 3).flat_map(|i| if i % 3 == 0 || i % 7 == 1 { vec![255; 3] } else { vec![0; 3] })
+            .collect();
+        let pixels = bmp::Pixels::new(11, 3, bmp::PixelData::RGB(rgb));
+        let mut data = Vec::new();
+        save_to_writer(&pixels, &mut data).unwrap();
+        assert_eq!(&data[..6], &[0, 0, 11, 3, 0b1101_0010, 0b1100_0000]);
+        assert_eq!(data.len(), 4 + 2 * 3);
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), pixels.to_rgb());
+        assert_eq!(load_from_reader(&mut &data[..9]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
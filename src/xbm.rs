@@ -0,0 +1,254 @@
+//! # Format desciption
+//! XBM: X11 bitmap as C source, `#define` lines with the width, height
+//! and optional hotspot, then the array of bytes with 8 pixels each,
+//! the least significant bit is the leftmost pixel and set bits are
+//! the foreground. Rows are padded to a byte. X10 bitmaps have an array
+//! of 16 bit shorts instead.
+//! https://en.wikipedia.org/wiki/X_BitMap
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use bmp;
+
+/// Every XBM starts with the width define
+pub const XBM_MAGIC: &'static [u8; 7] = b"#define";
+/// Array values per line written
+const VALUES_PER_LINE: usize = 12;
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Clone)]
+pub struct XBMHeader {
+    /// Prefix of the defines and the array
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub hotspot: Option<(u32, u32)>,
+    /// X10 bitmap of 16 bit values
+    pub x10: bool,
+}
+
+impl fmt::Display for XBMHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}x{} px", self.name, self.width, self.height)?;
+        if let Some((x, y)) = self.hotspot {
+            write!(f, ", hotspot {}x{}", x, y)?;
+        }
+        if self.x10 {
+            write!(f, ", X10")?;
+        }
+        Ok(())
+    }
+}
+
+/// Remove C comments, XBM has no strings to care about
+fn strip_comments(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u32::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Parse the defines and the array of the XBM source
+fn parse(text: &str) -> io::Result<(XBMHeader, Vec<u32>)> {
+    let text = strip_comments(text);
+    let (mut width, mut height, mut x_hot, mut y_hot) = (None, None, None, None);
+    let mut name = String::new();
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() != 3 || words[0] != "#define" {
+            continue;
+        }
+        let value = parse_number(words[2]);
+        if words[1].ends_with("_width") {
+            name = words[1][..words[1].len() - "_width".len()].to_owned();
+            width = value;
+        } else if words[1].ends_with("_height") {
+            height = value;
+        } else if words[1].ends_with("_x_hot") {
+            x_hot = value;
+        } else if words[1].ends_with("_y_hot") {
+            y_hot = value;
+        }
+    }
+    let (width, height) = match (width, height) {
+        (Some(w), Some(h)) if w > 0 && h > 0 => (w, h),
+        _ => return Err(invalid_data("XBM has no width and height defines".to_owned())),
+    };
+    let start = text.find('{').ok_or_else(|| invalid_data("XBM has no bits array".to_owned()))?;
+    let end = text[start..].find('}').map_or(text.len(), |end| start + end);
+    let x10 = text[..start].contains("short");
+    let mut values = Vec::new();
+    for item in text[start + 1..end].split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        values.push(parse_number(item).ok_or_else(|| invalid_data(format!("Invalid XBM value: {}", item)))?);
+    }
+    let header = XBMHeader {
+        name: name,
+        width: width,
+        height: height,
+        hotspot: match (x_hot, y_hot) {
+            (Some(x), Some(y)) => Some((x, y)),
+            _ => None,
+        },
+        x10: x10,
+    };
+    Ok((header, values))
+}
+
+fn read_text<R: ?Sized + Read>(r: &mut R) -> io::Result<String> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+pub fn load_header_from_file<P: AsRef<Path>>(p: P) -> io::Result<XBMHeader> {
+    let mut f = BufReader::new(File::open(p)?);
+    Ok(parse(&read_text(&mut f)?)?.0)
+}
+
+/// Decode XBM into 1 bit palette pixels, white background and black foreground
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let (h, values) = parse(&read_text(r)?)?;
+    let (width, height) = (h.width as usize, h.height as usize);
+    let bits_per_value = if h.x10 { 16 } else { 8 };
+    let values_per_row = (width + bits_per_value - 1) / bits_per_value;
+    if values.len() < values_per_row * height {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("XBM bits are truncated: {} values, expected {}", values.len(), values_per_row * height),
+        ));
+    }
+    let mut indexes = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let value = values[y * values_per_row + x / bits_per_value];
+            indexes.push((value >> (x % bits_per_value) & 1) as u8);
+        }
+    }
+    let palette = vec![bmp::RGBQuad::new(255, 255, 255), bmp::RGBQuad::new(0, 0, 0)];
+    Ok(bmp::Pixels::new(h.width, h.height, bmp::PixelData::Indexed {
+        palette: palette,
+        indexes: indexes,
+    }))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// C identifier made of the file name
+pub fn identifier<P: AsRef<Path>>(p: P) -> String {
+    let stem = p.as_ref().file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+    let mut name: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if name.chars().next().map_or(true, |c| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// Encode pixels as XBM, dark pixels become the foreground
+pub fn save_to_writer<W: ?Sized + Write>(
+    pixels: &bmp::Pixels, w: &mut W,
+    name: &str,
+    hotspot: Option<(u32, u32)>,
+) -> io::Result<()> {
+    let width = pixels.width as usize;
+    let row_size = (width + 7) / 8;
+    let mut bits = vec![0u8; row_size * pixels.height as usize];
+    for (i, px) in pixels.to_rgb().chunks(3).enumerate() {
+        let (x, y) = (i % width, i / width);
+        if (px[0] as u32 + px[1] as u32 + px[2] as u32) / 3 < 128 {
+            bits[y * row_size + x / 8] |= 1 << (x % 8);
+        }
+    }
+    writeln!(w, "#define {}_width {}", name, pixels.width)?;
+    writeln!(w, "#define {}_height {}", name, pixels.height)?;
+    if let Some((x, y)) = hotspot {
+        writeln!(w, "#define {}_x_hot {}", name, x)?;
+        writeln!(w, "#define {}_y_hot {}", name, y)?;
+    }
+    writeln!(w, "static unsigned char {}_bits[] = {{", name)?;
+    let lines: Vec<String> = bits.chunks(VALUES_PER_LINE).map(|line| {
+        let values: Vec<String> = line.iter().map(|b| format!("0x{:02x}", b)).collect();
+        format!("   {}", values.join(", "))
+    }).collect();
+    writeln!(w, "{} }};", lines.join(",\n"))
+}
+
+pub fn save_to_file<P: AsRef<Path>>(pixels: &bmp::Pixels, p: P, hotspot: Option<(u32, u32)>) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p.as_ref())?);
+    save_to_writer(pixels, &mut f, &identifier(p.as_ref()), hotspot)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_bits() {
+        let text = "/* test */\n#define t_width 10\n#define t_height 2\n#define t_x_hot 3\n#define t_y_hot 1\n\
+            static char t_bits[] = { /* row 0 */ 0x01, 0x02,\n 0x80, 3 };\n";
+        let (h, values) = parse(text).unwrap();
+        assert_eq!((h.name.as_str(), h.width, h.height, h.hotspot, h.x10), ("t", 10, 2, Some((3, 1)), false));
+        assert_eq!(values, vec![1, 2, 0x80, 3]);
+        let pixels = load_from_reader(&mut text.as_bytes()).unwrap();
+        match pixels.data {
+            bmp::PixelData::Indexed { ref indexes, .. } => assert_eq!(indexes, &vec![
+                1, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+                0, 0, 0, 0, 0, 0, 0, 1, 1, 1,
+            ]),
+            _ => panic!("XBM is not indexed"),
+        }
+
+        let x10 = "#define s_width 17\n#define s_height 1\nstatic short s_bits[] = { 0x8001, 0x0001 };";
+        let pixels = load_from_reader(&mut x10.as_bytes()).unwrap();
+        let black: Vec<usize> = pixels.to_rgb().chunks(3).enumerate().filter(|&(_, px)| px[0] == 0).map(|(i, _)| i).collect();
+        assert_eq!(black, vec![0, 15, 16]);
+
+        let truncated = "#define t_width 10\n#define t_height 2\nstatic char t_bits[] = { 1, 2, 3 ";
+        assert_eq!(load_from_reader(&mut truncated.as_bytes()).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(load_from_reader(&mut &b"#define t_width 2\nstatic char t_bits[] = { 0 };"[..]).is_err());
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let palette = vec![bmp::RGBQuad::new(255, 255, 255), bmp::RGBQuad::new(0, 0, 0)];
+        let indexes: Vec<u8> = (0..9 * 5).map(|i| (i * 7 % 3 == 0) as u8).collect();
+        let pixels = bmp::Pixels::new(9, 5, bmp::PixelData::Indexed { palette: palette, indexes: indexes });
+        for &hotspot in &[None, Some((4, 2))] {
+            let mut data = Vec::new();
+            save_to_writer(&pixels, &mut data, "icon", hotspot).unwrap();
+            let (h, values) = parse(&String::from_utf8(data.clone()).unwrap()).unwrap();
+            assert_eq!((h.name.as_str(), h.hotspot, values.len()), ("icon", hotspot, 10));
+            assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgb(), pixels.to_rgb());
+        }
+    }
+
+    #[test]
+    fn identifiers() {
+        assert_eq!(identifier("dir/my-icon.xbm"), "my_icon");
+        assert_eq!(identifier("1.xbm"), "_1");
+    }
+}
@@ -0,0 +1,429 @@
+//! # Format desciption
+//! XPM3: X11 pixmap as a C array of strings. The first string holds the
+//! width, height, number of colors, characters per pixel and optional
+//! hotspot, the next ones map pixel characters to colors of the visuals
+//! (`c` color, `g` and `g4` gray, `m` mono, `s` symbolic name) and the
+//! rest are pixel rows. Color "None" is transparent.
+//! https://www.x.org/docs/XPM/xpm.pdf
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use bmp;
+use xbm;
+
+pub const XPM_MAGIC: &'static [u8; 9] = b"/* XPM */";
+
+/// Characters used for pixels, quotes and backslashes are left out
+const PIXEL_CHARS: &'static [u8] =
+    b" .XoO+@#$%&*=-;:>,<1234567890qwertyuipasdfghjklzxcvbnmMNBVCZASDFGHJKLPIUYTREWQ!~^/()_`'][{}|";
+
+/// Color visual keys in the order of preference
+const VISUALS: [&'static str; 4] = ["c", "g", "g4", "m"];
+const KEYS: [&'static str; 5] = ["c", "g", "g4", "m", "s"];
+
+/// Common X11 color names, gray levels are `grayN`
+const COLOR_NAMES: [(&'static str, [u8; 3]); 22] = [
+    ("black", [0, 0, 0]),
+    ("white", [255, 255, 255]),
+    ("red", [255, 0, 0]),
+    ("green", [0, 255, 0]),
+    ("blue", [0, 0, 255]),
+    ("yellow", [255, 255, 0]),
+    ("cyan", [0, 255, 255]),
+    ("magenta", [255, 0, 255]),
+    ("gray", [190, 190, 190]),
+    ("grey", [190, 190, 190]),
+    ("darkgray", [169, 169, 169]),
+    ("darkgrey", [169, 169, 169]),
+    ("lightgray", [211, 211, 211]),
+    ("lightgrey", [211, 211, 211]),
+    ("orange", [255, 165, 0]),
+    ("brown", [165, 42, 42]),
+    ("pink", [255, 192, 203]),
+    ("purple", [160, 32, 240]),
+    ("navy", [0, 0, 128]),
+    ("maroon", [176, 48, 96]),
+    ("darkred", [139, 0, 0]),
+    ("darkgreen", [0, 100, 0]),
+];
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Clone)]
+pub struct XPMHeader {
+    pub width: u32,
+    pub height: u32,
+    pub colors: u32,
+    pub chars_per_pixel: u32,
+    pub hotspot: Option<(u32, u32)>,
+}
+
+impl XPMHeader {
+    fn parse(values: &str) -> io::Result<XPMHeader> {
+        let numbers: Vec<u32> = values.split_whitespace().take_while(|v| *v != "XPMEXT")
+            .map(|v| v.parse().map_err(|_| invalid_data(format!("Invalid XPM values: {}", values))))
+            .collect::<io::Result<_>>()?;
+        if (numbers.len() != 4 && numbers.len() != 6) || numbers[..4].iter().any(|&n| n == 0) {
+            return Err(invalid_data(format!("Invalid XPM values: {}", values)));
+        }
+        Ok(XPMHeader {
+            width: numbers[0],
+            height: numbers[1],
+            colors: numbers[2],
+            chars_per_pixel: numbers[3],
+            hotspot: if numbers.len() == 6 { Some((numbers[4], numbers[5])) } else { None },
+        })
+    }
+}
+
+impl fmt::Display for XPMHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} px, {} colors, {} chars per pixel",
+            self.width, self.height, self.colors, self.chars_per_pixel,
+        )?;
+        if let Some((x, y)) = self.hotspot {
+            write!(f, ", hotspot {}x{}", x, y)?;
+        }
+        Ok(())
+    }
+}
+
+/// String literals of the C source, comments are skipped
+fn c_strings(text: &str) -> Vec<String> {
+    let mut strings = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let mut s = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => if let Some(escaped) = chars.next() {
+                            s.push(escaped);
+                        },
+                        _ => s.push(c),
+                    }
+                }
+                strings.push(s);
+            },
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut prev = ' ';
+                while let Some(c) = chars.next() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            },
+            '/' if chars.peek() == Some(&'/') => {
+                while let Some(c) = chars.next() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    strings
+}
+
+/// Parse color value, None is transparent
+fn parse_color(value: &str) -> io::Result<Option<[u8; 3]>> {
+    let invalid = || invalid_data(format!("Unknown XPM color: {}", value));
+    if value.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    if value.starts_with('#') {
+        let hex = &value[1..];
+        if hex.is_empty() || hex.len() % 3 != 0 || hex.len() > 12 {
+            return Err(invalid());
+        }
+        // every component is 1 to 4 hex digits, the most significant 8 bits are taken
+        let digits = hex.len() / 3;
+        let mut rgb = [0u8; 3];
+        for (i, c) in rgb.iter_mut().enumerate() {
+            let v = u32::from_str_radix(&hex[i * digits..(i + 1) * digits], 16).map_err(|_| invalid())?;
+            *c = match digits {
+                1 => v * 17,
+                2 => v,
+                _ => v >> (digits * 4 - 8),
+            } as u8;
+        }
+        return Ok(Some(rgb));
+    }
+    let name: String = value.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_lowercase();
+    if let Some(&(_, rgb)) = COLOR_NAMES.iter().find(|&&(n, _)| n == name) {
+        return Ok(Some(rgb));
+    }
+    for prefix in &["gray", "grey"] {
+        if name.starts_with(prefix) {
+            if let Ok(level) = name[prefix.len()..].parse::<u32>() {
+                if level <= 100 {
+                    let g = ((level * 255 + 50) / 100) as u8;
+                    return Ok(Some([g, g, g]));
+                }
+            }
+        }
+    }
+    Err(invalid())
+}
+
+/// Color of the color definition after the pixel characters
+fn parse_color_definition(definition: &str) -> io::Result<Option<[u8; 3]>> {
+    let words: Vec<&str> = definition.split_whitespace().collect();
+    let mut values: HashMap<&str, String> = HashMap::new();
+    let mut i = 0;
+    while i < words.len() {
+        let key = words[i];
+        if !KEYS.contains(&key) {
+            return Err(invalid_data(format!("Invalid XPM color definition: {}", definition)));
+        }
+        let mut value = Vec::new();
+        i += 1;
+        // color names may have spaces
+        while i < words.len() && !KEYS.contains(&words[i]) {
+            value.push(words[i]);
+            i += 1;
+        }
+        values.insert(key, value.join(" "));
+    }
+    match VISUALS.iter().filter_map(|v| values.get(v)).next() {
+        Some(value) => parse_color(value),
+        None => Err(invalid_data(format!("XPM color definition has no color: {}", definition))),
+    }
+}
+
+fn read_strings<R: ?Sized + Read>(r: &mut R) -> io::Result<Vec<String>> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    let strings = c_strings(&String::from_utf8_lossy(&data));
+    if strings.is_empty() {
+        return Err(invalid_data("XPM has no strings".to_owned()));
+    }
+    Ok(strings)
+}
+
+pub fn load_header_from_file<P: AsRef<Path>>(p: P) -> io::Result<XPMHeader> {
+    let mut f = BufReader::new(File::open(p)?);
+    XPMHeader::parse(&read_strings(&mut f)?[0])
+}
+
+/// Decode XPM, upto 256 colors become palette pixels and the others RGB.
+/// Pixmaps with transparent color are RGBA.
+pub fn load_from_reader<R: ?Sized + Read>(r: &mut R) -> io::Result<bmp::Pixels> {
+    let strings = read_strings(r)?;
+    let h = XPMHeader::parse(&strings[0])?;
+    let (width, height, cpp) = (h.width as usize, h.height as usize, h.chars_per_pixel as usize);
+    let colors = h.colors as usize;
+    if strings.len() < 1 + colors + height {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("XPM is truncated: {} strings, expected {}", strings.len(), 1 + colors + height),
+        ));
+    }
+    let mut keys = HashMap::with_capacity(colors);
+    let mut table = Vec::with_capacity(colors);
+    for definition in &strings[1..1 + colors] {
+        let key: String = definition.chars().take(cpp).collect();
+        if key.chars().count() != cpp {
+            return Err(invalid_data(format!("Invalid XPM color definition: {}", definition)));
+        }
+        let rest: String = definition.chars().skip(cpp).collect();
+        keys.insert(key, table.len());
+        table.push(parse_color_definition(&rest)?);
+    }
+
+    let mut indexes = Vec::with_capacity(width * height);
+    for row in &strings[1 + colors..1 + colors + height] {
+        let chars: Vec<char> = row.chars().collect();
+        if chars.len() < width * cpp {
+            return Err(invalid_data(format!("XPM row is too short: {:?}", row)));
+        }
+        for key in chars.chunks(cpp).take(width) {
+            let key: String = key.iter().collect();
+            let idx = keys.get(&key).ok_or_else(|| invalid_data(format!("Unknown XPM pixel: {:?}", key)))?;
+            indexes.push(*idx);
+        }
+    }
+
+    let data = if table.iter().any(|c| c.is_none()) {
+        bmp::PixelData::RGBA(indexes.iter().flat_map(|&i| match table[i] {
+            Some(c) => vec![c[0], c[1], c[2], 255],
+            None => vec![0, 0, 0, 0],
+        }).collect())
+    } else if table.len() <= 256 {
+        bmp::PixelData::Indexed {
+            palette: table.iter().map(|c| {
+                let c = c.unwrap();
+                bmp::RGBQuad::new(c[0], c[1], c[2])
+            }).collect(),
+            indexes: indexes.iter().map(|&i| i as u8).collect(),
+        }
+    } else {
+        bmp::PixelData::RGB(indexes.iter().flat_map(|&i| table[i].unwrap().to_vec()).collect())
+    };
+    Ok(bmp::Pixels::new(h.width, h.height, data))
+}
+
+pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<bmp::Pixels> {
+    let mut f = BufReader::new(File::open(p)?);
+    load_from_reader(&mut f)
+}
+
+/// Pixel characters of the color number `idx`
+fn pixel_key(mut idx: usize, cpp: usize) -> String {
+    let mut key = String::with_capacity(cpp);
+    for _ in 0..cpp {
+        key.push(PIXEL_CHARS[idx % PIXEL_CHARS.len()] as char);
+        idx /= PIXEL_CHARS.len();
+    }
+    key
+}
+
+/// Encode pixels as XPM. Palette pixels keep the palette, the other
+/// colors are collected as they come. Pixels with alpha below half
+/// share the transparent color "None".
+pub fn save_to_writer<W: ?Sized + Write>(
+    pixels: &bmp::Pixels, w: &mut W,
+    name: &str,
+    hotspot: Option<(u32, u32)>,
+) -> io::Result<()> {
+    // color table entries, None is transparent, and their indexes per pixel
+    let mut table: Vec<Option<[u8; 3]>> = Vec::new();
+    let indexes: Vec<usize> = match pixels.data {
+        bmp::PixelData::Indexed { ref palette, ref indexes } => {
+            table = palette.iter().map(|c| Some([c.red(), c.green(), c.blue()])).collect();
+            indexes.iter().map(|&i| i as usize).collect()
+        },
+        _ => {
+            let mut seen = HashMap::new();
+            pixels.to_rgba().chunks(4).map(|px| {
+                let color = if px[3] < 128 { None } else { Some([px[0], px[1], px[2]]) };
+                *seen.entry(color).or_insert_with(|| {
+                    table.push(color);
+                    table.len() - 1
+                })
+            }).collect()
+        },
+    };
+    if let Some(&idx) = indexes.iter().find(|&&i| i >= table.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Palette index {} is out of {} colors", idx, table.len()),
+        ));
+    }
+    let mut cpp = 1;
+    while PIXEL_CHARS.len().pow(cpp as u32) < table.len() {
+        cpp += 1;
+    }
+
+    writeln!(w, "/* XPM */")?;
+    writeln!(w, "static char *{}[] = {{", name)?;
+    match hotspot {
+        Some((x, y)) => writeln!(w, "\"{} {} {} {} {} {}\",", pixels.width, pixels.height, table.len(), cpp, x, y)?,
+        None => writeln!(w, "\"{} {} {} {}\",", pixels.width, pixels.height, table.len(), cpp)?,
+    }
+    for (idx, color) in table.iter().enumerate() {
+        match *color {
+            Some(c) => writeln!(w, "\"{} c #{:02X}{:02X}{:02X}\",", pixel_key(idx, cpp), c[0], c[1], c[2])?,
+            None => writeln!(w, "\"{} c None\",", pixel_key(idx, cpp))?,
+        }
+    }
+    let keys: Vec<String> = (0..table.len()).map(|idx| pixel_key(idx, cpp)).collect();
+    let rows: Vec<String> = indexes.chunks(pixels.width as usize).map(|row| {
+        let row: String = row.iter().map(|&i| keys[i].as_str()).collect();
+        format!("\"{}\"", row)
+    }).collect();
+    writeln!(w, "{}", rows.join(",\n"))?;
+    writeln!(w, "}};")
+}
+
+pub fn save_to_file<P: AsRef<Path>>(pixels: &bmp::Pixels, p: P, hotspot: Option<(u32, u32)>) -> io::Result<()> {
+    let mut f = BufWriter::new(File::create(p.as_ref())?);
+    save_to_writer(pixels, &mut f, &xbm::identifier(p.as_ref()), hotspot)?;
+    f.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#F80").unwrap(), Some([255, 136, 0]));
+        assert_eq!(parse_color("#12AB34").unwrap(), Some([0x12, 0xAB, 0x34]));
+        assert_eq!(parse_color("#FFFF80007FFF").unwrap(), Some([255, 128, 127]));
+        assert_eq!(parse_color("Dark Gray").unwrap(), Some([169, 169, 169]));
+        assert_eq!(parse_color("grey50").unwrap(), Some([128, 128, 128]));
+        assert_eq!(parse_color("None").unwrap(), None);
+        for invalid in &["#12345", "#GG0000", "gray101", "chartreuse"] {
+            assert!(parse_color(invalid).is_err(), "{}", invalid);
+        }
+        assert_eq!(parse_color_definition("m black c light gray s edge").unwrap(), Some([211, 211, 211]));
+        assert_eq!(parse_color_definition("s edge g4 white").unwrap(), Some([255, 255, 255]));
+        assert!(parse_color_definition("s edge").is_err());
+        assert!(parse_color_definition("x red").is_err());
+    }
+
+    #[test]
+    fn load_pixmap() {
+        let text = "/* XPM */\nstatic char *t[] = {\n/* values */ \"3 2 3 2 1 0\",\n\
+            \"a c red\", \"b  c None\", \"\\\"x m white\",\n\"a b \\\"x\", // row\n\"\\\"xa b \"};";
+        let strings = c_strings(text);
+        assert_eq!(strings[0], "3 2 3 2 1 0");
+        assert_eq!(strings[3], "\"x m white");
+        let h = XPMHeader::parse(&strings[0]).unwrap();
+        assert_eq!((h.width, h.height, h.colors, h.chars_per_pixel, h.hotspot), (3, 2, 3, 2, Some((1, 0))));
+        assert_eq!(load_from_reader(&mut text.as_bytes()).unwrap().to_rgba(), vec![
+            255, 0, 0, 255, 0, 0, 0, 0, 255, 255, 255, 255,
+            255, 255, 255, 255, 255, 0, 0, 255, 0, 0, 0, 0,
+        ]);
+
+        assert_eq!(load_from_reader(&mut &text.as_bytes()[..text.len() - 12]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof);
+        assert!(load_from_reader(&mut &text.replace("\"a b \\\"x\"", "\"a c \\\"x\"").as_bytes()[..]).is_err());
+        assert!(XPMHeader::parse("3 2 0 1").is_err());
+        assert!(XPMHeader::parse("3 2 1").is_err());
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let palette: Vec<bmp::RGBQuad> = (0..16).map(|i| bmp::RGBQuad::new(i * 16, 255 - i * 16, i)).collect();
+        let indexes: Vec<u8> = (0..7 * 3).map(|i| (i * 5 % 16) as u8).collect();
+        let indexed = bmp::Pixels::new(7, 3, bmp::PixelData::Indexed { palette: palette, indexes: indexes });
+        // 300 colors need two characters per pixel and load as RGB
+        let rgb = bmp::Pixels::new(20, 15, bmp::PixelData::RGB((0..300u32).flat_map(|i| {
+            vec![(i * 7 % 256) as u8, (i / 256) as u8, 9]
+        }).collect()));
+        let rgba = bmp::Pixels::new(2, 2, bmp::PixelData::RGBA(vec![
+            1, 2, 3, 255, 0, 0, 0, 0, 1, 2, 3, 255, 50, 60, 70, 200,
+        ]));
+        for pixels in &[indexed, rgb] {
+            let mut data = Vec::new();
+            save_to_writer(pixels, &mut data, "image", Some((1, 2))).unwrap();
+            let h = XPMHeader::parse(&c_strings(&String::from_utf8(data.clone()).unwrap())[0]).unwrap();
+            assert_eq!(h.hotspot, Some((1, 2)));
+            assert_eq!((h.colors, h.chars_per_pixel), if h.colors > 16 { (300, 2) } else { (16, 1) });
+            let loaded = load_from_reader(&mut &data[..]).unwrap();
+            assert!(!loaded.has_alpha());
+            assert_eq!(loaded.to_rgb(), pixels.to_rgb());
+        }
+        let mut data = Vec::new();
+        save_to_writer(&rgba, &mut data, "image", None).unwrap();
+        assert_eq!(load_from_reader(&mut &data[..]).unwrap().to_rgba(), vec![
+            1, 2, 3, 255, 0, 0, 0, 0, 1, 2, 3, 255, 50, 60, 70, 255,
+        ]);
+        assert_eq!(c_strings(&String::from_utf8(data).unwrap())[0], "2 2 3 1");
+    }
+}
@@ -0,0 +1,698 @@
+//! # Zlib and deflate
+//! Zlib stream is a 2 byte header, deflate compressed data and Adler-32
+//! checksum of the uncompressed data. Deflate data is a sequence of blocks:
+//! stored, compressed with the fixed Huffman codes or with the dynamic
+//! codes described at the block start. Compressed blocks are made of
+//! literals and (length, distance) back references into the last 32 KB.
+//! Bits are packed starting from the least significant one, Huffman codes
+//! starting from the most significant one.
+//! https://tools.ietf.org/html/rfc1950
+//! https://tools.ietf.org/html/rfc1951
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+
+/// Deflate compression method with 32 KB window
+const ZLIB_CM_DEFLATE: u8 = 8;
+const ZLIB_MAX_WINDOW_BITS: u8 = 7;
+/// Preset dictionary flag
+const ZLIB_FDICT: u8 = 0x20;
+
+const MAX_BITS: usize = 15;
+/// Code length codes are upto 7 bits
+const MAX_CODE_LENGTH_BITS: usize = 7;
+const END_OF_BLOCK: usize = 256;
+const LITERAL_CODES: usize = 286;
+const DISTANCE_CODES: usize = 30;
+const CODE_LENGTH_CODES: usize = 19;
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: usize = 15;
+/// Longest hash chain walked looking for a match
+const MAX_CHAIN: usize = 64;
+/// Match long enough to stop looking for a better one
+const NICE_MATCH: usize = 128;
+/// Symbols of one compressed block
+const BLOCK_SYMBOLS: usize = 32768;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+/// Order of code length code lengths in the dynamic block header
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // sums can't overflow in 5552 bytes
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    b << 16 | a
+}
+
+/// CRC-32 of PNG chunks and gzip
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for n in 0..256 {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+        }
+        table[n] = c;
+    }
+    !data.iter().fold(!0u32, |crc, &b| table[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+/// Decompress zlib stream
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < 6 {
+        return Err(invalid_data(format!("Zlib stream is too short: {} bytes", data.len())));
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0F != ZLIB_CM_DEFLATE || cmf >> 4 > ZLIB_MAX_WINDOW_BITS || (cmf as u16 * 256 + flg as u16) % 31 != 0 {
+        return Err(invalid_data(format!("Invalid zlib header: {:#04X} {:#04X}", cmf, flg)));
+    }
+    if flg & ZLIB_FDICT != 0 {
+        return Err(invalid_data("Zlib preset dictionary is not supported".to_owned()));
+    }
+    let (output, used) = inflate_with_size(&data[2..])?;
+    let end = 2 + used;
+    if data.len() < end + 4 {
+        return Err(invalid_data("Zlib stream has no checksum".to_owned()));
+    }
+    let expected = (data[end] as u32) << 24 | (data[end + 1] as u32) << 16 | (data[end + 2] as u32) << 8 | data[end + 3] as u32;
+    let actual = adler32(&output);
+    if actual != expected {
+        return Err(invalid_data(format!("Zlib checksum mismatch: {:#010X} != {:#010X}", actual, expected)));
+    }
+    Ok(output)
+}
+
+/// Compress data into zlib stream
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // deflate with 32 KB window, default compression level
+    let mut output = vec![0x78, 0x9C];
+    output.extend(deflate(data));
+    let adler = adler32(data);
+    output.extend_from_slice(&[(adler >> 24) as u8, (adler >> 16) as u8, (adler >> 8) as u8, adler as u8]);
+    output
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Deflate data is truncated".to_owned()))?;
+            self.pos += 1;
+            self.bits |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1u32 << n) - 1);
+        self.bits >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Skip to the byte boundary
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+}
+
+/// Canonical Huffman code as numbers of codes of every length
+/// and symbols ordered by their codes
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        // over-subscribed codes are invalid, incomplete ones are allowed
+        let mut left = 1i32;
+        for len in 1..MAX_BITS + 1 {
+            left = left * 2 - counts[len] as i32;
+            if left < 0 {
+                return Err(invalid_data("Over-subscribed Huffman code".to_owned()));
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..MAX_BITS + 1 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; offsets[MAX_BITS + 1] as usize];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        counts[0] = 0;
+        Ok(Huffman {
+            counts: counts,
+            symbols: symbols,
+        })
+    }
+
+    fn decode(&self, r: &mut BitReader) -> io::Result<usize> {
+        // first code of the length and index of its symbol
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..MAX_BITS + 1 {
+            code |= r.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid_data("Invalid Huffman code".to_owned()))
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    for (symbol, len) in lengths.iter_mut().enumerate() {
+        *len = match symbol {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    (Huffman::new(&lengths).unwrap(), Huffman::new(&[5u8; DISTANCE_CODES]).unwrap())
+}
+
+fn dynamic_codes(r: &mut BitReader) -> io::Result<(Huffman, Huffman)> {
+    let literals = r.bits(5)? as usize + 257;
+    let distances = r.bits(5)? as usize + 1;
+    let code_lengths = r.bits(4)? as usize + 4;
+    if literals > LITERAL_CODES || distances > DISTANCE_CODES {
+        return Err(invalid_data(format!("Invalid dynamic block: {} literal and {} distance codes", literals, distances)));
+    }
+    let mut lengths = [0u8; CODE_LENGTH_CODES];
+    for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[symbol] = r.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths)?;
+
+    let mut lengths = Vec::with_capacity(literals + distances);
+    while lengths.len() < literals + distances {
+        let symbol = code_length_code.decode(r)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => match lengths.last() {
+                Some(&previous) => (previous, 3 + r.bits(2)? as usize),
+                None => return Err(invalid_data("Repeat of no code length".to_owned())),
+            },
+            17 => (0, 3 + r.bits(3)? as usize),
+            _ => (0, 11 + r.bits(7)? as usize),
+        };
+        if lengths.len() + repeat > literals + distances {
+            return Err(invalid_data("Too many code lengths".to_owned()));
+        }
+        lengths.extend(::std::iter::repeat(value).take(repeat));
+    }
+    if lengths[END_OF_BLOCK] == 0 {
+        return Err(invalid_data("Dynamic block has no end of block code".to_owned()));
+    }
+    Ok((Huffman::new(&lengths[..literals])?, Huffman::new(&lengths[literals..])?))
+}
+
+/// Decompress raw deflate data
+pub fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    inflate_with_size(data).map(|(output, _)| output)
+}
+
+/// Decompress raw deflate data, returns the output and the size of the used data
+fn inflate_with_size(data: &[u8]) -> io::Result<(Vec<u8>, usize)> {
+    let mut r = BitReader {
+        data: data,
+        pos: 0,
+        bits: 0,
+        count: 0,
+    };
+    let mut output = Vec::with_capacity(data.len() * 4);
+    loop {
+        let last = r.bits(1)? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.align();
+                let len = r.bits(16)? as usize;
+                let nlen = r.bits(16)? as usize;
+                if len != !nlen & 0xFFFF {
+                    return Err(invalid_data(format!("Stored block length {} doesn't match its complement", len)));
+                }
+                if r.pos + len > data.len() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Stored block is truncated".to_owned()));
+                }
+                output.extend_from_slice(&data[r.pos..r.pos + len]);
+                r.pos += len;
+            },
+            btype @ 1 | btype @ 2 => {
+                let (literal_code, distance_code) = if btype == 1 { fixed_codes() } else { dynamic_codes(&mut r)? };
+                loop {
+                    let symbol = literal_code.decode(&mut r)?;
+                    if symbol < END_OF_BLOCK {
+                        output.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == END_OF_BLOCK {
+                        break;
+                    }
+                    let idx = symbol - END_OF_BLOCK - 1;
+                    if idx >= LENGTH_BASE.len() {
+                        return Err(invalid_data(format!("Invalid length code: {}", symbol)));
+                    }
+                    let len = LENGTH_BASE[idx] as usize + r.bits(LENGTH_EXTRA[idx] as u32)? as usize;
+                    let idx = distance_code.decode(&mut r)?;
+                    if idx >= DISTANCE_BASE.len() {
+                        return Err(invalid_data(format!("Invalid distance code: {}", idx)));
+                    }
+                    let distance = DISTANCE_BASE[idx] as usize + r.bits(DISTANCE_EXTRA[idx] as u32)? as usize;
+                    if distance > output.len() {
+                        return Err(invalid_data(format!("Distance {} is too far back", distance)));
+                    }
+                    // the copy may overlap its own output
+                    let start = output.len() - distance;
+                    for i in 0..len {
+                        let byte = output[start + i];
+                        output.push(byte);
+                    }
+                }
+            },
+            _ => return Err(invalid_data("Invalid deflate block type".to_owned())),
+        }
+        if last {
+            return Ok((output, r.pos));
+        }
+    }
+}
+
+struct BitWriter {
+    data: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, n: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.data.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Write Huffman code, most significant bit first
+    fn write_code(&mut self, code: u16, len: u8) {
+        let mut reversed = 0u32;
+        for i in 0..len {
+            reversed |= ((code as u32 >> i) & 1) << (len - 1 - i);
+        }
+        self.write(reversed, len as u32);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.data.push(self.bits as u8);
+        }
+        self.data
+    }
+}
+
+/// Code lengths of the Huffman code for the symbol frequencies,
+/// frequencies are flattened until the longest code fits into `max_bits`
+fn code_lengths(freqs: &[u32], max_bits: usize) -> Vec<u8> {
+    let mut lengths = vec![0u8; freqs.len()];
+    let used: Vec<usize> = (0..freqs.len()).filter(|&s| freqs[s] > 0).collect();
+    if used.len() < 2 {
+        // a single code is still complete with a pair of 1 bit codes
+        let symbol = used.first().cloned().unwrap_or(0);
+        lengths[symbol] = 1;
+        lengths[if symbol == 0 { 1 } else { 0 }] = 1;
+        return lengths;
+    }
+    let mut weights: Vec<u64> = freqs.iter().map(|&f| f as u64).collect();
+    loop {
+        // leaves are the used symbols, internal nodes are added after them
+        let mut parent = vec![0usize; used.len() * 2 - 1];
+        let mut heap = BinaryHeap::new();
+        for (node, &symbol) in used.iter().enumerate() {
+            heap.push(Reverse((weights[symbol], node)));
+        }
+        let mut next = used.len();
+        while heap.len() > 1 {
+            let Reverse((w1, n1)) = heap.pop().unwrap();
+            let Reverse((w2, n2)) = heap.pop().unwrap();
+            parent[n1] = next;
+            parent[n2] = next;
+            heap.push(Reverse((w1 + w2, next)));
+            next += 1;
+        }
+        let root = next - 1;
+        let mut longest = 0;
+        for (node, &symbol) in used.iter().enumerate() {
+            let mut depth = 0;
+            let mut n = node;
+            while n != root {
+                n = parent[n];
+                depth += 1;
+            }
+            lengths[symbol] = depth as u8;
+            longest = longest.max(depth);
+        }
+        if longest <= max_bits {
+            return lengths;
+        }
+        for &symbol in &used {
+            weights[symbol] = (weights[symbol] + 1) / 2;
+        }
+    }
+}
+
+/// Canonical codes of the code lengths
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; MAX_BITS + 1];
+    for &len in lengths {
+        counts[len as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut next_code = [0u16; MAX_BITS + 1];
+    let mut code = 0u16;
+    for bits in 1..MAX_BITS + 1 {
+        code = (code + counts[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+    lengths.iter().map(|&len| {
+        if len == 0 {
+            return 0;
+        }
+        let code = next_code[len as usize];
+        next_code[len as usize] += 1;
+        code
+    }).collect()
+}
+
+/// Literal or back reference of LZ77 compressed data
+#[derive(Debug, Copy, Clone)]
+enum Token {
+    Literal(u8),
+    Match(u16, u16),
+}
+
+fn length_code(len: usize) -> usize {
+    (0..LENGTH_BASE.len()).rev().find(|&i| LENGTH_BASE[i] as usize <= len).unwrap()
+}
+
+fn distance_code(distance: usize) -> usize {
+    (0..DISTANCE_BASE.len()).rev().find(|&i| DISTANCE_BASE[i] as usize <= distance).unwrap()
+}
+
+/// Find back references with hash chains of 3 byte sequences, a match is
+/// dropped if the next position has a longer one
+fn lz77(data: &[u8]) -> Vec<Token> {
+    let hash = |pos: usize| {
+        ((data[pos] as usize) << 10 ^ (data[pos + 1] as usize) << 5 ^ data[pos + 2] as usize) & ((1 << HASH_BITS) - 1)
+    };
+    let mut head = vec![usize::max_value(); 1 << HASH_BITS];
+    let mut prev = vec![usize::max_value(); WINDOW_SIZE];
+    let insert = |pos: usize, head: &mut Vec<usize>, prev: &mut Vec<usize>| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(pos);
+            prev[pos % WINDOW_SIZE] = head[h];
+            head[h] = pos;
+        }
+    };
+    let longest_match = |pos: usize, head: &Vec<usize>, prev: &Vec<usize>| {
+        let (mut best_len, mut best_distance) = (0, 0);
+        if pos + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let max_len = MAX_MATCH.min(data.len() - pos);
+        let mut candidate = head[hash(pos)];
+        let mut chain = 0;
+        while candidate != usize::max_value() && candidate < pos && pos - candidate < WINDOW_SIZE && chain < MAX_CHAIN {
+            let mut len = 0;
+            while len < max_len && data[candidate + len] == data[pos + len] {
+                len += 1;
+            }
+            if len > best_len {
+                best_len = len;
+                best_distance = pos - candidate;
+                if len >= NICE_MATCH.min(max_len) {
+                    break;
+                }
+            }
+            candidate = prev[candidate % WINDOW_SIZE];
+            chain += 1;
+        }
+        if best_len >= MIN_MATCH { (best_len, best_distance) } else { (0, 0) }
+    };
+
+    let mut tokens = Vec::with_capacity(data.len() / 2);
+    let mut pos = 0;
+    while pos < data.len() {
+        let (len, distance) = longest_match(pos, &head, &prev);
+        insert(pos, &mut head, &mut prev);
+        if len == 0 {
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+            continue;
+        }
+        // lazy matching: prefer a longer match starting at the next byte
+        if len < NICE_MATCH && pos + 1 < data.len() {
+            let (next_len, _) = longest_match(pos + 1, &head, &prev);
+            if next_len > len {
+                tokens.push(Token::Literal(data[pos]));
+                pos += 1;
+                continue;
+            }
+        }
+        tokens.push(Token::Match(len as u16, distance as u16));
+        for p in pos + 1..pos + len {
+            insert(p, &mut head, &mut prev);
+        }
+        pos += len;
+    }
+    tokens
+}
+
+/// Write a block compressed with dynamic Huffman codes
+fn write_dynamic_block(w: &mut BitWriter, tokens: &[Token], last: bool) {
+    let mut literal_freqs = vec![0u32; LITERAL_CODES];
+    let mut distance_freqs = vec![0u32; DISTANCE_CODES];
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literal_freqs[byte as usize] += 1,
+            Token::Match(len, distance) => {
+                literal_freqs[END_OF_BLOCK + 1 + length_code(len as usize)] += 1;
+                distance_freqs[distance_code(distance as usize)] += 1;
+            },
+        }
+    }
+    literal_freqs[END_OF_BLOCK] = 1;
+    let literal_lengths = code_lengths(&literal_freqs, MAX_BITS);
+    let distance_lengths = code_lengths(&distance_freqs, MAX_BITS);
+    let literals = 257.max(literal_lengths.iter().rposition(|&l| l != 0).unwrap() + 1);
+    let distances = 1.max(distance_lengths.iter().rposition(|&l| l != 0).unwrap() + 1);
+
+    // run-length encoded code lengths: (symbol, extra bits value)
+    let all_lengths: Vec<u8> = literal_lengths[..literals].iter().chain(distance_lengths[..distances].iter()).cloned().collect();
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < all_lengths.len() {
+        let value = all_lengths[i];
+        let mut run = 1;
+        while i + run < all_lengths.len() && all_lengths[i + run] == value {
+            run += 1;
+        }
+        i += run;
+        if value == 0 {
+            while run >= 11 {
+                let n = run.min(138);
+                runs.push((18u8, n - 11));
+                run -= n;
+            }
+            if run >= 3 {
+                runs.push((17, run - 3));
+                run = 0;
+            }
+        } else {
+            runs.push((value, 0));
+            run -= 1;
+            while run >= 3 {
+                let n = run.min(6);
+                runs.push((16, n - 3));
+                run -= n;
+            }
+        }
+        for _ in 0..run {
+            runs.push((value, 0));
+        }
+    }
+    let mut code_length_freqs = vec![0u32; CODE_LENGTH_CODES];
+    for &(symbol, _) in &runs {
+        code_length_freqs[symbol as usize] += 1;
+    }
+    let code_length_lengths = code_lengths(&code_length_freqs, MAX_CODE_LENGTH_BITS);
+    let code_length_codes = canonical_codes(&code_length_lengths);
+    let header_lengths = 4.max(CODE_LENGTH_ORDER.iter().rposition(|&s| code_length_lengths[s] != 0).unwrap() + 1);
+
+    w.write(if last { 1 } else { 0 }, 1);
+    w.write(2, 2);
+    w.write((literals - 257) as u32, 5);
+    w.write((distances - 1) as u32, 5);
+    w.write((header_lengths - 4) as u32, 4);
+    for &symbol in &CODE_LENGTH_ORDER[..header_lengths] {
+        w.write(code_length_lengths[symbol] as u32, 3);
+    }
+    for &(symbol, extra) in &runs {
+        let symbol = symbol as usize;
+        w.write_code(code_length_codes[symbol], code_length_lengths[symbol]);
+        match symbol {
+            16 => w.write(extra as u32, 2),
+            17 => w.write(extra as u32, 3),
+            18 => w.write(extra as u32, 7),
+            _ => {},
+        }
+    }
+
+    let literal_codes = canonical_codes(&literal_lengths);
+    let distance_codes = canonical_codes(&distance_lengths);
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => w.write_code(literal_codes[byte as usize], literal_lengths[byte as usize]),
+            Token::Match(len, distance) => {
+                let idx = length_code(len as usize);
+                let symbol = END_OF_BLOCK + 1 + idx;
+                w.write_code(literal_codes[symbol], literal_lengths[symbol]);
+                w.write(len as u32 - LENGTH_BASE[idx] as u32, LENGTH_EXTRA[idx] as u32);
+                let idx = distance_code(distance as usize);
+                w.write_code(distance_codes[idx], distance_lengths[idx]);
+                w.write(distance as u32 - DISTANCE_BASE[idx] as u32, DISTANCE_EXTRA[idx] as u32);
+            },
+        }
+    }
+    w.write_code(literal_codes[END_OF_BLOCK], literal_lengths[END_OF_BLOCK]);
+}
+
+/// Compress data into raw deflate blocks
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter {
+        data: Vec::with_capacity(data.len() / 2),
+        bits: 0,
+        count: 0,
+    };
+    let tokens = lz77(data);
+    if tokens.is_empty() {
+        // the final fixed Huffman block with the end of block code only
+        w.write(1, 1);
+        w.write(1, 2);
+        w.write(0, 7);
+        return w.finish();
+    }
+    let blocks = (tokens.len() + BLOCK_SYMBOLS - 1) / BLOCK_SYMBOLS;
+    for (idx, block) in tokens.chunks(BLOCK_SYMBOLS).enumerate() {
+        write_dynamic_block(&mut w, block, idx + 1 == blocks);
+    }
+    w.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn inflate_stored_block() {
+        let data = [0x01, 0x05, 0x00, 0xFA, 0xFF, b'h', b'e', b'l', b'l', b'o'];
+        assert_eq!(inflate(&data).unwrap(), b"hello".to_vec());
+        // the complement of the length doesn't match
+        assert!(inflate(&[0x01, 0x05, 0x00, 0xFA, 0xFE, b'h', b'e', b'l', b'l', b'o']).is_err());
+        assert!(inflate(&data[..8]).is_err());
+    }
+
+    #[test]
+    fn inflate_fixed_block() {
+        // the repeated string is a match overlapping its own output
+        let data = [0x4B, 0x4C, 0x4A, 0x4E, 0x84, 0x21, 0x45, 0x00];
+        assert_eq!(inflate(&data).unwrap(), b"abcabcabcabc!".to_vec());
+        let stream = [0x78, 0x9C, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x07, 0x00, 0x06, 0x2C, 0x02, 0x15];
+        assert_eq!(decompress(&stream).unwrap(), b"hello".to_vec());
+        let mut corrupt = stream;
+        corrupt[12] ^= 1;
+        assert!(decompress(&corrupt).is_err());
+    }
+
+    #[test]
+    fn inflate_dynamic_block() {
+        let data = [
+            0x1D, 0x89, 0xC1, 0x0D, 0x00, 0x30, 0x10, 0x82, 0x66, 0x55, 0xD8, 0x7F, 0x86, 0x7A, 0x8D, 0x0F,
+            0x08, 0x42, 0x55, 0xCC, 0x98, 0xD4, 0x0D, 0xF8, 0xA9, 0x2C, 0x9C, 0xD3, 0xE6, 0xBE, 0x89, 0x0F,
+        ];
+        assert_eq!(data[0] >> 1 & 3, 2);
+        assert_eq!(inflate(&data).unwrap(), b"ccbdddcdacbdaabdbdbcccdddcdbcaabccddcbbaaabddcbd".to_vec());
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..100000).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }).collect();
+        let text: Vec<u8> = b"the quick brown fox jumps over the lazy dog ".iter().cycle().take(70000).cloned().collect();
+        let inputs = [Vec::new(), vec![7], vec![0; 1000], noise, text];
+        for data in inputs.iter() {
+            assert_eq!(&inflate(&deflate(data)).unwrap(), data);
+            assert_eq!(&decompress(&compress(data)).unwrap(), data);
+        }
+    }
+}